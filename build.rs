@@ -0,0 +1,18 @@
+fn main() {
+    // the functions of the C API are looked up by the native modules loaded
+    // at run time, so the executables export their symbols
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("linux") {
+        println!("cargo:rustc-link-arg=-rdynamic");
+    }
+    // a native module of rust shares the layout of the state, so the compiler
+    // is part of its ABI, see libpackage::ModuleAbi
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = std::process::Command::new(rustc)
+        .arg("-V")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=NAIVE_LUA2_RUSTC={}", version.trim());
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,236 @@
+//! brief: `#[derive(FromLua, IntoLua)]` for naive_lua2
+//!
+//! a struct with named fields maps to a table keyed by the field names,
+//! a tuple struct maps to a sequence and a unit struct to an empty table.
+//! an enum maps to a tagged table: `tag` holds the variant name, the fields
+//! follow the rules of the structs.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Generics, Ident};
+
+const TAG_KEY: &str = "tag";
+
+#[proc_macro_derive(IntoLua)]
+pub fn derive_into_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_into_lua(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(FromLua)]
+pub fn derive_from_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_from_lua(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn add_bound(mut generics: Generics, bound: TokenStream2) -> Generics {
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+    generics
+}
+
+/// brief: the key of a field, its name or its position from 1
+fn field_keys(fields: &Fields) -> Vec<TokenStream2> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => {
+                let name = ident.to_string();
+                quote!(::naive_lua2::common::obj::objvalue::Value::from(#name))
+            }
+            None => {
+                let position = i as i64 + 1;
+                quote!(::naive_lua2::common::obj::objvalue::Value::Integer(#position))
+            }
+        })
+        .collect()
+}
+
+/// brief: the pattern binding every field to `__field<i>`
+fn fields_pattern(path: TokenStream2, fields: &Fields) -> (TokenStream2, Vec<Ident>) {
+    let bindings: Vec<Ident> = (0..fields.len()).map(|i| format_ident!("__field{}", i)).collect();
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!(#path { #(#names: #bindings),* })
+        }
+        Fields::Unnamed(_) => quote!(#path ( #(#bindings),* )),
+        Fields::Unit => quote!(#path),
+    };
+    (pattern, bindings)
+}
+
+/// brief: fill `__table` with the bound fields
+fn set_fields(fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    let keys = field_keys(fields);
+    quote! {
+        #(
+            let __val = ::naive_lua2::common::obj::objconv::IntoLua::into_lua(#bindings, __state)?;
+            __state.raw_set(&__table, #keys, __val)?;
+        )*
+    }
+}
+
+/// brief: build `path` from the fields of `__table`
+fn get_fields(path: TokenStream2, fields: &Fields) -> TokenStream2 {
+    let keys = field_keys(fields);
+    let values = keys.iter().map(|key| {
+        quote! {
+            {
+                let __val = __state.raw_get(&__table, &#key);
+                ::naive_lua2::common::obj::objconv::FromLua::from_lua(__val, __state)?
+            }
+        }
+    });
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!(#path { #(#names: #values),* })
+        }
+        Fields::Unnamed(_) => quote!(#path ( #(#values),* )),
+        Fields::Unit => quote!(#path),
+    }
+}
+
+fn expand_into_lua(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let generics = add_bound(
+        input.generics.clone(),
+        quote!(::naive_lua2::common::obj::objconv::IntoLua),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, bindings) = fields_pattern(quote!(#name), &data.fields);
+            let set = set_fields(&data.fields, &bindings);
+            let nfields = data.fields.len();
+            quote! {
+                let #pattern = self;
+                let __table = __state.create_table_value(0, #nfields)?;
+                #set
+                Ok(::naive_lua2::common::obj::objvalue::Value::Table(__table))
+            }
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let vname = &variant.ident;
+                let tag = vname.to_string();
+                let (pattern, bindings) = fields_pattern(quote!(#name::#vname), &variant.fields);
+                let set = set_fields(&variant.fields, &bindings);
+                let nfields = variant.fields.len() + 1;
+                quote! {
+                    #pattern => {
+                        let __table = __state.create_table_value(0, #nfields)?;
+                        __state.raw_set(
+                            &__table,
+                            ::naive_lua2::common::obj::objvalue::Value::from(#TAG_KEY),
+                            ::naive_lua2::common::obj::objvalue::Value::from(#tag),
+                        )?;
+                        #set
+                        Ok(::naive_lua2::common::obj::objvalue::Value::Table(__table))
+                    }
+                }
+            });
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "IntoLua cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::naive_lua2::common::obj::objconv::IntoLua for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn into_lua(
+                self,
+                __state: &mut ::naive_lua2::common::state::statedef::LuaState,
+            ) -> ::naive_lua2::common::lua::LuaResult<::naive_lua2::common::obj::objvalue::Value> {
+                #body
+            }
+        }
+    })
+}
+
+fn expand_from_lua(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let type_name = name.to_string();
+    let generics = add_bound(
+        input.generics.clone(),
+        quote!(::naive_lua2::common::obj::objconv::FromLua),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let build = get_fields(quote!(#name), &data.fields);
+            quote!(Ok(#build))
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let vname = &variant.ident;
+                let tag = vname.to_string();
+                let build = get_fields(quote!(#name::#vname), &variant.fields);
+                quote!(#tag => Ok(#build),)
+            });
+            quote! {
+                let __tag = __state.raw_get(
+                    &__table,
+                    &::naive_lua2::common::obj::objvalue::Value::from(#TAG_KEY),
+                );
+                let __tag: ::std::string::String =
+                    ::naive_lua2::common::obj::objconv::FromLua::from_lua(__tag, __state)?;
+                match __tag.as_str() {
+                    #(#arms)*
+                    __other => Err(::naive_lua2::common::lua::LuaError::FromLuaConversion {
+                        from: "table",
+                        to: #type_name,
+                        message: Some(format!("unknown variant `{}`", __other)),
+                    }),
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "FromLua cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::naive_lua2::common::obj::objconv::FromLua for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn from_lua(
+                __value: ::naive_lua2::common::obj::objvalue::Value,
+                __state: &mut ::naive_lua2::common::state::statedef::LuaState,
+            ) -> ::naive_lua2::common::lua::LuaResult<Self> {
+                let __table = match __value {
+                    ::naive_lua2::common::obj::objvalue::Value::Table(__table) => __table,
+                    __other => {
+                        return Err(::naive_lua2::common::lua::LuaError::FromLuaConversion {
+                            from: __other.type_name(),
+                            to: #type_name,
+                            message: Some("expected a table".to_string()),
+                        })
+                    }
+                };
+                #body
+            }
+        }
+    })
+}
@@ -0,0 +1,63 @@
+use naive_lua2::common::lua::LuaError;
+use naive_lua2::common::obj::objtype::LRFUNC;
+use naive_lua2::common::obj::objvalue::{Function, Value};
+use naive_lua2::common::state::statedef::LuaState;
+use naive_lua2::stdlib::libaux::{check_integer, check_lstring};
+use naive_lua2::stdlib::libpackage::module_call;
+
+const SAMPLE_FUNCS: &[(&str, LRFUNC)] = &[("add", sample_add), ("greet", sample_greet)];
+
+/// brief: add(a, b), the sum of two integers
+fn sample_add(state: &mut LuaState) -> usize {
+    module_call(state, |state| {
+        let a = check_integer(state, 1, "add");
+        let b = check_integer(state, 2, "add");
+        state.push_integer(a.wrapping_add(b));
+        Ok(1)
+    })
+}
+
+/// brief: greet(name), a greeting of `name`
+fn sample_greet(state: &mut LuaState) -> usize {
+    module_call(state, |state| {
+        let name = check_lstring(state, 1, "greet");
+        state.push_str(&format!("hello, {}", name.to_string_lossy()));
+        Ok(1)
+    })
+}
+
+/// brief: the module `sample`, its functions and its version
+#[no_mangle]
+pub extern "C" fn luaopen_sample(state: &mut LuaState) -> usize {
+    module_call(state, |state| {
+        let module = state.create_table_value(0, SAMPLE_FUNCS.len() + 1)?;
+        for (name, lrfunc) in SAMPLE_FUNCS {
+            state.raw_set(
+                &module,
+                Value::from(*name),
+                Value::Function(Function::light(*lrfunc)),
+            )?;
+        }
+        state.raw_set(&module, Value::from("version"), Value::from("sample 1.0"))?;
+        state.push_value(&Value::Table(module));
+        Ok(1)
+    })
+}
+
+/// brief: the submodule `sample.sub`, the name and the file it was loaded with
+#[no_mangle]
+pub extern "C" fn luaopen_sample_sub(state: &mut LuaState) -> usize {
+    module_call(state, |state| {
+        let (name, filename): (String, String) = state.get_args()?;
+        state.push_str(&format!("{} from {}", name, filename));
+        Ok(1)
+    })
+}
+
+/// brief: the submodule `sample.broken`, which fails to open
+#[no_mangle]
+pub extern "C" fn luaopen_sample_broken(state: &mut LuaState) -> usize {
+    module_call(state, |_| {
+        Err(LuaError::Runtime("sample.broken cannot be opened".to_string()))
+    })
+}
@@ -0,0 +1,578 @@
+use std::ffi::{c_char, c_int, c_void, CString};
+
+use crate::common::lua::LuaError;
+use crate::common::obj::objtype::{FLT, INT};
+use crate::common::obj::objvalue::Value;
+use crate::common::state::statedef::LuaState;
+use crate::stdlib::libaux::{self, running_function_name};
+use crate::stdlib::libpackage::LOADED_TABLE;
+
+use super::capidef::*;
+
+/// brief: `LUAL_NUMSIZES`, the sizes of lua_Integer and lua_Number the
+/// headers were written for
+pub const LUAL_NUMSIZES: usize = std::mem::size_of::<INT>() * 16 + std::mem::size_of::<FLT>();
+
+pub const LUA_NOREF: c_int = -2;
+pub const LUA_REFNIL: c_int = -1;
+
+/// brief: the slot of a table of references that holds its free list
+const FREELIST: INT = 3;
+
+/// brief: a function of a library, `luaL_Reg`. the list ends with a null
+/// name, a null function is a placeholder set to false
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct luaL_Reg {
+    pub name: *const c_char,
+    pub func: Option<CFunction>,
+}
+
+fn fname(state: &mut LuaState) -> String {
+    running_function_name(state).unwrap_or_else(|| "?".to_string())
+}
+
+fn c_str(s: *const c_char) -> String {
+    // SAFETY: the strings of C given to the library end with a zero
+    String::from_utf8_lossy(unsafe { c_bytes(s) }).into_owned()
+}
+
+/// brief: the string argument at `arg`, its pointer lives while the argument
+/// is on the stack
+unsafe fn check_string(l: *mut LuaState, arg: c_int, len: *mut usize) -> *const c_char {
+    let s = lua_tolstring(l, arg, len);
+    if s.is_null() {
+        let state = state_of(l);
+        let fname = fname(state);
+        libaux::type_error(state, arg as isize, &fname, "string");
+    }
+    s
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkversion_(l: *mut LuaState, ver: FLT, sz: usize) {
+    let state = state_of(l);
+    if sz != LUAL_NUMSIZES {
+        state.error(LuaError::Runtime(
+            "core and library have incompatible numeric types".to_string(),
+        ));
+    }
+    if ver != LUA_VERSION_NUM as FLT {
+        state.error(LuaError::Runtime(format!(
+            "version mismatch: app. needs {}, Lua core provides {}",
+            ver, LUA_VERSION_NUM
+        )));
+    }
+}
+
+/// brief: push the field `e` of the metatable of obj, nothing is pushed and
+/// LUA_TNIL is returned when there is none
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_getmetafield(l: *mut LuaState, obj: c_int, e: *const c_char) -> c_int {
+    let state = state_of(l);
+    let val = value_at(state, obj);
+    match state.get_metafield(&val, &c_str(e)) {
+        Value::Nil => LUA_TNIL,
+        field => {
+            state.push_value(&field);
+            type_tag(&field)
+        }
+    }
+}
+
+/// brief: call the metamethod `e` of obj with obj, its result is pushed
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_callmeta(l: *mut LuaState, obj: c_int, e: *const c_char) -> c_int {
+    let state = state_of(l);
+    let val = value_at(state, obj);
+    let handler = state.get_metafield(&val, &c_str(e));
+    if handler.is_nil() {
+        return 0;
+    }
+    let result = match state.call_value(&handler, [val].into()) {
+        Ok(mut results) => results.pop_front().unwrap_or(Value::Nil),
+        Err(err) => state.error(err),
+    };
+    state.push_value(&result);
+    1
+}
+
+/// brief: push the string of any value, `__tostring` and `__name` are
+/// honoured
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_tolstring(
+    l: *mut LuaState,
+    idx: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    let state = state_of(l);
+    let val = value_at(state, idx);
+    let s = libaux::tolstring(state, &val);
+    state.push_value(&Value::String(s));
+    lua_tolstring(l, -1, len)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_argerror(
+    l: *mut LuaState,
+    arg: c_int,
+    extramsg: *const c_char,
+) -> c_int {
+    let state = state_of(l);
+    let fname = fname(state);
+    libaux::arg_error(state, arg as isize, &fname, &c_str(extramsg))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_typeerror(l: *mut LuaState, arg: c_int, tname: *const c_char) -> c_int {
+    let state = state_of(l);
+    let fname = fname(state);
+    libaux::type_error(state, arg as isize, &fname, &c_str(tname))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checklstring(
+    l: *mut LuaState,
+    arg: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    check_string(l, arg, len)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optlstring(
+    l: *mut LuaState,
+    arg: c_int,
+    def: *const c_char,
+    len: *mut usize,
+) -> *const c_char {
+    if libaux::is_none_or_nil(state_of(l), arg as isize) {
+        if !len.is_null() {
+            *len = if def.is_null() { 0 } else { c_bytes(def).len() };
+        }
+        return def;
+    }
+    check_string(l, arg, len)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checknumber(l: *mut LuaState, arg: c_int) -> FLT {
+    let state = state_of(l);
+    let fname = fname(state);
+    libaux::check_number(state, arg as isize, &fname)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optnumber(l: *mut LuaState, arg: c_int, def: FLT) -> FLT {
+    if libaux::is_none_or_nil(state_of(l), arg as isize) {
+        return def;
+    }
+    luaL_checknumber(l, arg)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkinteger(l: *mut LuaState, arg: c_int) -> INT {
+    let state = state_of(l);
+    let fname = fname(state);
+    libaux::check_integer(state, arg as isize, &fname)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optinteger(l: *mut LuaState, arg: c_int, def: INT) -> INT {
+    let state = state_of(l);
+    let fname = fname(state);
+    libaux::opt_integer(state, arg as isize, &fname, def)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkstack(l: *mut LuaState, sz: c_int, msg: *const c_char) {
+    if lua_checkstack(l, sz) == 0 {
+        let msg = if msg.is_null() {
+            "stack overflow".to_string()
+        } else {
+            format!("stack overflow ({})", c_str(msg))
+        };
+        state_of(l).error(LuaError::Runtime(msg));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checktype(l: *mut LuaState, arg: c_int, t: c_int) {
+    if lua_type(l, arg) != t {
+        luaL_typeerror(l, arg, lua_typename(l, t));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkany(l: *mut LuaState, arg: c_int) {
+    let state = state_of(l);
+    let fname = fname(state);
+    libaux::check_any(state, arg as isize, &fname);
+}
+
+/// brief: create the metatable `tname` in the registry with its `__name`
+/// and push it, 0 and the existing one when the registry has it already
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_newmetatable(l: *mut LuaState, tname: *const c_char) -> c_int {
+    let state = state_of(l);
+    let (metatable, created) = libaux::new_metatable(state, &c_str(tname));
+    state.push_value(&Value::Table(metatable));
+    created as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_setmetatable(l: *mut LuaState, tname: *const c_char) {
+    lua_getfield(l, LUA_REGISTRYINDEX, tname);
+    lua_setmetatable(l, -2);
+}
+
+/// brief: the memory of the userdata at `ud` when its metatable is the one
+/// registered as `tname`, null otherwise
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_testudata(
+    l: *mut LuaState,
+    ud: c_int,
+    tname: *const c_char,
+) -> *mut c_void {
+    let p = lua_touserdata(l, ud);
+    if p.is_null() || lua_getmetatable(l, ud) == 0 {
+        return std::ptr::null_mut();
+    }
+    lua_getfield(l, LUA_REGISTRYINDEX, tname);
+    let same = lua_rawequal(l, -1, -2) != 0;
+    lua_settop(l, -3);
+    if same {
+        p
+    } else {
+        std::ptr::null_mut()
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkudata(
+    l: *mut LuaState,
+    ud: c_int,
+    tname: *const c_char,
+) -> *mut c_void {
+    let p = luaL_testudata(l, ud, tname);
+    if p.is_null() {
+        luaL_typeerror(l, ud, tname);
+    }
+    p
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_where(l: *mut LuaState, lvl: c_int) {
+    let state = state_of(l);
+    let position = libaux::where_(state, lvl.max(0) as usize);
+    state.push_str(&position);
+}
+
+/// brief: push the traceback of the calls of `l1` from `level`, after
+/// `msg` when it is not null
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_traceback(
+    l: *mut LuaState,
+    l1: *mut LuaState,
+    msg: *const c_char,
+    level: c_int,
+) {
+    let msg = (!msg.is_null()).then(|| c_str(msg));
+    let text = libaux::traceback(state_of(l1), msg.as_deref(), level.max(0) as usize);
+    state_of(l).push_str(&text);
+}
+
+/// brief: the position of the string argument in the list `lst` ended by a
+/// null, `def` is taken when the argument is absent
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkoption(
+    l: *mut LuaState,
+    arg: c_int,
+    def: *const c_char,
+    lst: *const *const c_char,
+) -> c_int {
+    let name = if def.is_null() {
+        luaL_checklstring(l, arg, std::ptr::null_mut())
+    } else {
+        luaL_optlstring(l, arg, def, std::ptr::null_mut())
+    };
+    let name = c_bytes(name);
+    let mut i = 0;
+    while !(*lst.add(i)).is_null() {
+        if c_bytes(*lst.add(i)) == name {
+            return i as c_int;
+        }
+        i += 1;
+    }
+    let msg = format!("invalid option '{}'", String::from_utf8_lossy(name));
+    let state = state_of(l);
+    let fname = fname(state);
+    libaux::arg_error(state, arg as isize, &fname, &msg)
+}
+
+/// brief: pop a value into the table at `t` and give its reference, freed
+/// references are reused. nil gives LUA_REFNIL
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_ref(l: *mut LuaState, t: c_int) -> c_int {
+    let state = state_of(l);
+    let table = table_at(state, t);
+    let val = state.pop_value();
+    if val.is_nil() {
+        return LUA_REFNIL;
+    }
+    let free = match state.raw_get(&table, &Value::Integer(FREELIST)) {
+        Value::Integer(free) => free,
+        _ => {
+            let _ = state.raw_set(&table, Value::Integer(FREELIST), Value::Integer(0));
+            0
+        }
+    };
+    let reference = if free != 0 {
+        let next = state.raw_get(&table, &Value::Integer(free));
+        let _ = state.raw_set(&table, Value::Integer(FREELIST), next);
+        free
+    } else {
+        state.raw_len(&table) + 1
+    };
+    let _ = state.raw_set(&table, Value::Integer(reference), val);
+    reference as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_unref(l: *mut LuaState, t: c_int, reference: c_int) {
+    if reference < 0 {
+        return;
+    }
+    let state = state_of(l);
+    let table = table_at(state, t);
+    let free = state.raw_get(&table, &Value::Integer(FREELIST));
+    let _ = state.raw_set(&table, Value::Integer(reference as INT), free);
+    let _ = state.raw_set(&table, Value::Integer(FREELIST), Value::Integer(reference as INT));
+}
+
+/// brief: load a chunk and push its function, or push the message and give
+/// LUA_ERRSYNTAX
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadbufferx(
+    l: *mut LuaState,
+    buff: *const c_char,
+    sz: usize,
+    name: *const c_char,
+    mode: *const c_char,
+) -> c_int {
+    let state = state_of(l);
+    let chunk = if sz == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(buff as *const u8, sz)
+    };
+    let name = if name.is_null() {
+        "?".to_string()
+    } else {
+        c_str(name)
+    };
+    let mode = if mode.is_null() {
+        "bt".to_string()
+    } else {
+        c_str(mode)
+    };
+    match state.load(chunk, &name, &mode) {
+        Ok(function) => {
+            state.push_value(&Value::Function(function));
+            LUA_OK
+        }
+        Err(err) => {
+            state.push_value(&err.into_value());
+            LUA_ERRSYNTAX
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadstring(l: *mut LuaState, s: *const c_char) -> c_int {
+    luaL_loadbufferx(l, s, c_bytes(s).len(), s, std::ptr::null())
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_len(l: *mut LuaState, idx: c_int) -> INT {
+    let state = state_of(l);
+    let val = value_at(state, idx);
+    libaux::len(state, &val)
+}
+
+/// brief: set the functions of `reg` into the table below the top `nup`
+/// values, each one a closure with those values as its upvalues
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_setfuncs(l: *mut LuaState, reg: *const luaL_Reg, nup: c_int) {
+    luaL_checkstack(l, nup, c"too many upvalues".as_ptr());
+    let mut reg = reg;
+    while !(*reg).name.is_null() {
+        match (*reg).func {
+            Some(func) => {
+                for _ in 0..nup {
+                    lua_pushvalue(l, -nup);
+                }
+                lua_pushcclosure(l, func, nup);
+            }
+            None => lua_pushboolean(l, 0),
+        }
+        lua_setfield(l, -(nup + 2), (*reg).name);
+        reg = reg.add(1);
+    }
+    lua_settop(l, -nup - 1);
+}
+
+/// brief: push the table at `idx[fname]`, a new one is put there when
+/// missing and 0 is given
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_getsubtable(
+    l: *mut LuaState,
+    idx: c_int,
+    fname: *const c_char,
+) -> c_int {
+    if lua_getfield(l, idx, fname) == LUA_TTABLE {
+        return 1;
+    }
+    let idx = lua_absindex(l, idx);
+    lua_settop(l, -2);
+    lua_createtable(l, 0, 0);
+    lua_pushvalue(l, -1);
+    lua_setfield(l, idx, fname);
+    0
+}
+
+/// brief: push the module `modname`, opened by `openf` unless it is loaded
+/// already, and make it global when `glb` is set
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_requiref(
+    l: *mut LuaState,
+    modname: *const c_char,
+    openf: CFunction,
+    glb: c_int,
+) {
+    let loaded = CString::new(LOADED_TABLE).expect("a name without zeros");
+    luaL_getsubtable(l, LUA_REGISTRYINDEX, loaded.as_ptr());
+    lua_getfield(l, -1, modname);
+    if lua_toboolean(l, -1) == 0 {
+        lua_settop(l, -2);
+        lua_pushcclosure(l, openf, 0);
+        lua_pushstring(l, modname);
+        lua_callk(l, 1, 1, 0, None);
+        lua_pushvalue(l, -1);
+        lua_setfield(l, -3, modname);
+    }
+    lua_rotate(l, -2, -1);
+    lua_settop(l, -2);
+    if glb != 0 {
+        lua_pushvalue(l, -1);
+        lua_setglobal(l, modname);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::{c_int, CStr};
+    use std::ptr::{null, null_mut};
+
+    use crate::common::lua::LuaResult;
+    use crate::common::obj::objvalue::Function;
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    use super::*;
+
+    unsafe extern "C-unwind" fn twice(l: *mut LuaState) -> c_int {
+        let n = luaL_checkinteger(l, 1);
+        lua_pushinteger(l, n * 2);
+        1
+    }
+
+    unsafe extern "C-unwind" fn open_twice(l: *mut LuaState) -> c_int {
+        lua_createtable(l, 0, 1);
+        let reg = [
+            luaL_Reg {
+                name: c"twice".as_ptr(),
+                func: Some(twice),
+            },
+            luaL_Reg {
+                name: null(),
+                func: None,
+            },
+        ];
+        luaL_setfuncs(l, reg.as_ptr(), 0);
+        1
+    }
+
+    #[test]
+    fn requiref_and_argument_errors() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let l = state as *mut LuaState;
+        unsafe {
+            luaL_requiref(l, c"twice".as_ptr(), open_twice, 1);
+            lua_settop(l, 0);
+        }
+        let state = unsafe { &mut *l };
+        let module: Value = state.get_global("twice").unwrap();
+        let Value::Table(module) = module else {
+            panic!("the module is global");
+        };
+        let twice: Function = module.get(state, "twice").unwrap();
+        let n: INT = twice.call(state, 21).unwrap();
+        assert_eq!(n, 42);
+        let err = twice.call::<_, INT>(state, "x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad argument #1 to 'twice.twice' (number expected, got string)"
+        );
+        let loaded: LuaResult<Value> = state.registry().get(state, "_LOADED");
+        let Ok(Value::Table(loaded)) = loaded else {
+            panic!("the registry has the loaded modules");
+        };
+        assert!(loaded.contains_key(state, "twice").unwrap());
+    }
+
+    #[test]
+    fn metatables_and_references() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let l = state as *mut LuaState;
+        unsafe {
+            assert_eq!(luaL_newmetatable(l, c"point".as_ptr()), 1);
+            assert_eq!(luaL_newmetatable(l, c"point".as_ptr()), 0);
+            assert_eq!(lua_rawequal(l, 1, 2), 1);
+            lua_settop(l, 0);
+
+            let p = lua_newuserdatauv(l, 8, 0);
+            assert!(luaL_testudata(l, 1, c"point".as_ptr()).is_null());
+            luaL_setmetatable(l, c"point".as_ptr());
+            assert_eq!(luaL_testudata(l, 1, c"point".as_ptr()), p);
+            assert_eq!(lua_gettop(l), 1);
+            assert_eq!(luaL_getmetafield(l, 1, c"__name".as_ptr()), LUA_TSTRING);
+            assert_eq!(
+                CStr::from_ptr(lua_tolstring(l, -1, null_mut())).to_bytes(),
+                b"point"
+            );
+            assert_eq!(luaL_getmetafield(l, 1, c"__index".as_ptr()), LUA_TNIL);
+            let s = CStr::from_ptr(luaL_tolstring(l, 1, null_mut()));
+            assert!(s.to_bytes().starts_with(b"point: 0x"));
+            lua_settop(l, 0);
+
+            lua_createtable(l, 0, 0);
+            lua_pushstring(l, c"a".as_ptr());
+            let a = luaL_ref(l, 1);
+            lua_pushstring(l, c"b".as_ptr());
+            let b = luaL_ref(l, 1);
+            lua_pushnil(l);
+            assert_eq!(luaL_ref(l, 1), LUA_REFNIL);
+            assert_ne!(a, b);
+            lua_rawgeti(l, 1, b as INT);
+            assert_eq!(CStr::from_ptr(lua_tolstring(l, -1, null_mut())).to_bytes(), b"b");
+            luaL_unref(l, 1, a);
+            lua_pushstring(l, c"c".as_ptr());
+            assert_eq!(luaL_ref(l, 1), a);
+
+            assert_eq!(luaL_loadstring(l, c"return 1".as_ptr()), 3);
+        }
+    }
+}
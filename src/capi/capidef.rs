@@ -0,0 +1,1119 @@
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use crate::common::gc::gcdef::{GcObject, LuaUserData};
+use crate::common::lua::{LuaError, LUA_MUL_RET, LUA_REGISTRY_INDEX};
+use crate::common::obj::objconv::{fmt_number, str_to_number, MultiValue};
+use crate::common::obj::objtable::float_to_integer;
+use crate::common::obj::objtype::{FLT, INT};
+use crate::common::obj::objvalue::{Function, LuaString, Table, Value};
+use crate::common::state::statedef::{LuaState, StkElem};
+
+pub const LUA_VERSION_NUM: c_int = 504;
+
+pub const LUA_OK: c_int = 0;
+pub const LUA_ERRRUN: c_int = 2;
+pub const LUA_ERRSYNTAX: c_int = 3;
+pub const LUA_ERRMEM: c_int = 4;
+pub const LUA_ERRERR: c_int = 5;
+
+pub const LUA_TNONE: c_int = -1;
+pub const LUA_TNIL: c_int = 0;
+pub const LUA_TBOOLEAN: c_int = 1;
+pub const LUA_TLIGHTUSERDATA: c_int = 2;
+pub const LUA_TNUMBER: c_int = 3;
+pub const LUA_TSTRING: c_int = 4;
+pub const LUA_TTABLE: c_int = 5;
+pub const LUA_TFUNCTION: c_int = 6;
+pub const LUA_TUSERDATA: c_int = 7;
+pub const LUA_TTHREAD: c_int = 8;
+
+pub const LUA_OPEQ: c_int = 0;
+pub const LUA_OPLT: c_int = 1;
+pub const LUA_OPLE: c_int = 2;
+
+pub const LUA_REGISTRYINDEX: c_int = LUA_REGISTRY_INDEX as c_int;
+
+/// brief: a function of C, `lua_CFunction`
+pub type CFunction = unsafe extern "C-unwind" fn(*mut LuaState) -> c_int;
+
+/// brief: the continuation of a call, `lua_KFunction`. this machine has no
+/// coroutines, a continuation is never called
+pub type KFunction = unsafe extern "C-unwind" fn(*mut LuaState, c_int, isize) -> c_int;
+
+thread_local! {
+    // the upvalues of the C closures running, the innermost last
+    static UPVALUES: RefCell<Vec<Option<Table>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// brief: the upvalues of a C closure while it runs, left on return and on
+/// an error
+struct RunningClosure;
+
+impl RunningClosure {
+    fn enter(upvalues: Option<Table>) -> Self {
+        UPVALUES.with(|running| running.borrow_mut().push(upvalues));
+        RunningClosure
+    }
+}
+
+impl Drop for RunningClosure {
+    fn drop(&mut self) {
+        UPVALUES.with(|running| running.borrow_mut().pop());
+    }
+}
+
+fn running_upvalues() -> Option<Table> {
+    UPVALUES.with(|running| running.borrow().last().cloned().flatten())
+}
+
+/// brief: a block of memory of C, the memory of a full userdata made by
+/// lua_newuserdatauv. it is held by a raw pointer, not a box: C writes
+/// through the pointer it was given while the block moves in the heap
+pub(crate) struct CBlock(NonNull<[MaxAlign]>, usize);
+
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct MaxAlign([u8; 16]);
+
+impl CBlock {
+    fn new(size: usize) -> Self {
+        let blocks = size.div_ceil(std::mem::size_of::<MaxAlign>());
+        let memory = Box::into_raw(vec![MaxAlign([0; 16]); blocks].into_boxed_slice());
+        // SAFETY: a box is never null
+        CBlock(unsafe { NonNull::new_unchecked(memory) }, size)
+    }
+
+    fn as_ptr(&self) -> *mut c_void {
+        self.0.as_ptr() as *mut c_void
+    }
+}
+
+impl Drop for CBlock {
+    fn drop(&mut self) {
+        // SAFETY: the memory was given up by a box in new, and is freed once
+        drop(unsafe { Box::from_raw(self.0.as_ptr()) });
+    }
+}
+
+pub(crate) unsafe fn state_of<'a>(l: *mut LuaState) -> &'a mut LuaState {
+    // SAFETY: C passes the state it was given by the machine
+    &mut *l
+}
+
+/// brief: the bytes of a string of C
+pub(crate) unsafe fn c_bytes<'a>(s: *const c_char) -> &'a [u8] {
+    CStr::from_ptr(s).to_bytes()
+}
+
+/// brief: the upvalue a pseudo index below the registry stands for
+fn upvalue_index(idx: c_int) -> Option<INT> {
+    (idx < LUA_REGISTRYINDEX).then(|| (LUA_REGISTRYINDEX - idx) as INT)
+}
+
+/// brief: the value at an index of C, the upvalues of the running closure
+/// included
+pub(crate) fn elem_at(state: &mut LuaState, idx: c_int) -> StkElem {
+    match upvalue_index(idx) {
+        Some(n) => match running_upvalues() {
+            Some(upvalues) => {
+                let gc = state.check_ref(&upvalues.0);
+                state.table_get_raw(gc, &StkElem::new_integer(n))
+            }
+            None => StkElem::default(),
+        },
+        None => state.get_elem_at(idx as isize),
+    }
+}
+
+pub(crate) fn value_at(state: &mut LuaState, idx: c_int) -> Value {
+    let elem = elem_at(state, idx);
+    state.elem_to_value(&elem)
+}
+
+pub(crate) fn is_none_at(state: &mut LuaState, idx: c_int) -> bool {
+    match upvalue_index(idx) {
+        Some(n) => running_upvalues().is_none_or(|upvalues| n > upvalues.raw_len(state)),
+        None => state.is_none(idx as isize),
+    }
+}
+
+fn set_elem_at(state: &mut LuaState, idx: c_int, elem: StkElem) {
+    match upvalue_index(idx) {
+        Some(n) => {
+            let upvalues = running_upvalues().expect("an upvalue of a C closure");
+            let gc = state.check_ref(&upvalues.0);
+            let _ = state.table_set_raw(gc, StkElem::new_integer(n), elem);
+        }
+        None => state.set_elem_at(idx as isize, elem),
+    }
+}
+
+/// brief: the table at an index, an error for another value
+pub(crate) fn table_at(state: &mut LuaState, idx: c_int) -> Table {
+    match value_at(state, idx) {
+        Value::Table(table) => table,
+        val => state.error(LuaError::Runtime(format!(
+            "table expected, got {}",
+            val.type_name()
+        ))),
+    }
+}
+
+/// brief: the pointer to the zero ended bytes of a string on the stack
+fn string_ptr(state: &LuaState, elem: &StkElem) -> Option<(*const c_char, usize)> {
+    let bytes = state.get_heap().get_c_string(elem.as_gc()?)?;
+    Some((bytes.as_ptr() as *const c_char, bytes.len() - 1))
+}
+
+/// brief: the tag of the type of a value
+pub(crate) fn type_tag(val: &Value) -> c_int {
+    match val {
+        Value::Nil => LUA_TNIL,
+        Value::Boolean(_) => LUA_TBOOLEAN,
+        Value::LightUserData(_) => LUA_TLIGHTUSERDATA,
+        Value::Integer(_) | Value::Number(_) => LUA_TNUMBER,
+        Value::String(_) => LUA_TSTRING,
+        Value::Table(_) => LUA_TTABLE,
+        Value::Function(_) => LUA_TFUNCTION,
+        Value::UserData(_) => LUA_TUSERDATA,
+        Value::Thread(_) => LUA_TTHREAD,
+    }
+}
+
+/// brief: a number of a value, strings are converted
+pub(crate) fn to_number(val: &Value) -> Option<FLT> {
+    match val {
+        Value::Integer(i) => Some(*i as FLT),
+        Value::Number(n) => Some(*n),
+        Value::String(s) => match str_to_number(s.as_bytes())? {
+            Value::Integer(i) => Some(i as FLT),
+            Value::Number(n) => Some(n),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// brief: an integer of a value, floats of an integral value and strings are
+/// converted
+pub(crate) fn to_integer(val: &Value) -> Option<INT> {
+    match val {
+        Value::Integer(i) => Some(*i),
+        Value::Number(n) => float_to_integer(*n),
+        Value::String(s) => match str_to_number(s.as_bytes())? {
+            Value::Integer(i) => Some(i),
+            Value::Number(n) => float_to_integer(n),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// brief: a function of the machine calling a C function, with `upvalues`
+/// reachable by lua_upvalueindex
+pub(crate) fn c_closure(state: &mut LuaState, f: CFunction, upvalues: Option<Table>) -> Function {
+    let rfunc = Rc::new(move |state: &mut LuaState| {
+        let _running = RunningClosure::enter(upvalues.clone());
+        // SAFETY: the function was pushed by C as a lua_CFunction
+        let n = unsafe { f(state) };
+        n.max(0) as usize
+    });
+    state.create_c_closure(rfunc, f as *const ())
+}
+
+fn raise(state: &mut LuaState, result: Result<Value, LuaError>) -> Value {
+    match result {
+        Ok(val) => val,
+        Err(err) => state.error(err),
+    }
+}
+
+/// brief: the values of the top `n` slots, removed from the stack
+fn pop_values(state: &mut LuaState, n: usize) -> Vec<Value> {
+    let values = (1..=n as isize).rev().map(|i| state.get_value(-i)).collect();
+    state.set_top(-(n as isize) - 1);
+    values
+}
+
+// STACK
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_absindex(l: *mut LuaState, idx: c_int) -> c_int {
+    state_of(l).abs_index(idx as isize) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_gettop(l: *mut LuaState) -> c_int {
+    state_of(l).get_top() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_settop(l: *mut LuaState, idx: c_int) {
+    state_of(l).set_top(idx as isize);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushvalue(l: *mut LuaState, idx: c_int) {
+    let state = state_of(l);
+    let elem = elem_at(state, idx);
+    state.push_obj(elem);
+}
+
+/// brief: rotate the values from idx to the top by n slots toward the top
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rotate(l: *mut LuaState, idx: c_int, n: c_int) {
+    let state = state_of(l);
+    let first = state.abs_index(idx as isize);
+    let top = state.get_top() as isize;
+    let mut elems: Vec<StkElem> = (first..=top).map(|i| state.get_elem_at(i)).collect();
+    if elems.is_empty() {
+        return;
+    }
+    let len = elems.len() as isize;
+    elems.rotate_right(n.rem_euclid(len as c_int) as usize);
+    for (i, elem) in (first..=top).zip(elems) {
+        state.set_elem_at(i, elem);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_copy(l: *mut LuaState, fromidx: c_int, toidx: c_int) {
+    let state = state_of(l);
+    let elem = elem_at(state, fromidx);
+    set_elem_at(state, toidx, elem);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_checkstack(l: *mut LuaState, n: c_int) -> c_int {
+    state_of(l).stack_check(n.max(0) as usize).is_ok() as c_int
+}
+
+// ACCESS
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isnumber(l: *mut LuaState, idx: c_int) -> c_int {
+    to_number(&value_at(state_of(l), idx)).is_some() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isstring(l: *mut LuaState, idx: c_int) -> c_int {
+    let elem = elem_at(state_of(l), idx);
+    (elem.is_string() || elem.is_number()) as c_int
+}
+
+/// brief: every function of this machine is a native one
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_iscfunction(l: *mut LuaState, idx: c_int) -> c_int {
+    elem_at(state_of(l), idx).is_function() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isinteger(l: *mut LuaState, idx: c_int) -> c_int {
+    elem_at(state_of(l), idx).is_integer() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isuserdata(l: *mut LuaState, idx: c_int) -> c_int {
+    let elem = elem_at(state_of(l), idx);
+    (elem.is_ud() || elem.is_full_ud()) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_type(l: *mut LuaState, idx: c_int) -> c_int {
+    let state = state_of(l);
+    if is_none_at(state, idx) {
+        return LUA_TNONE;
+    }
+    type_tag(&value_at(state, idx))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_typename(_l: *mut LuaState, tp: c_int) -> *const c_char {
+    let name: &'static [u8] = match tp {
+        LUA_TNIL => b"nil\0",
+        LUA_TBOOLEAN => b"boolean\0",
+        LUA_TLIGHTUSERDATA | LUA_TUSERDATA => b"userdata\0",
+        LUA_TNUMBER => b"number\0",
+        LUA_TSTRING => b"string\0",
+        LUA_TTABLE => b"table\0",
+        LUA_TFUNCTION => b"function\0",
+        LUA_TTHREAD => b"thread\0",
+        _ => b"no value\0",
+    };
+    name.as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tonumberx(l: *mut LuaState, idx: c_int, isnum: *mut c_int) -> FLT {
+    let n = to_number(&value_at(state_of(l), idx));
+    if !isnum.is_null() {
+        *isnum = n.is_some() as c_int;
+    }
+    n.unwrap_or(0.0)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tointegerx(l: *mut LuaState, idx: c_int, isnum: *mut c_int) -> INT {
+    let n = to_integer(&value_at(state_of(l), idx));
+    if !isnum.is_null() {
+        *isnum = n.is_some() as c_int;
+    }
+    n.unwrap_or(0)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_toboolean(l: *mut LuaState, idx: c_int) -> c_int {
+    !elem_at(state_of(l), idx).is_falsy() as c_int
+}
+
+/// brief: the bytes of a string or a number, a number is turned into a
+/// string in its slot. the pointer lives as long as the value on the stack
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tolstring(
+    l: *mut LuaState,
+    idx: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    let state = state_of(l);
+    let mut elem = elem_at(state, idx);
+    if elem.is_number() {
+        let text = match state.elem_to_value(&elem) {
+            Value::Integer(i) => i.to_string(),
+            Value::Number(n) => fmt_number(n),
+            _ => unreachable!("a number"),
+        };
+        elem = StkElem::new_string(state.new_string(text.as_bytes()));
+        set_elem_at(state, idx, elem);
+    }
+    match string_ptr(state, &elem) {
+        Some((ptr, n)) => {
+            if !len.is_null() {
+                *len = n;
+            }
+            ptr
+        }
+        None => {
+            if !len.is_null() {
+                *len = 0;
+            }
+            std::ptr::null()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawlen(l: *mut LuaState, idx: c_int) -> u64 {
+    let state = state_of(l);
+    match value_at(state, idx) {
+        Value::String(s) => s.as_bytes().len() as u64,
+        Value::Table(table) => state.raw_len(&table) as u64,
+        Value::UserData(ud) => {
+            let gc = state.check_ref(&ud.0);
+            match state
+                .get_heap()
+                .get_ud(gc)
+                .and_then(|ud| ud.data.downcast_ref::<CBlock>())
+            {
+                Some(block) => block.1 as u64,
+                None => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// brief: the memory of a full userdata, the value of a rust userdata, or
+/// the pointer of a light userdata
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_touserdata(l: *mut LuaState, idx: c_int) -> *mut c_void {
+    let state = state_of(l);
+    match value_at(state, idx) {
+        Value::LightUserData(p) => p as *mut c_void,
+        Value::UserData(ud) => {
+            let gc = state.check_ref(&ud.0);
+            match state.get_heap().get_ud(gc) {
+                Some(LuaUserData { data, .. }) => match data.downcast_ref::<CBlock>() {
+                    Some(block) => block.as_ptr(),
+                    None => &**data as *const dyn std::any::Any as *mut c_void,
+                },
+                None => std::ptr::null_mut(),
+            }
+        }
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_topointer(l: *mut LuaState, idx: c_int) -> *const c_void {
+    let state = state_of(l);
+    match value_at(state, idx) {
+        Value::UserData(_) | Value::LightUserData(_) => lua_touserdata(l, idx),
+        val @ (Value::Table(_) | Value::Function(_) | Value::String(_)) => {
+            crate::stdlib::libaux::value_address(&val) as *const c_void
+        }
+        _ => std::ptr::null(),
+    }
+}
+
+/// brief: the C function of a value pushed by lua_pushcclosure, null for
+/// any other value
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tocfunction(l: *mut LuaState, idx: c_int) -> Option<CFunction> {
+    let state = state_of(l);
+    let cfunc = match value_at(state, idx) {
+        Value::Function(function) => state.get_cfunction(&function),
+        _ => return None,
+    };
+    // SAFETY: a closure keeps the address of the lua_CFunction it calls
+    (!cfunc.is_null()).then(|| std::mem::transmute::<*const (), CFunction>(cfunc))
+}
+
+// COMPARISON
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawequal(l: *mut LuaState, idx1: c_int, idx2: c_int) -> c_int {
+    let state = state_of(l);
+    if is_none_at(state, idx1) || is_none_at(state, idx2) {
+        return 0;
+    }
+    (value_at(state, idx1) == value_at(state, idx2)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_compare(l: *mut LuaState, idx1: c_int, idx2: c_int, op: c_int) -> c_int {
+    let state = state_of(l);
+    if is_none_at(state, idx1) || is_none_at(state, idx2) {
+        return 0;
+    }
+    let (a, b) = (value_at(state, idx1), value_at(state, idx2));
+    let result = match op {
+        LUA_OPEQ => state.equals(&a, &b),
+        LUA_OPLT => state.less_than(&a, &b),
+        LUA_OPLE => state.less_equal(&a, &b),
+        _ => state.error(LuaError::Runtime("invalid option".to_string())),
+    };
+    match result {
+        Ok(result) => result as c_int,
+        Err(err) => state.error(err),
+    }
+}
+
+// PUSH
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushnil(l: *mut LuaState) {
+    state_of(l).push_nil();
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushnumber(l: *mut LuaState, n: FLT) {
+    state_of(l).push_float(n);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushinteger(l: *mut LuaState, n: INT) {
+    state_of(l).push_integer(n);
+}
+
+/// brief: push a copy of `len` bytes, the pointer of the copy is returned
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushlstring(
+    l: *mut LuaState,
+    s: *const c_char,
+    len: usize,
+) -> *const c_char {
+    let state = state_of(l);
+    let bytes = if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(s as *const u8, len)
+    };
+    state.push_string(bytes);
+    let elem = state.get_elem_at(-1);
+    string_ptr(state, &elem).map_or(std::ptr::null(), |(ptr, _)| ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushstring(l: *mut LuaState, s: *const c_char) -> *const c_char {
+    if s.is_null() {
+        state_of(l).push_nil();
+        return std::ptr::null();
+    }
+    lua_pushlstring(l, s, c_bytes(s).len())
+}
+
+/// brief: push a C function with the top `n` values as its upvalues
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushcclosure(l: *mut LuaState, f: CFunction, n: c_int) {
+    let state = state_of(l);
+    let upvalues = (n > 0).then(|| {
+        let upvalues = state.new_table_value(n as usize, 0);
+        for (i, val) in pop_values(state, n as usize).into_iter().enumerate() {
+            let _ = state.raw_set(&upvalues, Value::Integer(i as INT + 1), val);
+        }
+        upvalues
+    });
+    let function = c_closure(state, f, upvalues);
+    state.push_value(&Value::Function(function));
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushboolean(l: *mut LuaState, b: c_int) {
+    state_of(l).push_bool(b != 0);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushlightuserdata(l: *mut LuaState, p: *mut c_void) {
+    state_of(l).push_value(&Value::LightUserData(p as *mut ()));
+}
+
+// GET
+
+fn push_index(state: &mut LuaState, obj: &Value, key: &Value) -> c_int {
+    let result = state.index(obj, key);
+    let val = raise(state, result);
+    let tag = type_tag(&val);
+    state.push_value(&val);
+    tag
+}
+
+fn push_raw(state: &mut LuaState, table: &Table, key: &Value) -> c_int {
+    let val = state.raw_get(table, key);
+    let tag = type_tag(&val);
+    state.push_value(&val);
+    tag
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getglobal(l: *mut LuaState, name: *const c_char) -> c_int {
+    let state = state_of(l);
+    let globals = Value::Table(state.globals());
+    push_index(state, &globals, &Value::String(LuaString::new(c_bytes(name))))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_gettable(l: *mut LuaState, idx: c_int) -> c_int {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    let key = state.pop_value();
+    push_index(state, &obj, &key)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getfield(l: *mut LuaState, idx: c_int, k: *const c_char) -> c_int {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    push_index(state, &obj, &Value::String(LuaString::new(c_bytes(k))))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_geti(l: *mut LuaState, idx: c_int, n: INT) -> c_int {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    push_index(state, &obj, &Value::Integer(n))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawget(l: *mut LuaState, idx: c_int) -> c_int {
+    let state = state_of(l);
+    let table = table_at(state, idx);
+    let key = state.pop_value();
+    push_raw(state, &table, &key)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawgeti(l: *mut LuaState, idx: c_int, n: INT) -> c_int {
+    let state = state_of(l);
+    let table = table_at(state, idx);
+    push_raw(state, &table, &Value::Integer(n))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawgetp(l: *mut LuaState, idx: c_int, p: *const c_void) -> c_int {
+    let state = state_of(l);
+    let table = table_at(state, idx);
+    push_raw(state, &table, &Value::LightUserData(p as *mut ()))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_createtable(l: *mut LuaState, narr: c_int, nrec: c_int) {
+    state_of(l).create_table(narr.max(0) as usize, nrec.max(0) as usize);
+}
+
+/// brief: push a full userdata of `size` bytes aligned for any type, without
+/// a metatable. the user values are not kept
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_newuserdatauv(
+    l: *mut LuaState,
+    size: usize,
+    _nuvalue: c_int,
+) -> *mut c_void {
+    let state = state_of(l);
+    let block = CBlock::new(size);
+    let ptr = block.as_ptr();
+    let gc = state.alloc_object(GcObject::UserData(LuaUserData {
+        data: Box::new(block),
+        metatable: None,
+        user_value: StkElem::default(),
+    }));
+    state.push_obj(StkElem::new_full_ud(gc));
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getmetatable(l: *mut LuaState, idx: c_int) -> c_int {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    match state.get_metatable(&obj) {
+        Some(metatable) => {
+            state.push_value(&Value::Table(metatable));
+            1
+        }
+        None => 0,
+    }
+}
+
+// SET
+
+fn set_index(state: &mut LuaState, obj: &Value, key: Value, val: Value) {
+    if let Err(err) = state.set_index(obj, key, val) {
+        state.error(err);
+    }
+}
+
+fn set_raw(state: &mut LuaState, table: &Table, key: Value, val: Value) {
+    if let Err(err) = state.raw_set(table, key, val) {
+        state.error(err);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setglobal(l: *mut LuaState, name: *const c_char) {
+    let state = state_of(l);
+    let globals = Value::Table(state.globals());
+    let val = state.pop_value();
+    set_index(state, &globals, Value::String(LuaString::new(c_bytes(name))), val);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_settable(l: *mut LuaState, idx: c_int) {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    let mut kv = pop_values(state, 2);
+    let (val, key) = (kv.pop().unwrap(), kv.pop().unwrap());
+    set_index(state, &obj, key, val);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setfield(l: *mut LuaState, idx: c_int, k: *const c_char) {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    let val = state.pop_value();
+    set_index(state, &obj, Value::String(LuaString::new(c_bytes(k))), val);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_seti(l: *mut LuaState, idx: c_int, n: INT) {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    let val = state.pop_value();
+    set_index(state, &obj, Value::Integer(n), val);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawset(l: *mut LuaState, idx: c_int) {
+    let state = state_of(l);
+    let table = table_at(state, idx);
+    let mut kv = pop_values(state, 2);
+    let (val, key) = (kv.pop().unwrap(), kv.pop().unwrap());
+    set_raw(state, &table, key, val);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawseti(l: *mut LuaState, idx: c_int, n: INT) {
+    let state = state_of(l);
+    let table = table_at(state, idx);
+    let val = state.pop_value();
+    set_raw(state, &table, Value::Integer(n), val);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawsetp(l: *mut LuaState, idx: c_int, p: *const c_void) {
+    let state = state_of(l);
+    let table = table_at(state, idx);
+    let val = state.pop_value();
+    set_raw(state, &table, Value::LightUserData(p as *mut ()), val);
+}
+
+/// brief: pop a table or nil and make it the metatable of the value at idx,
+/// the one of its type for a value other than a table or a full userdata
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setmetatable(l: *mut LuaState, idx: c_int) -> c_int {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    let metatable = match state.pop_value() {
+        Value::Table(metatable) => Some(metatable),
+        Value::Nil => None,
+        _ => state.error(LuaError::Runtime("table expected".to_string())),
+    };
+    match &obj {
+        Value::Table(table) => state.set_metatable(table, metatable.as_ref()),
+        Value::UserData(ud) => state.set_userdata_metatable(ud, metatable.as_ref()),
+        val => state.set_type_metatable(val, metatable.as_ref()),
+    }
+    1
+}
+
+// CALLS
+
+/// brief: call the function below the top `nargs` values, leaving
+/// `nresults` results, all of them for LUA_MULTRET
+fn call(state: &mut LuaState, nargs: c_int, nresults: c_int) -> Result<(), LuaError> {
+    let mut values = pop_values(state, nargs as usize + 1);
+    let func = values.remove(0);
+    let results = state.call_value(&func, MultiValue::from(values))?;
+    push_results(state, results, nresults);
+    Ok(())
+}
+
+fn push_results(state: &mut LuaState, results: MultiValue, nresults: c_int) {
+    let n = if nresults as isize == LUA_MUL_RET {
+        results.len()
+    } else {
+        nresults as usize
+    };
+    if state.stack_check(n).is_err() {
+        state.error(LuaError::Runtime("stack overflow".to_string()));
+    }
+    let mut results = results.into_iter();
+    for _ in 0..n {
+        state.push_value(&results.next().unwrap_or(Value::Nil));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_callk(
+    l: *mut LuaState,
+    nargs: c_int,
+    nresults: c_int,
+    _ctx: isize,
+    _k: Option<KFunction>,
+) {
+    let state = state_of(l);
+    if let Err(err) = call(state, nargs, nresults) {
+        state.error(err);
+    }
+}
+
+/// brief: call in protected mode, the error object is left on the stack
+/// after the message handler at `msgh` has run on it
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pcallk(
+    l: *mut LuaState,
+    nargs: c_int,
+    nresults: c_int,
+    msgh: c_int,
+    _ctx: isize,
+    _k: Option<KFunction>,
+) -> c_int {
+    let state = state_of(l);
+    let handler = (msgh != 0).then(|| value_at(state, msgh));
+    let err = match call(state, nargs, nresults) {
+        Ok(()) => return LUA_OK,
+        Err(err @ LuaError::CallbackPanic { .. }) => state.error(err),
+        Err(err) => err,
+    };
+    let mut status = match err {
+        LuaError::Memory(_) => LUA_ERRMEM,
+        _ => LUA_ERRRUN,
+    };
+    let mut val = err.into_value();
+    if let Some(handler) = handler {
+        val = match state.call_value(&handler, MultiValue::from([val])) {
+            Ok(mut results) => results.pop_front().unwrap_or(Value::Nil),
+            Err(err @ LuaError::CallbackPanic { .. }) => state.error(err),
+            Err(_) => {
+                status = LUA_ERRERR;
+                Value::from("error in error handling")
+            }
+        };
+    }
+    state.push_value(&val);
+    status
+}
+
+// MISCELLANEOUS
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_version(_l: *mut LuaState) -> FLT {
+    LUA_VERSION_NUM as FLT
+}
+
+/// brief: raise the value on the top as an error
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_error(l: *mut LuaState) -> c_int {
+    let state = state_of(l);
+    let val = state.pop_value();
+    state.error(LuaError::from_value(val))
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_next(l: *mut LuaState, idx: c_int) -> c_int {
+    let state = state_of(l);
+    let table = table_at(state, idx);
+    let key = state.pop_value();
+    match state.raw_next(&table, &key) {
+        Ok(Some((key, val))) => {
+            state.push_value(&key);
+            state.push_value(&val);
+            1
+        }
+        Ok(None) => 0,
+        Err(err) => state.error(err),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_concat(l: *mut LuaState, n: c_int) {
+    let state = state_of(l);
+    let values = pop_values(state, n.max(0) as usize);
+    let result = state.concat(values);
+    let val = raise(state, result);
+    state.push_value(&val);
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_len(l: *mut LuaState, idx: c_int) {
+    let state = state_of(l);
+    let obj = value_at(state, idx);
+    let result = state.len(&obj);
+    let len = raise(state, result);
+    state.push_value(&len);
+}
+
+/// brief: push the number of a numeral, the size of the string with its
+/// zero, 0 when it is no numeral and nothing is pushed
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_stringtonumber(l: *mut LuaState, s: *const c_char) -> usize {
+    let bytes = c_bytes(s);
+    match str_to_number(bytes) {
+        Some(n) => {
+            state_of(l).push_value(&n);
+            bytes.len() + 1
+        }
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::{c_int, CStr};
+    use std::ptr::null_mut;
+
+    use crate::common::obj::objvalue::Value;
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    use super::*;
+
+    fn top_values(state: &mut LuaState) -> Vec<Value> {
+        (1..=state.get_top() as isize)
+            .map(|i| state.get_value(i))
+            .collect()
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let l = state as *mut LuaState;
+        unsafe {
+            for i in 1..=4 {
+                lua_pushinteger(l, i);
+            }
+            lua_rotate(l, 2, 1);
+            assert_eq!(top_values(&mut *l), [1, 4, 2, 3].map(Value::Integer));
+            lua_rotate(l, -3, -1);
+            assert_eq!(top_values(&mut *l), [1, 2, 3, 4].map(Value::Integer));
+            lua_copy(l, 1, -1);
+            lua_pushvalue(l, 2);
+            assert_eq!(top_values(&mut *l), [1, 2, 3, 1, 2].map(Value::Integer));
+            assert_eq!(lua_absindex(l, -1), 5);
+            lua_settop(l, -3);
+            assert_eq!(lua_gettop(l), 3);
+            lua_settop(l, 4);
+            assert_eq!(lua_type(l, 4), LUA_TNIL);
+            assert_eq!(lua_type(l, 5), LUA_TNONE);
+            assert_eq!(CStr::from_ptr(lua_typename(l, LUA_TNONE)).to_bytes(), b"no value");
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let l = state as *mut LuaState;
+        unsafe {
+            lua_pushnumber(l, 3.0);
+            lua_pushstring(l, c"0x10".as_ptr());
+            lua_pushstring(l, c"x".as_ptr());
+            let mut isnum: c_int = -1;
+            assert_eq!(lua_tointegerx(l, 1, &mut isnum), 3);
+            assert_eq!(isnum, 1);
+            assert_eq!(lua_tointegerx(l, 2, null_mut()), 16);
+            assert_eq!(lua_tonumberx(l, 3, &mut isnum), 0.0);
+            assert_eq!(isnum, 0);
+            assert_eq!(lua_isinteger(l, 1), 0);
+            assert_eq!(lua_isnumber(l, 2), 1);
+
+            // a number becomes a string in its slot
+            let mut len = 0;
+            let s = lua_tolstring(l, 1, &mut len);
+            assert_eq!(CStr::from_ptr(s).to_bytes(), b"3.0");
+            assert_eq!(len, 3);
+            assert_eq!(lua_type(l, 1), LUA_TSTRING);
+            assert!(lua_tolstring(l, 4, &mut len).is_null());
+
+            let copy = lua_pushlstring(l, b"a\0b".as_ptr() as *const c_char, 3);
+            assert_eq!(std::slice::from_raw_parts(copy as *const u8, 4), b"a\0b\0");
+            assert_eq!(lua_rawlen(l, -1), 3);
+            assert_eq!(lua_stringtonumber(l, c" 12 ".as_ptr()), 5);
+            assert_eq!(lua_tointegerx(l, -1, null_mut()), 12);
+            assert_eq!(lua_stringtonumber(l, c"1e".as_ptr()), 0);
+        }
+    }
+
+    unsafe extern "C-unwind" fn counter(l: *mut LuaState) -> c_int {
+        let n = lua_tointegerx(l, lua_upvalueindex(1), null_mut()) + 1;
+        lua_pushinteger(l, n);
+        lua_copy(l, -1, lua_upvalueindex(1));
+        1
+    }
+
+    fn lua_upvalueindex(i: c_int) -> c_int {
+        LUA_REGISTRYINDEX - i
+    }
+
+    unsafe extern "C-unwind" fn fail(l: *mut LuaState) -> c_int {
+        lua_pushstring(l, c"failed".as_ptr());
+        lua_error(l)
+    }
+
+    #[test]
+    fn closures_and_calls() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let l = state as *mut LuaState;
+        unsafe {
+            lua_pushinteger(l, 10);
+            lua_pushcclosure(l, counter, 1);
+            lua_setglobal(l, c"counter".as_ptr());
+            for expected in 11..=12 {
+                assert_eq!(lua_getglobal(l, c"counter".as_ptr()), LUA_TFUNCTION);
+                lua_callk(l, 0, 1, 0, None);
+                assert_eq!(lua_tointegerx(l, -1, null_mut()), expected);
+                lua_settop(l, 0);
+            }
+
+            // the C function of a closure is given back, other values have none
+            lua_getglobal(l, c"counter".as_ptr());
+            let f = lua_tocfunction(l, -1).unwrap();
+            lua_pushinteger(l, 41);
+            lua_pushcclosure(l, f, 1);
+            lua_callk(l, 0, 1, 0, None);
+            assert_eq!(lua_tointegerx(l, -1, null_mut()), 42);
+            lua_getglobal(l, c"print".as_ptr());
+            lua_pushinteger(l, 1);
+            assert!(lua_tocfunction(l, -2).is_none());
+            assert!(lua_tocfunction(l, -1).is_none());
+            lua_settop(l, 0);
+
+            lua_pushcclosure(l, fail, 0);
+            assert_eq!(lua_pcallk(l, 0, 0, 0, 0, None), LUA_ERRRUN);
+            assert_eq!(
+                CStr::from_ptr(lua_tolstring(l, -1, null_mut())).to_bytes(),
+                b"failed"
+            );
+            lua_settop(l, 0);
+
+            // the handler sees the error object
+            lua_getglobal(l, c"tostring".as_ptr());
+            lua_pushcclosure(l, fail, 0);
+            lua_pushinteger(l, 1);
+            assert_eq!(lua_pcallk(l, 1, 0, 1, 0, None), LUA_ERRRUN);
+            assert_eq!(lua_gettop(l), 2);
+            lua_settop(l, 0);
+
+            lua_getglobal(l, c"select".as_ptr());
+            lua_pushstring(l, c"#".as_ptr());
+            lua_pushinteger(l, 1);
+            lua_pushinteger(l, 2);
+            assert_eq!(lua_pcallk(l, 3, -1, 0, 0, None), LUA_OK);
+            assert_eq!(top_values(&mut *l), [Value::Integer(2)]);
+        }
+    }
+
+    #[test]
+    fn tables_and_userdata() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let l = state as *mut LuaState;
+        unsafe {
+            lua_createtable(l, 0, 0);
+            lua_pushinteger(l, 1);
+            lua_setfield(l, 1, c"a".as_ptr());
+            lua_pushstring(l, c"b".as_ptr());
+            lua_pushinteger(l, 2);
+            lua_settable(l, 1);
+            lua_pushinteger(l, 3);
+            lua_rawseti(l, 1, 1);
+            assert_eq!(lua_getfield(l, 1, c"b".as_ptr()), LUA_TNUMBER);
+            assert_eq!(lua_rawgeti(l, 1, 1), LUA_TNUMBER);
+            assert_eq!(lua_geti(l, 1, 2), LUA_TNIL);
+            assert_eq!(
+                top_values(&mut *l)[1..],
+                [Value::Integer(2), Value::Integer(3), Value::Nil]
+            );
+            lua_settop(l, 1);
+            let mut n = 0;
+            lua_pushnil(l);
+            while lua_next(l, 1) != 0 {
+                n += 1;
+                lua_settop(l, -2);
+            }
+            assert_eq!(n, 3);
+
+            let p = lua_newuserdatauv(l, 24, 0) as *mut u64;
+            assert_eq!(p as usize % 16, 0);
+            *p.add(2) = 7;
+            assert_eq!(lua_touserdata(l, -1) as *mut u64, p);
+            assert_eq!(lua_rawlen(l, -1), 24);
+            assert_eq!(lua_getmetatable(l, -1), 0);
+            lua_pushvalue(l, 1);
+            lua_setmetatable(l, -2);
+            assert_eq!(lua_getmetatable(l, -1), 1);
+            assert_eq!(lua_rawequal(l, -1, 1), 1);
+            assert_eq!(*(lua_touserdata(l, 2) as *mut u64).add(2), 7);
+
+            lua_settop(l, 0);
+            lua_pushinteger(l, 1);
+            lua_pushnumber(l, 2.5);
+            lua_pushstring(l, c"x".as_ptr());
+            lua_concat(l, 3);
+            assert_eq!(
+                CStr::from_ptr(lua_tolstring(l, -1, null_mut())).to_bytes(),
+                b"12.5x"
+            );
+            lua_pushinteger(l, 1);
+            lua_pushnumber(l, 1.5);
+            assert_eq!(lua_compare(l, -2, -1, LUA_OPLT), 1);
+            assert_eq!(lua_compare(l, -1, -1, LUA_OPLE), 1);
+            assert_eq!(lua_compare(l, -1, -2, LUA_OPEQ), 0);
+            lua_len(l, 1);
+            assert_eq!(lua_tointegerx(l, -1, null_mut()), 5);
+        }
+    }
+}
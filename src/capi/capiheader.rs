@@ -0,0 +1,671 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// brief: the symbol every module built against the headers exports, so
+/// that package.loadlib calls its entry points as lua_CFunction
+pub const CAPI_MARK: &str = "naive_lua2_capi";
+
+/// brief: the declarations of `capidef`, the functions of lua.h and their
+/// macros. lua_pushfstring is written in the header over lua_pushlstring
+pub const LUA_H: &str = r#"/* lua.h of naive_lua2, generated by capi::capiheader */
+#ifndef lua_h
+#define lua_h
+
+#include <stdarg.h>
+#include <stddef.h>
+#include <stdio.h>
+#include <string.h>
+
+#define LUA_VERSION_MAJOR "5"
+#define LUA_VERSION_MINOR "4"
+#define LUA_VERSION_RELEASE "6"
+#define LUA_VERSION_NUM 504
+#define LUA_VERSION_RELEASE_NUM (LUA_VERSION_NUM * 100 + 6)
+#define LUA_VERSION "Lua " LUA_VERSION_MAJOR "." LUA_VERSION_MINOR
+
+#define LUA_MULTRET (-1)
+
+#define LUAI_MAXSTACK 1000000
+#define LUA_REGISTRYINDEX (-LUAI_MAXSTACK - 1000)
+#define lua_upvalueindex(i) (LUA_REGISTRYINDEX - (i))
+
+#define LUA_OK 0
+#define LUA_YIELD 1
+#define LUA_ERRRUN 2
+#define LUA_ERRSYNTAX 3
+#define LUA_ERRMEM 4
+#define LUA_ERRERR 5
+
+#define LUA_TNONE (-1)
+#define LUA_TNIL 0
+#define LUA_TBOOLEAN 1
+#define LUA_TLIGHTUSERDATA 2
+#define LUA_TNUMBER 3
+#define LUA_TSTRING 4
+#define LUA_TTABLE 5
+#define LUA_TFUNCTION 6
+#define LUA_TUSERDATA 7
+#define LUA_TTHREAD 8
+#define LUA_NUMTYPES 9
+
+#define LUA_MINSTACK 20
+
+#define LUA_RIDX_MAINTHREAD 1
+#define LUA_RIDX_GLOBALS 2
+#define LUA_RIDX_LAST LUA_RIDX_GLOBALS
+
+#define LUA_OPEQ 0
+#define LUA_OPLT 1
+#define LUA_OPLE 2
+
+typedef struct lua_State lua_State;
+
+typedef double lua_Number;
+typedef long long lua_Integer;
+typedef unsigned long long lua_Unsigned;
+typedef ptrdiff_t lua_KContext;
+
+typedef int (*lua_CFunction)(lua_State *L);
+typedef int (*lua_KFunction)(lua_State *L, int status, lua_KContext ctx);
+
+/* the mark of a module built against these headers, package.loadlib calls
+   the luaopen_ functions of a library exporting it as lua_CFunction */
+__attribute__((weak, visibility("default"))) const int naive_lua2_capi = LUA_VERSION_NUM;
+
+#define LUA_INTEGER_FMT "%lld"
+#define LUAI_NUMFFORMAT "%.14g"
+
+/* state manipulation */
+lua_Number lua_version(lua_State *L);
+
+/* basic stack manipulation */
+int lua_absindex(lua_State *L, int idx);
+int lua_gettop(lua_State *L);
+void lua_settop(lua_State *L, int idx);
+void lua_pushvalue(lua_State *L, int idx);
+void lua_rotate(lua_State *L, int idx, int n);
+void lua_copy(lua_State *L, int fromidx, int toidx);
+int lua_checkstack(lua_State *L, int n);
+
+/* access functions */
+int lua_isnumber(lua_State *L, int idx);
+int lua_isstring(lua_State *L, int idx);
+int lua_iscfunction(lua_State *L, int idx);
+int lua_isinteger(lua_State *L, int idx);
+int lua_isuserdata(lua_State *L, int idx);
+int lua_type(lua_State *L, int idx);
+const char *lua_typename(lua_State *L, int tp);
+
+lua_Number lua_tonumberx(lua_State *L, int idx, int *isnum);
+lua_Integer lua_tointegerx(lua_State *L, int idx, int *isnum);
+int lua_toboolean(lua_State *L, int idx);
+const char *lua_tolstring(lua_State *L, int idx, size_t *len);
+lua_Unsigned lua_rawlen(lua_State *L, int idx);
+/* NULL for the functions of the host, which are not C functions */
+lua_CFunction lua_tocfunction(lua_State *L, int idx);
+void *lua_touserdata(lua_State *L, int idx);
+const void *lua_topointer(lua_State *L, int idx);
+
+/* comparison */
+int lua_rawequal(lua_State *L, int idx1, int idx2);
+int lua_compare(lua_State *L, int idx1, int idx2, int op);
+
+/* push functions */
+void lua_pushnil(lua_State *L);
+void lua_pushnumber(lua_State *L, lua_Number n);
+void lua_pushinteger(lua_State *L, lua_Integer n);
+const char *lua_pushlstring(lua_State *L, const char *s, size_t len);
+const char *lua_pushstring(lua_State *L, const char *s);
+void lua_pushcclosure(lua_State *L, lua_CFunction fn, int n);
+void lua_pushboolean(lua_State *L, int b);
+void lua_pushlightuserdata(lua_State *L, void *p);
+
+/* get functions */
+int lua_getglobal(lua_State *L, const char *name);
+int lua_gettable(lua_State *L, int idx);
+int lua_getfield(lua_State *L, int idx, const char *k);
+int lua_geti(lua_State *L, int idx, lua_Integer n);
+int lua_rawget(lua_State *L, int idx);
+int lua_rawgeti(lua_State *L, int idx, lua_Integer n);
+int lua_rawgetp(lua_State *L, int idx, const void *p);
+void lua_createtable(lua_State *L, int narr, int nrec);
+void *lua_newuserdatauv(lua_State *L, size_t sz, int nuvalue);
+int lua_getmetatable(lua_State *L, int objindex);
+
+/* set functions */
+void lua_setglobal(lua_State *L, const char *name);
+void lua_settable(lua_State *L, int idx);
+void lua_setfield(lua_State *L, int idx, const char *k);
+void lua_seti(lua_State *L, int idx, lua_Integer n);
+void lua_rawset(lua_State *L, int idx);
+void lua_rawseti(lua_State *L, int idx, lua_Integer n);
+void lua_rawsetp(lua_State *L, int idx, const void *p);
+int lua_setmetatable(lua_State *L, int objindex);
+
+/* load and call functions */
+void lua_callk(lua_State *L, int nargs, int nresults, lua_KContext ctx, lua_KFunction k);
+#define lua_call(L, n, r) lua_callk(L, (n), (r), 0, NULL)
+int lua_pcallk(lua_State *L, int nargs, int nresults, int errfunc, lua_KContext ctx, lua_KFunction k);
+#define lua_pcall(L, n, r, f) lua_pcallk(L, (n), (r), (f), 0, NULL)
+
+/* miscellaneous functions */
+int lua_error(lua_State *L);
+int lua_next(lua_State *L, int idx);
+void lua_concat(lua_State *L, int n);
+void lua_len(lua_State *L, int idx);
+size_t lua_stringtonumber(lua_State *L, const char *s);
+
+/* useful macros */
+#define lua_getextraspace(L) ((void *)0)
+#define lua_tonumber(L, i) lua_tonumberx(L, (i), NULL)
+#define lua_tointeger(L, i) lua_tointegerx(L, (i), NULL)
+#define lua_pop(L, n) lua_settop(L, -(n) - 1)
+#define lua_newtable(L) lua_createtable(L, 0, 0)
+#define lua_register(L, n, f) (lua_pushcfunction(L, (f)), lua_setglobal(L, (n)))
+#define lua_pushcfunction(L, f) lua_pushcclosure(L, (f), 0)
+#define lua_isfunction(L, n) (lua_type(L, (n)) == LUA_TFUNCTION)
+#define lua_istable(L, n) (lua_type(L, (n)) == LUA_TTABLE)
+#define lua_islightuserdata(L, n) (lua_type(L, (n)) == LUA_TLIGHTUSERDATA)
+#define lua_isnil(L, n) (lua_type(L, (n)) == LUA_TNIL)
+#define lua_isboolean(L, n) (lua_type(L, (n)) == LUA_TBOOLEAN)
+#define lua_isthread(L, n) (lua_type(L, (n)) == LUA_TTHREAD)
+#define lua_isnone(L, n) (lua_type(L, (n)) == LUA_TNONE)
+#define lua_isnoneornil(L, n) (lua_type(L, (n)) <= 0)
+#define lua_pushliteral(L, s) lua_pushstring(L, "" s)
+#define lua_pushglobaltable(L) ((void)lua_rawgeti(L, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS))
+#define lua_tostring(L, i) lua_tolstring(L, (i), NULL)
+#define lua_insert(L, idx) lua_rotate(L, (idx), 1)
+#define lua_remove(L, idx) (lua_rotate(L, (idx), -1), lua_pop(L, 1))
+#define lua_replace(L, idx) (lua_copy(L, -1, (idx)), lua_pop(L, 1))
+#define lua_newuserdata(L, s) lua_newuserdatauv(L, s, 1)
+
+/* a string of a format, "%%", "%s", "%c", "%d", "%I", "%f", "%p" and "%U" */
+static inline const char *lua_pushvfstring(lua_State *L, const char *fmt, va_list argp) {
+  int n = 0;
+  const char *e;
+  while ((e = strchr(fmt, '%')) != NULL) {
+    char buff[64];
+    lua_pushlstring(L, fmt, (size_t)(e - fmt));
+    switch (*(e + 1)) {
+    case 's': {
+      const char *s = va_arg(argp, char *);
+      lua_pushstring(L, s == NULL ? "(null)" : s);
+      break;
+    }
+    case 'c':
+      buff[0] = (char)va_arg(argp, int);
+      lua_pushlstring(L, buff, 1);
+      break;
+    case 'd':
+      lua_pushinteger(L, va_arg(argp, int));
+      lua_tolstring(L, -1, NULL);
+      break;
+    case 'I':
+      lua_pushinteger(L, (lua_Integer)va_arg(argp, lua_Integer));
+      lua_tolstring(L, -1, NULL);
+      break;
+    case 'f':
+      lua_pushnumber(L, (lua_Number)va_arg(argp, double));
+      lua_tolstring(L, -1, NULL);
+      break;
+    case 'p':
+      lua_pushlstring(L, buff, (size_t)snprintf(buff, sizeof(buff), "%p", va_arg(argp, void *)));
+      break;
+    case 'U': {
+      unsigned long x = (unsigned long)va_arg(argp, long);
+      int len = 0;
+      if (x < 0x80) {
+        buff[len++] = (char)x;
+      } else {
+        char tail[8];
+        int ntail = 0;
+        unsigned int mfb = 0x3f;
+        do {
+          tail[ntail++] = (char)(0x80 | (x & 0x3f));
+          x >>= 6;
+          mfb >>= 1;
+        } while (x > mfb);
+        buff[len++] = (char)((~mfb << 1) | x);
+        while (ntail > 0)
+          buff[len++] = tail[--ntail];
+      }
+      lua_pushlstring(L, buff, (size_t)len);
+      break;
+    }
+    case '%':
+      lua_pushlstring(L, "%", 1);
+      break;
+    default:
+      lua_pushliteral(L, "invalid conversion '%");
+      lua_pushlstring(L, e + 1, 1);
+      lua_pushliteral(L, "' to format");
+      lua_concat(L, 3);
+      lua_error(L);
+      return NULL;
+    }
+    n += 2;
+    fmt = e + 2;
+    if (n >= LUA_MINSTACK) {
+      lua_concat(L, n);
+      n = 1;
+    }
+  }
+  lua_pushstring(L, fmt);
+  lua_concat(L, n + 1);
+  return lua_tolstring(L, -1, NULL);
+}
+
+static inline const char *lua_pushfstring(lua_State *L, const char *fmt, ...) {
+  const char *ret;
+  va_list argp;
+  va_start(argp, fmt);
+  ret = lua_pushvfstring(L, fmt, argp);
+  va_end(argp);
+  return ret;
+}
+
+#endif
+"#;
+
+/// brief: the declarations of `capiaux`, the functions of lauxlib.h, their
+/// macros and luaL_Buffer, which lives in the header over a userdata
+pub const LAUXLIB_H: &str = r#"/* lauxlib.h of naive_lua2, generated by capi::capiheader */
+#ifndef lauxlib_h
+#define lauxlib_h
+
+#include "lua.h"
+
+#define LUA_GNAME "_G"
+#define LUA_LOADED_TABLE "_LOADED"
+#define LUA_PRELOAD_TABLE "_PRELOAD"
+
+#define LUA_ERRFILE (LUA_ERRERR + 1)
+
+#define LUA_NOREF (-2)
+#define LUA_REFNIL (-1)
+
+typedef struct luaL_Reg {
+  const char *name;
+  lua_CFunction func;
+} luaL_Reg;
+
+#define LUAL_NUMSIZES (sizeof(lua_Integer) * 16 + sizeof(lua_Number))
+
+void luaL_checkversion_(lua_State *L, lua_Number ver, size_t sz);
+#define luaL_checkversion(L) luaL_checkversion_(L, LUA_VERSION_NUM, LUAL_NUMSIZES)
+
+int luaL_getmetafield(lua_State *L, int obj, const char *e);
+int luaL_callmeta(lua_State *L, int obj, const char *e);
+const char *luaL_tolstring(lua_State *L, int idx, size_t *len);
+int luaL_argerror(lua_State *L, int arg, const char *extramsg);
+int luaL_typeerror(lua_State *L, int arg, const char *tname);
+const char *luaL_checklstring(lua_State *L, int arg, size_t *l);
+const char *luaL_optlstring(lua_State *L, int arg, const char *def, size_t *l);
+lua_Number luaL_checknumber(lua_State *L, int arg);
+lua_Number luaL_optnumber(lua_State *L, int arg, lua_Number def);
+lua_Integer luaL_checkinteger(lua_State *L, int arg);
+lua_Integer luaL_optinteger(lua_State *L, int arg, lua_Integer def);
+
+void luaL_checkstack(lua_State *L, int sz, const char *msg);
+void luaL_checktype(lua_State *L, int arg, int t);
+void luaL_checkany(lua_State *L, int arg);
+
+int luaL_newmetatable(lua_State *L, const char *tname);
+void luaL_setmetatable(lua_State *L, const char *tname);
+void *luaL_testudata(lua_State *L, int ud, const char *tname);
+void *luaL_checkudata(lua_State *L, int ud, const char *tname);
+
+void luaL_where(lua_State *L, int lvl);
+void luaL_traceback(lua_State *L, lua_State *L1, const char *msg, int level);
+int luaL_checkoption(lua_State *L, int arg, const char *def, const char *const lst[]);
+
+int luaL_ref(lua_State *L, int t);
+void luaL_unref(lua_State *L, int t, int ref);
+
+int luaL_loadbufferx(lua_State *L, const char *buff, size_t sz, const char *name, const char *mode);
+int luaL_loadstring(lua_State *L, const char *s);
+
+lua_Integer luaL_len(lua_State *L, int idx);
+void luaL_setfuncs(lua_State *L, const luaL_Reg *l, int nup);
+int luaL_getsubtable(lua_State *L, int idx, const char *fname);
+void luaL_requiref(lua_State *L, const char *modname, lua_CFunction openf, int glb);
+
+static inline int luaL_error(lua_State *L, const char *fmt, ...) {
+  va_list argp;
+  va_start(argp, fmt);
+  luaL_where(L, 1);
+  lua_pushvfstring(L, fmt, argp);
+  va_end(argp);
+  lua_concat(L, 2);
+  return lua_error(L);
+}
+
+/* some useful macros */
+#define luaL_newlibtable(L, l) lua_createtable(L, 0, sizeof(l) / sizeof((l)[0]) - 1)
+#define luaL_newlib(L, l) (luaL_checkversion(L), luaL_newlibtable(L, l), luaL_setfuncs(L, l, 0))
+#define luaL_argcheck(L, cond, arg, extramsg) ((void)((cond) || luaL_argerror(L, (arg), (extramsg))))
+#define luaL_argexpected(L, cond, arg, tname) ((void)((cond) || luaL_typeerror(L, (arg), (tname))))
+#define luaL_checkstring(L, n) (luaL_checklstring(L, (n), NULL))
+#define luaL_optstring(L, n, d) (luaL_optlstring(L, (n), (d), NULL))
+#define luaL_typename(L, i) lua_typename(L, lua_type(L, (i)))
+#define luaL_dostring(L, s) (luaL_loadstring(L, s) || lua_pcall(L, 0, LUA_MULTRET, 0))
+#define luaL_getmetatable(L, n) (lua_getfield(L, LUA_REGISTRYINDEX, (n)))
+#define luaL_opt(L, f, n, d) (lua_isnoneornil(L, (n)) ? (d) : f(L, (n)))
+#define luaL_loadbuffer(L, s, sz, n) luaL_loadbufferx(L, s, sz, n, NULL)
+#define luaL_pushfail(L) lua_pushnil(L)
+
+/* generic buffer manipulation, the buffer keeps a slot on the stack: a
+ * placeholder while its bytes fit in the struct, the userdata holding them
+ * after that */
+#define LUAL_BUFFERSIZE ((int)(16 * sizeof(void *) * sizeof(lua_Number)))
+
+typedef struct luaL_Buffer {
+  char *b;
+  size_t size;
+  size_t n;
+  lua_State *L;
+  union {
+    char b[LUAL_BUFFERSIZE];
+  } init;
+} luaL_Buffer;
+
+#define luaL_bufflen(bf) ((bf)->n)
+#define luaL_buffaddr(bf) ((bf)->b)
+#define luaL_addchar(B, c) \
+  ((void)((B)->n < (B)->size || luaL_prepbuffsize((B), 1)), ((B)->b[(B)->n++] = (c)))
+#define luaL_addsize(B, s) ((B)->n += (s))
+#define luaL_buffsub(B, s) ((B)->n -= (s))
+#define luaL_prepbuffer(B) luaL_prepbuffsize(B, LUAL_BUFFERSIZE)
+
+/* room for sz more bytes, the slot of the buffer is at boxidx */
+static inline char *luaL_prepbuffsize_(luaL_Buffer *B, size_t sz, int boxidx) {
+  size_t newsize;
+  char *newbuff;
+  if (B->size - B->n >= sz)
+    return B->b + B->n;
+  newsize = B->size * 2;
+  if (newsize - B->n < sz)
+    newsize = B->n + sz;
+  newbuff = (char *)lua_newuserdatauv(B->L, newsize, 0);
+  memcpy(newbuff, B->b, B->n);
+  lua_replace(B->L, boxidx - 1);
+  B->b = newbuff;
+  B->size = newsize;
+  return newbuff + B->n;
+}
+
+static inline char *luaL_prepbuffsize(luaL_Buffer *B, size_t sz) {
+  return luaL_prepbuffsize_(B, sz, -1);
+}
+
+static inline void luaL_buffinit(lua_State *L, luaL_Buffer *B) {
+  B->L = L;
+  B->b = B->init.b;
+  B->n = 0;
+  B->size = LUAL_BUFFERSIZE;
+  lua_pushlightuserdata(L, (void *)B);
+}
+
+static inline char *luaL_buffinitsize(lua_State *L, luaL_Buffer *B, size_t sz) {
+  luaL_buffinit(L, B);
+  return luaL_prepbuffsize(B, sz);
+}
+
+static inline void luaL_addlstring(luaL_Buffer *B, const char *s, size_t l) {
+  if (l > 0) {
+    memcpy(luaL_prepbuffsize(B, l), s, l);
+    luaL_addsize(B, l);
+  }
+}
+
+static inline void luaL_addstring(luaL_Buffer *B, const char *s) {
+  luaL_addlstring(B, s, strlen(s));
+}
+
+/* add the value on the top, the slot of the buffer is below it */
+static inline void luaL_addvalue(luaL_Buffer *B) {
+  size_t len;
+  const char *s = lua_tolstring(B->L, -1, &len);
+  char *b = luaL_prepbuffsize_(B, len, -2);
+  memcpy(b, s, len);
+  luaL_addsize(B, len);
+  lua_pop(B->L, 1);
+}
+
+static inline void luaL_pushresult(luaL_Buffer *B) {
+  lua_pushlstring(B->L, B->b, B->n);
+  lua_remove(B->L, -2);
+}
+
+static inline void luaL_pushresultsize(luaL_Buffer *B, size_t sz) {
+  luaL_addsize(B, sz);
+  luaL_pushresult(B);
+}
+
+#endif
+"#;
+
+/// brief: write lua.h and lauxlib.h into `dir` for the modules of C
+pub fn write_headers(dir: &Path) -> io::Result<()> {
+    fs::write(dir.join("lua.h"), LUA_H)?;
+    fs::write(dir.join("lauxlib.h"), LAUXLIB_H)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use crate::common::obj::objconv::MultiValue;
+    use crate::common::obj::objvalue::{Function, Table, Value};
+    use crate::machine::machdef::Machine;
+
+    use super::write_headers;
+
+    const CAPITEST_C: &str = r#"
+#include "lauxlib.h"
+
+static int add(lua_State *L) {
+  lua_pushinteger(L, luaL_checkinteger(L, 1) + luaL_checkinteger(L, 2));
+  return 1;
+}
+
+static int greet(lua_State *L) {
+  size_t len;
+  const char *name = luaL_checklstring(L, 1, &len);
+  lua_pushfstring(L, "hello, %s (%d) %U%%", name, (int)len, 0x20AC);
+  return 1;
+}
+
+static int fail(lua_State *L) {
+  return luaL_error(L, "failed with %d", 42);
+}
+
+static int inner(lua_State *L) {
+  lua_pushliteral(L, "inner");
+  return lua_error(L);
+}
+
+static int safe(lua_State *L) {
+  lua_pushcfunction(L, inner);
+  lua_pushinteger(L, lua_pcall(L, 0, 0, 0));
+  lua_insert(L, -2);
+  return 2;
+}
+
+static int count(lua_State *L) {
+  lua_Integer n = lua_tointeger(L, lua_upvalueindex(1)) + 1;
+  lua_pushinteger(L, n);
+  lua_copy(L, -1, lua_upvalueindex(1));
+  return 1;
+}
+
+static int counter(lua_State *L) {
+  lua_pushinteger(L, luaL_optinteger(L, 1, 0));
+  lua_pushcclosure(L, count, 1);
+  return 1;
+}
+
+typedef struct Point {
+  lua_Integer x, y;
+} Point;
+
+static int point_new(lua_State *L) {
+  Point *p = (Point *)lua_newuserdatauv(L, sizeof(Point), 0);
+  p->x = luaL_checkinteger(L, 1);
+  p->y = luaL_checkinteger(L, 2);
+  luaL_setmetatable(L, "capitest.point");
+  return 1;
+}
+
+static int point_x(lua_State *L) {
+  lua_pushinteger(L, ((Point *)luaL_checkudata(L, 1, "capitest.point"))->x);
+  return 1;
+}
+
+static int point_tostring(lua_State *L) {
+  Point *p = (Point *)luaL_checkudata(L, 1, "capitest.point");
+  lua_pushfstring(L, "point(%I, %I)", p->x, p->y);
+  return 1;
+}
+
+static int join(lua_State *L) {
+  luaL_Buffer b;
+  lua_Integer i, n;
+  const char *sep = luaL_optstring(L, 2, ",");
+  luaL_checktype(L, 1, LUA_TTABLE);
+  n = luaL_len(L, 1);
+  luaL_buffinit(L, &b);
+  for (i = 1; i <= n; i++) {
+    lua_geti(L, 1, i);
+    luaL_addvalue(&b);
+    if (i < n)
+      luaL_addstring(&b, sep);
+  }
+  luaL_addchar(&b, '.');
+  luaL_pushresult(&b);
+  return 1;
+}
+
+static int mode(lua_State *L) {
+  static const char *const modes[] = {"read", "write", NULL};
+  lua_pushinteger(L, luaL_checkoption(L, 1, "read", modes));
+  return 1;
+}
+
+static const luaL_Reg point_methods[] = {{"x", point_x}, {NULL, NULL}};
+
+static const luaL_Reg funcs[] = {
+    {"add", add},         {"greet", greet}, {"fail", fail}, {"safe", safe},
+    {"counter", counter}, {"new", point_new}, {"join", join}, {"mode", mode},
+    {"version", NULL},    {NULL, NULL}};
+
+int luaopen_capitest(lua_State *L) {
+  luaL_newlib(L, funcs);
+  lua_pushliteral(L, LUA_VERSION);
+  lua_setfield(L, -2, "version");
+  if (luaL_newmetatable(L, "capitest.point")) {
+    lua_pushcfunction(L, point_tostring);
+    lua_setfield(L, -2, "__tostring");
+    luaL_newlib(L, point_methods);
+    lua_setfield(L, -2, "__index");
+  }
+  lua_pop(L, 1);
+  return 1;
+}
+"#;
+
+    /// brief: the directory of the test module, compiled with the C compiler
+    /// of the system against the headers
+    fn build_capitest() -> PathBuf {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("target")
+            .join("capi");
+        fs::create_dir_all(&dir).unwrap();
+        write_headers(&dir).unwrap();
+        let source = dir.join("capitest.c");
+        fs::write(&source, CAPITEST_C).unwrap();
+        let status = Command::new("cc")
+            .args(["-shared", "-fPIC", "-fexceptions", "-Wall", "-Werror", "-I"])
+            .arg(&dir)
+            .arg("-o")
+            .arg(dir.join("libcapitest.so"))
+            .arg(&source)
+            .status()
+            .expect("cc runs");
+        assert!(status.success(), "the test module compiles");
+        dir
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // miri cannot spawn the build nor dlopen its output
+    fn c_modules_are_loaded() {
+        let dir = build_capitest();
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let package: Table = state.get_global("package").unwrap();
+        let cpath = format!("{}/lib?.so", dir.to_str().unwrap());
+        package.set(state, "cpath", cpath).unwrap();
+        let require: Function = state.get_global("require").unwrap();
+        let module: Table = require.call(state, "capitest").unwrap();
+        let call = |state: &mut _, name: &str, args: MultiValue| -> Result<MultiValue, String> {
+            let function: Function = module.get(state, name).unwrap();
+            function.call(state, args).map_err(|err| err.to_string())
+        };
+
+        let version: String = module.get(state, "version").unwrap();
+        assert_eq!(version, "Lua 5.4");
+        let sum = call(state, "add", [Value::from(40), Value::from("2")].into());
+        assert_eq!(sum, Ok([Value::Integer(42)].into()));
+        let err = call(state, "add", [Value::from(1)].into()).unwrap_err();
+        assert_eq!(
+            err,
+            "bad argument #2 to 'capitest.add' (number expected, got no value)"
+        );
+        let greeting = call(state, "greet", [Value::from("lua")].into());
+        assert_eq!(greeting, Ok([Value::from("hello, lua (3) \u{20ac}%")].into()));
+        let err = call(state, "fail", MultiValue::new()).unwrap_err();
+        assert_eq!(err, "failed with 42");
+        let caught = call(state, "safe", MultiValue::new());
+        assert_eq!(caught, Ok([Value::Integer(2), Value::from("inner")].into()));
+
+        let counter = call(state, "counter", [Value::from(10)].into()).unwrap();
+        let Some(Value::Function(count)) = counter.front().cloned() else {
+            panic!("counter gives a closure");
+        };
+        assert_eq!(count.call::<_, i64>(state, ()), Ok(11));
+        assert_eq!(count.call::<_, i64>(state, ()), Ok(12));
+
+        let point = call(state, "new", [Value::from(3), Value::from(4)].into()).unwrap();
+        let point = point[0].clone();
+        let x = state.call_method(&point, "x", MultiValue::new()).unwrap();
+        assert_eq!(x, MultiValue::from([Value::Integer(3)]));
+        let tostring: Function = state.get_global("tostring").unwrap();
+        let text: String = tostring.call(state, point).unwrap();
+        assert_eq!(text, "point(3, 4)");
+        let err = call(state, "new", [Value::from(1.5)].into()).unwrap_err();
+        assert_eq!(
+            err,
+            "bad argument #1 to 'capitest.new' (number has no integer representation)"
+        );
+
+        // the buffer grows past the bytes of its struct
+        let words: Vec<String> = (0..1000).map(|i| format!("w{}", i)).collect();
+        let table = state.create_table_value(words.len(), 0).unwrap();
+        for (i, word) in words.iter().enumerate() {
+            table.raw_set(state, i as i64 + 1, word.as_str()).unwrap();
+        }
+        let joined = call(state, "join", [Value::Table(table), Value::from("; ")].into());
+        let expected = format!("{}.", words.join("; "));
+        assert_eq!(joined, Ok([Value::from(expected.as_str())].into()));
+
+        assert_eq!(
+            call(state, "mode", MultiValue::new()),
+            Ok([Value::Integer(0)].into())
+        );
+        let err = call(state, "mode", [Value::from("x")].into()).unwrap_err();
+        assert_eq!(err, "bad argument #1 to 'capitest.mode' (invalid option 'x')");
+    }
+}
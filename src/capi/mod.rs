@@ -0,0 +1,7 @@
+// the functions of the ABI take raw pointers from C, their contracts are the
+// ones of lua.h and lauxlib.h
+#[allow(clippy::missing_safety_doc)]
+pub mod capiaux;
+#[allow(clippy::missing_safety_doc)]
+pub mod capidef;
+pub mod capiheader;
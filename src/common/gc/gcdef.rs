@@ -0,0 +1,362 @@
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::common::obj::objdef::TObj;
+use crate::common::obj::objtable::LuaTable;
+use crate::common::obj::objtype::{GcRef, RFUNC};
+
+static NEXT_HEAP_ID: AtomicU32 = AtomicU32::new(1);
+
+/// brief: a full userdata, a rust value with a metatable
+pub struct LuaUserData {
+    pub data: Box<dyn Any>,
+    pub metatable: Option<GcRef>,
+    pub user_value: TObj,
+}
+
+impl std::fmt::Debug for LuaUserData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaUserData")
+            .field("metatable", &self.metatable)
+            .field("user_value", &self.user_value)
+            .finish()
+    }
+}
+
+/// brief: a rust closure, shared so that a call holds it while the heap changes.
+/// the second field is the lua_CFunction a closure of the C API calls, null
+/// for the others
+#[derive(Clone)]
+pub struct RustClosure(pub(crate) Rc<RFUNC>, pub(crate) *const ());
+
+impl std::fmt::Debug for RustClosure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RustClosure({:p})", Rc::as_ptr(&self.0) as *const ())
+    }
+}
+
+#[derive(Debug)]
+pub enum GcObject {
+    String(Box<[u8]>),
+    Table(LuaTable),
+    UserData(LuaUserData),
+    Closure(RustClosure),
+}
+
+impl GcObject {
+    /// brief: an estimate of the bytes the object holds
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<GcSlot>()
+            + match self {
+                GcObject::String(bytes) => bytes.len(),
+                GcObject::Table(table) => table.size(),
+                GcObject::UserData(ud) => std::mem::size_of_val(&*ud.data),
+                GcObject::Closure(_) => std::mem::size_of::<RustClosure>(),
+            }
+    }
+
+    /// brief: the values the object refers to, for the collector
+    fn for_each_ref(&self, mut f: impl FnMut(&TObj)) {
+        match self {
+            GcObject::String(_) | GcObject::Closure(_) => {}
+            GcObject::Table(table) => {
+                if let Some(mt) = table.get_metatable() {
+                    f(&TObj::new_table(mt));
+                }
+                table.for_each_value(f);
+            }
+            GcObject::UserData(ud) => {
+                if let Some(mt) = ud.metatable {
+                    f(&TObj::new_table(mt));
+                }
+                f(&ud.user_value);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct GcSlot {
+    gen: u32,
+    obj: Option<GcObject>,
+    size: usize,
+    pin: Option<Rc<()>>, // shared with the host handles, rooted while shared
+    marked: bool,
+}
+
+/// brief: the objects of a state, addressed by index and generation
+/// a reference to a freed object is detected, never dereferenced
+#[derive(Debug)]
+pub struct Heap {
+    id: u32,
+    slots: Vec<GcSlot>,
+    free: Vec<u32>,
+    strings: HashMap<u64, Vec<GcRef>>, // interned strings by hash
+    bytes: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_HEAP_ID.fetch_add(1, Ordering::Relaxed),
+            slots: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::new(),
+            bytes: 0,
+        }
+    }
+
+    /// brief: unique among the heaps of the process
+    #[inline(always)]
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    /// brief: the bytes held by the live objects
+    #[inline(always)]
+    pub fn get_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn alloc(&mut self, obj: GcObject) -> GcRef {
+        let size = obj.size();
+        self.bytes += size;
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.obj = Some(obj);
+            slot.size = size;
+            slot.marked = false;
+            GcRef {
+                index,
+                gen: slot.gen,
+            }
+        } else {
+            let index = self.slots.len() as u32;
+            self.slots.push(GcSlot {
+                gen: 0,
+                obj: Some(obj),
+                size,
+                pin: None,
+                marked: false,
+            });
+            GcRef { index, gen: 0 }
+        }
+    }
+
+    /// brief: the string object of `bytes`, created if it does not exist
+    pub fn intern(&mut self, bytes: &[u8]) -> GcRef {
+        let hash = hash_bytes(bytes);
+        if let Some(refs) = self.strings.get(&hash) {
+            for gc in refs.iter() {
+                if self.get_string(*gc) == Some(bytes) {
+                    return *gc;
+                }
+            }
+        }
+        // the bytes end with a zero for C, it is no part of the string
+        let gc = self.alloc(GcObject::String([bytes, &[0]].concat().into()));
+        self.strings.entry(hash).or_default().push(gc);
+        gc
+    }
+
+    #[inline(always)]
+    pub fn get(&self, gc: GcRef) -> Option<&GcObject> {
+        match self.slots.get(gc.index as usize) {
+            Some(slot) if slot.gen == gc.gen => slot.obj.as_ref(),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self, gc: GcRef) -> Option<&mut GcObject> {
+        match self.slots.get_mut(gc.index as usize) {
+            Some(slot) if slot.gen == gc.gen => slot.obj.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn get_string(&self, gc: GcRef) -> Option<&[u8]> {
+        self.get_c_string(gc).map(|bytes| &bytes[..bytes.len() - 1])
+    }
+
+    /// brief: the bytes of a string with the zero that ends them
+    pub fn get_c_string(&self, gc: GcRef) -> Option<&[u8]> {
+        match self.get(gc) {
+            Some(GcObject::String(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn get_table(&self, gc: GcRef) -> Option<&LuaTable> {
+        match self.get(gc) {
+            Some(GcObject::Table(table)) => Some(table),
+            _ => None,
+        }
+    }
+
+    pub fn get_table_mut(&mut self, gc: GcRef) -> Option<&mut LuaTable> {
+        match self.get_mut(gc) {
+            Some(GcObject::Table(table)) => Some(table),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_closure(&self, gc: GcRef) -> Option<&RustClosure> {
+        match self.get(gc) {
+            Some(GcObject::Closure(closure)) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn get_ud(&self, gc: GcRef) -> Option<&LuaUserData> {
+        match self.get(gc) {
+            Some(GcObject::UserData(ud)) => Some(ud),
+            _ => None,
+        }
+    }
+
+    pub fn get_ud_mut(&mut self, gc: GcRef) -> Option<&mut LuaUserData> {
+        match self.get_mut(gc) {
+            Some(GcObject::UserData(ud)) => Some(ud),
+            _ => None,
+        }
+    }
+
+    /// brief: re-estimate the size of an object after it changed
+    /// return the bytes it grew by
+    pub fn resize(&mut self, gc: GcRef) -> isize {
+        let size = match self.get(gc) {
+            Some(obj) => obj.size(),
+            None => return 0,
+        };
+        let slot = &mut self.slots[gc.index as usize];
+        let delta = size as isize - slot.size as isize;
+        slot.size = size;
+        self.bytes = (self.bytes as isize + delta) as usize;
+        delta
+    }
+
+    /// brief: the pin of a live object, the object survives while a clone is held
+    pub fn pin(&mut self, gc: GcRef) -> Option<Rc<()>> {
+        match self.slots.get_mut(gc.index as usize) {
+            Some(slot) if slot.gen == gc.gen && slot.obj.is_some() => {
+                Some(slot.pin.get_or_insert_with(|| Rc::new(())).clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// brief: a full collection, mark from the roots and the pinned objects,
+    /// then free the rest. return the bytes freed
+    pub fn collect<'a>(&mut self, roots: impl Iterator<Item = &'a TObj>) -> usize {
+        let mut gray: Vec<GcRef> = Vec::new();
+
+        for root in roots {
+            if let Some(gc) = root.as_gc() {
+                gray.push(gc);
+            }
+        }
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let pinned = match &slot.pin {
+                Some(pin) => Rc::strong_count(pin) > 1,
+                None => false,
+            };
+            if !pinned {
+                slot.pin = None;
+            } else if slot.obj.is_some() {
+                gray.push(GcRef {
+                    index: index as u32,
+                    gen: slot.gen,
+                });
+            }
+        }
+
+        while let Some(gc) = gray.pop() {
+            let slot = match self.slots.get_mut(gc.index as usize) {
+                Some(slot) if slot.gen == gc.gen && slot.obj.is_some() => slot,
+                _ => continue,
+            };
+            if slot.marked {
+                continue;
+            }
+            slot.marked = true;
+            if let Some(obj) = &slot.obj {
+                obj.for_each_ref(|val| {
+                    if let Some(gc) = val.as_gc() {
+                        gray.push(gc);
+                    }
+                });
+            }
+        }
+
+        let mut freed = 0;
+        for index in 0..self.slots.len() {
+            let slot = &mut self.slots[index];
+            if slot.obj.is_none() {
+                continue;
+            }
+            if slot.marked {
+                slot.marked = false;
+                continue;
+            }
+            freed += self.free_slot(index);
+        }
+        self.bytes -= freed;
+        freed
+    }
+
+    /// brief: free every object, pinned or not, as when the state closes.
+    /// return the bytes freed
+    pub fn free_all(&mut self) -> usize {
+        let mut freed = 0;
+        for index in 0..self.slots.len() {
+            if self.slots[index].obj.is_some() {
+                freed += self.free_slot(index);
+            }
+        }
+        self.bytes -= freed;
+        freed
+    }
+
+    /// brief: drop the object of a slot and recycle it, return its size
+    fn free_slot(&mut self, index: usize) -> usize {
+        let slot = &mut self.slots[index];
+        let obj = slot.obj.take().unwrap();
+        let gc = GcRef {
+            index: index as u32,
+            gen: slot.gen,
+        };
+        let size = slot.size;
+        slot.size = 0;
+        slot.gen = slot.gen.wrapping_add(1);
+        slot.pin = None;
+        self.free.push(index as u32);
+
+        if let GcObject::String(bytes) = obj {
+            let hash = hash_bytes(&bytes[..bytes.len() - 1]);
+            if let Some(refs) = self.strings.get_mut(&hash) {
+                refs.retain(|other| *other != gc);
+                if refs.is_empty() {
+                    self.strings.remove(&hash);
+                }
+            }
+        }
+        size
+    }
+}
@@ -0,0 +1 @@
+pub mod gcdef;
\ No newline at end of file
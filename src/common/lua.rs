@@ -1,13 +1,21 @@
-#[derive(Debug, Clone, Copy)]
+use std::any::Any;
+use std::sync::{Arc, Mutex};
+
+use crate::common::obj::objconv::fmt_number;
+use crate::common::obj::objvalue::Value;
+
+#[derive(Debug, Clone, Copy, Default)]
 pub enum LuaStateStatus {
+    #[default]
     LuaOk = 0,
     LuaErrErr = 1,
     LuaErrMem = 2, // failed allocating memory
     LuaErrRun = 3,
 } // R[0-3] &15
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub enum LuaCallInfoStatus {
+    #[default]
     CallOk = 0,
     TooManyCall = 1,
     StackOverFlow = 2,
@@ -19,20 +27,10 @@ pub enum ErrCode {
     NullPointer = 1,
     NoneObject = 2,
     OverFlow = 3,
+    Runtime = 4, // a lua error raised by a function
     //MisMatch,
 } // R[8-11] &(15<<8)
 
-impl Default for LuaCallInfoStatus {
-    fn default() -> Self {
-        LuaCallInfoStatus::CallOk
-    }
-}
-
-impl Default for LuaStateStatus {
-    fn default() -> Self {
-        LuaStateStatus::LuaOk
-    }
-}
 
 pub const LUA_MIN_STACK: u32 = 20; // for callinfo structure
 pub const LUA_STACK_SIZE: u32 = 2 * LUA_MIN_STACK; // initial stack size
@@ -40,9 +38,374 @@ pub const LUA_EXTRA_STACK: u32 = 5;
 pub const LUA_MAX_STACK: u32 = 15000;
 pub const LUA_ERROR_STACK: u32 = 200;
 
+pub const LUA_MAX_TAG_LOOP: usize = 2000; // the length of a meta method chain
 pub const LUA_MUL_RET: isize = -1;
+pub const LUA_RAISE_TOP: usize = usize::MAX; // a rust function returning it raises its top value
+pub const LUA_MAX_STACK_LIMIT: usize = 1_000_000; // no budget goes beyond
+pub const LUA_REGISTRY_INDEX: isize = -(LUA_MAX_STACK_LIMIT as isize) - 1000; // pseudo index
+pub const LUA_RIDX_MAINTHREAD: i64 = 1;
+pub const LUA_RIDX_GLOBALS: i64 = 2;
 pub const LUA_MAX_CALLS: usize = 200;
 pub const LUA_CI_LEN: usize = 10; // need not pop out
+pub const LUA_MAX_RCALLS: usize = 200; // nested rust -> vm -> rust entries
+
+pub const LUA_GC_PAUSE: u32 = 200; // percent
+pub const LUA_GC_STEP_MUL: u32 = 100; // percent
+pub const LUA_GC_STEP_SIZE: u32 = 13; // log2 of the step size in bytes
+
+/// brief: the standard libraries a machine opens on start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StdLib(u32);
+
+impl StdLib {
+    pub const NONE: StdLib = StdLib(0);
+    pub const BASE: StdLib = StdLib(1 << 0);
+    pub const PACKAGE: StdLib = StdLib(1 << 1);
+    pub const STRING: StdLib = StdLib(1 << 2);
+    pub const TABLE: StdLib = StdLib(1 << 3);
+    pub const MATH: StdLib = StdLib(1 << 4);
+    pub const IO: StdLib = StdLib(1 << 5);
+    pub const OS: StdLib = StdLib(1 << 6);
+    pub const UTF8: StdLib = StdLib(1 << 7);
+    pub const ALL: StdLib = StdLib((1 << 8) - 1);
+
+    #[inline]
+    pub fn contains(self, other: StdLib) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for StdLib {
+    fn default() -> Self {
+        StdLib::ALL
+    }
+}
+
+impl std::ops::BitOr for StdLib {
+    type Output = StdLib;
+
+    fn bitor(self, rhs: StdLib) -> StdLib {
+        StdLib(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for StdLib {
+    fn bitor_assign(&mut self, rhs: StdLib) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// brief: a memory quota hook, asked before every managed allocation of a
+/// machine. it is not an allocator: the memory itself comes from the global
+/// allocator of rust, the quota only grants or refuses a size. `in_use` is
+/// the number of bytes the machine holds, `request` the number it is about
+/// to add; returning false refuses the allocation (LuaErrMem)
+pub trait MemoryQuota {
+    fn grant(&self, in_use: usize, request: usize) -> bool;
+}
+
+/// brief: the default quota, every request is granted
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Unlimited;
+
+impl MemoryQuota for Unlimited {
+    #[inline]
+    fn grant(&self, _in_use: usize, _request: usize) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcParams {
+    pub pause: u32,     // a cycle starts once the heap grows by this percent
+    pub step_mul: u32,  // the work of a step, in percent of the bytes it stands for
+    pub step_size: u32, // log2 of the bytes allocated between steps
+}
+
+impl Default for GcParams {
+    fn default() -> Self {
+        Self {
+            pause: LUA_GC_PAUSE,
+            step_mul: LUA_GC_STEP_MUL,
+            step_size: LUA_GC_STEP_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    MinStackTooSmall,
+    StackSizeTooSmall, // initial stack cannot hold min stack plus the extra slots
+    StackSizeTooLarge, // initial stack is greater than the max stack
+    MaxStackTooLarge,  // max stack is greater than the hard limit
+    MaxCallsTooSmall,
+    CallInfoTooLarge, // initial callinfo vector is greater than the max calls
+    MaxRustCallsTooSmall,
+    MemoryLimitTooSmall, // the initial stack, the registry or the libraries do not fit in the memory cap
+    GcStepMulTooSmall,
+    GcStepSizeTooLarge,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            ConfigError::MinStackTooSmall => "min stack must be at least 1",
+            ConfigError::StackSizeTooSmall => "stack size must hold min stack plus extra slots",
+            ConfigError::StackSizeTooLarge => "stack size must not exceed max stack",
+            ConfigError::MaxStackTooLarge => "max stack must not exceed 1000000 slots",
+            ConfigError::MaxCallsTooSmall => "max calls must be at least 1",
+            ConfigError::CallInfoTooLarge => "callinfo length must not exceed max calls",
+            ConfigError::MaxRustCallsTooSmall => "max rust calls must be at least 1",
+            ConfigError::MemoryLimitTooSmall => "memory limit cannot hold the initial stack and libraries",
+            ConfigError::GcStepMulTooSmall => "gc step multiplier must be positive",
+            ConfigError::GcStepSizeTooLarge => "gc step size must be below 2^30 bytes",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// brief: the errors surfaced to the host
+#[derive(Debug, Clone, PartialEq)]
+pub enum LuaError {
+    FromLuaConversion {
+        from: &'static str,
+        to: &'static str,
+        message: Option<String>,
+    }, // a lua value cannot become the rust type
+    ToLuaConversion {
+        from: &'static str,
+        to: &'static str,
+        message: Option<String>,
+    }, // a rust value cannot become a lua value
+    Runtime(String),
+    Object(Value), // an error object other than a utf-8 string, `error({})`
+    Memory(String),
+    UserDataTypeMismatch,   // the userdata holds another rust type
+    UserDataBorrowError,    // the userdata is borrowed mutably already
+    UserDataBorrowMutError, // the userdata is borrowed already
+    UserDataDestructed,     // the scope of the userdata has ended
+    CallbackDestructed,     // the scope of the callback has ended
+    CallbackPanic {
+        message: String,
+        payload: PanicPayload,
+    }, // a rust function panicked, see Machine::call
+}
+
+/// brief: the payload of a rust panic caught at a callback boundary
+/// the clones of an error share it, the first `take` gets it
+#[derive(Clone)]
+pub struct PanicPayload(Arc<Mutex<Option<Box<dyn Any + Send>>>>);
+
+impl PanicPayload {
+    pub fn new(payload: Box<dyn Any + Send>) -> Self {
+        PanicPayload(Arc::new(Mutex::new(Some(payload))))
+    }
+
+    /// brief: the message of a panic raised with a string, `panic!("..")`
+    pub fn message_of(payload: &(dyn Any + Send)) -> String {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "<non-string panic payload>".to_string()
+        }
+    }
+
+    /// brief: the payload, none once taken
+    pub fn take(&self) -> Option<Box<dyn Any + Send>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner()).take()
+    }
+}
+
+impl std::fmt::Debug for PanicPayload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PanicPayload(..)")
+    }
+}
+
+impl PartialEq for PanicPayload {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl LuaError {
+    /// brief: the error raised with an error object, a utf-8 string is a
+    /// runtime error
+    pub fn from_value(val: Value) -> Self {
+        match val {
+            Value::String(s) => match s.to_str() {
+                Ok(message) => LuaError::Runtime(message.to_string()),
+                Err(_) => LuaError::Object(Value::String(s)),
+            },
+            val => LuaError::Object(val),
+        }
+    }
+
+    /// brief: the error object a protected call returns to lua
+    pub fn into_value(self) -> Value {
+        match self {
+            LuaError::Runtime(message) => Value::from(message.as_str()),
+            LuaError::Object(val) => val,
+            err => Value::from(err.to_string().as_str()),
+        }
+    }
+
+    /// brief: the error of a panic caught at a callback boundary
+    pub fn callback_panic(payload: Box<dyn Any + Send>) -> Self {
+        LuaError::CallbackPanic {
+            message: PanicPayload::message_of(&*payload),
+            payload: PanicPayload::new(payload),
+        }
+    }
+
+    /// brief: panic again with the original payload if the error is a caught
+    /// panic whose payload has not been taken, return the error otherwise
+    pub fn resume_panic(self) -> Self {
+        if let LuaError::CallbackPanic { payload, .. } = &self {
+            if let Some(payload) = payload.take() {
+                std::panic::resume_unwind(payload);
+            }
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for LuaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LuaError::FromLuaConversion { from, to, message } => {
+                write!(f, "cannot convert lua {} to {}", from, to)?;
+                match message {
+                    Some(message) => write!(f, " ({})", message),
+                    None => Ok(()),
+                }
+            }
+            LuaError::ToLuaConversion { from, to, message } => {
+                write!(f, "cannot convert {} to lua {}", from, to)?;
+                match message {
+                    Some(message) => write!(f, " ({})", message),
+                    None => Ok(()),
+                }
+            }
+            LuaError::Runtime(message) => f.write_str(message),
+            LuaError::Object(Value::String(s)) => f.write_str(&s.to_string_lossy()),
+            LuaError::Object(Value::Integer(i)) => write!(f, "{}", i),
+            LuaError::Object(Value::Number(n)) => f.write_str(&fmt_number(*n)),
+            LuaError::Object(val) => write!(f, "(error object is a {} value)", val.type_name()),
+            LuaError::Memory(message) => f.write_str(message),
+            LuaError::UserDataTypeMismatch => f.write_str("userdata is not of the expected type"),
+            LuaError::UserDataBorrowError => f.write_str("userdata already mutably borrowed"),
+            LuaError::UserDataBorrowMutError => f.write_str("userdata already borrowed"),
+            LuaError::UserDataDestructed => f.write_str("userdata has been destroyed"),
+            LuaError::CallbackDestructed => f.write_str("callback has been destroyed"),
+            LuaError::CallbackPanic { message, .. } => write!(f, "rust panic: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LuaError {}
+
+pub type LuaResult<T> = Result<T, LuaError>;
+
+/// brief: the resource budget of a machine, fixed when the machine is built
+pub struct LuaConfig {
+    pub min_stack: usize,
+    pub stack_size: usize,
+    pub max_stack: usize,
+    pub max_calls: usize,
+    pub ci_len: usize,
+    pub max_rcalls: usize,
+    pub libs: StdLib,
+    pub memory_limit: Option<usize>,
+    pub memory_quota: Box<dyn MemoryQuota>,
+    pub gc: GcParams,
+}
+
+impl Default for LuaConfig {
+    fn default() -> Self {
+        Self {
+            min_stack: LUA_MIN_STACK as usize,
+            stack_size: LUA_STACK_SIZE as usize,
+            max_stack: LUA_MAX_STACK as usize,
+            max_calls: LUA_MAX_CALLS,
+            ci_len: LUA_CI_LEN,
+            max_rcalls: LUA_MAX_RCALLS,
+            libs: StdLib::default(),
+            memory_limit: None,
+            memory_quota: Box::new(Unlimited),
+            gc: GcParams::default(),
+        }
+    }
+}
+
+impl std::fmt::Debug for LuaConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaConfig")
+            .field("min_stack", &self.min_stack)
+            .field("stack_size", &self.stack_size)
+            .field("max_stack", &self.max_stack)
+            .field("max_calls", &self.max_calls)
+            .field("ci_len", &self.ci_len)
+            .field("max_rcalls", &self.max_rcalls)
+            .field("libs", &self.libs)
+            .field("memory_limit", &self.memory_limit)
+            .field("gc", &self.gc)
+            .finish()
+    }
+}
+
+impl LuaConfig {
+    /// brief: check the budget is self-consistent
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.min_stack == 0 {
+            return Err(ConfigError::MinStackTooSmall);
+        }
+        if self.stack_size < self.min_stack + LUA_EXTRA_STACK as usize {
+            return Err(ConfigError::StackSizeTooSmall);
+        }
+        if self.stack_size > self.max_stack {
+            return Err(ConfigError::StackSizeTooLarge);
+        }
+        if self.max_stack > LUA_MAX_STACK_LIMIT {
+            return Err(ConfigError::MaxStackTooLarge);
+        }
+        if self.max_calls == 0 {
+            return Err(ConfigError::MaxCallsTooSmall);
+        }
+        if self.ci_len > self.max_calls {
+            return Err(ConfigError::CallInfoTooLarge);
+        }
+        if self.max_rcalls == 0 {
+            return Err(ConfigError::MaxRustCallsTooSmall);
+        }
+        if let Some(limit) = self.memory_limit {
+            if limit < self.initial_bytes() {
+                return Err(ConfigError::MemoryLimitTooSmall);
+            }
+        }
+        if self.gc.step_mul == 0 {
+            return Err(ConfigError::GcStepMulTooSmall);
+        }
+        if self.gc.step_size >= 30 {
+            return Err(ConfigError::GcStepSizeTooLarge);
+        }
+        Ok(())
+    }
+
+    /// brief: the bytes the stack and the callinfo vector take on start. the
+    /// registry, the globals and the libraries come on top, build checks them
+    pub fn initial_bytes(&self) -> usize {
+        self.stack_size * LUA_STACK_SLOT_SIZE + self.ci_len * LUA_CI_SLOT_SIZE
+    }
+}
+
+pub const LUA_STACK_SLOT_SIZE: usize =
+    std::mem::size_of::<crate::common::state::statedef::StkElem>();
+pub const LUA_CI_SLOT_SIZE: usize = std::mem::size_of::<crate::common::state::statedef::CallInfo>();
 
 #[allow(unused_macros)]
 macro_rules! cast {
@@ -64,3 +427,65 @@ macro_rules! restorestack {
         (L->stack + (o))
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// brief: a quota refusing everything, never reached by validate
+    struct Refuse;
+
+    impl MemoryQuota for Refuse {
+        fn grant(&self, _in_use: usize, _request: usize) -> bool {
+            false
+        }
+    }
+
+    fn config(change: impl FnOnce(&mut LuaConfig)) -> Result<(), ConfigError> {
+        let mut config = LuaConfig::default();
+        change(&mut config);
+        config.validate()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(LuaConfig::default().validate(), Ok(()));
+        assert_eq!(config(|c| c.memory_quota = Box::new(Refuse)), Ok(()));
+        let smallest = config(|c| {
+            c.min_stack = 1;
+            c.stack_size = 1 + LUA_EXTRA_STACK as usize;
+            c.max_stack = c.stack_size;
+            c.max_calls = 1;
+            c.ci_len = 1;
+            c.max_rcalls = 1;
+            c.memory_limit = Some(c.initial_bytes());
+        });
+        assert_eq!(smallest, Ok(()));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        assert_eq!(config(|c| c.min_stack = 0), Err(ConfigError::MinStackTooSmall));
+        assert_eq!(config(|c| c.stack_size = c.min_stack), Err(ConfigError::StackSizeTooSmall));
+        assert_eq!(config(|c| c.max_stack = c.stack_size - 1), Err(ConfigError::StackSizeTooLarge));
+        let limit = LUA_MAX_STACK_LIMIT + 1;
+        assert_eq!(config(|c| c.max_stack = limit), Err(ConfigError::MaxStackTooLarge));
+        assert_eq!(config(|c| c.max_calls = 0), Err(ConfigError::MaxCallsTooSmall));
+        assert_eq!(config(|c| c.ci_len = c.max_calls + 1), Err(ConfigError::CallInfoTooLarge));
+        assert_eq!(config(|c| c.max_rcalls = 0), Err(ConfigError::MaxRustCallsTooSmall));
+        let limit = LuaConfig::default().initial_bytes() - 1;
+        assert_eq!(config(|c| c.memory_limit = Some(limit)), Err(ConfigError::MemoryLimitTooSmall));
+        assert_eq!(config(|c| c.gc.step_mul = 0), Err(ConfigError::GcStepMulTooSmall));
+        assert_eq!(config(|c| c.gc.step_size = 30), Err(ConfigError::GcStepSizeTooLarge));
+        // min stack above the initial stack is caught before the max stack
+        let both = config(|c| {
+            c.min_stack = c.stack_size;
+            c.max_stack = 0;
+        });
+        assert_eq!(both, Err(ConfigError::StackSizeTooSmall));
+        assert_eq!(
+            ConfigError::StackSizeTooLarge.to_string(),
+            "stack size must not exceed max stack"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+pub mod gc;
 pub mod obj;
 pub mod state;
 pub mod lua;
\ No newline at end of file
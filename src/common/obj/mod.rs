@@ -1,3 +1,6 @@
+pub mod objconv;
 pub mod objdef;
-pub mod objtrait;
-pub mod objtype;
\ No newline at end of file
+pub mod objtable;
+pub mod objtype;
+pub mod objud;
+pub mod objvalue;
\ No newline at end of file
@@ -0,0 +1,757 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+use crate::common::lua::{LuaError, LuaResult};
+use crate::common::state::statedef::LuaState;
+
+use super::objtable::float_to_integer;
+use super::objtype::{FLT, INT};
+use super::objud::UserData;
+use super::objvalue::{AnyUserData, Function, LuaString, Table, Value};
+
+/// brief: the values passed to or returned from a function, first value in front
+pub type MultiValue = VecDeque<Value>;
+
+/// brief: a rust value that can become a lua value
+pub trait IntoLua {
+    fn into_lua(self, state: &mut LuaState) -> LuaResult<Value>;
+}
+
+/// brief: a rust value that can be made of a lua value
+pub trait FromLua: Sized {
+    fn from_lua(value: Value, state: &mut LuaState) -> LuaResult<Self>;
+}
+
+/// brief: rust values that become any number of lua values
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self, state: &mut LuaState) -> LuaResult<MultiValue>;
+}
+
+/// brief: rust values made of any number of lua values, the missing ones are nil
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(values: MultiValue, state: &mut LuaState) -> LuaResult<Self>;
+}
+
+/// brief: the rest of the arguments or results, `...` of lua
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T> Variadic<T> {
+    pub fn new() -> Self {
+        Variadic(Vec::new())
+    }
+}
+
+impl<T> From<Vec<T>> for Variadic<T> {
+    fn from(values: Vec<T>) -> Self {
+        Variadic(values)
+    }
+}
+
+impl<T> std::ops::Deref for Variadic<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> std::ops::DerefMut for Variadic<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+
+fn from_error(value: &Value, to: &'static str, message: Option<String>) -> LuaError {
+    LuaError::FromLuaConversion {
+        from: value.type_name(),
+        to,
+        message,
+    }
+}
+
+/// brief: format a float the way lua does, "%.14g" and ".0" for integral values
+pub fn fmt_number(number: FLT) -> String {
+    if number.is_nan() {
+        return if number.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if number.is_infinite() {
+        return if number < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    if number == 0.0 {
+        return if number.is_sign_negative() { "-0.0" } else { "0.0" }.to_string();
+    }
+
+    // round to 14 significant digits first, the exponent may change
+    let sci = format!("{:.13e}", number);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+
+    let mut text = if !(-4..14).contains(&exp) {
+        let mantissa = trim_fraction(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        trim_fraction(&format!("{:.*}", (13 - exp) as usize, number)).to_string()
+    };
+    if !text.contains(['.', 'e']) {
+        text.push_str(".0");
+    }
+    text
+}
+
+fn trim_fraction(text: &str) -> &str {
+    if text.contains('.') {
+        text.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        text
+    }
+}
+
+/// brief: the number a string converts to, as the lexer reads a numeral
+/// the integers in hexadecimal wrap around
+pub fn str_to_number(bytes: &[u8]) -> Option<Value> {
+    let text = std::str::from_utf8(bytes).ok()?.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        // a float if there is a dot or an exponent
+        if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            let number = hex_to_number(hex.as_bytes())?;
+            return Some(Value::Number(if negative { -number } else { number }));
+        }
+        let value = hex.bytes().fold(0 as INT, |acc, b| {
+            acc.wrapping_mul(16).wrapping_add((b as char).to_digit(16).unwrap() as INT)
+        });
+        return Some(Value::Integer(if negative { value.wrapping_neg() } else { value }));
+    }
+
+    // only digits, a dot and an exponent, rust also accepts "inf" and "nan"
+    let is_numeral = !digits.is_empty()
+        && digits.bytes().any(|b| b.is_ascii_digit())
+        && digits
+            .bytes()
+            .all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'+' | b'-'));
+    if !is_numeral {
+        return None;
+    }
+    if digits.bytes().all(|b| b.is_ascii_digit()) {
+        if let Ok(value) = text.parse::<INT>() {
+            return Some(Value::Integer(value));
+        }
+    }
+    text.parse::<FLT>().ok().map(Value::Number)
+}
+
+/// brief: a hexadecimal float without its "0x", as lua_strx2number reads it:
+/// hex digits with an optional dot, then an optional binary exponent "p"
+fn hex_to_number(hex: &[u8]) -> Option<FLT> {
+    const MAX_SIG_DIG: i32 = 30; // the digits beyond only count in the exponent
+
+    let (mut number, mut exp) = (0.0 as FLT, 0i32);
+    let (mut sig_dig, mut any_dig, mut has_dot) = (0, false, false);
+    let mut pos = 0;
+    while let Some(&b) = hex.get(pos) {
+        if b == b'.' {
+            if has_dot {
+                return None;
+            }
+            has_dot = true;
+        } else if let Some(digit) = (b as char).to_digit(16) {
+            any_dig = true;
+            if sig_dig == 0 && digit == 0 {
+                // leading zeros are not significant
+            } else if sig_dig < MAX_SIG_DIG {
+                sig_dig += 1;
+                number = number * 16.0 + digit as FLT;
+            } else {
+                exp = exp.saturating_add(1);
+            }
+            if has_dot {
+                exp = exp.saturating_sub(1);
+            }
+        } else {
+            break;
+        }
+        pos += 1;
+    }
+    if !any_dig {
+        return None;
+    }
+    exp = exp.saturating_mul(4);
+
+    if let Some(b'p' | b'P') = hex.get(pos) {
+        let rest = &hex[pos + 1..];
+        let (negative, digits) = match rest.first() {
+            Some(b'-') => (true, &rest[1..]),
+            Some(b'+') => (false, &rest[1..]),
+            _ => (false, rest),
+        };
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        let value = digits.iter().fold(0i32, |acc, b| {
+            acc.saturating_mul(10).saturating_add((b - b'0') as i32)
+        });
+        exp = exp.saturating_add(if negative { -value } else { value });
+    } else if pos != hex.len() {
+        return None;
+    }
+    Some(ldexp(number, exp))
+}
+
+/// brief: `number * 2^exp`, in steps so that no power overflows on the way.
+/// the powers are made of their bits, exact where powi may round
+fn ldexp(mut number: FLT, mut exp: i32) -> FLT {
+    // 2^e for a normal exponent, -1022 <= e <= 1023
+    let pow2 = |e: i32| FLT::from_bits(((e + 1023) as u64) << 52);
+    while exp > 1023 && number.is_finite() && number != 0.0 {
+        number *= pow2(1023);
+        exp -= 1023;
+    }
+    while exp < -1022 && number != 0.0 {
+        number *= pow2(-1022);
+        exp += 1022;
+    }
+    number * pow2(exp.clamp(-1022, 1023))
+}
+
+impl IntoLua for Value {
+    fn into_lua(self, _state: &mut LuaState) -> LuaResult<Value> {
+        Ok(self)
+    }
+}
+
+impl FromLua for Value {
+    fn from_lua(value: Value, _state: &mut LuaState) -> LuaResult<Self> {
+        Ok(value)
+    }
+}
+
+impl IntoLua for bool {
+    fn into_lua(self, _state: &mut LuaState) -> LuaResult<Value> {
+        Ok(Value::Boolean(self))
+    }
+}
+
+/// brief: nil and false are false, everything else is true
+impl FromLua for bool {
+    fn from_lua(value: Value, _state: &mut LuaState) -> LuaResult<Self> {
+        Ok(value.is_truthy())
+    }
+}
+
+/// brief: the integer of a number or a numeric string
+fn to_integer(value: &Value) -> Option<INT> {
+    match value {
+        Value::Integer(i) => Some(*i),
+        Value::Number(n) => float_to_integer(*n),
+        Value::String(s) => match str_to_number(s.as_bytes())? {
+            Value::Integer(i) => Some(i),
+            Value::Number(n) => float_to_integer(n),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// brief: the float of a number or a numeric string
+fn to_number(value: &Value) -> Option<FLT> {
+    match value {
+        Value::Integer(i) => Some(*i as FLT),
+        Value::Number(n) => Some(*n),
+        Value::String(s) => match str_to_number(s.as_bytes())? {
+            Value::Integer(i) => Some(i as FLT),
+            Value::Number(n) => Some(n),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl IntoLua for $ty {
+                fn into_lua(self, _state: &mut LuaState) -> LuaResult<Value> {
+                    match INT::try_from(self) {
+                        Ok(i) => Ok(Value::Integer(i)),
+                        Err(_) => Ok(Value::Number(self as FLT)),
+                    }
+                }
+            }
+
+            impl FromLua for $ty {
+                fn from_lua(value: Value, _state: &mut LuaState) -> LuaResult<Self> {
+                    let integer = match to_integer(&value) {
+                        Some(i) => i,
+                        None => {
+                            let message = match value {
+                                Value::Number(_) => Some("number has no integer representation".to_string()),
+                                _ => None,
+                            };
+                            return Err(from_error(&value, stringify!($ty), message));
+                        }
+                    };
+                    <$ty>::try_from(integer).map_err(|_| {
+                        from_error(&value, stringify!($ty), Some("out of range".to_string()))
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+macro_rules! impl_float {
+    ($($ty:ty),*) => {
+        $(
+            impl IntoLua for $ty {
+                fn into_lua(self, _state: &mut LuaState) -> LuaResult<Value> {
+                    Ok(Value::Number(self as FLT))
+                }
+            }
+
+            impl FromLua for $ty {
+                fn from_lua(value: Value, _state: &mut LuaState) -> LuaResult<Self> {
+                    match to_number(&value) {
+                        Some(n) => Ok(n as $ty),
+                        None => Err(from_error(&value, stringify!($ty), None)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_float!(f32, f64);
+
+impl IntoLua for LuaString {
+    fn into_lua(self, _state: &mut LuaState) -> LuaResult<Value> {
+        Ok(Value::String(self))
+    }
+}
+
+/// brief: numbers are converted to strings as lua does
+impl FromLua for LuaString {
+    fn from_lua(value: Value, _state: &mut LuaState) -> LuaResult<Self> {
+        match value {
+            Value::String(s) => Ok(s),
+            Value::Integer(i) => Ok(LuaString::from(i.to_string().as_str())),
+            Value::Number(n) => Ok(LuaString::from(fmt_number(n).as_str())),
+            other => Err(from_error(&other, "string", None)),
+        }
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self, _state: &mut LuaState) -> LuaResult<Value> {
+        Ok(Value::from(self.as_str()))
+    }
+}
+
+impl IntoLua for &str {
+    fn into_lua(self, _state: &mut LuaState) -> LuaResult<Value> {
+        Ok(Value::from(self))
+    }
+}
+
+impl FromLua for String {
+    fn from_lua(value: Value, state: &mut LuaState) -> LuaResult<Self> {
+        let type_name = value.type_name();
+        let s = LuaString::from_lua(value, state)?;
+        match s.to_str() {
+            Ok(s) => Ok(s.to_string()),
+            Err(e) => Err(LuaError::FromLuaConversion {
+                from: type_name,
+                to: "String",
+                message: Some(e.to_string()),
+            }),
+        }
+    }
+}
+
+macro_rules! impl_handle {
+    ($ty:ident, $variant:ident, $name:expr) => {
+        impl IntoLua for $ty {
+            fn into_lua(self, _state: &mut LuaState) -> LuaResult<Value> {
+                Ok(Value::$variant(self))
+            }
+        }
+
+        impl FromLua for $ty {
+            fn from_lua(value: Value, _state: &mut LuaState) -> LuaResult<Self> {
+                match value {
+                    Value::$variant(handle) => Ok(handle),
+                    other => Err(from_error(&other, $name, None)),
+                }
+            }
+        }
+    };
+}
+
+impl_handle!(Table, Table, "table");
+impl_handle!(Function, Function, "function");
+impl_handle!(AnyUserData, UserData, "userdata");
+
+/// brief: a UserData value becomes a full userdata
+impl<T: UserData> IntoLua for T {
+    fn into_lua(self, state: &mut LuaState) -> LuaResult<Value> {
+        state.create_userdata(self).map(Value::UserData)
+    }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self, state: &mut LuaState) -> LuaResult<Value> {
+        match self {
+            Some(val) => val.into_lua(state),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: Value, state: &mut LuaState) -> LuaResult<Self> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_lua(value, state).map(Some),
+        }
+    }
+}
+
+/// brief: a sequence, the keys 1..n
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self, state: &mut LuaState) -> LuaResult<Value> {
+        let table = state.create_table_value(self.len(), 0)?;
+        for (i, val) in self.into_iter().enumerate() {
+            let val = val.into_lua(state)?;
+            state.raw_set(&table, Value::Integer(i as INT + 1), val)?;
+        }
+        Ok(Value::Table(table))
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: Value, state: &mut LuaState) -> LuaResult<Self> {
+        let table = match value {
+            Value::Table(table) => table,
+            other => return Err(from_error(&other, "Vec", Some("expected a table".to_string()))),
+        };
+        let len = state.raw_len(&table);
+        let mut values = Vec::with_capacity(len as usize);
+        for i in 1..=len {
+            let val = state.raw_get(&table, &Value::Integer(i));
+            values.push(T::from_lua(val, state)?);
+        }
+        Ok(values)
+    }
+}
+
+impl<K: IntoLua, V: IntoLua, S> IntoLua for HashMap<K, V, S> {
+    fn into_lua(self, state: &mut LuaState) -> LuaResult<Value> {
+        let table = state.create_table_value(0, self.len())?;
+        for (key, val) in self.into_iter() {
+            let key = key.into_lua(state)?;
+            let val = val.into_lua(state)?;
+            state.raw_set(&table, key, val)?;
+        }
+        Ok(Value::Table(table))
+    }
+}
+
+impl<K, V, S> FromLua for HashMap<K, V, S>
+where
+    K: FromLua + Eq + Hash,
+    V: FromLua,
+    S: std::hash::BuildHasher + Default,
+{
+    fn from_lua(value: Value, state: &mut LuaState) -> LuaResult<Self> {
+        let table = match value {
+            Value::Table(table) => table,
+            other => return Err(from_error(&other, "HashMap", Some("expected a table".to_string()))),
+        };
+        let mut map = HashMap::with_hasher(S::default());
+        for (key, val) in state.raw_pairs(&table) {
+            map.insert(K::from_lua(key, state)?, V::from_lua(val, state)?);
+        }
+        Ok(map)
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self, state: &mut LuaState) -> LuaResult<MultiValue> {
+        Ok(MultiValue::from([self.into_lua(state)?]))
+    }
+}
+
+/// brief: the first value, the rest are dropped
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(mut values: MultiValue, state: &mut LuaState) -> LuaResult<Self> {
+        T::from_lua(values.pop_front().unwrap_or(Value::Nil), state)
+    }
+}
+
+impl IntoLuaMulti for MultiValue {
+    fn into_lua_multi(self, _state: &mut LuaState) -> LuaResult<MultiValue> {
+        Ok(self)
+    }
+}
+
+impl FromLuaMulti for MultiValue {
+    fn from_lua_multi(values: MultiValue, _state: &mut LuaState) -> LuaResult<Self> {
+        Ok(values)
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for Variadic<T> {
+    fn into_lua_multi(self, state: &mut LuaState) -> LuaResult<MultiValue> {
+        self.0.into_iter().map(|val| val.into_lua(state)).collect()
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(values: MultiValue, state: &mut LuaState) -> LuaResult<Self> {
+        values
+            .into_iter()
+            .map(|val| T::from_lua(val, state))
+            .collect::<LuaResult<Vec<T>>>()
+            .map(Variadic)
+    }
+}
+
+impl IntoLuaMulti for () {
+    fn into_lua_multi(self, _state: &mut LuaState) -> LuaResult<MultiValue> {
+        Ok(MultiValue::new())
+    }
+}
+
+impl FromLuaMulti for () {
+    fn from_lua_multi(_values: MultiValue, _state: &mut LuaState) -> LuaResult<Self> {
+        Ok(())
+    }
+}
+
+/// brief: every element is a single value but the last, which may take the rest
+macro_rules! impl_tuple {
+    ($($name:ident)* ; $last:ident) => {
+        impl<$($name: IntoLua,)* $last: IntoLuaMulti> IntoLuaMulti for ($($name,)* $last,) {
+            #[allow(non_snake_case)]
+            fn into_lua_multi(self, state: &mut LuaState) -> LuaResult<MultiValue> {
+                let ($($name,)* $last,) = self;
+                let mut values = MultiValue::new();
+                $(values.push_back($name.into_lua(state)?);)*
+                values.extend($last.into_lua_multi(state)?);
+                Ok(values)
+            }
+        }
+
+        impl<$($name: FromLua,)* $last: FromLuaMulti> FromLuaMulti for ($($name,)* $last,) {
+            #[allow(non_snake_case, unused_mut)]
+            fn from_lua_multi(mut values: MultiValue, state: &mut LuaState) -> LuaResult<Self> {
+                $(let $name = $name::from_lua(values.pop_front().unwrap_or(Value::Nil), state)?;)*
+                let $last = $last::from_lua_multi(values, state)?;
+                Ok(($($name,)* $last,))
+            }
+        }
+    };
+}
+
+impl_tuple!(; A);
+impl_tuple!(A; B);
+impl_tuple!(A B; C);
+impl_tuple!(A B C; D);
+impl_tuple!(A B C D; E);
+impl_tuple!(A B C D E; F);
+impl_tuple!(A B C D E F; G);
+impl_tuple!(A B C D E F G; H);
+impl_tuple!(A B C D E F G H; I);
+impl_tuple!(A B C D E F G H I; J);
+impl_tuple!(A B C D E F G H I J; K);
+impl_tuple!(A B C D E F G H I J K; L);
+
+#[cfg(test)]
+mod test {
+    use core::ptr::null_mut;
+    use std::collections::HashMap;
+
+    use crate::common::lua::{LuaConfig, LuaError};
+    use crate::common::obj::objconv::{fmt_number, str_to_number};
+    use crate::common::obj::objconv::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, Variadic};
+    use crate::common::obj::objvalue::Value;
+    use crate::common::state::statedef::LuaState;
+    use crate::{FromLua, IntoLua};
+
+    fn new_state() -> LuaState {
+        LuaState::mainthread_new(null_mut(), LuaConfig::default()).unwrap()
+    }
+
+    fn roundtrip<T: IntoLua + FromLua>(state: &mut LuaState, val: T) -> T {
+        let val = val.into_lua(state).unwrap();
+        T::from_lua(val, state).unwrap()
+    }
+
+    #[derive(Debug, PartialEq, FromLua, IntoLua)]
+    struct Point {
+        x: i32,
+        y: f64,
+        label: Option<String>,
+    }
+
+    #[derive(Debug, PartialEq, FromLua, IntoLua)]
+    struct Pair(String, Vec<i64>);
+
+    #[derive(Debug, PartialEq, FromLua, IntoLua)]
+    enum Shape {
+        Empty,
+        Circle { center: Point, radius: f64 },
+        Segment(Point, Point),
+    }
+
+    #[test]
+    fn primitives_convert_as_lua_does() {
+        let mut state = new_state();
+        assert_eq!(roundtrip(&mut state, 42u8), 42);
+        assert_eq!(roundtrip(&mut state, -3i64), -3);
+        assert_eq!(roundtrip(&mut state, 0.25f32), 0.25);
+        assert_eq!(roundtrip(&mut state, "héllo".to_string()), "héllo");
+
+        assert_eq!(i32::from_lua(Value::Number(3.0), &mut state), Ok(3));
+        assert_eq!(i32::from_lua(Value::from(" 0x10 "), &mut state), Ok(16));
+        assert!(i32::from_lua(Value::Number(3.5), &mut state).is_err());
+        assert!(u8::from_lua(Value::Integer(256), &mut state).is_err());
+        assert_eq!(String::from_lua(Value::Number(2.0), &mut state), Ok("2.0".to_string()));
+        assert!(!bool::from_lua(Value::Nil, &mut state).unwrap());
+        assert_eq!(Option::<i32>::from_lua(Value::Nil, &mut state), Ok(None));
+        assert_eq!(u64::MAX.into_lua(&mut state), Ok(Value::Number(u64::MAX as f64)));
+    }
+
+    #[test]
+    fn containers_become_tables() {
+        let mut state = new_state();
+        assert_eq!(roundtrip(&mut state, vec![1, 2, 3]), vec![1, 2, 3]);
+
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), 1.5);
+        map.insert("b".to_string(), -2.0);
+        assert_eq!(roundtrip(&mut state, map.clone()), map);
+
+        let err = Vec::<i32>::from_lua(Value::Integer(1), &mut state).unwrap_err();
+        assert!(matches!(err, LuaError::FromLuaConversion { from: "number", .. }));
+    }
+
+    #[test]
+    fn tuples_and_variadics() {
+        let mut state = new_state();
+        let values = (1, "two", Variadic(vec![3.0, 4.0]))
+            .into_lua_multi(&mut state)
+            .unwrap();
+        assert_eq!(values.len(), 4);
+
+        let (a, b, rest): (i64, String, Variadic<f64>) =
+            FromLuaMulti::from_lua_multi(values, &mut state).unwrap();
+        assert_eq!((a, b.as_str(), rest.0), (1, "two", vec![3.0, 4.0]));
+
+        // missing values are nil
+        let (a, b): (Option<i64>, Option<i64>) =
+            FromLuaMulti::from_lua_multi(Default::default(), &mut state).unwrap();
+        assert_eq!((a, b), (None, None));
+    }
+
+    #[test]
+    fn derived_structs_and_enums() {
+        let mut state = new_state();
+        let point = Point {
+            x: 1,
+            y: 2.5,
+            label: Some("p".to_string()),
+        };
+        let val = point.into_lua(&mut state).unwrap();
+        let table = match &val {
+            Value::Table(table) => table.clone(),
+            other => panic!("expected a table, got {:?}", other),
+        };
+        assert_eq!(state.raw_get(&table, &Value::from("x")), Value::Integer(1));
+        assert_eq!(
+            Point::from_lua(val, &mut state).unwrap(),
+            Point {
+                x: 1,
+                y: 2.5,
+                label: Some("p".to_string())
+            }
+        );
+
+        let pair = Pair("a".to_string(), vec![7]);
+        assert_eq!(roundtrip(&mut state, pair), Pair("a".to_string(), vec![7]));
+
+        let shapes = vec![
+            Shape::Empty,
+            Shape::Circle {
+                center: Point { x: 0, y: 0.0, label: None },
+                radius: 1.0,
+            },
+            Shape::Segment(
+                Point { x: 0, y: 0.0, label: None },
+                Point { x: 1, y: 1.0, label: None },
+            ),
+        ];
+        let val = shapes.into_lua(&mut state).unwrap();
+        let shapes = Vec::<Shape>::from_lua(val, &mut state).unwrap();
+        assert_eq!(shapes[0], Shape::Empty);
+        assert!(matches!(shapes[1], Shape::Circle { radius, .. } if radius == 1.0));
+
+        let table = state.create_table_value(0, 1).unwrap();
+        state.raw_set(&table, Value::from("tag"), Value::from("Square")).unwrap();
+        assert!(Shape::from_lua(Value::Table(table), &mut state).is_err());
+    }
+
+    #[test]
+    fn numbers_format_like_lua() {
+        assert_eq!(fmt_number(1.0), "1.0");
+        assert_eq!(fmt_number(0.1), "0.1");
+        assert_eq!(fmt_number(1e15), "1e+15");
+        assert_eq!(fmt_number(-1.5e-7), "-1.5e-07");
+        assert_eq!(fmt_number(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(fmt_number(f64::INFINITY), "inf");
+
+        assert_eq!(str_to_number(b"10"), Some(Value::Integer(10)));
+        assert_eq!(str_to_number(b"1e2"), Some(Value::Number(100.0)));
+        assert_eq!(str_to_number(b"inf"), None);
+        assert_eq!(str_to_number(b""), None);
+    }
+
+    #[test]
+    fn hex_floats_are_numbers() {
+        let cases: &[(&[u8], f64)] = &[
+            (b"0x1p4", 16.0),
+            (b"0x1.8", 1.5),
+            (b"0X.8", 0.5),
+            (b"0xA.", 10.0),
+            (b"0x1P-2", 0.25),
+            (b"0x1.8p+1", 3.0),
+            (b" -0x10.4p0 ", -16.25),
+            (b"0x.1p4", 1.0),
+            (b"0x1p-1074", f64::from_bits(1)),
+            (b"0x1p1024", f64::INFINITY),
+            (b"0x1p-99999999999", 0.0),
+            (b"0x000000000000000000000000000000000001p0", 1.0),
+            (b"0x1000000000000000000000000000000000000p-144", 1.0),
+        ];
+        for (text, number) in cases {
+            assert_eq!(str_to_number(text), Some(Value::Number(*number)), "{:?}", text);
+        }
+        // without a dot or an exponent it stays an integer
+        assert_eq!(str_to_number(b"0x10"), Some(Value::Integer(16)));
+        assert_eq!(str_to_number(b"0xffffffffffffffff"), Some(Value::Integer(-1)));
+
+        let malformed: &[&[u8]] = &[
+            b"0x", b"0x.", b"0xp1", b"0x1p", b"0x1p+", b"0x1.2.3", b"0x1q", b"0x1p2.5",
+        ];
+        for text in malformed {
+            assert_eq!(str_to_number(text), None, "{:?}", text);
+        }
+    }
+}
@@ -1,9 +1,4 @@
-use std::ptr::NonNull;
-
-use super::{
-    objtrait::ObjectTrait,
-    objtype::{Bool, DataType, Integer, Nil, Number, RFunction, UserData, FLT, INT, RFUNC},
-};
+use super::objtype::{DataType, GcRef, FLT, INT, LRFUNC};
 
 #[derive(Debug)]
 pub enum TObject {
@@ -16,39 +11,37 @@ pub enum TObject {
     TFunction = 7,
     TThread = 8,
     TNone = 9,
+    TUserData = 10,
 }
 
 pub const BASIC_TYPE_BIT: usize = 4;
 
 impl TObject {
     pub fn is_function(label: u8) -> bool {
-        if label & 7u8 == 7 {
-            true
-        } else {
-            false
-        }
+        label & 7u8 == 7
     }
 }
 
 #[derive(Debug)]
 pub enum TNumber {
-    NumInt = (TObject::TNumber as isize | (0 << 4)), //1
+    NumInt = TObject::TNumber as isize, //1
     NumFlt = (TObject::TNumber as isize | (1 << 4)), //17
 }
 
 #[derive(Debug)]
 pub enum TFuction {
-    TLCL = (TObject::TFunction as isize | (0 << 4)), //7
+    TLCL = TObject::TFunction as isize, //7
     TLRF = (TObject::TFunction as isize | (1 << 4)), //23 type: light rust function
-    TCCL = (TObject::TFunction as isize | (2 << 4)), //39
+    TCCL = (TObject::TFunction as isize | (2 << 4)), //39 type: rust closure
 }
 
 #[derive(Debug)]
 pub enum TString {
-    LngStr = (TObject::TString as isize | (0 << 4)), //4
+    LngStr = TObject::TString as isize, //4
     ShrStr = (TObject::TString as isize | (1 << 4)), //20
 }
 
+/// brief: a tagged value, 16 bytes: an 8-byte payload and a 1-byte tag
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
 pub struct LuaTObject {
@@ -58,11 +51,39 @@ pub struct LuaTObject {
 
 pub type TObj = LuaTObject;
 
+const _: () = assert!(std::mem::size_of::<LuaTObject>() == 16);
+
 impl Default for LuaTObject {
     fn default() -> Self {
         Self {
             value: Default::default(),
-            val_type: Default::default(),
+            val_type: TObject::TNil as u8,
+        }
+    }
+}
+
+impl std::fmt::Debug for LuaTObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(integer) = self.as_integer() {
+            write!(f, "Integer({})", integer)
+        } else if let Some(number) = self.as_float() {
+            write!(f, "Number({:?})", number)
+        } else if let Some(boolean) = self.as_bool() {
+            write!(f, "Boolean({})", boolean)
+        } else if let Some(ud) = self.as_ud() {
+            write!(f, "LightUserData({:p})", ud)
+        } else if let Some(lrfunc) = self.as_lrfunc() {
+            write!(f, "LightRustFunction({:p})", lrfunc as *const ())
+        } else if let Some(gc) = self.as_rfunc() {
+            write!(f, "RustClosure(#{}.{})", gc.index, gc.gen)
+        } else if let Some(gc) = self.as_gc() {
+            write!(f, "Object(tag {}, #{}.{})", self.val_type, gc.index, gc.gen)
+        } else if self.is_thread() {
+            f.write_str("Thread")
+        } else if self.is_nil() {
+            f.write_str("Nil")
+        } else {
+            write!(f, "Unknown(tag {})", self.val_type)
         }
     }
 }
@@ -73,11 +94,156 @@ impl LuaTObject {
         self.val_type
     }
 
+    /// brief: the basic type, variant bits masked out
+    #[inline(always)]
+    pub fn get_basic_type(&self) -> u8 {
+        self.val_type & ((1 << BASIC_TYPE_BIT) - 1)
+    }
+
     #[inline(always)]
     pub fn get_value(&self) -> DataType {
         self.value
     }
 
+    #[inline(always)]
+    pub fn is_nil(&self) -> bool {
+        self.val_type == TObject::TNil as u8
+    }
+
+    #[inline(always)]
+    pub fn is_integer(&self) -> bool {
+        self.val_type == TNumber::NumInt as u8
+    }
+
+    #[inline(always)]
+    pub fn is_float(&self) -> bool {
+        self.val_type == TNumber::NumFlt as u8
+    }
+
+    #[inline(always)]
+    pub fn is_number(&self) -> bool {
+        self.get_basic_type() == TObject::TNumber as u8
+    }
+
+    #[inline(always)]
+    pub fn is_bool(&self) -> bool {
+        self.val_type == TObject::TBoolean as u8
+    }
+
+    #[inline(always)]
+    pub fn is_ud(&self) -> bool {
+        self.val_type == TObject::TLightUserData as u8
+    }
+
+    #[inline(always)]
+    pub fn is_function(&self) -> bool {
+        TObject::is_function(self.val_type)
+    }
+
+    #[inline(always)]
+    pub fn is_string(&self) -> bool {
+        self.get_basic_type() == TObject::TString as u8
+    }
+
+    #[inline(always)]
+    pub fn is_table(&self) -> bool {
+        self.val_type == TObject::TTable as u8
+    }
+
+    #[inline(always)]
+    pub fn is_thread(&self) -> bool {
+        self.val_type == TObject::TThread as u8
+    }
+
+    #[inline(always)]
+    pub fn is_full_ud(&self) -> bool {
+        self.val_type == TObject::TUserData as u8
+    }
+
+    /// brief: the value lives in the heap
+    #[inline(always)]
+    pub fn is_collectable(&self) -> bool {
+        self.is_string() || self.is_table() || self.is_full_ud() || self.val_type == TFuction::TCCL as u8
+    }
+
+    /// brief: the heap reference of a string, a table, a full userdata or a rust closure
+    #[inline(always)]
+    pub fn as_gc(&self) -> Option<GcRef> {
+        if self.is_collectable() {
+            Some(unsafe { self.value.val_gc })
+        } else {
+            None
+        }
+    }
+
+    /// brief: the tag and the payload bits, equal bits mean raw-equal values
+    /// except for floats, see the table keys
+    #[inline(always)]
+    pub fn raw_bits(&self) -> (u8, u64) {
+        (self.val_type, unsafe { self.value.val_int } as u64)
+    }
+
+    /// brief: nil and false are false, everything else is true
+    #[inline(always)]
+    pub fn is_falsy(&self) -> bool {
+        self.is_nil() || (self.is_bool() && !unsafe { self.value.val_bl })
+    }
+
+    #[inline(always)]
+    pub fn as_integer(&self) -> Option<INT> {
+        if self.is_integer() {
+            Some(unsafe { self.value.val_int })
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_float(&self) -> Option<FLT> {
+        if self.is_float() {
+            Some(unsafe { self.value.val_num })
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_bool(&self) -> Option<bool> {
+        if self.is_bool() {
+            Some(unsafe { self.value.val_bl })
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_ud(&self) -> Option<*mut ()> {
+        if self.is_ud() {
+            Some(unsafe { self.value.val_ud })
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_lrfunc(&self) -> Option<LRFUNC> {
+        if self.val_type == TFuction::TLRF as u8 {
+            Some(unsafe { self.value.val_lrfunc })
+        } else {
+            None
+        }
+    }
+
+    /// brief: the heap reference of a rust closure, see LuaState::get_rfunc
+    #[inline(always)]
+    pub fn as_rfunc(&self) -> Option<GcRef> {
+        if self.val_type == TFuction::TCCL as u8 {
+            Some(unsafe { self.value.val_gc })
+        } else {
+            None
+        }
+    }
+
     pub fn new_integer(integer: INT) -> Self {
         let mut obj = LuaTObject::default();
         obj.set_integer(integer);
@@ -86,7 +252,7 @@ impl LuaTObject {
 
     #[inline(always)]
     pub fn set_integer(&mut self, integer: INT) {
-        self.value.val_int = Integer::new(Some(integer));
+        self.value.val_int = integer;
         self.val_type = TNumber::NumInt as u8;
     }
 
@@ -98,7 +264,7 @@ impl LuaTObject {
 
     #[inline(always)]
     pub fn set_float(&mut self, number: FLT) {
-        self.value.val_num = Number::new(Some(number));
+        self.value.val_num = number;
         self.val_type = TNumber::NumFlt as u8;
     }
 
@@ -110,7 +276,8 @@ impl LuaTObject {
 
     #[inline(always)]
     pub fn set_bool(&mut self, boolean: bool) {
-        self.value.val_bl = Bool::new(Some(boolean));
+        self.value.val_int = 0;
+        self.value.val_bl = boolean;
         self.val_type = TObject::TBoolean as u8;
     }
 
@@ -120,7 +287,7 @@ impl LuaTObject {
 
     #[inline(always)]
     pub fn set_nil(&mut self) {
-        self.value.val_nil = Nil::new(None);
+        self.value.val_int = 0;
         self.val_type = TObject::TNil as u8;
     }
 
@@ -132,28 +299,61 @@ impl LuaTObject {
 
     #[inline(always)]
     pub fn set_ud(&mut self, ud: *mut ()) {
-        self.value.val_ud = UserData::new(Some(ud));
+        self.value.val_ud = ud;
         self.val_type = TObject::TLightUserData as u8;
     }
 
-    pub fn new_rfunc(rfunc: &RFUNC) -> Self {
+    pub fn new_lrfunc(lrfunc: LRFUNC) -> Self {
         let mut obj = LuaTObject::default();
-        obj.set_rfunc(rfunc);
-        //dbg!(rfunc as *const());
+        obj.set_lrfunc(lrfunc);
         obj
     }
 
-    pub fn set_rfunc(&mut self, rfunc: &RFUNC) {
-        //let state=Machine::machine().get_state();
-        //dbg!("set_rfunc");
-        //rfunc(state);
-        //self.value.val_rfunc =
-        //let x=RFunction::new(Some((rfunc)));
-        self.value.val_rfunc = RFunction::new(Some(NonNull::from(rfunc)));
-        //dbg!("ddddddddddddddddddd",unsafe{self.value.val_rfunc.into_inner()}.unwrap().as_ptr() as *const());
+    #[inline(always)]
+    pub fn set_lrfunc(&mut self, lrfunc: LRFUNC) {
+        self.value.val_lrfunc = lrfunc;
         self.val_type = TFuction::TLRF as u8;
     }
 
+    /// brief: `gc` is the closure object in the heap, see LuaState::push_rfunc
+    pub fn new_rfunc(gc: GcRef) -> Self {
+        LuaTObject::new_gc(TFuction::TCCL as u8, gc)
+    }
+
+    #[inline(always)]
+    pub fn set_rfunc(&mut self, gc: GcRef) {
+        self.value.val_gc = gc;
+        self.val_type = TFuction::TCCL as u8;
+    }
+
+    pub fn new_string(gc: GcRef) -> Self {
+        LuaTObject::new_gc(TString::ShrStr as u8, gc)
+    }
+
+    pub fn new_table(gc: GcRef) -> Self {
+        LuaTObject::new_gc(TObject::TTable as u8, gc)
+    }
+
+    pub fn new_full_ud(gc: GcRef) -> Self {
+        LuaTObject::new_gc(TObject::TUserData as u8, gc)
+    }
+
+    #[inline(always)]
+    fn new_gc(tag: u8, gc: GcRef) -> Self {
+        LuaTObject {
+            value: DataType { val_gc: gc },
+            val_type: tag,
+        }
+    }
+
+    /// brief: the main thread, the only thread of a state
+    pub fn new_thread() -> Self {
+        LuaTObject {
+            value: Default::default(),
+            val_type: TObject::TThread as u8,
+        }
+    }
+
     pub fn new_obj(obj: LuaTObject) -> Self {
         let mut _obj = LuaTObject::default();
         _obj.set_obj(obj);
@@ -166,3 +366,100 @@ impl LuaTObject {
         self.value = obj.value;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::common::obj::objtype::GcRef;
+    use crate::common::state::statedef::LuaState;
+
+    use super::*;
+
+    fn one(_: &mut LuaState) -> usize {
+        1
+    }
+
+    #[test]
+    fn every_tag_round_trips() {
+        for integer in [0, 1, -1, INT::MIN, INT::MAX] {
+            let obj = TObj::new_integer(integer);
+            assert_eq!(obj.get_type(), TNumber::NumInt as u8);
+            assert_eq!(obj.as_integer(), Some(integer));
+            assert_eq!(obj.as_float(), None);
+            assert_eq!(obj.raw_bits(), (TNumber::NumInt as u8, integer as u64));
+        }
+        for number in [0.5, -2.25, FLT::MAX, -FLT::INFINITY] {
+            let obj = TObj::new_float(number);
+            assert_eq!(obj.get_type(), TNumber::NumFlt as u8);
+            assert_eq!(obj.as_float(), Some(number));
+            assert_eq!(obj.as_integer(), None);
+        }
+        for boolean in [false, true] {
+            let obj = TObj::new_bool(boolean);
+            assert_eq!(obj.as_bool(), Some(boolean));
+            assert_eq!(obj.raw_bits(), (TObject::TBoolean as u8, boolean as u64));
+            assert_eq!(obj.is_falsy(), !boolean);
+        }
+        let nil = TObj::new_nil();
+        assert!(nil.is_nil() && nil.is_falsy());
+        assert_eq!(nil.raw_bits(), (TObject::TNil as u8, 0));
+
+        let mut byte = 0u8;
+        let ud = &mut byte as *mut u8 as *mut ();
+        assert_eq!(TObj::new_ud(ud).as_ud(), Some(ud));
+        let function: LRFUNC = one;
+        let lrfunc = TObj::new_lrfunc(function);
+        assert_eq!(lrfunc.as_lrfunc().map(|f| f as usize), Some(function as usize));
+        assert!(lrfunc.is_function() && !lrfunc.is_collectable());
+        assert!(TObj::new_thread().is_thread());
+
+        let gc = GcRef {
+            index: u32::MAX,
+            gen: 7,
+        };
+        let objects = [
+            (TObj::new_string(gc), TString::ShrStr as u8),
+            (TObj::new_table(gc), TObject::TTable as u8),
+            (TObj::new_full_ud(gc), TObject::TUserData as u8),
+            (TObj::new_rfunc(gc), TFuction::TCCL as u8),
+        ];
+        for (obj, tag) in objects {
+            assert_eq!(obj.get_type(), tag);
+            assert_eq!(obj.as_gc(), Some(gc));
+            assert_eq!(TObj::new_obj(obj).raw_bits(), obj.raw_bits());
+        }
+        assert_eq!(TObj::new_rfunc(gc).as_rfunc(), Some(gc));
+        assert_eq!(TObj::new_table(gc).as_rfunc(), None);
+    }
+
+    #[test]
+    fn nan_payloads_are_kept() {
+        let payloads: [u64; 5] = [
+            0x7ff8_0000_0000_0000, // the quiet nan
+            0x7ff8_dead_beef_0001,
+            0x7ff0_0000_0000_0001, // a signaling nan
+            0xfff8_0000_0000_0000, // negative
+            0xffff_ffff_ffff_ffff,
+        ];
+        for bits in payloads {
+            let obj = TObj::new_float(FLT::from_bits(bits));
+            assert!(obj.is_float());
+            assert_eq!(obj.as_float().map(FLT::to_bits), Some(bits));
+            assert_eq!(obj.raw_bits(), (TNumber::NumFlt as u8, bits));
+            // a nan payload never reads as another type
+            assert!(obj.as_integer().is_none() && obj.as_gc().is_none());
+        }
+    }
+
+    #[test]
+    fn negative_zero_keeps_its_sign() {
+        let negative = TObj::new_float(-0.0);
+        let positive = TObj::new_float(0.0);
+        let number = negative.as_float().unwrap();
+        assert!(number == 0.0 && number.is_sign_negative());
+        assert!(positive.as_float().unwrap().is_sign_positive());
+        // the bits differ, raw equality of floats goes through the value
+        assert_ne!(negative.raw_bits(), positive.raw_bits());
+        assert_ne!(negative.raw_bits(), TObj::new_integer(0).raw_bits());
+        assert_eq!(std::mem::size_of::<TObj>(), 16);
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use super::objdef::TObj;
+use super::objtype::{GcRef, INT};
+
+/// brief: a key of the hash part, the tag and the payload bits of a normalized value
+/// strings are interned, so equal strings share the bits as well
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TableKey(u8, u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableError {
+    NilKey,
+    NaNKey,
+    InvalidNextKey,
+}
+
+/// brief: a lua table, an array part for the keys 1..n and a hash part
+/// that keeps the insertion order so that `next` is stable while existing
+/// fields are assigned
+#[derive(Debug, Default)]
+pub struct LuaTable {
+    array: Vec<TObj>,
+    nodes: Vec<(TObj, TObj)>,
+    index: HashMap<TableKey, usize>,
+    dead_nodes: usize, // nodes whose value is nil
+    metatable: Option<GcRef>,
+}
+
+impl LuaTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_capacity(narray: usize, nhash: usize) -> Self {
+        Self {
+            array: Vec::with_capacity(narray),
+            nodes: Vec::with_capacity(nhash),
+            index: HashMap::with_capacity(nhash),
+            dead_nodes: 0,
+            metatable: None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_metatable(&self) -> Option<GcRef> {
+        self.metatable
+    }
+
+    #[inline(always)]
+    pub fn set_metatable(&mut self, metatable: Option<GcRef>) {
+        self.metatable = metatable;
+    }
+
+    /// brief: floats with an integral value are keyed as integers
+    fn normalize(key: &TObj) -> Result<TObj, TableError> {
+        if key.is_nil() {
+            return Err(TableError::NilKey);
+        }
+        if let Some(number) = key.as_float() {
+            if number.is_nan() {
+                return Err(TableError::NaNKey);
+            }
+            if let Some(integer) = float_to_integer(number) {
+                return Ok(TObj::new_integer(integer));
+            }
+        }
+        Ok(*key)
+    }
+
+    #[inline(always)]
+    fn key_of(key: &TObj) -> TableKey {
+        let (tag, bits) = key.raw_bits();
+        TableKey(tag, bits)
+    }
+
+    /// brief: the slot of an integer key in the array part
+    #[inline(always)]
+    fn array_slot(&self, key: INT) -> Option<usize> {
+        if key >= 1 && (key as u64) <= self.array.len() as u64 {
+            Some(key as usize - 1)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_int(&self, key: INT) -> TObj {
+        if let Some(slot) = self.array_slot(key) {
+            return self.array[slot];
+        }
+        if self.index.is_empty() {
+            return TObj::default();
+        }
+        self.get_node(&TObj::new_integer(key))
+    }
+
+    pub fn get(&self, key: &TObj) -> TObj {
+        let key = match LuaTable::normalize(key) {
+            Ok(key) => key,
+            Err(_) => return TObj::default(),
+        };
+        if let Some(integer) = key.as_integer() {
+            return self.get_int(integer);
+        }
+        self.get_node(&key)
+    }
+
+    fn get_node(&self, key: &TObj) -> TObj {
+        match self.index.get(&LuaTable::key_of(key)) {
+            Some(node) => self.nodes[*node].1,
+            None => TObj::default(),
+        }
+    }
+
+    pub fn set_int(&mut self, key: INT, val: TObj) {
+        let _ = self.set(TObj::new_integer(key), val);
+    }
+
+    pub fn set(&mut self, key: TObj, val: TObj) -> Result<(), TableError> {
+        let key = LuaTable::normalize(&key)?;
+
+        if let Some(integer) = key.as_integer() {
+            if let Some(slot) = self.array_slot(integer) {
+                self.array[slot] = val;
+                return Ok(());
+            }
+        }
+
+        let tkey = LuaTable::key_of(&key);
+        if let Some(node) = self.index.get(&tkey) {
+            // an existing field, assigned in place
+            let node = &mut self.nodes[*node];
+            if node.1.is_nil() && !val.is_nil() {
+                self.dead_nodes -= 1;
+            } else if !node.1.is_nil() && val.is_nil() {
+                self.dead_nodes += 1;
+            }
+            node.1 = val;
+            return Ok(());
+        }
+
+        if val.is_nil() {
+            return Ok(());
+        }
+
+        // a new key, the parts may be reorganized
+        if key.as_integer() == Some(self.array.len() as INT + 1) {
+            self.array.push(val);
+            self.migrate_to_array();
+            return Ok(());
+        }
+
+        if self.dead_nodes > 8 && self.dead_nodes * 2 > self.nodes.len() {
+            self.compact();
+        }
+        self.index.insert(tkey, self.nodes.len());
+        self.nodes.push((key, val));
+        Ok(())
+    }
+
+    /// brief: move the keys n+1, n+2.. of the hash part to the array part
+    fn migrate_to_array(&mut self) {
+        if self.index.is_empty() {
+            return;
+        }
+        loop {
+            let next = TObj::new_integer(self.array.len() as INT + 1);
+            let node = match self.index.get(&LuaTable::key_of(&next)) {
+                Some(node) => *node,
+                None => break,
+            };
+            let val = self.nodes[node].1;
+            if val.is_nil() {
+                break;
+            }
+            self.array.push(val);
+            self.nodes[node].1 = TObj::default();
+            self.dead_nodes += 1;
+        }
+        if self.dead_nodes * 2 > self.nodes.len() {
+            self.compact();
+        }
+    }
+
+    /// brief: drop the nodes with nil values
+    fn compact(&mut self) {
+        self.nodes.retain(|(_, val)| !val.is_nil());
+        self.index.clear();
+        for (i, (key, _)) in self.nodes.iter().enumerate() {
+            self.index.insert(LuaTable::key_of(key), i);
+        }
+        self.dead_nodes = 0;
+    }
+
+    /// brief: a border of the table, `#t` without meta methods
+    pub fn len(&self) -> INT {
+        let mut j = self.array.len();
+        if j > 0 && self.array[j - 1].is_nil() {
+            // a border inside the array part, binary search
+            let mut i = 0;
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.array[m - 1].is_nil() {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i as INT;
+        }
+        if self.index.is_empty() {
+            return j as INT;
+        }
+        // unbound search in the hash part
+        let mut i = j as INT;
+        let mut j = i + 1;
+        while !self.get_int(j).is_nil() {
+            i = j;
+            if j > INT::MAX / 2 {
+                // pathological table, linear search
+                let mut k = 1;
+                while !self.get_int(k).is_nil() {
+                    k += 1;
+                }
+                return k - 1;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let m = i + (j - i) / 2;
+            if self.get_int(m).is_nil() {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+        i
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next(&TObj::default()).ok().flatten().is_none()
+    }
+
+    /// brief: the field after `key`, the first one if `key` is nil
+    pub fn next(&self, key: &TObj) -> Result<Option<(TObj, TObj)>, TableError> {
+        let start_node = if key.is_nil() {
+            match self.next_in_array(0) {
+                Some(pair) => return Ok(Some(pair)),
+                None => 0,
+            }
+        } else {
+            let key = LuaTable::normalize(key).map_err(|_| TableError::InvalidNextKey)?;
+            let in_array = key.as_integer().and_then(|integer| self.array_slot(integer));
+            if let Some(slot) = in_array {
+                match self.next_in_array(slot + 1) {
+                    Some(pair) => return Ok(Some(pair)),
+                    None => 0,
+                }
+            } else {
+                match self.index.get(&LuaTable::key_of(&key)) {
+                    Some(node) => node + 1,
+                    None => return Err(TableError::InvalidNextKey),
+                }
+            }
+        };
+        Ok(self.nodes[start_node.min(self.nodes.len())..]
+            .iter()
+            .find(|(_, val)| !val.is_nil())
+            .copied())
+    }
+
+    fn next_in_array(&self, from: usize) -> Option<(TObj, TObj)> {
+        (from..self.array.len())
+            .find(|slot| !self.array[*slot].is_nil())
+            .map(|slot| (TObj::new_integer(slot as INT + 1), self.array[slot]))
+    }
+
+    /// brief: every value the table holds, for the collector
+    pub fn for_each_value(&self, mut f: impl FnMut(&TObj)) {
+        self.array.iter().for_each(&mut f);
+        for (key, val) in self.nodes.iter() {
+            if !val.is_nil() {
+                f(key);
+                f(val);
+            }
+        }
+    }
+
+    /// brief: an estimate of the bytes the table holds
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<LuaTable>()
+            + self.array.capacity() * std::mem::size_of::<TObj>()
+            + self.nodes.capacity() * std::mem::size_of::<(TObj, TObj)>()
+            + self.index.capacity() * std::mem::size_of::<(TableKey, usize)>()
+    }
+}
+
+/// brief: the integer a float equals to, if any
+pub fn float_to_integer(number: f64) -> Option<INT> {
+    if number.fract() == 0.0 && number >= -(2f64.powi(63)) && number < 2f64.powi(63) {
+        Some(number as INT)
+    } else {
+        None
+    }
+}
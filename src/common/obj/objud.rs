@@ -0,0 +1,439 @@
+use std::any::{type_name, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use crate::common::lua::{LuaError, LuaResult};
+use crate::common::state::statedef::LuaState;
+
+use super::objconv::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti};
+use super::objtype::{GcRef, RFUNC};
+use super::objvalue::{AnyUserData, Value};
+
+/// brief: a rust type exposed to lua as a full userdata
+/// the methods and fields are declared once, the metatable is built on the
+/// first userdata of the type and cached by its TypeId
+///
+/// ```ignore
+/// impl UserData for Counter {
+///     fn add_fields(fields: &mut UserDataRegistry<Self>) {
+///         fields.add_field_method_get("count", |_, this| Ok(this.count));
+///     }
+///
+///     fn add_methods(methods: &mut UserDataRegistry<Self>) {
+///         methods.add_method_mut("incr", |_, this, step: i64| {
+///             this.count += step;
+///             Ok(())
+///         });
+///     }
+/// }
+/// ```
+pub trait UserData: Sized + 'static {
+    fn add_fields(_fields: &mut UserDataRegistry<Self>) {}
+
+    fn add_methods(_methods: &mut UserDataRegistry<Self>) {}
+}
+
+type Getter = Rc<dyn Fn(&mut LuaState, &AnyUserData) -> LuaResult<Value>>;
+type Setter = Rc<dyn Fn(&mut LuaState, &AnyUserData, Value) -> LuaResult<()>>;
+
+/// brief: the methods, meta methods and fields of a UserData type
+/// `__index` and `__newindex` are taken by the fields and the methods
+pub struct UserDataRegistry<T> {
+    methods: Vec<(String, Rc<RFUNC>)>,
+    meta_methods: Vec<(String, Rc<RFUNC>)>,
+    getters: HashMap<String, Getter>,
+    setters: HashMap<String, Setter>,
+    _type: PhantomData<T>,
+}
+
+/// brief: a rust closure whose errors are raised as lua errors
+fn wrap(f: impl Fn(&mut LuaState) -> LuaResult<usize> + 'static) -> Rc<RFUNC> {
+    Rc::new(move |state: &mut LuaState| match f(state) {
+        Ok(nresults) => nresults,
+        Err(err) => state.error(err),
+    })
+}
+
+/// brief: a rust closure taking and returning converted values
+pub(crate) fn rust_function<A, R, F>(function: F) -> Rc<RFUNC>
+where
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+    F: Fn(&mut LuaState, A) -> LuaResult<R> + 'static,
+{
+    wrap(move |state| {
+        let args: A = state.get_args()?;
+        let results = function(state, args)?;
+        state.push_results(results)
+    })
+}
+
+/// brief: the value of a userdata, owned or borrowed by a scope
+/// the borrow flag is checked at runtime, a conflicting borrow is a lua error
+pub(crate) enum UserDataCell<T> {
+    Owned(RefCell<T>),
+    Borrowed {
+        ptr: NonNull<T>,
+        flag: RefCell<()>,
+        mutable: bool,
+    },
+}
+
+/// brief: the value of a userdata whose scope has ended
+pub(crate) struct DestroyedUserData;
+
+impl<T> UserDataCell<T> {
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&T) -> R) -> LuaResult<R> {
+        match self {
+            UserDataCell::Owned(cell) => {
+                let this = cell.try_borrow().map_err(|_| LuaError::UserDataBorrowError)?;
+                Ok(f(&this))
+            }
+            UserDataCell::Borrowed { ptr, flag, .. } => {
+                let _guard = flag.try_borrow().map_err(|_| LuaError::UserDataBorrowError)?;
+                // SAFETY: the scope that made the cell outlives it, see Scope
+                Ok(f(unsafe { ptr.as_ref() }))
+            }
+        }
+    }
+
+    pub(crate) fn with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> LuaResult<R> {
+        match self {
+            UserDataCell::Owned(cell) => {
+                let mut this = cell.try_borrow_mut().map_err(|_| LuaError::UserDataBorrowMutError)?;
+                Ok(f(&mut this))
+            }
+            UserDataCell::Borrowed { ptr, flag, mutable } => {
+                if !mutable {
+                    return Err(LuaError::UserDataBorrowMutError);
+                }
+                let _guard = flag.try_borrow_mut().map_err(|_| LuaError::UserDataBorrowMutError)?;
+                // SAFETY: the cell was made of a mutable borrow, the flag keeps it unique
+                Ok(f(unsafe { &mut *ptr.as_ptr() }))
+            }
+        }
+    }
+}
+
+/// brief: the shared cell of a userdata of type T
+pub(crate) fn userdata_cell<T: 'static>(state: &LuaState, ud: &AnyUserData) -> LuaResult<Rc<UserDataCell<T>>> {
+    let gc = state.check_ref(&ud.0);
+    let data = match state.get_heap().get_ud(gc) {
+        Some(ud) => &ud.data,
+        None => return Err(LuaError::UserDataTypeMismatch),
+    };
+    if data.is::<DestroyedUserData>() {
+        return Err(LuaError::UserDataDestructed);
+    }
+    data.downcast_ref::<Rc<UserDataCell<T>>>()
+        .cloned()
+        .ok_or(LuaError::UserDataTypeMismatch)
+}
+
+impl<T: UserData> UserDataRegistry<T> {
+    fn new() -> Self {
+        Self {
+            methods: Vec::new(),
+            meta_methods: Vec::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            _type: PhantomData,
+        }
+    }
+
+    fn method<A, R, F>(method: F) -> Rc<RFUNC>
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, &T, A) -> LuaResult<R> + 'static,
+    {
+        wrap(move |state| {
+            let (ud, args): (AnyUserData, A) = state.get_args()?;
+            let cell = userdata_cell::<T>(state, &ud)?;
+            let results = cell.with(|this| method(state, this, args))??;
+            state.push_results(results)
+        })
+    }
+
+    fn method_mut<A, R, F>(method: F) -> Rc<RFUNC>
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, &mut T, A) -> LuaResult<R> + 'static,
+    {
+        wrap(move |state| {
+            let (ud, args): (AnyUserData, A) = state.get_args()?;
+            let cell = userdata_cell::<T>(state, &ud)?;
+            let results = cell.with_mut(|this| method(state, this, args))??;
+            state.push_results(results)
+        })
+    }
+
+    /// brief: `ud:name(...)`, the value is borrowed immutably
+    pub fn add_method<A, R, F>(&mut self, name: &str, method: F)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, &T, A) -> LuaResult<R> + 'static,
+    {
+        self.methods.push((name.to_string(), Self::method(method)));
+    }
+
+    /// brief: `ud:name(...)`, the value is borrowed mutably
+    pub fn add_method_mut<A, R, F>(&mut self, name: &str, method: F)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, &mut T, A) -> LuaResult<R> + 'static,
+    {
+        self.methods.push((name.to_string(), Self::method_mut(method)));
+    }
+
+    /// brief: `ud.name(...)`, no value is passed
+    pub fn add_function<A, R, F>(&mut self, name: &str, function: F)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, A) -> LuaResult<R> + 'static,
+    {
+        self.methods.push((name.to_string(), rust_function(function)));
+    }
+
+    /// brief: a meta method such as `__tostring`, the value is the first argument
+    pub fn add_meta_method<A, R, F>(&mut self, name: &str, method: F)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, &T, A) -> LuaResult<R> + 'static,
+    {
+        self.meta_methods.push((name.to_string(), Self::method(method)));
+    }
+
+    pub fn add_meta_method_mut<A, R, F>(&mut self, name: &str, method: F)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, &mut T, A) -> LuaResult<R> + 'static,
+    {
+        self.meta_methods.push((name.to_string(), Self::method_mut(method)));
+    }
+
+    /// brief: a meta method taking its arguments as they are, such as `__add`
+    /// where the value may be either operand
+    pub fn add_meta_function<A, R, F>(&mut self, name: &str, function: F)
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, A) -> LuaResult<R> + 'static,
+    {
+        self.meta_methods.push((name.to_string(), rust_function(function)));
+    }
+
+    /// brief: `ud.name` reads the field
+    pub fn add_field_method_get<R, F>(&mut self, name: &str, getter: F)
+    where
+        R: IntoLua,
+        F: Fn(&mut LuaState, &T) -> LuaResult<R> + 'static,
+    {
+        let getter: Getter = Rc::new(move |state: &mut LuaState, ud: &AnyUserData| {
+            let cell = userdata_cell::<T>(state, ud)?;
+            let val = cell.with(|this| getter(state, this))??;
+            val.into_lua(state)
+        });
+        self.getters.insert(name.to_string(), getter);
+    }
+
+    /// brief: `ud.name = val` writes the field
+    pub fn add_field_method_set<A, F>(&mut self, name: &str, setter: F)
+    where
+        A: FromLua,
+        F: Fn(&mut LuaState, &mut T, A) -> LuaResult<()> + 'static,
+    {
+        let setter: Setter = Rc::new(move |state: &mut LuaState, ud: &AnyUserData, val: Value| {
+            let val = A::from_lua(val, state)?;
+            let cell = userdata_cell::<T>(state, ud)?;
+            cell.with_mut(|this| setter(state, this, val))?
+        });
+        self.setters.insert(name.to_string(), setter);
+    }
+
+    fn into_metatable(self, state: &mut LuaState) -> LuaResult<GcRef> {
+        let methods = state.create_table_value(0, self.methods.len())?;
+        for (name, method) in self.methods {
+            let method = state.create_closure(method)?;
+            state.raw_set(&methods, Value::from(name.as_str()), Value::Function(method))?;
+        }
+
+        let metatable = state.create_table_value(0, self.meta_methods.len() + 3)?;
+        for (name, method) in self.meta_methods {
+            let method = state.create_closure(method)?;
+            state.raw_set(&metatable, Value::from(name.as_str()), Value::Function(method))?;
+        }
+        state.raw_set(&metatable, Value::from("__name"), Value::from(type_name::<T>()))?;
+
+        let getters = self.getters;
+        let index = wrap(move |state| {
+            let (ud, key): (AnyUserData, Value) = state.get_args()?;
+            let getter = match &key {
+                Value::String(name) => name.to_str().ok().and_then(|name| getters.get(name)),
+                _ => None,
+            };
+            let val = match getter {
+                Some(getter) => getter(state, &ud)?,
+                None => state.raw_get(&methods, &key),
+            };
+            state.push_results(val)
+        });
+        let index = state.create_closure(index)?;
+        state.raw_set(&metatable, Value::from("__index"), Value::Function(index))?;
+
+        let setters = self.setters;
+        let newindex = wrap(move |state| {
+            let (ud, key, val): (AnyUserData, Value, Value) = state.get_args()?;
+            let setter = match &key {
+                Value::String(name) => name.to_str().ok().and_then(|name| setters.get(name)),
+                _ => None,
+            };
+            match setter {
+                Some(setter) => setter(state, &ud, val)?,
+                None => {
+                    return Err(LuaError::Runtime(format!(
+                        "no writable field {:?} in {}",
+                        key,
+                        type_name::<T>()
+                    )))
+                }
+            }
+            Ok(0)
+        });
+        let newindex = state.create_closure(newindex)?;
+        state.raw_set(&metatable, Value::from("__newindex"), Value::Function(newindex))?;
+
+        Ok(metatable.0.gc)
+    }
+}
+
+/// brief: the metatable of the userdata of type T, built on first use
+pub(crate) fn userdata_metatable<T: UserData>(state: &mut LuaState) -> LuaResult<GcRef> {
+    if let Some(metatable) = state.get_ud_metatable(TypeId::of::<T>()) {
+        return Ok(metatable);
+    }
+    let mut registry = UserDataRegistry::<T>::new();
+    T::add_fields(&mut registry);
+    T::add_methods(&mut registry);
+    let metatable = registry.into_metatable(state)?;
+    state.set_ud_metatable(TypeId::of::<T>(), metatable);
+    Ok(metatable)
+}
+
+#[cfg(test)]
+mod test {
+    use core::ptr::null_mut;
+
+    use crate::common::lua::{LuaConfig, LuaError};
+    use crate::common::obj::objconv::{IntoLua, MultiValue};
+    use crate::common::obj::objud::{UserData, UserDataRegistry};
+    use crate::common::obj::objvalue::Value;
+    use crate::common::state::statedef::LuaState;
+
+    fn new_state() -> LuaState {
+        LuaState::mainthread_new(null_mut(), LuaConfig::default()).unwrap()
+    }
+
+    struct Counter {
+        count: i64,
+    }
+
+    impl UserData for Counter {
+        fn add_fields(fields: &mut UserDataRegistry<Self>) {
+            fields.add_field_method_get("count", |_, this| Ok(this.count));
+            fields.add_field_method_set("count", |_, this, count: i64| {
+                this.count = count;
+                Ok(())
+            });
+        }
+
+        fn add_methods(methods: &mut UserDataRegistry<Self>) {
+            methods.add_method_mut("incr", |_, this, step: Option<i64>| {
+                this.count += step.unwrap_or(1);
+                Ok(this.count)
+            });
+            methods.add_method_mut("reenter", |state, _this, ud: Value| {
+                // the value is borrowed mutably while the nested call reads it
+                state.call_method(&ud, "incr", MultiValue::new()).map(|_| ())
+            });
+            methods.add_function("zero", |state, ()| state.create_userdata(Counter { count: 0 }));
+            methods.add_meta_method("__tostring", |_, this, ()| Ok(format!("Counter({})", this.count)));
+        }
+    }
+
+    struct Other;
+
+    impl UserData for Other {}
+
+    #[test]
+    fn methods_and_fields_dispatch() {
+        let mut state = new_state();
+        let counter = Value::UserData(state.create_userdata(Counter { count: 1 }).unwrap());
+
+        let results = state.call_method(&counter, "incr", MultiValue::from([Value::Integer(2)]));
+        assert_eq!(results.unwrap(), MultiValue::from([Value::Integer(3)]));
+        assert_eq!(state.index(&counter, &Value::from("count")).unwrap(), Value::Integer(3));
+
+        state.set_index(&counter, Value::from("count"), Value::Integer(10)).unwrap();
+        assert_eq!(state.index(&counter, &Value::from("count")).unwrap(), Value::Integer(10));
+        assert!(state.set_index(&counter, Value::from("missing"), Value::Nil).is_err());
+
+        let tostring = state.get_metafield(&counter, "__tostring");
+        let results = state.call_value(&tostring, MultiValue::from([counter.clone()])).unwrap();
+        assert_eq!(results[0], Value::from("Counter(10)"));
+
+        let zero = state.index(&counter, &Value::from("zero")).unwrap();
+        let zero = state.call_value(&zero, MultiValue::new()).unwrap().pop_front().unwrap();
+        assert_eq!(state.index(&zero, &Value::from("count")).unwrap(), Value::Integer(0));
+    }
+
+    #[test]
+    fn metatable_is_cached_per_type() {
+        let mut state = new_state();
+        let a = Value::UserData(state.create_userdata(Counter { count: 0 }).unwrap());
+        let b = Value::UserData(state.create_userdata(Counter { count: 0 }).unwrap());
+        let c = Value::UserData(state.create_userdata(Other).unwrap());
+        let (ma, mb, mc) = (state.get_metatable(&a), state.get_metatable(&b), state.get_metatable(&c));
+        assert!(ma.is_some());
+        assert_eq!(ma, mb);
+        assert_ne!(ma, mc);
+
+        // the metatable survives a collection without any userdata alive
+        drop((a, b, c));
+        state.collect_garbage();
+        let d = Value::UserData(state.create_userdata(Counter { count: 0 }).unwrap());
+        assert_eq!(state.get_metatable(&d), mb);
+    }
+
+    #[test]
+    fn conflicting_borrow_is_a_lua_error() {
+        let mut state = new_state();
+        let counter = Value::UserData(state.create_userdata(Counter { count: 0 }).unwrap());
+        let err = state
+            .call_method(&counter, "reenter", MultiValue::from([counter.clone()]))
+            .unwrap_err();
+        assert_eq!(err, LuaError::UserDataBorrowMutError);
+
+        // the borrow is released, the value is usable again
+        assert!(state.call_method(&counter, "incr", MultiValue::new()).is_ok());
+    }
+
+    #[test]
+    fn wrong_userdata_type_is_a_lua_error() {
+        let mut state = new_state();
+        let counter = Value::UserData(state.create_userdata(Counter { count: 0 }).unwrap());
+        let other = state.create_userdata(Other).unwrap().into_lua(&mut state).unwrap();
+        let incr = state.index(&counter, &Value::from("incr")).unwrap();
+        let err = state.call_value(&incr, MultiValue::from([other])).unwrap_err();
+        assert_eq!(err, LuaError::UserDataTypeMismatch);
+    }
+}
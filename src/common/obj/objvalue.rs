@@ -0,0 +1,679 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use crate::common::lua::LuaResult;
+use crate::common::state::statedef::LuaState;
+
+use super::objconv::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, MultiValue};
+use super::objtable::float_to_integer;
+use super::objtype::{GcRef, FLT, INT, LRFUNC};
+
+/// brief: a reference to an object in the heap of a state, held by the host
+/// the object is not collected while a reference is alive
+#[derive(Clone)]
+pub(crate) struct LuaRef {
+    pub(crate) heap: u32,
+    pub(crate) gc: GcRef,
+    pub(crate) _pin: Rc<()>,
+}
+
+impl PartialEq for LuaRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.heap == other.heap && self.gc == other.gc
+    }
+}
+
+impl Eq for LuaRef {}
+
+impl Hash for LuaRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.heap.hash(state);
+        self.gc.hash(state);
+    }
+}
+
+/// brief: an owned copy of a lua string, the bytes need not be utf-8
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct LuaString(Rc<[u8]>);
+
+impl LuaString {
+    pub fn new(bytes: &[u8]) -> Self {
+        LuaString(bytes.into())
+    }
+
+    #[inline(always)]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.0)
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0).into_owned()
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        LuaString::new(s.as_bytes())
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        LuaString::new(bytes)
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        for byte in self.0.iter() {
+            match byte {
+                b'"' => f.write_str("\\\"")?,
+                b'\\' => f.write_str("\\\\")?,
+                b'\n' => f.write_str("\\n")?,
+                b'\r' => f.write_str("\\r")?,
+                b'\t' => f.write_str("\\t")?,
+                0x20..=0x7e => write!(f, "{}", *byte as char)?,
+                _ => write!(f, "\\x{:02x}", byte)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+/// brief: a handle of a table
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Table(pub(crate) LuaRef);
+
+impl fmt::Debug for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "table: #{}", self.0.gc.index)
+    }
+}
+
+impl Table {
+    /// brief: t[key] with the `__index` meta method
+    pub fn get<K: IntoLua, V: FromLua>(&self, state: &mut LuaState, key: K) -> LuaResult<V> {
+        let key = key.into_lua(state)?;
+        let val = state.index(&Value::Table(self.clone()), &key)?;
+        V::from_lua(val, state)
+    }
+
+    /// brief: t[key] = val with the `__newindex` meta method
+    pub fn set<K: IntoLua, V: IntoLua>(&self, state: &mut LuaState, key: K, val: V) -> LuaResult<()> {
+        let key = key.into_lua(state)?;
+        let val = val.into_lua(state)?;
+        state.set_index(&Value::Table(self.clone()), key, val)
+    }
+
+    /// brief: t[key] without meta methods
+    pub fn raw_get<K: IntoLua, V: FromLua>(&self, state: &mut LuaState, key: K) -> LuaResult<V> {
+        let key = key.into_lua(state)?;
+        let val = state.raw_get(self, &key);
+        V::from_lua(val, state)
+    }
+
+    /// brief: t[key] = val without meta methods
+    pub fn raw_set<K: IntoLua, V: IntoLua>(&self, state: &mut LuaState, key: K, val: V) -> LuaResult<()> {
+        let key = key.into_lua(state)?;
+        let val = val.into_lua(state)?;
+        state.raw_set(self, key, val)
+    }
+
+    /// brief: #t with the `__len` meta method
+    pub fn len(&self, state: &mut LuaState) -> LuaResult<INT> {
+        let len = state.len(&Value::Table(self.clone()))?;
+        INT::from_lua(len, state)
+    }
+
+    /// brief: #t without meta methods
+    pub fn raw_len(&self, state: &LuaState) -> INT {
+        state.raw_len(self)
+    }
+
+    /// brief: t[key] ~= nil, with the `__index` meta method
+    pub fn contains_key<K: IntoLua>(&self, state: &mut LuaState, key: K) -> LuaResult<bool> {
+        let val: Value = self.get(state, key)?;
+        Ok(!val.is_nil())
+    }
+
+    pub fn get_metatable(&self, state: &mut LuaState) -> Option<Table> {
+        state.get_metatable(&Value::Table(self.clone()))
+    }
+
+    pub fn set_metatable(&self, state: &mut LuaState, metatable: Option<&Table>) {
+        state.set_metatable(self, metatable)
+    }
+
+    /// brief: the fields in the order of `next`, without meta methods
+    ///
+    /// the iterator holds the table and the last key, so a collection while
+    /// iterating keeps both alive. assigning existing fields is allowed,
+    /// adding new ones is not
+    ///
+    /// ```ignore
+    /// for pair in table.pairs::<String, i64>(state) {
+    ///     let (key, val) = pair?;
+    /// }
+    /// ```
+    pub fn pairs<'a, K: FromLua, V: FromLua>(&self, state: &'a mut LuaState) -> TablePairs<'a, K, V> {
+        TablePairs {
+            state,
+            table: self.clone(),
+            key: Some(Value::Nil),
+            _marker: PhantomData,
+        }
+    }
+
+    /// brief: t[1], t[2], ... up to the first nil, without meta methods
+    pub fn sequence_values<'a, V: FromLua>(&self, state: &'a mut LuaState) -> TableSequence<'a, V> {
+        TableSequence {
+            state,
+            table: self.clone(),
+            index: Some(1),
+            _marker: PhantomData,
+        }
+    }
+
+    /// brief: call `f` on every field, the state stays usable inside
+    pub fn for_each<K, V, F>(&self, state: &mut LuaState, mut f: F) -> LuaResult<()>
+    where
+        K: FromLua,
+        V: FromLua,
+        F: FnMut(&mut LuaState, K, V) -> LuaResult<()>,
+    {
+        let mut key = Value::Nil;
+        while let Some((next_key, val)) = state.raw_next(self, &key)? {
+            key = next_key;
+            let k = K::from_lua(key.clone(), state)?;
+            let v = V::from_lua(val, state)?;
+            f(state, k, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// brief: the iterator of Table::pairs
+pub struct TablePairs<'a, K, V> {
+    state: &'a mut LuaState,
+    table: Table,
+    key: Option<Value>, // none once ended
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: FromLua, V: FromLua> Iterator for TablePairs<'_, K, V> {
+    type Item = LuaResult<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.key.take()?;
+        let (key, val) = match self.state.raw_next(&self.table, &key) {
+            Ok(Some(pair)) => pair,
+            Ok(None) => return None,
+            Err(err) => return Some(Err(err)),
+        };
+        self.key = Some(key.clone());
+        let pair = K::from_lua(key, self.state).and_then(|k| Ok((k, V::from_lua(val, self.state)?)));
+        Some(pair)
+    }
+}
+
+/// brief: the iterator of Table::sequence_values
+pub struct TableSequence<'a, V> {
+    state: &'a mut LuaState,
+    table: Table,
+    index: Option<INT>, // none once ended
+    _marker: PhantomData<V>,
+}
+
+impl<V: FromLua> Iterator for TableSequence<'_, V> {
+    type Item = LuaResult<V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.index.take()?;
+        let val = self.state.raw_get(&self.table, &Value::Integer(index));
+        if val.is_nil() {
+            return None;
+        }
+        self.index = index.checked_add(1);
+        Some(V::from_lua(val, self.state))
+    }
+}
+
+/// brief: a handle of a full userdata
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct AnyUserData(pub(crate) LuaRef);
+
+impl fmt::Debug for AnyUserData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "userdata: #{}", self.0.gc.index)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum FuncRef {
+    Light(LRFUNC),
+    Closure(LuaRef),
+}
+
+/// brief: a handle of a function
+#[derive(Clone)]
+pub struct Function(pub(crate) FuncRef);
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (FuncRef::Light(a), FuncRef::Light(b)) => *a as usize == *b as usize,
+            (FuncRef::Closure(a), FuncRef::Closure(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Function {}
+
+impl Function {
+    /// brief: a function of a light rust function, nothing is allocated
+    pub fn light(lrfunc: LRFUNC) -> Function {
+        Function(FuncRef::Light(lrfunc))
+    }
+
+    /// brief: call the function in protected mode, a lua error is returned
+    ///
+    /// ```ignore
+    /// let add: Function = state.get_global("add")?;
+    /// let sum: i64 = add.call(state, (1, 2))?;
+    /// ```
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(&self, state: &mut LuaState, args: A) -> LuaResult<R> {
+        let args = args.into_lua_multi(state)?;
+        let results = state.call_value(&Value::Function(self.clone()), args)?;
+        R::from_lua_multi(results, state)
+    }
+
+    /// brief: a function calling this one with `args` before its own arguments
+    pub fn bind<A: IntoLuaMulti>(&self, state: &mut LuaState, args: A) -> LuaResult<Function> {
+        let bound = args.into_lua_multi(state)?;
+        let func = Value::Function(self.clone());
+        state.create_function(move |state, rest: MultiValue| {
+            let mut args = bound.clone();
+            args.extend(rest);
+            state.call_value(&func, args)
+        })
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            FuncRef::Light(lrfunc) => write!(f, "function: builtin: {:p}", *lrfunc as *const ()),
+            FuncRef::Closure(closure) => write!(f, "function: closure: #{}", closure.gc.index),
+        }
+    }
+}
+
+impl Hash for Function {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            FuncRef::Light(lrfunc) => (*lrfunc as usize).hash(state),
+            FuncRef::Closure(closure) => closure.hash(state),
+        }
+    }
+}
+
+/// brief: a handle of a thread, a state has its main thread only
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Thread {
+    pub(crate) heap: u32,
+}
+
+/// brief: a lua value owned by the host
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Integer(INT),
+    Number(FLT),
+    String(LuaString),
+    Table(Table),
+    Function(Function),
+    Thread(Thread),
+    UserData(AnyUserData),
+    LightUserData(*mut ()),
+}
+
+impl Value {
+    /// brief: the name `type` returns for the value
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+            Value::Thread(_) => "thread",
+            Value::UserData(_) | Value::LightUserData(_) => "userdata",
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    /// brief: nil and false are false, everything else is true
+    #[inline(always)]
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+}
+
+/// brief: raw equality, no `__eq`
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Integer(a), Value::Number(b)) | (Value::Number(b), Value::Integer(a)) => {
+                float_to_integer(*b) == Some(*a)
+            }
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Thread(a), Value::Thread(b)) => a == b,
+            (Value::UserData(a), Value::UserData(b)) => a == b,
+            (Value::LightUserData(a), Value::LightUserData(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// brief: hash as a table key, a float with an integral value hashes
+/// as the integer it equals to
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Nil => 0u8.hash(state),
+            Value::Boolean(b) => {
+                1u8.hash(state);
+                b.hash(state);
+            }
+            Value::Integer(i) => {
+                2u8.hash(state);
+                i.hash(state);
+            }
+            Value::Number(n) => match float_to_integer(*n) {
+                Some(i) => {
+                    2u8.hash(state);
+                    i.hash(state);
+                }
+                None => {
+                    3u8.hash(state);
+                    n.to_bits().hash(state);
+                }
+            },
+            Value::String(s) => {
+                4u8.hash(state);
+                s.hash(state);
+            }
+            Value::Table(t) => {
+                5u8.hash(state);
+                t.hash(state);
+            }
+            Value::Function(f) => {
+                6u8.hash(state);
+                f.hash(state);
+            }
+            Value::Thread(t) => {
+                7u8.hash(state);
+                t.hash(state);
+            }
+            Value::UserData(u) => {
+                8u8.hash(state);
+                u.hash(state);
+            }
+            Value::LightUserData(p) => {
+                9u8.hash(state);
+                p.hash(state);
+            }
+        }
+    }
+}
+
+/// brief: a value as a lua table key, never nil nor NaN, and a float with
+/// an integral value is the integer it equals to. unlike a value it is Eq,
+/// for the maps and sets of the host
+#[derive(Clone, PartialEq, Hash, Debug)]
+pub struct ValueKey(Value);
+
+impl Eq for ValueKey {}
+
+impl ValueKey {
+    /// brief: the key of a value, none for the values lua refuses as keys
+    pub fn new(value: Value) -> Option<ValueKey> {
+        match value {
+            Value::Nil => None,
+            Value::Number(n) if n.is_nan() => None,
+            Value::Number(n) => Some(ValueKey(float_to_integer(n).map_or(Value::Number(n), Value::Integer))),
+            value => Some(ValueKey(value)),
+        }
+    }
+
+    pub fn as_value(&self) -> &Value {
+        &self.0
+    }
+
+    pub fn into_value(self) -> Value {
+        self.0
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => f.write_str("nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Number(n) => {
+                if n.is_finite() && n.fract() == 0.0 && n.abs() < 1e16 {
+                    write!(f, "{:.1}", n)
+                } else {
+                    write!(f, "{}", n)
+                }
+            }
+            Value::String(s) => write!(f, "{:?}", s),
+            Value::Table(t) => write!(f, "{:?}", t),
+            Value::Function(func) => write!(f, "{:?}", func),
+            Value::Thread(_) => f.write_str("thread: main"),
+            Value::UserData(u) => write!(f, "{:?}", u),
+            Value::LightUserData(p) => write!(f, "lightuserdata: {:p}", *p),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<INT> for Value {
+    fn from(i: INT) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl From<FLT> for Value {
+    fn from(n: FLT) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<LuaString> for Value {
+    fn from(s: LuaString) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<Table> for Value {
+    fn from(t: Table) -> Self {
+        Value::Table(t)
+    }
+}
+
+impl From<Function> for Value {
+    fn from(func: Function) -> Self {
+        Value::Function(func)
+    }
+}
+
+impl From<AnyUserData> for Value {
+    fn from(u: AnyUserData) -> Self {
+        Value::UserData(u)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::ptr::null_mut;
+    use std::collections::HashMap;
+
+    use crate::common::lua::{LuaConfig, LuaError};
+    use crate::common::state::statedef::LuaState;
+
+    use super::{Table, Value};
+
+    fn new_state() -> LuaState {
+        LuaState::mainthread_new(null_mut(), LuaConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn typed_get_set() {
+        let mut state = new_state();
+        let t = state.create_table_value(0, 0).unwrap();
+        t.set(&mut state, "name", "lua").unwrap();
+        t.raw_set(&mut state, 1, 2.5).unwrap();
+        assert_eq!(t.get::<_, String>(&mut state, "name").unwrap(), "lua");
+        assert_eq!(t.raw_get::<_, f64>(&mut state, 1).unwrap(), 2.5);
+        assert_eq!(t.get::<_, Option<i64>>(&mut state, "missing").unwrap(), None);
+        assert!(t.contains_key(&mut state, "name").unwrap());
+        assert!(!t.contains_key(&mut state, 2).unwrap());
+        assert!(t.get::<_, i64>(&mut state, "name").is_err());
+    }
+
+    #[test]
+    fn meta_methods_are_honoured() {
+        let mut state = new_state();
+        let t = state.create_table_value(0, 0).unwrap();
+        let fallback = state.create_table_value(0, 0).unwrap();
+        fallback.set(&mut state, "x", 7).unwrap();
+        let mt = state.create_table_value(0, 0).unwrap();
+        mt.set(&mut state, "__index", fallback.clone()).unwrap();
+        let len = state.create_function(|_, _: Table| Ok(42)).unwrap();
+        mt.set(&mut state, "__len", len).unwrap();
+        t.set_metatable(&mut state, Some(&mt));
+
+        assert_eq!(t.get_metatable(&mut state), Some(mt.clone()));
+        assert_eq!(t.get::<_, i64>(&mut state, "x").unwrap(), 7);
+        assert_eq!(t.raw_get::<_, Value>(&mut state, "x").unwrap(), Value::Nil);
+        assert!(t.contains_key(&mut state, "x").unwrap());
+        assert_eq!(t.len(&mut state).unwrap(), 42);
+        assert_eq!(t.raw_len(&state), 0);
+
+        t.set_metatable(&mut state, None);
+        assert_eq!(t.get_metatable(&mut state), None);
+        assert_eq!(t.len(&mut state).unwrap(), 0);
+    }
+
+    #[test]
+    fn pairs_survive_collection() {
+        let mut state = new_state();
+        let t = state.create_table_value(0, 0).unwrap();
+        for i in 0..50 {
+            t.set(&mut state, format!("k{}", i), i).unwrap();
+        }
+        let mut seen = HashMap::new();
+        for pair in t.pairs::<String, i64>(&mut state) {
+            let (k, v) = pair.unwrap();
+            seen.insert(k, v);
+        }
+        assert_eq!(seen.len(), 50);
+        assert_eq!(seen["k13"], 13);
+
+        // the key of the iterator is pinned across a full collection
+        let mut count = 0;
+        t.for_each(&mut state, |state, k: String, _: i64| {
+            state.collect_garbage();
+            let v: i64 = t.raw_get(state, k.as_str())?;
+            t.raw_set(state, k, v + 1)?;
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 50);
+        assert_eq!(t.get::<_, i64>(&mut state, "k0").unwrap(), 1);
+    }
+
+    #[test]
+    fn sequence_values_stop_at_nil() {
+        let mut state = new_state();
+        let t = state.create_table_value(0, 0).unwrap();
+        for i in 1..=3 {
+            t.raw_set(&mut state, i, i * 10).unwrap();
+        }
+        t.raw_set(&mut state, 5, 50).unwrap();
+        let values: Vec<i64> = t.sequence_values(&mut state).collect::<Result<_, _>>().unwrap();
+        assert_eq!(values, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn stack_table_api() {
+        let mut state = new_state();
+        let t = state.create_table_value(0, 0).unwrap();
+        state.push_value(&Value::Table(t.clone()));
+        let base = state.get_top() as isize;
+
+        state.push_integer(5);
+        state.set_field(base, "a").unwrap();
+        state.push_str("b");
+        state.push_integer(6);
+        state.set_table(base).unwrap();
+        state.push_integer(7);
+        state.raw_seti(base, 1).unwrap();
+
+        state.get_field(base, "a").unwrap();
+        assert_eq!(state.pop_integer(), 5);
+        state.push_str("b");
+        state.get_table(base).unwrap();
+        assert_eq!(state.pop_integer(), 6);
+        state.raw_geti(base, 1).unwrap();
+        assert_eq!(state.pop_integer(), 7);
+        assert_eq!(state.to_table(base), Some(t));
+
+        let mut count = 0;
+        state.push_nil();
+        while state.next(base).unwrap() {
+            count += 1;
+            state.set_top(-2);
+        }
+        assert_eq!(count, 3);
+        assert_eq!(state.get_top() as isize, base);
+
+        state.push_integer(1);
+        let err = state.raw_geti(-1, 1).unwrap_err();
+        assert_eq!(err, LuaError::Runtime("table expected, got number".to_string()));
+    }
+}
@@ -1 +1 @@
-pub mod statedef;
\ No newline at end of file
+pub mod statedef;pub mod statedump;pub mod statescope;
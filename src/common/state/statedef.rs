@@ -1,140 +1,127 @@
-use core::cell::UnsafeCell;
 use core::mem::{size_of, swap};
-use core::ptr::null_mut;
 use core::ptr::NonNull;
-
-use crate::common::lua::{ErrCode, LUA_MAX_CALLS};
-use crate::common::lua::{LuaCallInfoStatus, LuaStateStatus};
-use crate::common::lua::{LUA_CI_LEN, LUA_MAX_STACK};
-use crate::common::lua::{LUA_EXTRA_STACK, LUA_MIN_STACK, LUA_STACK_SIZE};
-
-use crate::common::obj::objdef::{TNumber, TObj, TObject};
-use crate::common::obj::objtrait::ObjectTrait;
-use crate::common::obj::objtype::{FLT, INT, RFUNC};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::rc::Rc;
+
+use crate::common::gc::gcdef::{GcObject, Heap, LuaUserData, RustClosure};
+use crate::common::lua::ErrCode;
+use crate::common::lua::{GcParams, LuaCallInfoStatus, LuaConfig, LuaStateStatus};
+use crate::common::lua::{LUA_CI_SLOT_SIZE, LUA_STACK_SLOT_SIZE};
+use crate::common::lua::{LUA_EXTRA_STACK, LUA_REGISTRY_INDEX};
+use crate::common::lua::{LuaError, LuaResult, LUA_MAX_TAG_LOOP, LUA_MUL_RET};
+use crate::common::lua::{LUA_RIDX_GLOBALS, LUA_RIDX_MAINTHREAD};
+
+use crate::common::obj::objconv::{fmt_number, FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, MultiValue};
+use crate::common::obj::objdef::TObj;
+use crate::common::obj::objtable::LuaTable;
+use crate::common::obj::objtype::{GcRef, FLT, INT, LRFUNC, RFUNC};
+use crate::common::obj::objud::{rust_function, userdata_cell, userdata_metatable};
+use crate::common::obj::objud::{DestroyedUserData, UserData, UserDataCell};
+use crate::common::obj::objvalue::{
+    AnyUserData, FuncRef, Function, LuaRef, LuaString, Table, Thread, Value,
+};
+use crate::machine::machdef::Routine;
+
+use super::statedump::check_header;
+use super::statescope::Scope;
 
 const ILLEGAL_INDEX: usize = usize::MAX;
-
-pub type StkElem = TObj;
-
-/// brief: a macro to get the pointer
-macro_rules! ptr_get {
-    ($self:ident,$stack:ident) => {{
-        if let Some(stk) = $self.$stack {
-            let ptr = stk.as_ptr();
-            if !ptr.is_null() {
-                Ok(unsafe { &mut *ptr })
+const LUA_SIGNATURE_FIRST: u8 = 0x1b; // the first byte of a binary chunk, "\x1bLua"
+const LUA_IDSIZE: usize = 60;        // the size of a chunk id, with its terminator
+
+/// brief: the name of a chunk in messages, "=name" is used as is, "@file"
+/// is a file name and any other source is quoted as a string
+pub fn chunk_id(source: &str) -> String {
+    let src = source.as_bytes();
+    let out: Vec<u8> = match src.first() {
+        Some(b'=') if src.len() <= LUA_IDSIZE => src[1..].to_vec(),
+        Some(b'=') => src[1..LUA_IDSIZE].to_vec(),
+        Some(b'@') if src.len() <= LUA_IDSIZE => src[1..].to_vec(),
+        Some(b'@') => [b"...", &src[src.len() - (LUA_IDSIZE - 4)..]].concat(),
+        _ => {
+            let room = LUA_IDSIZE - "[string \"...\"]".len() - 1;
+            let newline = src.iter().position(|b| *b == b'\n');
+            let mut out = b"[string \"".to_vec();
+            if src.len() < room && newline.is_none() {
+                out.extend_from_slice(src);
             } else {
-                Err(ErrCode::NullPointer)
+                let len = newline.unwrap_or(src.len()).min(room);
+                out.extend_from_slice(&src[..len]);
+                out.extend_from_slice(b"...");
             }
-        } else {
-            Err(ErrCode::NoneObject)
-        }
-    }};
-    ($sth:expr) => {
-        if let Some(stack) = $sth {
-            Ok(stack)
-        } else {
-            Err(ErrCode::NoneObject)
+            out.extend_from_slice(b"\"]");
+            out
         }
     };
-    ($sth:expr,$t:ty) => {
-        &mut $sth
-    };
-}
-
-macro_rules! ptr_init {
-    ($content:expr) => {
-        NonNull::from($content)
-    };
-    ($content:ident) => {
-        NonNull::new($content)
-    };
-    ($self:ident,$ci:ident) => {
-        NonNull::from(&$self.$ci)
-    };
+    String::from_utf8_lossy(&out).into_owned()
 }
 
-static mut META: *mut Meta = null_mut();
+/// brief: the payload a lua error unwinds with, see Routine::protect
+pub(crate) struct LuaThrow(pub LuaError);
 
-#[derive(Default,Debug)]
-#[allow(dead_code)]
-struct GlobalState {
-    mainthread: Option<NonNull<LuaState>>,
-    userdata: Option<NonNull<()>>,
-}
+// SAFETY: an error object holds handles of the state that raised it, the
+// protected call catching it runs on the same thread. a throw escaping every
+// protected call ends its thread, which drops or strands the state before
+// another thread may join and see the payload
+unsafe impl Send for LuaThrow {}
 
-#[derive(Default,Debug)]
-#[allow(dead_code)]
-struct Base {
-    pub extra: [char; LUA_EXTRASPACE],
-    pub state: LuaState,
-}
+pub type StkElem = TObj;
 
-#[derive(Default,Debug)]
+#[derive(Default, Debug)]
 #[allow(dead_code)]
-struct Meta {
-    pub base: Base,
-    pub global: GlobalState,
+struct GlobalState {
+    userdata: Option<NonNull<()>>, // opaque to the machine, never dereferenced
+    config: LuaConfig,
+    total_bytes: usize, // bytes granted by the quota
+    rfuncs: HashMap<*const RFUNC, GcRef>, // the closures of push_rfunc, by address
+    ud_metatables: HashMap<TypeId, GcRef>,  // the metatables of the UserData types
+    type_metatables: HashMap<&'static str, GcRef>, // the metatables shared by a basic type, by type name
+    heap: Heap,
+    registry: Option<GcRef>,
+    gc_threshold: usize, // heap bytes that start the next cycle
+    gc_estimate: usize,  // heap bytes alive after the last collection
+    gc_stepped: usize,   // heap bytes at the last step of the cycle
+    gc_work: usize,      // work paid in the cycle, a collection once it covers the estimate
+    gc_stopped: bool,    // no collection but the explicit ones
+    gc_generational: bool, // the mode asked for, the collector is the same
+    warn_on: bool,       // warnings are emitted, off on start
+    warn_cont: bool,     // a warning is being continued
 }
 
-#[inline(always)]
-#[allow(dead_code)]
-fn get_meta_mut() -> Option<&'static mut Meta> {
-    if !unsafe { META.is_null() } {
-        unsafe { Some(&mut *META) }
-    } else {
-        None
-    }
-}
-#[macro_export]
-macro_rules! get_meta {
-    () => {
-        if let Some(meta) = get_meta_mut() {
-            Ok(meta)
-        } else {
-            Err(ErrCode::NoneObject)
+impl GlobalState {
+    /// brief: ask the quota and the memory cap for `request` more bytes
+    fn grant(&mut self, request: usize) -> bool {
+        if let Some(limit) = self.config.memory_limit {
+            if self.total_bytes + request > limit {
+                return false;
+            }
         }
-    };
-}
-#[macro_export]
-macro_rules! get_global_state {
-    () => {
-        get_meta!().ok().unwrap().global
-    };
-}
-
-#[macro_export]
-macro_rules! get_main_state {
-    () => {
-        get_meta!().ok().unwrap().base.state
-    };
-}
-
-impl Meta {
-    pub fn new() {
-        unsafe { META = Box::leak(Box::new(Meta::default())) };
-
-        unsafe{dbg!(META)};
-        unsafe{dbg!(&META.as_mut().unwrap().global)};
+        if !self.config.memory_quota.grant(self.total_bytes, request) {
+            return false;
+        }
+        self.total_bytes += request;
+        true
     }
-}
 
-impl Drop for Meta {
-    fn drop(&mut self) {
-        println!("Dropping the meta..");
+    fn release(&mut self, bytes: usize) {
+        self.total_bytes -= bytes.min(self.total_bytes);
     }
 }
 
 macro_rules! stack_push {
     ($stack:ident,$dtype:ty,$times:expr) => {
         for _time in 0..$times {
-            $stack.0.push(UnsafeCell::from(<$dtype>::default()));
+            $stack.0.push(<$dtype>::default());
         }
     };
 }
 
 #[derive(Debug)]
-pub struct Stack(Vec<UnsafeCell<StkElem>>);
+pub struct Stack(Vec<StkElem>);
 
 impl Stack {
     /// brief: alloc a new stack with capacity and length
@@ -146,75 +133,47 @@ impl Stack {
             return None;
         }
 
-        let mut stk = Stack {
-            0: Vec::with_capacity(capacity),
-        };
+        let mut stk = Stack(Vec::with_capacity(capacity));
 
         stack_push!(stk, StkElem, length);
-        return Some(stk);
+        Some(stk)
     }
 
     #[inline(always)]
     #[allow(dead_code)]
-    pub fn get_mut_elem(&self, index: usize) -> Option<&mut StkElem> {
-        if let Some(stk) = self.0.get(index) {
-            Some(unsafe { &mut *(stk.get()) })
-        } else {
-            None
-        }
+    pub fn get_mut_elem(&mut self, index: usize) -> Option<&mut StkElem> {
+        self.0.get_mut(index)
     }
 
     #[inline(always)]
     #[allow(dead_code)]
     pub fn get_ref_elem(&self, index: usize) -> Option<&StkElem> {
-        if let Some(stk) = self.0.get(index) {
-            Some(unsafe { &*(stk.get()) })
-        } else {
-            None
-        }
-    }
-
-    #[inline(always)]
-    #[allow(dead_code)]
-    pub fn get_ptr(&self, index: usize) -> Option<*mut StkElem> {
-        if let Some(stk) = self.0.get(index) {
-            Some(stk.get())
-        } else {
-            None
-        }
+        self.0.get(index)
     }
 
     #[inline(always)]
     #[allow(dead_code)]
     pub fn get_elem(&self, index: usize) -> Option<StkElem> {
-        if let Some(stk) = self.0.get(index) {
-            Some(unsafe { *(stk.get()) })
-        } else {
-            None
-        }
+        self.0.get(index).copied()
     }
 
     #[inline(always)]
     #[allow(dead_code)]
-    pub fn swap_elem(&self, index: usize, new_stkelem: &mut StkElem) -> Result<ErrCode, ErrCode> {
-        if let Some(s) = self.0.get(index) {
-            let stk = s.get();
-            if !stk.is_null() {
-                swap(unsafe { &mut *stk }, new_stkelem);
-            } else {
-                return Err(ErrCode::NullPointer);
-            }
-            return Ok(ErrCode::Fine);
+    pub fn swap_elem(&mut self, index: usize, new_stkelem: &mut StkElem) -> Result<ErrCode, ErrCode> {
+        if let Some(stk) = self.0.get_mut(index) {
+            swap(stk, new_stkelem);
+            Ok(ErrCode::Fine)
         } else {
-            return Err(ErrCode::NoneObject);
+            Err(ErrCode::NoneObject)
         }
     }
 
-    fn increase(&mut self, need: usize) -> Result<usize, ErrCode> {
+    /// brief: the number of slots the stack grows by to hold `need` more
+    fn increase_size(&self, need: usize, max_stack: usize) -> Result<usize, ErrCode> {
         // the space that has been allocated
         let old_alloc = self.0.len();
 
-        if old_alloc > LUA_MAX_STACK as usize {
+        if old_alloc > max_stack {
             return Err(ErrCode::OverFlow);
         }
         // a branch that program will not step in for sure
@@ -227,28 +186,24 @@ impl Stack {
             to_add = to_add2;
         }
 
-        if old_alloc + to_add > LUA_MAX_STACK as usize {
+        if old_alloc + to_add > max_stack {
             return Err(ErrCode::OverFlow);
         }
 
+        Ok(to_add)
+    }
+
+    fn increase(&mut self, to_add: usize) {
         // capacity >= length
         stack_push!(self, StkElem, to_add);
-
-        return Ok(to_add);
     }
 
     #[allow(dead_code)]
     fn decrease(&mut self, _starting_pos: usize) {}
 }
 
-impl Drop for Stack {
-    fn drop(&mut self) {
-        self.0.clear();
-    }
-}
-
 #[derive(Debug)]
-pub struct CallInfoVec(Vec<UnsafeCell<CallInfo>>);
+pub struct CallInfoVec(Vec<CallInfo>);
 
 impl CallInfoVec {
     fn new(capacity: usize, length: usize) -> Option<CallInfoVec> {
@@ -257,34 +212,31 @@ impl CallInfoVec {
             return None;
         }
 
-        let mut civ = CallInfoVec {
-            0: Vec::with_capacity(capacity),
-        };
-        //unsafe { civ.0.set_len(length) };
+        let mut civ = CallInfoVec(Vec::with_capacity(capacity));
         stack_push!(civ, CallInfo, length);
-        dbg!(civ.0.len());
         Some(civ)
     }
 
-    pub fn swap_elem(&self, index: usize, new_ci: &mut CallInfo) -> Result<ErrCode, ErrCode> {
-        if let Some(c) = self.0.get(index) {
-            let ci = c.get();
-            if !ci.is_null() {
-                swap(unsafe { &mut *ci }, new_ci);
-            } else {
-                return Err(ErrCode::NullPointer);
-            }
-            return Ok(ErrCode::Fine);
+    pub fn swap_elem(&mut self, index: usize, new_ci: &mut CallInfo) -> Result<ErrCode, ErrCode> {
+        if let Some(ci) = self.0.get_mut(index) {
+            swap(ci, new_ci);
+            Ok(ErrCode::Fine)
         } else {
-            return Err(ErrCode::NoneObject);
+            Err(ErrCode::NoneObject)
         }
     }
 
-    fn increase(&mut self, civ_top_index: usize, need: usize) -> Result<ErrCode, ErrCode> {
+    /// brief: the number of callinfos the vector grows by, 0 if it need not grow
+    fn increase_size(
+        &self,
+        civ_top_index: usize,
+        need: usize,
+        max_calls: usize,
+    ) -> Result<usize, ErrCode> {
         // the space that has been allocated
         let old_alloc = self.0.len();
 
-        if old_alloc > LUA_MAX_CALLS as usize {
+        if old_alloc > max_calls {
             return Err(ErrCode::OverFlow);
         }
         // will never happen
@@ -298,55 +250,49 @@ impl CallInfoVec {
                 to_add = to_add2;
             }
 
-            if old_alloc + to_add > LUA_MAX_STACK as usize {
+            // never grow past the call limit
+            if old_alloc + to_add > max_calls {
+                to_add = max_calls - old_alloc;
+            }
+            if civ_top_index + need > old_alloc + to_add {
                 return Err(ErrCode::OverFlow);
             }
-            // capacity >= length
-            stack_push!(self, CallInfo, to_add);
-            return Ok(ErrCode::Fine);
+            Ok(to_add)
         }
         // need add space
         else {
-            return Ok(ErrCode::Fine);
+            Ok(0)
         } // it is not necessary to add
     }
 
+    fn increase(&mut self, to_add: usize) {
+        // capacity >= length
+        stack_push!(self, CallInfo, to_add);
+    }
+
     #[allow(dead_code)]
     fn decrease(&mut self, _starting_pos: usize) {}
 
     #[inline(always)]
     #[allow(dead_code)]
     pub fn get_ref_elem(&self, index: usize) -> Option<&CallInfo> {
-        if let Some(ci) = self.0.get(index) {
-            Some(unsafe { &*(ci.get()) })
-        } else {
-            None
-        }
+        self.0.get(index)
     }
 
     #[inline(always)]
     #[allow(dead_code)]
-    pub fn get_mut_elem(&self, index: usize) -> Option<&mut CallInfo> {
-        if let Some(ci) = self.0.get(index) {
-            Some(unsafe { &mut *(ci.get()) })
-        } else {
-            None
-        }
-    }
-}
-
-impl Drop for CallInfoVec {
-    fn drop(&mut self) {
-        self.0.clear();
+    pub fn get_mut_elem(&mut self, index: usize) -> Option<&mut CallInfo> {
+        self.0.get_mut(index)
     }
 }
 
 const LUA_EXTRASPACE: usize = size_of::<*mut ()>();
+const LUA_GC_MIN_THRESHOLD: usize = 64 * 1024;
 
-#[derive(Default,Debug)]
+#[derive(Debug)]
 #[allow(dead_code)]
 pub struct LuaState {
-    stack: Option<NonNull<Stack>>, // a pointer to the stack
+    stack: Stack, // the state owns its stack
 
     stack_last_index: usize,
 
@@ -354,20 +300,18 @@ pub struct LuaState {
 
     stack_size: usize,
 
-    next: Option<NonNull<LuaState>>,     // default value: None
-    previous: Option<NonNull<LuaState>>, // default value: None
-
-    civ: Option<NonNull<CallInfoVec>>,
+    civ: CallInfoVec,
     ncalls: usize,
-    //cci_index: usize,
-    global: Option<NonNull<GlobalState>>,
+    nrcalls: usize, // nested entries from rust into the machine
+    cci_index: usize,
+    ci_err_index: usize,
+    global: GlobalState,
     status: LuaStateStatus,
 }
 
-#[derive(Default,Debug)]
+#[derive(Default, Debug)]
 #[allow(dead_code)]
 pub struct CallInfo {
-    stack: Option<NonNull<Stack>>,
     stack_func_index: usize,
     stack_top_index: usize,
     nresult: isize,
@@ -376,14 +320,12 @@ pub struct CallInfo {
 
 impl CallInfo {
     fn new(
-        stk: Option<NonNull<Stack>>,
         stack_func_index: usize,
         stack_top_index: usize,
         nres: isize,
         status: LuaCallInfoStatus,
     ) -> Self {
         Self {
-            stack: stk,
             stack_func_index,
             stack_top_index,
             nresult: nres,
@@ -392,11 +334,7 @@ impl CallInfo {
     }
 
     fn ci_check(&self, size: usize) -> bool {
-        if self.stack_func_index + size >= self.stack_top_index {
-            false
-        } else {
-            true
-        }
+        self.stack_func_index + size < self.stack_top_index
     }
 }
 
@@ -413,217 +351,518 @@ impl LuaState {
         self.stack_top_index
     }
 
-    pub fn change_ncalls(&mut self, step: usize, direction: bool) {
-        self.ncalls = {
+    #[inline(always)]
+    pub fn get_ncalls(&self) -> usize {
+        self.ncalls
+    }
+
+    #[inline(always)]
+    pub fn get_nrcalls(&self) -> usize {
+        self.nrcalls
+    }
+
+    pub fn change_nrcalls(&mut self, step: usize, direction: bool) {
+        self.nrcalls = {
             if direction {
-                self.ncalls + step
+                self.nrcalls + step
             } else {
-                self.ncalls - step
+                self.nrcalls - step
             }
         }
     }
 
-    pub fn write_ci_status(&self, ci_index: usize, status: LuaCallInfoStatus) {
-        let cci: &mut CallInfo = ptr_get!(ptr_get!(self, civ).ok().unwrap().get_mut_elem(ci_index))
-            .ok()
-            .unwrap();
+    /// brief: the index of the current call info
+    #[inline(always)]
+    pub fn get_cci_index(&self) -> usize {
+        self.cci_index
+    }
+
+    #[inline(always)]
+    pub fn set_cci_index(&mut self, index: usize) {
+        self.cci_index = index;
+    }
+
+    /// brief: the index of the call info that raised the last error
+    #[inline(always)]
+    pub fn get_ci_err_index(&self) -> usize {
+        self.ci_err_index
+    }
+
+    #[inline(always)]
+    pub fn set_ci_err_index(&mut self, index: usize) {
+        self.ci_err_index = index;
+    }
+
+    /// brief: the resource budget the machine was built with
+    pub fn get_config(&self) -> &LuaConfig {
+        &self.global.config
+    }
+
+    /// brief: the bytes the quota has granted so far
+    pub fn get_total_bytes(&self) -> usize {
+        self.global.total_bytes
+    }
+
+    pub fn write_ci_status(&mut self, ci_index: usize, status: LuaCallInfoStatus) {
+        let cci = self.civ.get_mut_elem(ci_index).unwrap();
         cci.callstatus = status;
     }
 
     pub fn get_ci_status(&self, ci_index: usize) -> LuaCallInfoStatus {
-        let cci = ptr_get!(ptr_get!(self, civ).ok().unwrap().get_ref_elem(ci_index))
-            .ok()
-            .unwrap();
+        let cci = self.civ.get_ref_elem(ci_index).unwrap();
         cci.callstatus
     }
 
-    pub fn get_stack_mut_ref(&self) -> Option<&mut Stack> {
-        if let Some(stack) = self.stack {
-            let ptr = stack.as_ptr();
-            if !ptr.is_null() {
-                Some(unsafe { &mut *ptr })
-            } else {
-                None
+    pub fn get_stack_ref(&self) -> &Stack {
+        &self.stack
+    }
+
+    pub fn get_stack_mut_ref(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+
+    pub fn get_civ_ref(&self) -> &CallInfoVec {
+        &self.civ
+    }
+
+    pub fn get_civ_mut_ref(&mut self) -> &mut CallInfoVec {
+        &mut self.civ
+    }
+
+    /// brief: the rust closure a value of type TCCL refers to
+    pub(crate) fn get_rfunc(&self, elem: &StkElem) -> Option<Rc<RFUNC>> {
+        let closure = self.get_heap().get_closure(elem.as_rfunc()?)?;
+        Some(closure.0.clone())
+    }
+
+    /// brief: put a rust closure in the heap
+    pub fn new_closure_ref(&mut self, rfunc: Rc<RFUNC>) -> GcRef {
+        self.alloc_object(GcObject::Closure(RustClosure(rfunc, std::ptr::null())))
+    }
+
+    /// brief: the closure object of a static closure, made once while it is alive
+    fn intern_rfunc(&mut self, rfunc: &'static RFUNC) -> GcRef {
+        let key = rfunc as *const RFUNC;
+        if let Some(gc) = self.global.rfuncs.get(&key) {
+            if self.get_heap().get_closure(*gc).is_some() {
+                return *gc;
             }
-        } else {
-            None
         }
+        let gc = self.new_closure_ref(Rc::new(move |state: &mut LuaState| rfunc(state)));
+        self.global.rfuncs.insert(key, gc);
+        gc
     }
 
-    pub fn get_civ_mut_ref(&self) -> Option<&mut CallInfoVec> {
-        if let Some(civ) = self.civ {
-            let ptr = civ.as_ptr();
-            if !ptr.is_null() {
-                Some(unsafe { &mut *ptr })
-            } else {
-                None
+    pub(crate) fn get_ud_metatable(&self, id: TypeId) -> Option<GcRef> {
+        self.global.ud_metatables.get(&id).copied()
+    }
+
+    pub(crate) fn set_ud_metatable(&mut self, id: TypeId, metatable: GcRef) {
+        self.global.ud_metatables.insert(id, metatable);
+    }
+
+    pub fn get_heap(&self) -> &Heap {
+        &self.global.heap
+    }
+
+    pub fn get_heap_mut(&mut self) -> &mut Heap {
+        &mut self.global.heap
+    }
+
+    /// brief: the table of the registry
+    pub fn get_registry(&self) -> GcRef {
+        self.global.registry.unwrap()
+    }
+
+    /// brief: the table of the global variables, kept in the registry
+    pub fn get_globals(&self) -> GcRef {
+        let registry = self.get_heap().get_table(self.get_registry()).unwrap();
+        registry.get_int(LUA_RIDX_GLOBALS).as_gc().unwrap()
+    }
+
+    /// brief: put an object in the heap, collect first if the budget refuses it
+    pub fn alloc_object(&mut self, obj: GcObject) -> GcRef {
+        self.try_alloc_object(obj).unwrap_or_else(|err| self.error(err))
+    }
+
+    /// brief: put an object in the heap, a refused allocation is returned
+    /// instead of raised, for the host outside any protected call
+    fn try_alloc_object(&mut self, obj: GcObject) -> LuaResult<GcRef> {
+        let size = obj.size();
+        if !self.global.grant(size) {
+            self.collect_garbage();
+            if !self.global.grant(size) {
+                return Err(LuaError::Memory("not enough memory".to_string()));
+            }
+        }
+        Ok(self.global.heap.alloc(obj))
+    }
+
+    /// brief: the interned string of `bytes`
+    pub fn new_string(&mut self, bytes: &[u8]) -> GcRef {
+        self.try_new_string(bytes).unwrap_or_else(|err| self.error(err))
+    }
+
+    /// brief: the interned string of `bytes`, a refused allocation is returned
+    fn try_new_string(&mut self, bytes: &[u8]) -> LuaResult<GcRef> {
+        let before = self.global.heap.get_bytes();
+        let gc = self.global.heap.intern(bytes);
+        let grown = self.global.heap.get_bytes() - before;
+        if grown > 0 && !self.global.grant(grown) {
+            return Err(LuaError::Memory("not enough memory".to_string()));
+        }
+        Ok(gc)
+    }
+
+    pub fn new_table_ref(&mut self, narray: usize, nhash: usize) -> GcRef {
+        self.alloc_object(GcObject::Table(LuaTable::with_capacity(narray, nhash)))
+    }
+
+    /// brief: the bytes of a string value
+    pub fn get_str_elem(&self, elem: &StkElem) -> Option<&[u8]> {
+        if !elem.is_string() {
+            return None;
+        }
+        self.get_heap().get_string(elem.as_gc()?)
+    }
+
+    /// brief: t[key] without meta methods
+    pub fn table_get_raw(&self, table: GcRef, key: &StkElem) -> StkElem {
+        match self.get_heap().get_table(table) {
+            Some(table) => table.get(key),
+            None => StkElem::default(),
+        }
+    }
+
+    /// brief: t[key] = val without meta methods
+    pub fn table_set_raw(&mut self, table: GcRef, key: StkElem, val: StkElem) -> Result<(), ErrCode> {
+        let heap = &mut self.global.heap;
+        match heap.get_table_mut(table) {
+            Some(t) => t.set(key, val).map_err(|_| ErrCode::NoneObject)?,
+            None => return Err(ErrCode::NoneObject),
+        }
+        let grown = heap.resize(table);
+        if grown > 0 {
+            if !self.global.grant(grown as usize) {
+                self.status = LuaStateStatus::LuaErrMem;
+                return Err(ErrCode::OverFlow);
             }
         } else {
-            None
+            self.global.release((-grown) as usize);
+        }
+        Ok(())
+    }
+
+    /// brief: a full collection, the stack and the registry are the roots
+    pub fn collect_garbage(&mut self) -> usize {
+        let top = self.stack_top_index;
+        let mut roots: Vec<TObj> = self.global.registry.map(TObj::new_table).into_iter().collect();
+        roots.extend(self.global.ud_metatables.values().map(|gc| TObj::new_table(*gc)));
+        roots.extend(self.global.type_metatables.values().map(|gc| TObj::new_table(*gc)));
+        let roots = self.stack.0[..top].iter().chain(roots.iter());
+        let freed = self.global.heap.collect(roots);
+        self.global.release(freed);
+
+        let pause = self.get_config().gc.pause as usize;
+        let estimate = self.global.heap.get_bytes();
+        self.global.gc_estimate = estimate;
+        self.global.gc_threshold = (estimate / 100 * pause).max(LUA_GC_MIN_THRESHOLD);
+        self.global.gc_stepped = self.global.gc_threshold;
+        self.global.gc_work = 0;
+        freed
+    }
+
+    /// brief: a step of the cycle, as if `allocated` bytes were allocated.
+    /// the step pays `step_mul` percent of them as work, the cycle ends with
+    /// a full collection once the work covers the bytes alive after the last
+    /// one. return whether the cycle ended
+    pub fn step_garbage(&mut self, allocated: usize) -> bool {
+        let step_mul = self.get_config().gc.step_mul as usize;
+        self.global.gc_stepped = self.global.gc_stepped.max(self.global.heap.get_bytes());
+        let work = allocated.saturating_mul(step_mul) / 100;
+        self.global.gc_work = self.global.gc_work.saturating_add(work);
+        if self.global.gc_work >= self.global.gc_estimate {
+            self.collect_garbage();
+            return true;
+        }
+        false
+    }
+
+    /// brief: free every object as the state closes, the userdata are dropped
+    /// and release what they hold. nothing of the state can be used after
+    pub(crate) fn close(&mut self) {
+        let freed = self.global.heap.free_all();
+        self.global.release(freed);
+    }
+
+    /// brief: past the threshold a cycle runs, it takes a step every
+    /// `2^step_size` bytes the heap grows
+    #[inline]
+    pub fn check_gc(&mut self) {
+        let bytes = self.global.heap.get_bytes();
+        if self.global.gc_stopped || bytes <= self.global.gc_threshold {
+            return;
+        }
+        let allocated = bytes.saturating_sub(self.global.gc_stepped);
+        if allocated >> self.get_config().gc.step_size > 0 {
+            self.step_garbage(allocated);
+        }
+    }
+
+    /// brief: stop or restart the automatic collection
+    pub fn set_gc_running(&mut self, running: bool) {
+        self.global.gc_stopped = !running;
+    }
+
+    pub fn is_gc_running(&self) -> bool {
+        !self.global.gc_stopped
+    }
+
+    pub fn get_gc_params_mut(&mut self) -> &mut GcParams {
+        &mut self.global.config.gc
+    }
+
+    /// brief: record the collector mode, return whether it was generational
+    /// both modes run the same stop-the-world collector
+    pub fn set_gc_generational(&mut self, generational: bool) -> bool {
+        std::mem::replace(&mut self.global.gc_generational, generational)
+    }
+
+    /// brief: emit a warning to stderr, `tocont` continues it with the next one
+    /// the control messages "@on" and "@off" switch the warnings
+    pub fn warning(&mut self, msg: &[u8], tocont: bool) {
+        if !self.global.warn_cont && !tocont && msg.first() == Some(&b'@') {
+            match msg {
+                b"@on" => self.global.warn_on = true,
+                b"@off" => self.global.warn_on = false,
+                _ => {}
+            }
+            return;
+        }
+        if self.global.warn_on {
+            let mut stderr = std::io::stderr().lock();
+            if !self.global.warn_cont {
+                let _ = stderr.write_all(b"Lua warning: ");
+            }
+            let _ = stderr.write_all(msg);
+            if !tocont {
+                let _ = stderr.write_all(b"\n");
+            }
         }
+        self.global.warn_cont = tocont;
     }
 
     fn stack_init(&mut self) -> Result<ErrCode, ErrCode> {
-        // initialize the stack, drop the memory manually
+        let (max_stack, stack_size) = (self.get_config().max_stack, self.get_config().stack_size);
+
+        if !self.global.grant(stack_size * LUA_STACK_SLOT_SIZE) {
+            self.status = LuaStateStatus::LuaErrMem;
+            return Err(ErrCode::OverFlow);
+        }
 
-        let stk_opt = Stack::new(LUA_MAX_STACK as usize, LUA_STACK_SIZE as usize);
+        let stk_opt = Stack::new(max_stack, stack_size);
         // None type will return only if length size is greater that capacity
 
         if let Some(stack) = stk_opt {
-            self.stack = Some(ptr_init!(Box::leak(Box::new(stack))));
-            // static lifetime
+            self.stack = stack;
 
             // set the current size of the stack
-            self.stack_size = LUA_STACK_SIZE as usize;
-            self.stack_last_index = 0usize + (LUA_STACK_SIZE - LUA_EXTRA_STACK) as usize;
+            self.stack_size = stack_size;
+            self.stack_last_index = stack_size - LUA_EXTRA_STACK as usize;
             self.stack_top_index = 0;
             // pos 0 is assumed to take
 
-            return Ok(ErrCode::Fine);
+            Ok(ErrCode::Fine)
         } else {
-            return Err(ErrCode::OverFlow);
+            Err(ErrCode::OverFlow)
         }
     }
 
-    pub fn stack_check(&mut self, need: usize) {
+    pub fn stack_check(&mut self, need: usize) -> Result<ErrCode, ErrCode> {
         if self.stack_top_index + need > self.stack_last_index {
-            self.stack_increase(need);
+            return self.stack_increase(need);
         }
+        Ok(ErrCode::Fine)
+    }
+
+    /// brief: the number of free slots a rust function is guaranteed
+    pub fn get_min_stack(&self) -> usize {
+        self.get_config().min_stack
     }
 
     /// true: legal
     /// false: illegal
     pub fn calls_check(&self) -> bool {
-        !(self.ncalls >= LUA_MAX_CALLS)
+        self.ncalls < self.get_config().max_calls
     }
 
-    fn stack_increase(&mut self, size: usize) {
-        let size_add = ptr_get!(self, stack)
-            .ok()
-            .unwrap()
-            .increase(size)
-            .ok()
-            .unwrap();
+    /// true: legal
+    /// false: illegal
+    pub fn rcalls_check(&self) -> bool {
+        self.nrcalls < self.get_config().max_rcalls
+    }
+
+    fn stack_increase(&mut self, size: usize) -> Result<ErrCode, ErrCode> {
+        let max_stack = self.get_config().max_stack;
+        let size_add = self.stack.increase_size(size, max_stack)?;
+
+        if !self.global.grant(size_add * LUA_STACK_SLOT_SIZE) {
+            self.status = LuaStateStatus::LuaErrMem;
+            return Err(ErrCode::OverFlow);
+        }
+
+        self.stack.increase(size_add);
         self.stack_size += size_add;
         self.stack_last_index = self.stack_size - LUA_EXTRA_STACK as usize;
+        Ok(ErrCode::Fine)
     }
 
     pub fn stack_shrink(&mut self, ci_index: usize) {
-        let cci = ptr_get!(ptr_get!(self, civ).ok().unwrap().get_mut_elem(ci_index))
-            .ok()
-            .unwrap();
+        let cci = self.civ.get_mut_elem(ci_index).unwrap();
         let func_index = cci.stack_func_index;
-        ptr_get!(self, stack).ok().unwrap().decrease(func_index);
-        //stack_last_index:
-        //stack_size
-
         cci.stack_top_index = ILLEGAL_INDEX;
         cci.stack_func_index = ILLEGAL_INDEX;
-    }
-
-    fn stack_clear(&mut self) {
-        drop(self.stack.take());
-        self.stack = None;
-        self.stack_size = 0;
-        self.stack_top_index = ILLEGAL_INDEX;
-        self.stack_last_index = ILLEGAL_INDEX;
+        self.stack.decrease(func_index);
+        //stack_last_index:
+        //stack_size
     }
 
     #[allow(dead_code)]
     pub fn civ_init(&mut self) -> Result<ErrCode, ErrCode> {
-        let civ_opt = CallInfoVec::new(LUA_MAX_CALLS, LUA_CI_LEN);
+        let (max_calls, ci_len) = (self.get_config().max_calls, self.get_config().ci_len);
+
+        if !self.global.grant(ci_len * LUA_CI_SLOT_SIZE) {
+            self.status = LuaStateStatus::LuaErrMem;
+            return Err(ErrCode::OverFlow);
+        }
+
+        let civ_opt = CallInfoVec::new(max_calls, ci_len.max(1));
         // None type will return only if length size is greater that capacity
-        
-        if let Some(civ) = civ_opt {
-            let civ_box = Box::new(civ);
-            // static lifetime
-            dbg!(&civ_box.0.len());
-            // let mut cci = CallInfo::new(
-            //     self.stack,
-            //     0,
-            //     LUA_MIN_STACK as usize,
-            //     Default::default(),
-            //     LuaCallInfoStatus::CallOk,
-            // ); // act as the main function
-
-            // let _ = civ_box.swap_elem(0, &mut cci);
-
-            self.civ = Some(ptr_init!(Box::leak(civ_box)));
-            // static lifetime
-
-            self.ncalls = 0;
-
-            return Ok(ErrCode::Fine);
+
+        if let Some(mut civ) = civ_opt {
+            let mut cci = CallInfo::new(
+                0,
+                self.get_min_stack(),
+                Default::default(),
+                LuaCallInfoStatus::CallOk,
+            ); // act as the main function
+
+            let _ = civ.swap_elem(0, &mut cci);
+
+            self.civ = civ;
+            self.ncalls = 1;
+            self.cci_index = 0;
+
+            Ok(ErrCode::Fine)
         } else {
-            return Err(ErrCode::OverFlow);
+            Err(ErrCode::OverFlow)
         }
     }
 
     #[allow(dead_code)]
-    pub fn add_next_ci(&mut self, func_index: usize, nresult: isize) -> usize {
+    pub fn add_next_ci(&mut self, func_index: usize, nresult: isize) -> Result<usize, ErrCode> {
         // try to increase the civ
-        let civ_ptr = ptr_get!(self, civ).ok().unwrap();
+        let max_calls = self.get_config().max_calls;
 
-        let _ = civ_ptr.increase(self.ncalls, 1).ok().unwrap();
+        let to_add = self.civ.increase_size(self.ncalls, 1, max_calls)?;
+        if to_add > 0 {
+            if !self.global.grant(to_add * LUA_CI_SLOT_SIZE) {
+                self.status = LuaStateStatus::LuaErrMem;
+                return Err(ErrCode::OverFlow);
+            }
+            self.civ.increase(to_add);
+        }
 
         let mut ci = CallInfo::new(
-            self.stack,
             func_index,
-            self.stack_top_index + LUA_MIN_STACK as usize,
+            self.stack_top_index + self.get_min_stack(),
             nresult,
             LuaCallInfoStatus::CallOk,
         );
 
-        let _ = civ_ptr.swap_elem(self.ncalls, &mut ci).ok().unwrap();
+        let _ = self.civ.swap_elem(self.ncalls, &mut ci).ok().unwrap();
 
         self.ncalls += 1;
-        return self.ncalls - 1;
+        Ok(self.ncalls - 1)
     }
 
-    pub fn cci_check(&self, index: usize, size: usize) -> bool {
-        ptr_get!(ptr_get!(self, civ).ok().unwrap().get_ref_elem(index))
-            .ok()
-            .unwrap()
-            .ci_check(size)
+    /// brief: drop the callinfo on the top, return the index of the new top
+    pub fn remove_ci(&mut self) -> usize {
+        let mut callinfo = CallInfo::default();
+        self.ncalls -= 1;
+        let _ = self.civ.swap_elem(self.ncalls, &mut callinfo).ok().unwrap();
+        self.ncalls - 1
     }
 
-    pub fn civ_shrink(&mut self, ci_index: usize) {
-        // without any cleaning
-        ptr_get!(self, civ).ok().unwrap().decrease(ci_index);
+    pub fn cci_check(&self, index: usize, size: usize) -> bool {
+        self.civ.get_ref_elem(index).unwrap().ci_check(size)
     }
 
-    fn callvec_clear(&mut self) {
-        drop(self.civ.take());
-        //self.cci_index = ILLEGAL_INDEX;
-
-        self.ncalls = 0; // no space
+    /// brief: drop the call infos of the calls an error escaped from
+    pub fn unwind_ci(&mut self, ncalls: usize, nrcalls: usize) {
+        while self.ncalls > ncalls {
+            self.remove_ci();
+        }
+        self.cci_index = self.ncalls - 1;
+        self.nrcalls = nrcalls;
     }
 
-    pub fn mainthread_new(ud: *const ()) -> &'static mut LuaState {
-        // initialize meta, with default value
-        Meta::new();
-
-        // global state accepts userdata
-        get_global_state!().userdata = Some(ptr_init!(unsafe { &*ud }));
-        
-        //get_main_state!() = Default::default();
+    /// brief: raise a lua error, the nearest protected call catches it
+    pub fn error(&mut self, err: LuaError) -> ! {
+        self.status = match err {
+            LuaError::Memory(_) => LuaStateStatus::LuaErrMem,
+            _ => LuaStateStatus::LuaErrRun,
+        };
+        std::panic::resume_unwind(Box::new(LuaThrow(err)))
+    }
 
-        // link the state with global state
-        get_main_state!().global = Some(ptr_init!(ptr_get!(get_global_state!(), NonNull)));
+    pub fn civ_shrink(&mut self, ci_index: usize) {
+        // without any cleaning
+        self.civ.decrease(ci_index);
+    }
 
-        // link the global state with the state
-        get_global_state!().mainthread = Some(ptr_init!(ptr_get!(get_main_state!(), NonNull)));
+    /// brief: create the main thread, the state owns its stack, its call info
+    /// vector and the global state
+    pub fn mainthread_new(ud: *mut (), config: LuaConfig) -> Result<LuaState, ErrCode> {
+        let mut state = LuaState {
+            stack: Stack(Vec::new()),
+            stack_last_index: 0,
+            stack_top_index: 0,
+            stack_size: 0,
+            civ: CallInfoVec(Vec::new()),
+            ncalls: 0,
+            nrcalls: 0,
+            cci_index: 0,
+            ci_err_index: ILLEGAL_INDEX,
+            global: GlobalState {
+                userdata: NonNull::new(ud),
+                config,
+                ..Default::default()
+            },
+            status: LuaStateStatus::LuaOk,
+        };
 
         // stack initialize
-        let _ = get_main_state!().stack_init().ok().unwrap();
+        state.stack_init()?;
 
         // civ initialize
-        let _ = get_main_state!().civ_init().ok().unwrap();
+        state.civ_init()?;
+
+        // registry initialize, the main thread and the globals live there
+        state.global.gc_threshold = LUA_GC_MIN_THRESHOLD;
+        state.global.gc_stepped = LUA_GC_MIN_THRESHOLD;
+        let tables = [(2, 0), (0, 0)].map(|(narray, nhash)| {
+            state.try_alloc_object(GcObject::Table(LuaTable::with_capacity(narray, nhash)))
+        });
+        let [Ok(registry), Ok(globals)] = tables else {
+            state.status = LuaStateStatus::LuaErrMem;
+            return Err(ErrCode::OverFlow);
+        };
+        state.global.registry = Some(registry);
+        state.table_set_raw(registry, TObj::new_integer(LUA_RIDX_MAINTHREAD), TObj::new_thread())?;
+        state.table_set_raw(registry, TObj::new_integer(LUA_RIDX_GLOBALS), TObj::new_table(globals))?;
 
-        dbg!(&get_global_state!());
-        dbg!(&get_main_state!());
-        dbg!(&get_main_state!().civ);
-        &mut get_main_state!()
+        Ok(state)
     }
 
     #[inline(always)]
@@ -633,14 +872,12 @@ impl LuaState {
 
     #[inline(always)]
     pub fn move_top(&mut self, step: usize, direction: bool) {
-        dbg!(self.stack_top_index);
-        assert!(self.stack_last_index>=self.stack_top_index);
+        debug_assert!(self.stack_last_index >= self.stack_top_index);
         if direction {
             self.stack_top_index += step;
         } else {
             self.stack_top_index -= step;
         }
-        dbg!(self.stack_top_index);
     }
 
     #[inline(always)]
@@ -650,27 +887,18 @@ impl LuaState {
 
     pub fn push_integer(&mut self, integer: INT) {
         let mut elem = StkElem::new_integer(integer);
-
-        //unsafe{dbg!("before: ",&elem.get_value().val_int)};
-        //dbg!("before: ",&elem.get_type());
-        let _ = ptr_get!(self, stack)
-            .ok()
-            .unwrap()
+        let _ = self
+            .stack
             .swap_elem(self.stack_top_index, &mut elem)
             .ok()
             .unwrap();
-        dbg!(self.stack_top_index);
-        //dbg!("after push",unsafe{self.stack.unwrap().as_ref().0.get(self.stack_top_index).unwrap().get().as_ref().unwrap().get_value().val_int});
-        //dbg!("after push",unsafe{self.stack.unwrap().as_ref().0.get(self.stack_top_index).unwrap().get().as_ref().unwrap().get_type()});
         self.increase_top();
-        dbg!(self.stack_top_index);
     }
 
     pub fn push_float(&mut self, number: FLT) {
         let mut elem = StkElem::new_float(number);
-        let _ = ptr_get!(self, stack)
-            .ok()
-            .unwrap()
+        let _ = self
+            .stack
             .swap_elem(self.stack_top_index, &mut elem)
             .ok()
             .unwrap();
@@ -679,9 +907,8 @@ impl LuaState {
 
     pub fn push_bool(&mut self, boolean: bool) {
         let mut elem = StkElem::new_bool(boolean);
-        let _ = ptr_get!(self, stack)
-            .ok()
-            .unwrap()
+        let _ = self
+            .stack
             .swap_elem(self.stack_top_index, &mut elem)
             .ok()
             .unwrap();
@@ -690,9 +917,8 @@ impl LuaState {
 
     pub fn push_nil(&mut self) {
         let mut elem = StkElem::new_nil();
-        let _ = ptr_get!(self, stack)
-            .ok()
-            .unwrap()
+        let _ = self
+            .stack
             .swap_elem(self.stack_top_index, &mut elem)
             .ok()
             .unwrap();
@@ -700,36 +926,30 @@ impl LuaState {
     }
 
     pub fn push_ud(&mut self, ud: Option<*mut ()>) {
-        if let Some(ud_) = ud {
-            let mut elem = StkElem::new_ud(ud_);
-            let _ = ptr_get!(self, stack)
-                .ok()
-                .unwrap()
-                .swap_elem(self.stack_top_index, &mut elem)
-                .ok()
-                .unwrap();
-        } else {
-            let mut elem = StkElem::new_ud(null_mut());
-            let _ = ptr_get!(self, stack)
-                .ok()
-                .unwrap()
-                .swap_elem(self.stack_top_index, &mut elem)
-                .ok()
-                .unwrap();
-        }
-        self.increase_top();
-    }
+        let mut elem = StkElem::new_ud(ud.unwrap_or(core::ptr::null_mut()));
+        let _ = self
+            .stack
+            .swap_elem(self.stack_top_index, &mut elem)
+            .ok()
+            .unwrap();
+        self.increase_top();
+    }
 
-    pub fn push_rfunc(&mut self, rfunc: &RFUNC) {
-        //let mut x=rfunc;
-        //x(&mut get_main_state!());
-        let mut elem = StkElem::new_rfunc(rfunc);
-        //let x=unsafe{elem.get_value().val_rfunc.into_inner().unwrap().as_ref()};
-        //x(&mut get_main_state!());
-        //dbg!("push_rfunc",unsafe{elem.get_value().val_rfunc.into_inner()}.unwrap().as_ptr() as *const());
-        let _ = ptr_get!(self, stack)
+    pub fn push_lrfunc(&mut self, lrfunc: LRFUNC) {
+        let mut elem = StkElem::new_lrfunc(lrfunc);
+        let _ = self
+            .stack
+            .swap_elem(self.stack_top_index, &mut elem)
             .ok()
-            .unwrap()
+            .unwrap();
+        self.increase_top();
+    }
+
+    pub fn push_rfunc(&mut self, rfunc: &'static RFUNC) {
+        let gc = self.intern_rfunc(rfunc);
+        let mut elem = StkElem::new_rfunc(gc);
+        let _ = self
+            .stack
             .swap_elem(self.stack_top_index, &mut elem)
             .ok()
             .unwrap();
@@ -738,9 +958,8 @@ impl LuaState {
 
     pub fn push_obj(&mut self, obj: StkElem) {
         let mut elem = StkElem::new_obj(obj);
-        let _ = ptr_get!(self, stack)
-            .ok()
-            .unwrap()
+        let _ = self
+            .stack
             .swap_elem(self.stack_top_index, &mut elem)
             .ok()
             .unwrap();
@@ -752,11 +971,9 @@ impl LuaState {
     }
 
     pub fn pop_stack(&mut self) -> StkElem {
-        dbg!(self.stack_top_index);
         let mut elem = StkElem::default();
-        let _ = ptr_get!(self, stack)
-            .ok()
-            .unwrap()
+        let _ = self
+            .stack
             .swap_elem(self.stack_top_index - 1, &mut elem)
             .ok()
             .unwrap();
@@ -765,71 +982,1116 @@ impl LuaState {
         elem
     }
 
-    pub fn pop_integer(&mut self) -> INT {
-        let elem = self.pop_stack();
-        if elem.get_type() != TNumber::NumInt as u8 {
-            panic!("FATAL ERROR: MISMATCH TYPE");
+    /// brief: the slot of the first argument of the running function
+    pub fn get_base_index(&self) -> usize {
+        if self.cci_index == 0 {
+            0
         } else {
-            unsafe { elem.get_value().val_int }.into_inner().unwrap()
+            self.civ.get_ref_elem(self.cci_index).unwrap().stack_func_index + 1
         }
     }
 
-    pub fn pop_float(&mut self) -> FLT {
-        let elem = self.pop_stack();
-        if elem.get_type() != TNumber::NumFlt as u8 {
-            panic!("FATAL ERROR: MISMATCH TYPE");
+    /// brief: the functions of the running calls, the innermost first
+    pub fn get_call_functions(&mut self) -> Vec<Value> {
+        let mut functions = Vec::with_capacity(self.cci_index);
+        for ci_index in (1..=self.cci_index).rev() {
+            let func_index = self.civ.get_ref_elem(ci_index).unwrap().stack_func_index;
+            let elem = self.stack.0[func_index];
+            functions.push(self.elem_to_value(&elem));
+        }
+        functions
+    }
+
+    /// brief: the number of values of the running function
+    pub fn get_top(&self) -> usize {
+        self.stack_top_index - self.get_base_index()
+    }
+
+    /// brief: idx >= 0 sets the number of values, filling with nil
+    /// idx < 0 drops -idx-1 values
+    pub fn set_top(&mut self, idx: isize) {
+        let new_top = if idx >= 0 {
+            self.get_base_index() + idx as usize
         } else {
-            unsafe { elem.get_value().val_num }.into_inner().unwrap()
+            (self.stack_top_index as isize + idx + 1) as usize
+        };
+        while self.stack_top_index < new_top {
+            self.push_nil();
         }
+        self.stack_top_index = new_top;
     }
 
-    pub fn pop_bool(&mut self) -> bool {
-        let elem = self.pop_stack();
-        if elem.get_type() != TObject::TBoolean as u8 {
-            panic!("FATAL ERROR: MISMATCH TYPE");
+    /// brief: the slot of a stack index, 1 is the first argument and -1 the top
+    /// None for the pseudo indices and the acceptable indices above the top
+    pub fn index_to_slot(&self, idx: isize) -> Option<usize> {
+        if idx > 0 {
+            let slot = self.get_base_index() + idx as usize - 1;
+            if slot < self.stack_top_index {
+                Some(slot)
+            } else {
+                None
+            }
+        } else if idx < 0 && idx > LUA_REGISTRY_INDEX {
+            let slot = self.stack_top_index as isize + idx;
+            if slot >= self.get_base_index() as isize {
+                Some(slot as usize)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// brief: turn a relative index into one that survives pushes and pops
+    pub fn abs_index(&self, idx: isize) -> isize {
+        if idx > 0 || idx <= LUA_REGISTRY_INDEX {
+            idx
+        } else {
+            self.stack_top_index as isize + idx + 1 - self.get_base_index() as isize
+        }
+    }
+
+    /// brief: the value at a stack index, nil for an empty index
+    pub fn get_elem_at(&self, idx: isize) -> StkElem {
+        if idx == LUA_REGISTRY_INDEX {
+            return StkElem::new_table(self.get_registry());
+        }
+        match self.index_to_slot(idx) {
+            Some(slot) => self.stack.0[slot],
+            None => StkElem::default(),
+        }
+    }
+
+    /// brief: the index holds no value at all, not even nil
+    pub fn is_none(&self, idx: isize) -> bool {
+        idx != LUA_REGISTRY_INDEX && self.index_to_slot(idx).is_none()
+    }
+
+    pub fn set_elem_at(&mut self, idx: isize, elem: StkElem) {
+        let slot = self.index_to_slot(idx).expect("invalid stack index");
+        self.stack.0[slot] = elem;
+    }
+
+    /// brief: push a copy of the value at a stack index
+    pub fn push_index(&mut self, idx: isize) {
+        let elem = self.get_elem_at(idx);
+        self.push_obj(elem);
+    }
+
+    pub fn push_string(&mut self, bytes: &[u8]) {
+        let gc = self.new_string(bytes);
+        self.push_obj(StkElem::new_string(gc));
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.push_string(s.as_bytes());
+    }
+
+    /// brief: push a new empty table with room for narray and nhash fields
+    pub fn create_table(&mut self, narray: usize, nhash: usize) {
+        let gc = self.new_table_ref(narray, nhash);
+        self.push_obj(StkElem::new_table(gc));
+    }
+
+    pub fn new_table(&mut self) {
+        self.create_table(0, 0);
+    }
+
+    /// brief: push the table of the global variables
+    pub fn push_globals(&mut self) {
+        let globals = self.get_globals();
+        self.push_obj(StkElem::new_table(globals));
+    }
+
+    fn new_lua_ref(&mut self, gc: GcRef) -> LuaRef {
+        LuaRef {
+            heap: self.global.heap.get_id(),
+            gc,
+            _pin: self.global.heap.pin(gc).expect("a live object"),
+        }
+    }
+
+    /// brief: an owned copy of a stack value, objects are pinned by the handle
+    pub fn elem_to_value(&mut self, elem: &StkElem) -> Value {
+        if let Some(boolean) = elem.as_bool() {
+            Value::Boolean(boolean)
+        } else if let Some(integer) = elem.as_integer() {
+            Value::Integer(integer)
+        } else if let Some(number) = elem.as_float() {
+            Value::Number(number)
+        } else if elem.is_string() {
+            Value::String(LuaString::new(self.get_str_elem(elem).unwrap_or_default()))
+        } else if elem.is_table() {
+            Value::Table(Table(self.new_lua_ref(elem.as_gc().unwrap())))
+        } else if elem.is_full_ud() {
+            Value::UserData(AnyUserData(self.new_lua_ref(elem.as_gc().unwrap())))
+        } else if let Some(lrfunc) = elem.as_lrfunc() {
+            Value::Function(Function(FuncRef::Light(lrfunc)))
+        } else if let Some(gc) = elem.as_rfunc() {
+            Value::Function(Function(FuncRef::Closure(self.new_lua_ref(gc))))
+        } else if elem.is_thread() {
+            Value::Thread(Thread {
+                heap: self.global.heap.get_id(),
+            })
+        } else if let Some(ud) = elem.as_ud() {
+            Value::LightUserData(ud)
         } else {
-            unsafe { elem.get_value().val_bl }.into_inner().unwrap()
+            Value::Nil
+        }
+    }
+
+    pub(crate) fn check_ref(&self, lref: &LuaRef) -> GcRef {
+        if lref.heap != self.global.heap.get_id() {
+            panic!("value belongs to another state");
+        }
+        lref.gc
+    }
+
+    /// brief: the stack value of a host value, strings are interned
+    pub fn value_to_elem(&mut self, val: &Value) -> StkElem {
+        self.try_value_to_elem(val).unwrap_or_else(|err| self.error(err))
+    }
+
+    /// brief: the stack value of a host value, a string the heap cannot take
+    /// is returned as an error
+    fn try_value_to_elem(&mut self, val: &Value) -> LuaResult<StkElem> {
+        Ok(match val {
+            Value::Nil => StkElem::new_nil(),
+            Value::Boolean(b) => StkElem::new_bool(*b),
+            Value::Integer(i) => StkElem::new_integer(*i),
+            Value::Number(n) => StkElem::new_float(*n),
+            Value::String(s) => StkElem::new_string(self.try_new_string(s.as_bytes())?),
+            Value::Table(t) => StkElem::new_table(self.check_ref(&t.0)),
+            Value::UserData(u) => StkElem::new_full_ud(self.check_ref(&u.0)),
+            Value::Function(f) => match &f.0 {
+                FuncRef::Light(lrfunc) => StkElem::new_lrfunc(*lrfunc),
+                FuncRef::Closure(closure) => StkElem::new_rfunc(self.check_ref(closure)),
+            },
+            Value::Thread(t) => {
+                if t.heap != self.global.heap.get_id() {
+                    panic!("value belongs to another state");
+                }
+                StkElem::new_thread()
+            }
+            Value::LightUserData(p) => StkElem::new_ud(*p),
+        })
+    }
+
+    /// brief: an owned copy of the value at a stack index
+    pub fn get_value(&mut self, idx: isize) -> Value {
+        let elem = self.get_elem_at(idx);
+        self.elem_to_value(&elem)
+    }
+
+    pub fn push_value(&mut self, val: &Value) {
+        let elem = self.value_to_elem(val);
+        self.push_obj(elem);
+    }
+
+    pub fn pop_value(&mut self) -> Value {
+        let elem = self.get_elem_at(-1);
+        let val = self.elem_to_value(&elem);
+        let _ = self.pop_stack();
+        val
+    }
+
+    /// brief: a new empty table held by the host
+    pub fn create_table_value(&mut self, narray: usize, nhash: usize) -> LuaResult<Table> {
+        let gc = self.try_alloc_object(GcObject::Table(LuaTable::with_capacity(narray, nhash)))?;
+        Ok(Table(self.new_lua_ref(gc)))
+    }
+
+    /// brief: a new empty table for the libraries, a refused allocation is raised
+    pub(crate) fn new_table_value(&mut self, narray: usize, nhash: usize) -> Table {
+        let gc = self.new_table_ref(narray, nhash);
+        Table(self.new_lua_ref(gc))
+    }
+
+    /// brief: t[key] without meta methods
+    pub fn raw_get(&mut self, table: &Table, key: &Value) -> Value {
+        let gc = self.check_ref(&table.0);
+        let key = self.value_to_elem(key);
+        let elem = self.table_get_raw(gc, &key);
+        self.elem_to_value(&elem)
+    }
+
+    /// brief: t[key] = val without meta methods
+    pub fn raw_set(&mut self, table: &Table, key: Value, val: Value) -> LuaResult<()> {
+        match key {
+            Value::Nil => return Err(LuaError::Runtime("index is nil".to_string())),
+            Value::Number(n) if n.is_nan() => return Err(LuaError::Runtime("index is NaN".to_string())),
+            _ => {}
+        }
+        let gc = self.check_ref(&table.0);
+        let key = self.try_value_to_elem(&key)?;
+        let val = self.try_value_to_elem(&val)?;
+        self.table_set_raw(gc, key, val)
+            .map_err(|_| LuaError::Memory("not enough memory".to_string()))
+    }
+
+    /// brief: #t without meta methods
+    pub fn raw_len(&self, table: &Table) -> INT {
+        let gc = self.check_ref(&table.0);
+        match self.get_heap().get_table(gc) {
+            Some(table) => table.len(),
+            None => 0,
+        }
+    }
+
+    /// brief: the fields of a table in the order of `next`
+    pub fn raw_pairs(&mut self, table: &Table) -> Vec<(Value, Value)> {
+        let gc = self.check_ref(&table.0);
+        let mut elems = Vec::new();
+        if let Some(table) = self.get_heap().get_table(gc) {
+            let mut key = StkElem::default();
+            while let Ok(Some((next_key, val))) = table.next(&key) {
+                elems.push((next_key, val));
+                key = next_key;
+            }
+        }
+        elems
+            .iter()
+            .map(|(key, val)| (self.elem_to_value(key), self.elem_to_value(val)))
+            .collect()
+    }
+
+    /// brief: the field after `key` in the order of `next`, nil starts
+    pub fn raw_next(&mut self, table: &Table, key: &Value) -> LuaResult<Option<(Value, Value)>> {
+        let gc = self.check_ref(&table.0);
+        let key = self.value_to_elem(key);
+        let next = match self.get_heap().get_table(gc) {
+            Some(table) => table.next(&key),
+            None => Ok(None),
+        };
+        match next {
+            Ok(Some((key, val))) => Ok(Some((self.elem_to_value(&key), self.elem_to_value(&val)))),
+            Ok(None) => Ok(None),
+            Err(_) => Err(LuaError::Runtime("invalid key to 'next'".to_string())),
+        }
+    }
+
+    /// brief: #obj with the `__len` meta method
+    pub fn len(&mut self, obj: &Value) -> LuaResult<Value> {
+        if let Value::String(s) = obj {
+            return Ok(Value::Integer(s.as_bytes().len() as INT));
+        }
+        let handler = self.get_metafield(obj, "__len");
+        if !handler.is_nil() {
+            let mut results = self.call_value(&handler, MultiValue::from([obj.clone()]))?;
+            return Ok(results.pop_front().unwrap_or(Value::Nil));
+        }
+        match obj {
+            Value::Table(table) => Ok(Value::Integer(self.raw_len(table))),
+            _ => Err(LuaError::Runtime(format!(
+                "attempt to get length of a {} value",
+                obj.type_name()
+            ))),
+        }
+    }
+
+    /// brief: a function of a rust closure
+    pub fn create_closure(&mut self, rfunc: Rc<RFUNC>) -> LuaResult<Function> {
+        let gc = self.try_alloc_object(GcObject::Closure(RustClosure(rfunc, std::ptr::null())))?;
+        Ok(Function(FuncRef::Closure(self.new_lua_ref(gc))))
+    }
+
+    /// brief: a function of a rust closure for the libraries, a refused
+    /// allocation is raised
+    pub(crate) fn new_closure(&mut self, rfunc: Rc<RFUNC>) -> Function {
+        let gc = self.new_closure_ref(rfunc);
+        Function(FuncRef::Closure(self.new_lua_ref(gc)))
+    }
+
+    /// brief: a function of a rust closure calling the C function at `cfunc`,
+    /// which get_cfunction gives back
+    pub(crate) fn create_c_closure(&mut self, rfunc: Rc<RFUNC>, cfunc: *const ()) -> Function {
+        let gc = self.alloc_object(GcObject::Closure(RustClosure(rfunc, cfunc)));
+        Function(FuncRef::Closure(self.new_lua_ref(gc)))
+    }
+
+    /// brief: the C function a closure of the C API calls, null for other functions
+    pub(crate) fn get_cfunction(&self, function: &Function) -> *const () {
+        match &function.0 {
+            FuncRef::Closure(closure) => {
+                let gc = self.check_ref(closure);
+                self.get_heap().get_closure(gc).map_or(std::ptr::null(), |closure| closure.1)
+            }
+            FuncRef::Light(_) => std::ptr::null(),
+        }
+    }
+
+    /// brief: a function of a rust closure taking and returning converted values
+    /// an error returned by the closure is raised as a lua error
+    pub fn create_function<A, R, F>(&mut self, function: F) -> LuaResult<Function>
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, A) -> LuaResult<R> + 'static,
+    {
+        self.create_closure(rust_function(function))
+    }
+
+    /// brief: the table of the global variables
+    pub fn globals(&mut self) -> Table {
+        let globals = self.get_globals();
+        Table(self.new_lua_ref(globals))
+    }
+
+    /// brief: the table of the registry, where the libraries keep their state
+    pub fn registry(&mut self) -> Table {
+        let registry = self.get_registry();
+        Table(self.new_lua_ref(registry))
+    }
+
+    pub fn get_global<V: FromLua>(&mut self, name: &str) -> LuaResult<V> {
+        let globals = self.globals();
+        let val = self.raw_get(&globals, &Value::from(name));
+        V::from_lua(val, self)
+    }
+
+    pub fn set_global<V: IntoLua>(&mut self, name: &str, val: V) -> LuaResult<()> {
+        let globals = self.globals();
+        let val = val.into_lua(self)?;
+        self.raw_set(&globals, Value::from(name), val)
+    }
+
+    /// brief: a full userdata holding `data`, with the metatable of its type
+    pub fn create_userdata<T: UserData>(&mut self, data: T) -> LuaResult<AnyUserData> {
+        let metatable = userdata_metatable::<T>(self)?;
+        let gc = self.try_alloc_object(GcObject::UserData(LuaUserData {
+            data: Box::new(Rc::new(UserDataCell::Owned(RefCell::new(data)))),
+            metatable: Some(metatable),
+            user_value: TObj::default(),
+        }))?;
+        Ok(AnyUserData(self.new_lua_ref(gc)))
+    }
+
+    /// brief: a full userdata of a value borrowed by a scope, see Scope
+    pub(crate) fn create_userdata_cell<T: UserData>(&mut self, cell: UserDataCell<T>) -> LuaResult<AnyUserData> {
+        let metatable = userdata_metatable::<T>(self)?;
+        let gc = self.try_alloc_object(GcObject::UserData(LuaUserData {
+            data: Box::new(Rc::new(cell)),
+            metatable: Some(metatable),
+            user_value: TObj::default(),
+        }))?;
+        Ok(AnyUserData(self.new_lua_ref(gc)))
+    }
+
+    /// brief: make the callbacks and the userdata of a scope unusable
+    pub(crate) fn destroy_objects(&mut self, closures: &[GcRef], userdata: &[GcRef]) {
+        for gc in closures.iter() {
+            if let Some(GcObject::Closure(closure)) = self.global.heap.get_mut(*gc) {
+                let destroyed: Rc<RFUNC> = Rc::new(|state: &mut LuaState| state.error(LuaError::CallbackDestructed));
+                let old = std::mem::replace(&mut closure.0, destroyed);
+                drop(old);
+            }
+        }
+        for gc in userdata.iter() {
+            if let Some(ud) = self.global.heap.get_ud_mut(*gc) {
+                let old = std::mem::replace(&mut ud.data, Box::new(DestroyedUserData));
+                drop(old);
+            }
+        }
+    }
+
+    /// brief: run `f` with a scope, the callbacks and the userdata made by the
+    /// scope may borrow data of the caller, they are destroyed when `f` returns
+    pub fn scope<'env, R>(&mut self, f: impl FnOnce(&mut LuaState, &Scope<'env>) -> R) -> R {
+        let scope = Scope::new(self.global.heap.get_id());
+        let res = catch_unwind(AssertUnwindSafe(|| f(&mut *self, &scope)));
+        scope.destroy(self);
+        match res {
+            Ok(res) => res,
+            Err(payload) => resume_unwind(payload),
+        }
+    }
+
+    /// brief: run `f` on the value of a userdata, borrowed immutably
+    pub fn borrow_userdata<T: 'static, R>(&self, ud: &AnyUserData, f: impl FnOnce(&T) -> R) -> LuaResult<R> {
+        userdata_cell::<T>(self, ud)?.with(f)
+    }
+
+    /// brief: run `f` on the value of a userdata, borrowed mutably
+    pub fn borrow_userdata_mut<T: 'static, R>(
+        &self,
+        ud: &AnyUserData,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> LuaResult<R> {
+        userdata_cell::<T>(self, ud)?.with_mut(f)
+    }
+
+    /// brief: the metatable of a table or a full userdata
+    pub fn get_metatable(&mut self, val: &Value) -> Option<Table> {
+        let metatable = match val {
+            Value::Table(t) => self.get_heap().get_table(self.check_ref(&t.0))?.get_metatable(),
+            Value::UserData(u) => self.get_heap().get_ud(self.check_ref(&u.0))?.metatable,
+            val => self.global.type_metatables.get(val.type_name()).copied(),
+        }?;
+        Some(Table(self.new_lua_ref(metatable)))
+    }
+
+    /// brief: set the metatable all the values of the type of `val` share,
+    /// the string metatable for a string
+    pub fn set_type_metatable(&mut self, val: &Value, metatable: Option<&Table>) {
+        let type_name = val.type_name();
+        match metatable {
+            Some(metatable) => {
+                let gc = self.check_ref(&metatable.0);
+                self.global.type_metatables.insert(type_name, gc);
+            }
+            None => {
+                self.global.type_metatables.remove(type_name);
+            }
+        }
+    }
+
+    pub fn set_metatable(&mut self, table: &Table, metatable: Option<&Table>) {
+        let metatable = metatable.map(|mt| self.check_ref(&mt.0));
+        let gc = self.check_ref(&table.0);
+        if let Some(table) = self.get_heap_mut().get_table_mut(gc) {
+            table.set_metatable(metatable);
+        }
+    }
+
+    /// brief: set the metatable of one full userdata
+    pub fn set_userdata_metatable(&mut self, ud: &AnyUserData, metatable: Option<&Table>) {
+        let metatable = metatable.map(|mt| self.check_ref(&mt.0));
+        let gc = self.check_ref(&ud.0);
+        if let Some(ud) = self.get_heap_mut().get_ud_mut(gc) {
+            ud.metatable = metatable;
+        }
+    }
+
+    /// brief: the field `event` of the metatable of `val`, nil if none
+    pub fn get_metafield(&mut self, val: &Value, event: &str) -> Value {
+        match self.get_metatable(val) {
+            Some(metatable) => self.raw_get(&metatable, &Value::from(event)),
+            None => Value::Nil,
+        }
+    }
+
+    /// brief: t[key] with the `__index` meta method
+    pub fn index(&mut self, obj: &Value, key: &Value) -> LuaResult<Value> {
+        let mut obj = obj.clone();
+        for _ in 0..LUA_MAX_TAG_LOOP {
+            if let Value::Table(table) = &obj {
+                let val = self.raw_get(table, key);
+                if !val.is_nil() {
+                    return Ok(val);
+                }
+            }
+            let handler = self.get_metafield(&obj, "__index");
+            match handler {
+                Value::Nil => {
+                    if let Value::Table(_) = obj {
+                        return Ok(Value::Nil);
+                    }
+                    return Err(LuaError::Runtime(format!(
+                        "attempt to index a {} value",
+                        obj.type_name()
+                    )));
+                }
+                Value::Function(_) => {
+                    let mut results = self.call_value(&handler, MultiValue::from([obj, key.clone()]))?;
+                    return Ok(results.pop_front().unwrap_or(Value::Nil));
+                }
+                handler => obj = handler,
+            }
+        }
+        Err(LuaError::Runtime("'__index' chain too long; possible loop".to_string()))
+    }
+
+    /// brief: t[key] = val with the `__newindex` meta method
+    pub fn set_index(&mut self, obj: &Value, key: Value, val: Value) -> LuaResult<()> {
+        let mut obj = obj.clone();
+        for _ in 0..LUA_MAX_TAG_LOOP {
+            let handler = self.get_metafield(&obj, "__newindex");
+            if let Value::Table(table) = &obj {
+                if handler.is_nil() || !self.raw_get(table, &key).is_nil() {
+                    return self.raw_set(table, key, val);
+                }
+            }
+            match handler {
+                Value::Nil => {
+                    return Err(LuaError::Runtime(format!(
+                        "attempt to index a {} value",
+                        obj.type_name()
+                    )))
+                }
+                Value::Function(_) => {
+                    self.call_value(&handler, MultiValue::from([obj, key, val]))?;
+                    return Ok(());
+                }
+                handler => obj = handler,
+            }
+        }
+        Err(LuaError::Runtime("'__newindex' chain too long; possible loop".to_string()))
+    }
+
+    /// brief: the type of a value in messages, the `__name` of its metatable first
+    fn obj_type_name(&mut self, val: &Value) -> String {
+        if let Value::Table(_) | Value::UserData(_) = val {
+            if let Value::String(name) = self.get_metafield(val, "__name") {
+                return name.to_string_lossy();
+            }
+        }
+        val.type_name().to_string()
+    }
+
+    /// brief: a == b with the `__eq` meta method
+    pub fn equals(&mut self, a: &Value, b: &Value) -> LuaResult<bool> {
+        let handler = match (a, b) {
+            (Value::Table(_), Value::Table(_)) | (Value::UserData(_), Value::UserData(_)) if a != b => {
+                match self.get_metafield(a, "__eq") {
+                    Value::Nil => self.get_metafield(b, "__eq"),
+                    handler => handler,
+                }
+            }
+            _ => return Ok(a == b),
+        };
+        if handler.is_nil() {
+            return Ok(false);
+        }
+        let mut results = self.call_value(&handler, MultiValue::from([a.clone(), b.clone()]))?;
+        Ok(results.pop_front().unwrap_or(Value::Nil).is_truthy())
+    }
+
+    /// brief: a < b with the `__lt` meta method, integers and floats compare
+    /// by their exact values
+    pub fn less_than(&mut self, a: &Value, b: &Value) -> LuaResult<bool> {
+        const TWO_POW_63: FLT = 9223372036854775808.0;
+        match (a, b) {
+            (Value::Integer(i), Value::Integer(j)) => return Ok(i < j),
+            (Value::Number(f), Value::Number(g)) => return Ok(f < g),
+            (Value::Integer(i), Value::Number(f)) => {
+                return Ok(if f.is_nan() || *f <= -TWO_POW_63 {
+                    false
+                } else if *f >= TWO_POW_63 {
+                    true
+                } else {
+                    *i < f.ceil() as INT
+                })
+            }
+            (Value::Number(f), Value::Integer(i)) => {
+                return Ok(if f.is_nan() || *f >= TWO_POW_63 {
+                    false
+                } else if *f < -TWO_POW_63 {
+                    true
+                } else {
+                    (f.floor() as INT) < *i
+                })
+            }
+            (Value::String(s), Value::String(t)) => return Ok(s.as_bytes() < t.as_bytes()),
+            _ => {}
+        }
+        let handler = match self.get_metafield(a, "__lt") {
+            Value::Nil => self.get_metafield(b, "__lt"),
+            handler => handler,
+        };
+        if handler.is_nil() {
+            let (t1, t2) = (self.obj_type_name(a), self.obj_type_name(b));
+            return Err(LuaError::Runtime(if t1 == t2 {
+                format!("attempt to compare two {} values", t1)
+            } else {
+                format!("attempt to compare {} with {}", t1, t2)
+            }));
+        }
+        let mut results = self.call_value(&handler, MultiValue::from([a.clone(), b.clone()]))?;
+        Ok(results.pop_front().unwrap_or(Value::Nil).is_truthy())
+    }
+
+    /// brief: a <= b with the `__le` meta method
+    pub fn less_equal(&mut self, a: &Value, b: &Value) -> LuaResult<bool> {
+        match (a, b) {
+            (Value::Integer(_) | Value::Number(_), Value::Integer(_) | Value::Number(_)) => {
+                // a <= b is not b < a, but for nan
+                let nan = matches!(a, Value::Number(f) if f.is_nan())
+                    || matches!(b, Value::Number(g) if g.is_nan());
+                return Ok(!nan && !self.less_than(b, a)?);
+            }
+            (Value::String(s), Value::String(t)) => return Ok(s.as_bytes() <= t.as_bytes()),
+            _ => {}
+        }
+        let handler = match self.get_metafield(a, "__le") {
+            Value::Nil => self.get_metafield(b, "__le"),
+            handler => handler,
+        };
+        if handler.is_nil() {
+            let (t1, t2) = (self.obj_type_name(a), self.obj_type_name(b));
+            return Err(LuaError::Runtime(if t1 == t2 {
+                format!("attempt to compare two {} values", t1)
+            } else {
+                format!("attempt to compare {} with {}", t1, t2)
+            }));
+        }
+        let mut results = self.call_value(&handler, MultiValue::from([a.clone(), b.clone()]))?;
+        Ok(results.pop_front().unwrap_or(Value::Nil).is_truthy())
+    }
+
+    /// brief: the concatenation of `values` from the right, strings and numbers
+    /// are joined, other pairs go through the `__concat` meta method
+    pub fn concat(&mut self, mut values: Vec<Value>) -> LuaResult<Value> {
+        fn piece(val: &Value) -> Option<Vec<u8>> {
+            match val {
+                Value::String(s) => Some(s.as_bytes().to_vec()),
+                Value::Integer(i) => Some(i.to_string().into_bytes()),
+                Value::Number(n) => Some(fmt_number(*n).into_bytes()),
+                _ => None,
+            }
+        }
+        let Some(mut top) = values.pop() else {
+            return Ok(Value::from(""));
+        };
+        while let Some(left) = values.pop() {
+            top = match (piece(&left), piece(&top)) {
+                (Some(mut bytes), Some(right)) => {
+                    bytes.extend_from_slice(&right);
+                    Value::String(LuaString::new(&bytes))
+                }
+                (l, _) => {
+                    let handler = match self.get_metafield(&left, "__concat") {
+                        Value::Nil => self.get_metafield(&top, "__concat"),
+                        handler => handler,
+                    };
+                    if handler.is_nil() {
+                        let culprit = if l.is_none() { &left } else { &top };
+                        return Err(LuaError::Runtime(format!(
+                            "attempt to concatenate a {} value",
+                            culprit.type_name()
+                        )));
+                    }
+                    let mut results = self.call_value(&handler, MultiValue::from([left, top]))?;
+                    results.pop_front().unwrap_or(Value::Nil)
+                }
+            };
+        }
+        Ok(top)
+    }
+
+    /// brief: call a function in protected mode, a lua error inside is returned
+    /// a value other than a function is called through its `__call`
+    pub fn call_value(&mut self, func: &Value, args: MultiValue) -> LuaResult<MultiValue> {
+        let (func, args) = match func {
+            Value::Function(_) => (func.clone(), args),
+            obj => match self.get_metafield(obj, "__call") {
+                handler @ Value::Function(_) => {
+                    let mut args = args;
+                    args.push_front(obj.clone());
+                    (handler, args)
+                }
+                _ => {
+                    return Err(LuaError::Runtime(format!(
+                        "attempt to call a {} value",
+                        obj.type_name()
+                    )))
+                }
+            },
+        };
+        let func = &func;
+        if self.stack_check(args.len() + 1).is_err() {
+            return Err(LuaError::Runtime("stack overflow".to_string()));
+        }
+        let func_index = self.stack_top_index;
+        self.push_value(func);
+        for arg in args.iter() {
+            self.push_value(arg);
+        }
+        Routine::pcall(self, func_index, LUA_MUL_RET)?;
+
+        let results = (func_index..self.stack_top_index)
+            .map(|slot| {
+                let elem = self.stack.0[slot];
+                self.elem_to_value(&elem)
+            })
+            .collect();
+        self.move_top_to(func_index);
+        Ok(results)
+    }
+
+    /// brief: the function of a chunk, `mode` holds the kinds accepted,
+    /// "b" binary and "t" text. this machine has no compiler and no
+    /// undump, a text chunk is refused with an error and a binary one after
+    /// its header is checked
+    pub fn load(&mut self, chunk: &[u8], chunkname: &str, mode: &str) -> LuaResult<Function> {
+        let (binary, kind) = match chunk.first() {
+            Some(&LUA_SIGNATURE_FIRST) => (true, "binary"),
+            _ => (false, "text"),
+        };
+        if !mode.contains(if binary { 'b' } else { 't' }) {
+            return Err(LuaError::Runtime(format!(
+                "attempt to load a {} chunk (mode is '{}')",
+                kind, mode
+            )));
+        }
+        if binary {
+            check_header(chunk, chunkname)?;
+        }
+        Err(LuaError::Runtime(format!(
+            "{}: cannot load a {} chunk, this machine has no {}",
+            chunk_id(chunkname),
+            kind,
+            if binary { "undump" } else { "compiler" }
+        )))
+    }
+
+    /// brief: obj:name(args...)
+    pub fn call_method(&mut self, obj: &Value, name: &str, args: MultiValue) -> LuaResult<MultiValue> {
+        let method = self.index(obj, &Value::from(name))?;
+        let mut args = args;
+        args.push_front(obj.clone());
+        self.call_value(&method, args)
+    }
+
+    /// brief: the arguments of the running rust function
+    pub fn get_args<A: FromLuaMulti>(&mut self) -> LuaResult<A> {
+        let args = (1..=self.get_top() as isize)
+            .map(|idx| self.get_value(idx))
+            .collect();
+        A::from_lua_multi(args, self)
+    }
+
+    /// brief: push the results of a rust function, return the number of them
+    pub fn push_results<R: IntoLuaMulti>(&mut self, results: R) -> LuaResult<usize> {
+        let results = results.into_lua_multi(self)?;
+        if self.stack_check(results.len()).is_err() {
+            return Err(LuaError::Runtime("stack overflow".to_string()));
+        }
+        for val in results.iter() {
+            self.push_value(val);
+        }
+        Ok(results.len())
+    }
+
+    /// brief: the table at a stack index, none for any other value
+    pub fn to_table(&mut self, idx: isize) -> Option<Table> {
+        match self.get_value(idx) {
+            Value::Table(table) => Some(table),
+            _ => None,
+        }
+    }
+
+    fn table_at(&mut self, idx: isize) -> LuaResult<Table> {
+        let val = self.get_value(idx);
+        match val {
+            Value::Table(table) => Ok(table),
+            _ => Err(LuaError::Runtime(format!("table expected, got {}", val.type_name()))),
+        }
+    }
+
+    /// brief: push t[k], t at `idx` and k at the top, which is replaced
+    pub fn get_table(&mut self, idx: isize) -> LuaResult<()> {
+        let obj = self.get_value(idx);
+        let key = self.pop_value();
+        let val = self.index(&obj, &key)?;
+        self.push_value(&val);
+        Ok(())
+    }
+
+    /// brief: t[k] = v, t at `idx`, k below v at the top, both are popped
+    pub fn set_table(&mut self, idx: isize) -> LuaResult<()> {
+        let obj = self.get_value(idx);
+        let val = self.pop_value();
+        let key = self.pop_value();
+        self.set_index(&obj, key, val)
+    }
+
+    /// brief: push t[name], t at `idx`
+    pub fn get_field(&mut self, idx: isize, name: &str) -> LuaResult<()> {
+        let obj = self.get_value(idx);
+        let val = self.index(&obj, &Value::from(name))?;
+        self.push_value(&val);
+        Ok(())
+    }
+
+    /// brief: t[name] = v, t at `idx`, v at the top is popped
+    pub fn set_field(&mut self, idx: isize, name: &str) -> LuaResult<()> {
+        let obj = self.get_value(idx);
+        let val = self.pop_value();
+        self.set_index(&obj, Value::from(name), val)
+    }
+
+    /// brief: get_table without meta methods
+    pub fn raw_get_at(&mut self, idx: isize) -> LuaResult<()> {
+        let table = self.table_at(idx)?;
+        let key = self.pop_value();
+        let val = self.raw_get(&table, &key);
+        self.push_value(&val);
+        Ok(())
+    }
+
+    /// brief: set_table without meta methods
+    pub fn raw_set_at(&mut self, idx: isize) -> LuaResult<()> {
+        let table = self.table_at(idx)?;
+        let val = self.pop_value();
+        let key = self.pop_value();
+        self.raw_set(&table, key, val)
+    }
+
+    /// brief: push t[n] without meta methods, t at `idx`
+    pub fn raw_geti(&mut self, idx: isize, n: INT) -> LuaResult<()> {
+        let table = self.table_at(idx)?;
+        let val = self.raw_get(&table, &Value::Integer(n));
+        self.push_value(&val);
+        Ok(())
+    }
+
+    /// brief: t[n] = v without meta methods, t at `idx`, v at the top is popped
+    pub fn raw_seti(&mut self, idx: isize, n: INT) -> LuaResult<()> {
+        let table = self.table_at(idx)?;
+        let val = self.pop_value();
+        self.raw_set(&table, Value::Integer(n), val)
+    }
+
+    /// brief: pop a key and push the next key and value of the table at `idx`
+    /// nothing is pushed at the end of the traversal
+    pub fn next(&mut self, idx: isize) -> LuaResult<bool> {
+        let table = self.table_at(idx)?;
+        let key = self.pop_value();
+        match self.raw_next(&table, &key)? {
+            Some((key, val)) => {
+                self.push_value(&key);
+                self.push_value(&val);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn pop_integer(&mut self) -> INT {
+        match self.pop_stack().as_integer() {
+            Some(val) => val,
+            None => panic!("FATAL ERROR: MISMATCH TYPE"),
+        }
+    }
+
+    pub fn pop_float(&mut self) -> FLT {
+        match self.pop_stack().as_float() {
+            Some(val) => val,
+            None => panic!("FATAL ERROR: MISMATCH TYPE"),
+        }
+    }
+
+    pub fn pop_bool(&mut self) -> bool {
+        match self.pop_stack().as_bool() {
+            Some(val) => val,
+            None => panic!("FATAL ERROR: MISMATCH TYPE"),
         }
     }
 
     pub fn pop_nil(&mut self) {
-        let elem = self.pop_stack();
-        if elem.get_type() != TObject::TNil as u8 {
+        if !self.pop_stack().is_nil() {
             panic!("FATAL ERROR: MISMATCH TYPE");
         }
     }
 
     pub fn pop_ud(&mut self) -> *const () {
-        let elem = self.pop_stack();
-        if elem.get_type() != TObject::TLightUserData as u8 {
-            panic!("FATAL ERROR: MISMATCH TYPE");
-        } else {
-            unsafe { elem.get_value().val_ud }.into_inner().unwrap()
+        match self.pop_stack().as_ud() {
+            Some(val) => val,
+            None => panic!("FATAL ERROR: MISMATCH TYPE"),
         }
     }
 }
 
-impl Drop for LuaState {
-    fn drop(&mut self) {
-        self.stack_clear();
-        self.callvec_clear();
+#[cfg(test)]
+mod test {
+    use core::ptr::null_mut;
+
+    use std::cell::Cell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    use crate::common::lua::LuaConfig;
+    use crate::common::obj::objud::UserData;
+    use crate::common::obj::objvalue::{Value, ValueKey};
+    use crate::common::state::statedef::LuaState;
+
+    fn new_state() -> LuaState {
+        LuaState::mainthread_new(null_mut(), LuaConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn push_pop_roundtrip() {
+        let mut state = new_state();
+        state.push_integer(3);
+        state.push_float(1.5);
+        state.push_bool(true);
+        state.push_nil();
+        state.push_ud(None);
+        assert!(state.pop_ud().is_null());
+        state.pop_nil();
+        assert!(state.pop_bool());
+        assert_eq!(state.pop_float(), 1.5);
+        assert_eq!(state.pop_integer(), 3);
+        assert_eq!(state.get_top_index(), 0);
     }
-}
 
-// mod test{
-//     use crate::common::state::statedef::LuaState;
-
-    
-    
-    
-
-//     #[test]
-    
-//     fn test1(){
-//         let state=LuaState::mainthread_new(null_mut());
-//         state.push_integer(3);
-//         dbg!("starting to pop");
-//         let x=state.pop_integer();
-//         println!("{}",x);
-//     }
-// }
+    #[test]
+    fn stack_grows_past_initial_size() {
+        let mut state = new_state();
+        let size = state.get_config().stack_size;
+        for i in 0..size * 4 {
+            state.stack_check(1).unwrap();
+            state.push_integer(i as i64);
+        }
+        for i in (0..size * 4).rev() {
+            assert_eq!(state.pop_integer(), i as i64);
+        }
+    }
+
+    #[test]
+    fn stack_stops_at_max_stack() {
+        let config = LuaConfig {
+            max_stack: 64,
+            ..Default::default()
+        };
+        let mut state = LuaState::mainthread_new(null_mut(), config).unwrap();
+        assert!(state.stack_check(100).is_err());
+    }
+
+    #[test]
+    fn foreign_rfunc_index_is_checked() {
+        fn answer(_state: &mut LuaState) -> usize {
+            0
+        }
+        let mut state = new_state();
+        state.push_rfunc(&answer);
+        let elem = state.pop_stack();
+        assert!(state.get_rfunc(&elem).is_some());
+
+        // a value from one state does not resolve in another
+        let other = new_state();
+        assert!(other.get_rfunc(&elem).is_none());
+    }
+
+    #[test]
+    fn value_roundtrip() {
+        let mut state = new_state();
+        let values = [
+            Value::Nil,
+            Value::Boolean(false),
+            Value::Integer(-7),
+            Value::Number(0.5),
+            Value::from("a\"b"),
+        ];
+        for val in values.iter() {
+            state.push_value(val);
+        }
+        for val in values.iter().rev() {
+            assert_eq!(&state.pop_value(), val);
+        }
+
+        state.new_table();
+        let table = state.get_value(-1);
+        state.push_value(&table);
+        assert_eq!(state.pop_value(), table);
+        assert_eq!(table.type_name(), "table");
+    }
+
+    #[test]
+    fn value_equality_and_hash_follow_lua_keys() {
+        assert_eq!(Value::Integer(1), Value::Number(1.0));
+        assert_ne!(Value::Integer(1), Value::Number(1.5));
+        assert_ne!(Value::Number(f64::NAN), Value::Number(f64::NAN));
+        assert_ne!(Value::from("1"), Value::Integer(1));
+
+        // a set of the host keys values as a lua table does
+        let key = |value: Value| ValueKey::new(value).unwrap();
+        let mut keys = HashSet::new();
+        keys.insert(key(Value::Integer(2)));
+        assert!(keys.contains(&key(Value::Number(2.0))));
+        assert!(!keys.contains(&key(Value::from("2"))));
+        assert_eq!(key(Value::Number(2.0)).into_value(), Value::Integer(2));
+        assert!(matches!(key(Value::Number(0.5)).as_value(), Value::Number(n) if *n == 0.5));
+        assert!(ValueKey::new(Value::Nil).is_none());
+        assert!(ValueKey::new(Value::Number(f64::NAN)).is_none());
+
+        let mut state = new_state();
+        state.new_table();
+        state.new_table();
+        let second = state.pop_value();
+        let first = state.pop_value();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn value_debug_is_readable() {
+        assert_eq!(format!("{:?}", Value::Nil), "nil");
+        assert_eq!(format!("{:?}", Value::Number(1.0)), "1.0");
+        assert_eq!(format!("{:?}", Value::Integer(1)), "1");
+        assert_eq!(format!("{:?}", Value::from("a\n")), "\"a\\n\"");
+    }
+
+    #[test]
+    fn handles_keep_tables_alive() {
+        let mut state = new_state();
+        state.new_table();
+        let table = state.pop_value();
+        state.new_table();
+        let _ = state.pop_stack();
+
+        assert!(state.collect_garbage() > 0);
+        state.push_value(&table);
+        assert_eq!(state.pop_value(), table);
+
+        drop(table);
+        assert!(state.collect_garbage() > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "another state")]
+    fn foreign_handle_is_rejected() {
+        let mut state = new_state();
+        state.new_table();
+        let table = state.pop_value();
+        let mut other = new_state();
+        other.push_value(&table);
+    }
+
+    #[test]
+    fn comparisons_follow_lua() {
+        let mut state = new_state();
+        assert!(state.less_than(&Value::Integer(1), &Value::Number(1.5)).unwrap());
+        assert!(!state.less_than(&Value::Number(f64::NAN), &Value::Integer(1)).unwrap());
+        assert!(state.less_than(&Value::Integer(i64::MAX), &Value::Number(9.3e18)).unwrap());
+        assert!(state.less_than(&Value::Number(-9.3e18), &Value::Integer(i64::MIN)).unwrap());
+        assert!(!state.less_than(&Value::Integer(i64::MAX), &Value::Number(9.2e18)).unwrap());
+        assert!(state.less_than(&Value::from("a"), &Value::from("b")).unwrap());
+        assert_eq!(
+            state.less_than(&Value::Integer(1), &Value::from("x")).unwrap_err().to_string(),
+            "attempt to compare number with string"
+        );
+        let (a, b) = (state.create_table_value(0, 0).unwrap(), state.create_table_value(0, 0).unwrap());
+        let (a, b) = (Value::Table(a), Value::Table(b));
+        assert_eq!(
+            state.less_than(&a, &b).unwrap_err().to_string(),
+            "attempt to compare two table values"
+        );
+        assert!(state.equals(&Value::Integer(1), &Value::Number(1.0)).unwrap());
+        assert!(state.equals(&a, &a).unwrap());
+        assert!(!state.equals(&a, &b).unwrap());
+    }
+
+    #[test]
+    fn close_drops_every_object() {
+        struct Flag(Rc<Cell<bool>>);
+
+        impl UserData for Flag {}
+
+        impl Drop for Flag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let mut state = new_state();
+        let dropped = Rc::new(Cell::new(false));
+        let ud = state.create_userdata(Flag(dropped.clone())).unwrap();
+        state.collect_garbage();
+        assert!(!dropped.get());
+        state.close();
+        assert!(dropped.get());
+        assert_eq!(state.get_heap().get_bytes(), 0);
+        drop(ud);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::common::lua::{LuaError, LuaResult};
+use crate::common::obj::objtype::{FLT, INT};
+
+pub const LUA_SIGNATURE: &[u8] = b"\x1bLua";
+pub const LUAC_VERSION: u8 = 0x54; // major * 16 + minor
+pub const LUAC_FORMAT: u8 = 0; // the official format
+pub const LUAC_DATA: &[u8] = b"\x19\x93\r\n\x1a\n"; // catches the conversions of text mode
+pub const LUAC_INT: INT = 0x5678; // the byte order of integers
+pub const LUAC_NUM: FLT = 370.5; // the layout of floats
+
+const INSTRUCTION_SIZE: u8 = 4; // the size of an instruction of lua 5.4
+
+/// brief: the size of the header of a binary chunk
+pub const LUAC_HEADER_SIZE: usize =
+    LUA_SIGNATURE.len() + 2 + LUAC_DATA.len() + 3 + std::mem::size_of::<INT>() + std::mem::size_of::<FLT>();
+
+/// brief: the name of a binary chunk in messages, as lundump names it
+fn binary_name(chunkname: &str) -> &str {
+    match chunkname.as_bytes().first() {
+        Some(b'@' | b'=') => &chunkname[1..],
+        Some(&b) if b == LUA_SIGNATURE[0] => "binary string",
+        _ => chunkname,
+    }
+}
+
+/// brief: check the header of a binary chunk against the one of this
+/// platform, the size of the header is given back
+pub fn check_header(chunk: &[u8], chunkname: &str) -> LuaResult<usize> {
+    let error =
+        |why: &str| LuaError::Runtime(format!("{}: bad binary format ({})", binary_name(chunkname), why));
+    let mut pos = 0;
+    let mut take = |n: usize| -> LuaResult<&[u8]> {
+        let bytes = chunk.get(pos..pos + n).ok_or_else(|| error("truncated chunk"))?;
+        pos += n;
+        Ok(bytes)
+    };
+    // the first byte was checked by the caller
+    if take(LUA_SIGNATURE.len())? != LUA_SIGNATURE {
+        return Err(error("not a binary chunk"));
+    }
+    if take(1)?[0] != LUAC_VERSION {
+        return Err(error("version mismatch"));
+    }
+    if take(1)?[0] != LUAC_FORMAT {
+        return Err(error("format mismatch"));
+    }
+    if take(LUAC_DATA.len())? != LUAC_DATA {
+        return Err(error("corrupted chunk"));
+    }
+    let sizes = [
+        (INSTRUCTION_SIZE, "Instruction"),
+        (std::mem::size_of::<INT>() as u8, "lua_Integer"),
+        (std::mem::size_of::<FLT>() as u8, "lua_Number"),
+    ];
+    for (size, name) in sizes {
+        if take(1)?[0] != size {
+            return Err(error(&format!("{} size mismatch", name)));
+        }
+    }
+    let int = take(std::mem::size_of::<INT>())?;
+    if INT::from_ne_bytes(int.try_into().unwrap()) != LUAC_INT {
+        return Err(error("integer format mismatch"));
+    }
+    let num = take(std::mem::size_of::<FLT>())?;
+    if FLT::from_ne_bytes(num.try_into().unwrap()) != LUAC_NUM {
+        return Err(error("float format mismatch"));
+    }
+    Ok(pos)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::machine::machdef::Machine;
+
+    use super::*;
+
+    /// brief: the header luac writes on this platform
+    fn header() -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(LUA_SIGNATURE);
+        out.push(LUAC_VERSION);
+        out.push(LUAC_FORMAT);
+        out.extend_from_slice(LUAC_DATA);
+        out.push(INSTRUCTION_SIZE);
+        out.push(std::mem::size_of::<INT>() as u8);
+        out.push(std::mem::size_of::<FLT>() as u8);
+        out.extend_from_slice(&LUAC_INT.to_ne_bytes());
+        out.extend_from_slice(&LUAC_NUM.to_ne_bytes());
+        out
+    }
+
+    fn header_error(chunk: &[u8]) -> String {
+        check_header(chunk, "=probe").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn headers_are_checked() {
+        let good = header();
+        assert_eq!(good.len(), LUAC_HEADER_SIZE);
+        assert_eq!(&good[..6], b"\x1bLua\x54\x00");
+        assert_eq!(check_header(&good, "=probe").unwrap(), LUAC_HEADER_SIZE);
+
+        let cases: &[(usize, u8, &str)] = &[
+            (1, b'X', "not a binary chunk"),
+            (4, 0x53, "version mismatch"),
+            (5, 1, "format mismatch"),
+            (8, b'\n', "corrupted chunk"),
+            (12, 8, "Instruction size mismatch"),
+            (13, 4, "lua_Integer size mismatch"),
+            (14, 4, "lua_Number size mismatch"),
+            (15, 0, "integer format mismatch"),
+            (LUAC_HEADER_SIZE - 1, 0, "float format mismatch"),
+        ];
+        for (pos, byte, why) in cases {
+            let mut bad = good.clone();
+            bad[*pos] = *byte;
+            assert_eq!(header_error(&bad), format!("probe: bad binary format ({})", why));
+        }
+        assert_eq!(
+            header_error(&good[..10]),
+            "probe: bad binary format (truncated chunk)"
+        );
+        let err = check_header(b"\x1bLuaX", "\x1bLuaX").unwrap_err().to_string();
+        assert_eq!(err, "binary string: bad binary format (version mismatch)");
+    }
+
+    #[test]
+    fn binary_chunks_are_checked() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let mut chunk = header();
+        chunk.push(1);
+        let err = state.load(&chunk, "=probe", "t").unwrap_err().to_string();
+        assert_eq!(err, "attempt to load a binary chunk (mode is 't')");
+        let err = state.load(b"return 1", "=probe", "b").unwrap_err().to_string();
+        assert_eq!(err, "attempt to load a text chunk (mode is 'b')");
+        let err = state.load(&chunk[..20], "=probe", "bt").unwrap_err().to_string();
+        assert_eq!(err, "probe: bad binary format (truncated chunk)");
+        let err = state.load(&chunk, "=probe", "bt").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "probe: cannot load a binary chunk, this machine has no undump"
+        );
+    }
+}
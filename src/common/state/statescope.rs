@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use crate::common::lua::LuaResult;
+use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti};
+use crate::common::obj::objtype::{GcRef, RFUNC};
+use crate::common::obj::objud::{UserData, UserDataCell};
+use crate::common::obj::objvalue::{AnyUserData, FuncRef, Function};
+
+use super::statedef::LuaState;
+
+/// brief: the callbacks and the userdata that may borrow data living for 'env
+/// they are destroyed when the scope ends, see LuaState::scope
+/// a later call raises "callback has been destroyed", a later access to a
+/// userdata raises "userdata has been destroyed"
+pub struct Scope<'env> {
+    heap: u32,
+    closures: RefCell<Vec<GcRef>>,
+    userdata: RefCell<Vec<GcRef>>,
+    _env: PhantomData<&'env mut &'env ()>, // invariant, 'env cannot shrink
+}
+
+impl<'env> Scope<'env> {
+    pub(crate) fn new(heap: u32) -> Self {
+        Self {
+            heap,
+            closures: RefCell::new(Vec::new()),
+            userdata: RefCell::new(Vec::new()),
+            _env: PhantomData,
+        }
+    }
+
+    fn check_state(&self, state: &LuaState) {
+        if state.get_heap().get_id() != self.heap {
+            panic!("scope belongs to another state");
+        }
+    }
+
+    /// brief: a function of a rust closure borrowing data of the caller
+    pub fn create_function<A, R, F>(&self, state: &mut LuaState, function: F) -> LuaResult<Function>
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaState, A) -> LuaResult<R> + 'env,
+    {
+        self.check_state(state);
+        let rfunc: Rc<dyn Fn(&mut LuaState) -> usize + 'env> = Rc::new(move |state: &mut LuaState| {
+            let results = state.get_args().and_then(|args| function(state, args));
+            match results.and_then(|results| state.push_results(results)) {
+                Ok(nresults) => nresults,
+                Err(err) => state.error(err),
+            }
+        });
+        // SAFETY: the closure is replaced in the heap when the scope ends,
+        // before 'env ends. a call holds its clone only while it runs
+        let rfunc = unsafe { std::mem::transmute::<Rc<dyn Fn(&mut LuaState) -> usize + 'env>, Rc<RFUNC>>(rfunc) };
+        let function = state.create_closure(rfunc)?;
+        if let FuncRef::Closure(closure) = &function.0 {
+            self.closures.borrow_mut().push(closure.gc);
+        }
+        Ok(function)
+    }
+
+    /// brief: a userdata owning `data`, the value is dropped when the scope ends
+    pub fn create_userdata<T: UserData>(&self, state: &mut LuaState, data: T) -> LuaResult<AnyUserData> {
+        self.add_userdata(state, UserDataCell::Owned(RefCell::new(data)))
+    }
+
+    /// brief: a userdata borrowing `data`, its mutable methods raise an error
+    pub fn create_userdata_ref<T: UserData>(&self, state: &mut LuaState, data: &'env T) -> LuaResult<AnyUserData> {
+        let cell = UserDataCell::Borrowed {
+            ptr: NonNull::from(data),
+            flag: RefCell::new(()),
+            mutable: false,
+        };
+        self.add_userdata(state, cell)
+    }
+
+    /// brief: a userdata borrowing `data` mutably
+    pub fn create_userdata_ref_mut<T: UserData>(
+        &self,
+        state: &mut LuaState,
+        data: &'env mut T,
+    ) -> LuaResult<AnyUserData> {
+        let cell = UserDataCell::Borrowed {
+            ptr: NonNull::from(data),
+            flag: RefCell::new(()),
+            mutable: true,
+        };
+        self.add_userdata(state, cell)
+    }
+
+    fn add_userdata<T: UserData>(&self, state: &mut LuaState, cell: UserDataCell<T>) -> LuaResult<AnyUserData> {
+        self.check_state(state);
+        let ud = state.create_userdata_cell(cell)?;
+        self.userdata.borrow_mut().push(ud.0.gc);
+        Ok(ud)
+    }
+
+    /// brief: destroy what the scope made, the objects stay in the heap
+    /// until they are collected
+    pub(crate) fn destroy(&self, state: &mut LuaState) {
+        state.destroy_objects(&self.closures.borrow(), &self.userdata.borrow());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::ptr::null_mut;
+    use std::cell::Cell;
+
+    use crate::common::lua::{LuaConfig, LuaError};
+    use crate::common::obj::objconv::MultiValue;
+    use crate::common::obj::objud::{UserData, UserDataRegistry};
+    use crate::common::obj::objvalue::Value;
+    use crate::common::state::statedef::LuaState;
+
+    fn new_state() -> LuaState {
+        LuaState::mainthread_new(null_mut(), LuaConfig::default()).unwrap()
+    }
+
+    struct Account {
+        balance: i64,
+    }
+
+    impl UserData for Account {
+        fn add_fields(fields: &mut UserDataRegistry<Self>) {
+            fields.add_field_method_get("balance", |_, this| Ok(this.balance));
+        }
+
+        fn add_methods(methods: &mut UserDataRegistry<Self>) {
+            methods.add_method_mut("deposit", |_, this, amount: i64| {
+                this.balance += amount;
+                Ok(())
+            });
+        }
+    }
+
+    #[test]
+    fn callback_borrows_local_data() {
+        let mut state = new_state();
+        let calls = Cell::new(0);
+        let escaped = state.scope(|state, scope| {
+            let count = scope.create_function(state, |_, step: i64| {
+                calls.set(calls.get() + step);
+                Ok(calls.get())
+            }).unwrap();
+            let count = Value::Function(count);
+            let results = state.call_value(&count, MultiValue::from([Value::Integer(2)]));
+            assert_eq!(results.unwrap(), MultiValue::from([Value::Integer(2)]));
+            count
+        });
+        assert_eq!(calls.get(), 2);
+
+        // the function outlived the scope, calling it is an error
+        let err = state.call_value(&escaped, MultiValue::new()).unwrap_err();
+        assert_eq!(err, LuaError::CallbackDestructed);
+        assert_eq!(err.to_string(), "callback has been destroyed");
+    }
+
+    #[test]
+    fn userdata_borrows_local_data() {
+        let mut state = new_state();
+        let mut account = Account { balance: 10 };
+        let escaped = state.scope(|state, scope| {
+            let ud = scope.create_userdata_ref_mut(state, &mut account).unwrap();
+            let ud = Value::UserData(ud);
+            state
+                .call_method(&ud, "deposit", MultiValue::from([Value::Integer(5)]))
+                .unwrap();
+            assert_eq!(state.index(&ud, &Value::from("balance")).unwrap(), Value::Integer(15));
+            ud
+        });
+        assert_eq!(account.balance, 15);
+
+        let err = state.index(&escaped, &Value::from("balance")).unwrap_err();
+        assert_eq!(err, LuaError::UserDataDestructed);
+    }
+
+    #[test]
+    fn shared_borrow_rejects_mutation() {
+        let mut state = new_state();
+        let account = Account { balance: 1 };
+        state.scope(|state, scope| {
+            let ud = Value::UserData(scope.create_userdata_ref(state, &account).unwrap());
+            assert_eq!(state.index(&ud, &Value::from("balance")).unwrap(), Value::Integer(1));
+            let err = state
+                .call_method(&ud, "deposit", MultiValue::from([Value::Integer(1)]))
+                .unwrap_err();
+            assert_eq!(err, LuaError::UserDataBorrowMutError);
+        });
+        assert_eq!(account.balance, 1);
+    }
+
+    #[test]
+    fn scope_destroys_on_panic() {
+        let mut state = new_state();
+        let mut escaped = None;
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            state.scope(|state, scope| {
+                let f = scope.create_function(state, |_, ()| Ok(())).unwrap();
+                escaped = Some(Value::Function(f));
+                panic!("leaving the scope");
+            })
+        }));
+        assert!(res.is_err());
+        let err = state.call_value(&escaped.unwrap(), MultiValue::new()).unwrap_err();
+        assert_eq!(err, LuaError::CallbackDestructed);
+    }
+}
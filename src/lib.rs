@@ -0,0 +1,9 @@
+// the derive macros name this crate by its path, so do the tests inside it
+extern crate self as naive_lua2;
+
+pub mod capi;
+pub mod common;
+pub mod machine;
+pub mod stdlib;
+
+pub use naive_lua2_derive::{FromLua, IntoLua};
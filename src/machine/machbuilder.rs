@@ -0,0 +1,258 @@
+use crate::common::lua::{ConfigError, GcParams, LuaConfig, MemoryQuota, StdLib};
+
+use super::machdef::Machine;
+
+/// brief: collect the resource budget of a machine, checked once in `build`
+///
+/// ```ignore
+/// let machine = Machine::builder()
+///     .stack_size(64)
+///     .max_stack(4096)
+///     .max_calls(64)
+///     .libs(StdLib::BASE | StdLib::STRING)
+///     .memory_limit(1 << 20)
+///     .build()?;
+/// ```
+#[derive(Debug, Default)]
+pub struct MachineBuilder {
+    config: LuaConfig,
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: LuaConfig::default(),
+        }
+    }
+
+    /// brief: the free slots every rust function is guaranteed on entry
+    pub fn min_stack(mut self, slots: usize) -> Self {
+        self.config.min_stack = slots;
+        self
+    }
+
+    /// brief: the slots the stack is allocated with
+    pub fn stack_size(mut self, slots: usize) -> Self {
+        self.config.stack_size = slots;
+        self
+    }
+
+    /// brief: the slots the stack may grow to
+    pub fn max_stack(mut self, slots: usize) -> Self {
+        self.config.max_stack = slots;
+        self
+    }
+
+    /// brief: the depth of the call info vector
+    pub fn max_calls(mut self, depth: usize) -> Self {
+        self.config.max_calls = depth;
+        self
+    }
+
+    /// brief: the call infos allocated on start
+    pub fn ci_len(mut self, len: usize) -> Self {
+        self.config.ci_len = len;
+        self
+    }
+
+    /// brief: the depth of nested entries from rust into the machine
+    pub fn max_rust_calls(mut self, depth: usize) -> Self {
+        self.config.max_rcalls = depth;
+        self
+    }
+
+    /// brief: the standard libraries opened on start
+    pub fn libs(mut self, libs: StdLib) -> Self {
+        self.config.libs = libs;
+        self
+    }
+
+    /// brief: the bytes the machine may hold at most
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.config.memory_limit = Some(bytes);
+        self
+    }
+
+    /// brief: the quota asked before every managed allocation, see MemoryQuota
+    pub fn memory_quota(mut self, quota: impl MemoryQuota + 'static) -> Self {
+        self.config.memory_quota = Box::new(quota);
+        self
+    }
+
+    /// brief: the collector waits for memory to grow by `pause` percent
+    pub fn gc_pause(mut self, pause: u32) -> Self {
+        self.config.gc.pause = pause;
+        self
+    }
+
+    /// brief: the collector speed relative to allocation, in percent
+    pub fn gc_step_mul(mut self, step_mul: u32) -> Self {
+        self.config.gc.step_mul = step_mul;
+        self
+    }
+
+    /// brief: the log2 of the bytes allocated between collector steps
+    pub fn gc_step_size(mut self, step_size: u32) -> Self {
+        self.config.gc.step_size = step_size;
+        self
+    }
+
+    /// brief: all the parameters of the collector at once
+    pub fn gc(mut self, params: GcParams) -> Self {
+        self.config.gc = params;
+        self
+    }
+
+    /// brief: validate the budget and start the machine, a memory budget
+    /// too small for the registry, the globals and the libraries is an error
+    pub fn build(self) -> Result<Machine, ConfigError> {
+        self.config.validate()?;
+        Machine::with_config(self.config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::lua::{ConfigError, LuaConfig, LuaError, MemoryQuota, StdLib};
+    use crate::common::obj::objvalue::{Function, Value};
+    use crate::machine::machdef::Machine;
+
+    /// brief: a quota refusing everything
+    struct Refuse;
+
+    impl MemoryQuota for Refuse {
+        fn grant(&self, _in_use: usize, _request: usize) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn invalid_budget_is_not_built() {
+        let err = Machine::builder().stack_size(64).max_stack(32).build().err();
+        assert_eq!(err, Some(ConfigError::StackSizeTooLarge));
+        let err = Machine::builder().max_calls(0).build().err();
+        assert_eq!(err, Some(ConfigError::MaxCallsTooSmall));
+        let initial = LuaConfig::default().initial_bytes();
+        let err = Machine::builder().memory_limit(initial - 1).build().err();
+        assert_eq!(err, Some(ConfigError::MemoryLimitTooSmall));
+    }
+
+    #[test]
+    fn budget_must_hold_the_libraries() {
+        // the stack fits, the registry and the libraries do not
+        let initial = LuaConfig::default().initial_bytes();
+        let err = Machine::builder().memory_limit(initial).build().err();
+        assert_eq!(err, Some(ConfigError::MemoryLimitTooSmall));
+        let err = Machine::builder().memory_quota(Refuse).build().err();
+        assert_eq!(err, Some(ConfigError::MemoryLimitTooSmall));
+
+        let opened = Machine::new().get_state().get_total_bytes();
+        assert!(Machine::builder().memory_limit(opened).build().is_ok());
+    }
+
+    #[test]
+    fn host_allocations_report_exhausted_memory() {
+        let initial = LuaConfig::default().initial_bytes();
+        let mut machine = Machine::builder()
+            .libs(StdLib::NONE)
+            .memory_limit(initial + 4096)
+            .build()
+            .unwrap();
+        let state = machine.get_state();
+        let mut held = Vec::new();
+        let err = loop {
+            match state.create_table_value(4, 0) {
+                Ok(table) => held.push(table),
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, LuaError::Memory(_)));
+        let mut functions = Vec::new();
+        let err = loop {
+            match state.create_function(|_, ()| Ok(())) {
+                Ok(function) => functions.push(function),
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, LuaError::Memory(_)));
+
+        // the memory comes back once the host lets the tables go
+        drop((held, functions));
+        state.collect_garbage();
+        assert!(state.create_table_value(4, 0).is_ok());
+    }
+
+    #[test]
+    fn built_machine_honours_its_budget() {
+        let mut machine = Machine::builder()
+            .stack_size(64)
+            .max_stack(256)
+            .max_calls(8)
+            .ci_len(4)
+            .libs(StdLib::BASE)
+            .gc_pause(150)
+            .build()
+            .unwrap();
+        let state = machine.get_state();
+        assert_eq!(state.get_config().max_stack, 256);
+        assert_eq!(state.get_gc_params_mut().pause, 150);
+
+        // the stack grows up to the max stack, never past it
+        assert!(state.stack_check(100).is_ok());
+        assert!(state.stack_check(300).is_err());
+
+        // only the libraries asked for are opened
+        assert!(matches!(
+            state.get_global::<Value>("print"),
+            Ok(Value::Function(_))
+        ));
+        assert_eq!(state.get_global::<Value>("string"), Ok(Value::Nil));
+
+        // calls nest up to the call limit, the deepest one reports the overflow
+        let deepest = state.create_function(|state, depth: i64| {
+            let deepest: Function = state.get_global("deepest")?;
+            match deepest.call::<_, i64>(state, depth + 1) {
+                Err(LuaError::Runtime(message)) if message == "stack overflow (too many calls)" => Ok(depth),
+                res => res,
+            }
+        }).unwrap();
+        state.set_global("deepest", deepest.clone()).unwrap();
+        let depth = deepest.call::<_, i64>(state, 1).unwrap();
+        assert!((1..8).contains(&depth));
+        assert_eq!(state.get_top_index(), 0);
+    }
+
+    /// brief: the heap bytes the automatic collection first runs at, with
+    /// some live bytes in a global and garbage allocated one table at a time
+    fn collected_at(step_mul: u32, step_size: u32) -> usize {
+        let mut machine = Machine::builder()
+            .libs(StdLib::NONE)
+            .gc_step_mul(step_mul)
+            .gc_step_size(step_size)
+            .build()
+            .unwrap();
+        let state = machine.get_state();
+        let live = state.create_table_value(0, 0).unwrap();
+        for i in 1..300 {
+            live.set(state, i, format!("{:032}", i)).unwrap();
+        }
+        state.set_global("live", live).unwrap();
+        state.collect_garbage();
+        loop {
+            let before = state.get_heap().get_bytes();
+            state.new_table_ref(8, 0);
+            state.check_gc();
+            if state.get_heap().get_bytes() < before {
+                return before;
+            }
+        }
+    }
+
+    #[test]
+    fn gc_steps_follow_the_budget() {
+        // a faster collector pays the work of a cycle with less allocation
+        assert!(collected_at(400, 13) < collected_at(100, 13));
+        // smaller steps end a fast cycle nearer to its threshold
+        assert!(collected_at(1000, 10) < collected_at(1000, 16));
+    }
+}
@@ -1,153 +1,97 @@
-use std::ptr::{null_mut, NonNull};
-const ILLEGAL_INDEX: usize = usize::MAX;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::ptr::null_mut;
 use crate::common::{
-    lua::ErrCode,
-    lua::{LuaCallInfoStatus, LuaStateStatus, LUA_MIN_STACK, LUA_MUL_RET},
+    lua::{ConfigError, ErrCode, LuaError},
+    lua::{LuaCallInfoStatus, LuaConfig, LuaStateStatus, LUA_MUL_RET, LUA_RAISE_TOP},
     obj::{
-        objdef::{TNumber, TObject, BASIC_TYPE_BIT},
-        objtrait::ObjectTrait,
+        objdef::{TObject, BASIC_TYPE_BIT},
+        objtype::{INT, RFUNC},
     },
-    state::statedef::{CallInfo, LuaState, StkElem},
+    state::statedef::{LuaState, LuaThrow, StkElem},
 };
 
-macro_rules! ptr_get {
-    ($self:ident,$stack:ident) => {{
-        if let Some(stk) = $self.$stack {
-            let ptr = stk.as_ptr();
-            if !ptr.is_null() {
-                Ok(unsafe { &mut *ptr })
-            } else {
-                Err(ErrCode::NullPointer)
-            }
-        } else {
-            Err(ErrCode::NoneObject)
-        }
-    }};
-    ($self:ident,$stack:ident,$elem:ident) => {{
-        if let Some(stk) = $self.$stack.$elem {
-            let ptr = stk.as_ptr();
-            if !ptr.is_null() {
-                Ok(unsafe { &mut *ptr })
-            } else {
-                Err(ErrCode::NullPointer)
-            }
-        } else {
-            Err(ErrCode::NoneObject)
-        }
-    }};
-    ($sth:expr) => {
-        if let Some(stack) = $sth {
-            Ok(stack)
-        } else {
-            Err(ErrCode::NoneObject)
-        }
-    };
-}
+use crate::stdlib::libinit::open_libs;
 
-macro_rules! ptr_init {
-    ($content:expr) => {
-        NonNull::from($content)
-    };
-    ($content:ident) => {
-        NonNull::new($content)
-    };
-    ($self:ident,$ci:ident) => {
-        NonNull::from(&$self.$ci)
-    };
-}
+use super::machbuilder::MachineBuilder;
 
-#[allow(dead_code)]
+/// brief: a machine owns its main state, dropping the machine frees it
 pub struct Machine {
-    dynamo: Routine,
-    //wiper: ErrorHandler,
-    ci_err_index: usize,
-    cstate_status: LuaStateStatus,
+    state: LuaState,
 }
 
-static mut MACHINE: *mut Machine = null_mut();
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
+}
 
 impl Machine {
-    pub fn machine() -> &'static mut Self {
-        if unsafe { MACHINE.is_null() } {
-            unsafe { MACHINE = Box::leak(Box::new(Machine::new())) };
-            unsafe {
-                return &mut *MACHINE;
-            }
-        } else {
-            return unsafe { &mut *MACHINE };
-        }
+    /// brief: a machine with the default budget
+    pub fn new() -> Self {
+        Machine::with_config(LuaConfig::default()).expect("the default budget has no memory limit")
     }
 
-    pub fn get_state(&mut self) -> &mut LuaState {
-        unsafe { self.dynamo.cstate.unwrap().as_mut() }
+    /// brief: configure a machine of its own resource budget
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
     }
 
-    #[allow(dead_code)]
-    fn new() -> Self {
-        // start the machine
-        let mut machine = Machine {
-            dynamo: Routine::new(),
-            //wiper: Default::default(),
-            ci_err_index: ILLEGAL_INDEX,
-            cstate_status: LuaStateStatus::LuaOk,
-        };
+    pub fn get_state(&mut self) -> &mut LuaState {
+        &mut self.state
+    }
 
+    /// brief: start a machine, the registry, the globals and the libraries
+    /// are opened protected: a budget too small for them is an error
+    pub(crate) fn with_config(config: LuaConfig) -> Result<Self, ConfigError> {
         // generate the states
-        let state = LuaState::mainthread_new(null_mut());
+        let mut state =
+            LuaState::mainthread_new(null_mut(), config).map_err(|_| ConfigError::MemoryLimitTooSmall)?;
         // null_mut => for temp
+        let libs = state.get_config().libs;
+        Routine::protect(&mut state, |state| open_libs(state, libs))
+            .map_err(|_| ConfigError::MemoryLimitTooSmall)?;
 
-        // link the state to dynamo
-        machine.dynamo.cstate = Some(ptr_init!(state));
-
-        machine
+        Ok(Machine { state })
     }
 
-    pub fn call(&mut self, narg: usize, sresults: isize) -> usize {
-        let state = ptr_get!(self, dynamo, cstate).ok().unwrap();
-        let func_index = state.get_top_index() - (narg + 1);
-        dbg!(state.get_top_index());
-        dbg!(func_index);
-        let status = self.execute(func_index, sresults);
-        return status;
+    /// brief: call the function below the top `narg` values
+    /// return the number of results left where the function was
+    pub fn call(&mut self, narg: usize, sresults: isize) -> Result<usize, LuaError> {
+        let func_index = self.state.get_top_index() - (narg + 1);
+        self.execute(func_index, sresults)
     }
 
     // INTERFACE
-    #[allow(dead_code)]
-    pub fn execute(&mut self, func_index: usize, sresults: isize) -> usize {
-        self.execute_unprotected(func_index, sresults);
-        let state = ptr_get!(self, dynamo, cstate).ok().unwrap();
-        let last = state.pop_stack();
-        if last.get_type() == TNumber::NumInt as u8 {
-            let outcome = unsafe { last.get_value().val_int }.into_inner().unwrap();
-            if outcome != 0 {
-                println!("Virtual Machine: Failed running..");
-            }
-            //lack sth
-            else {
-                println!("Virtual Machine: Successful running..");
-            }
-        } else {
-            panic!("FATAL ERROR!");
-        }
-        println!("The program is completed.");
-        return 1;
+    /// brief: call the function at func_index in protected mode
+    /// a panic of a rust function is resumed here, after the state is restored
+    pub fn execute(&mut self, func_index: usize, sresults: isize) -> Result<usize, LuaError> {
+        Routine::pcall(&mut self.state, func_index, sresults).map_err(LuaError::resume_panic)?;
+        Ok(self.state.get_top_index() - func_index)
     }
 
     pub fn execute_unprotected(&mut self, func_index: usize, sresults: isize) {
         // try to run here, if it does not work, back to here
-        let res = self.dynamo.run(func_index, sresults);
-        let state = ptr_get!(self, dynamo, cstate).ok().unwrap();
+        let state = &mut self.state;
+        let res = match Routine::protect(state, |state| Routine::run(state, func_index, sresults)) {
+            Ok(res) => res,
+            Err(err) => {
+                state.move_top_to(func_index);
+                err.resume_panic();
+                Err(ErrCode::Runtime)
+            }
+        };
         if let Err(ecode) = res {
             state.move_top_to(func_index);
 
-            state.stack_shrink(self.dynamo.ci_err_index);
-            state.civ_shrink(self.dynamo.ci_err_index);
+            let ci_err_index = state.get_ci_err_index();
+            state.stack_shrink(ci_err_index);
+            state.civ_shrink(ci_err_index);
 
             let code1 = state.get_status() as isize;
-            let code2 = state.get_ci_status(self.dynamo.ci_err_index) as isize;
+            let code2 = state.get_ci_status(ci_err_index) as isize;
             let code3 = ecode as isize;
             let code = (code3 << 8) | (code2 << 4) | code1;
-            state.push_errcode(code as i32);
+            state.push_errcode(code as INT);
         } else {
             state.push_errcode(0);
         }
@@ -156,141 +100,441 @@ impl Machine {
     //pub fn error() {}
 }
 
-#[allow(dead_code)]
-pub struct Routine {
-    ci_err_index: usize,
-    cci_index: usize,
-    cci_status: LuaCallInfoStatus,
-    cstate: Option<NonNull<LuaState>>,
-}
+/// brief: the call protocol, working on the state passed in
+pub struct Routine;
 
 impl Routine {
-    pub fn get_state(&mut self) -> &mut LuaState {
-        ptr_get!(self, cstate).ok().unwrap()
+    /// brief: mark the current call info with `status` and remember where the error is
+    fn raise(state: &mut LuaState, status: LuaCallInfoStatus) {
+        let cci_index = state.get_cci_index();
+        state.set_ci_err_index(cci_index);
+        state.write_ci_status(cci_index, status);
+        if !matches!(state.get_status(), LuaStateStatus::LuaErrMem) {
+            state.set_status(LuaStateStatus::LuaErrErr);
+        }
     }
 
-    fn new() -> Self {
-        Self {
-            ci_err_index: ILLEGAL_INDEX,
-            cci_index: 0, // as cache
-            cci_status: LuaCallInfoStatus::CallOk,
-            cstate: Default::default(),
+    /// brief: run `f`, a lua error raised inside is caught and returned,
+    /// the call infos entered since are dropped. other panics pass through
+    pub fn protect<R>(state: &mut LuaState, f: impl FnOnce(&mut LuaState) -> R) -> Result<R, LuaError> {
+        let (ncalls, nrcalls) = (state.get_ncalls(), state.get_nrcalls());
+        match catch_unwind(AssertUnwindSafe(|| f(&mut *state))) {
+            Ok(res) => Ok(res),
+            Err(payload) => match payload.downcast::<LuaThrow>() {
+                Ok(throw) => {
+                    let cci_index = state.get_cci_index();
+                    state.set_ci_err_index(cci_index);
+                    state.unwind_ci(ncalls, nrcalls);
+                    Err(throw.0)
+                }
+                Err(payload) => resume_unwind(payload),
+            },
         }
     }
 
-    fn run(&mut self, func_index: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
-        let state = ptr_get!(self, cstate).ok().unwrap();
+    /// brief: call the function at func_index in protected mode
+    /// on error the stack is cut back to func_index
+    pub fn pcall(state: &mut LuaState, func_index: usize, sresults: isize) -> Result<(), LuaError> {
+        let (ncalls, nrcalls) = (state.get_ncalls(), state.get_nrcalls());
+        let res = match Routine::protect(state, |state| Routine::run(state, func_index, sresults)) {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_)) => {
+                let ci_status = state.get_ci_status(state.get_ci_err_index());
+                state.unwind_ci(ncalls, nrcalls);
+                Err(match (state.get_status(), ci_status) {
+                    (LuaStateStatus::LuaErrMem, _) => LuaError::Memory("not enough memory".to_string()),
+                    (_, LuaCallInfoStatus::TooManyCall) => LuaError::Runtime("stack overflow (too many calls)".to_string()),
+                    _ => LuaError::Runtime("stack overflow".to_string()),
+                })
+            }
+            Err(err) => Err(err),
+        };
+        if res.is_err() {
+            state.move_top_to(func_index);
+            state.set_status(LuaStateStatus::LuaOk);
+        }
+        res
+    }
 
-        if !state.calls_check() {
-            self.ci_err_index = self.cci_index;
-            state.write_ci_status(self.cci_index, LuaCallInfoStatus::TooManyCall);
-            state.set_status(LuaStateStatus::LuaErrErr);
+    pub fn run(state: &mut LuaState, func_index: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
+        if !state.rcalls_check() {
+            Routine::raise(state, LuaCallInfoStatus::TooManyCall);
             return Err(ErrCode::OverFlow);
         }
 
-        // add ncalls
-        state.change_ncalls(1, true);
+        // add nrcalls
+        state.change_nrcalls(1, true);
 
         // after entering precall function, no more try catch block
-        self.pre_call(func_index, sresults)?;
+        let res = Routine::pre_call(state, func_index, sresults);
 
-        // minus ncalls
-        state.change_ncalls(1, false);
+        // minus nrcalls
+        state.change_nrcalls(1, false);
 
-        Ok(ErrCode::Fine)
+        res
     }
 
     // prepare for function call.
     // if we call a c function, just directly call it
     // if we call a lua function, the function is just for preparation
-    fn pre_call(&mut self, func_index: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
-        // get the current state
-        let state = ptr_get!(self, cstate).ok().unwrap();
-
-        // get the current stack
-        let stack = ptr_get!(state.get_stack_mut_ref()).ok().unwrap();
+    fn pre_call(state: &mut LuaState, func_index: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
+        state.check_gc();
 
-        let obj = ptr_get!(stack.get_ref_elem(func_index)).ok().unwrap();
+        let obj = state.get_stack_ref().get_elem(func_index).unwrap();
 
         // function label
         let label = obj.get_type();
-        dbg!(label);
         // mismatched type
         if !TObject::is_function(label) {
-            panic!("FATAL ERROR: Mismatch Type!");
+            let typename = state.elem_to_value(&obj).type_name();
+            state.error(LuaError::Runtime(format!("attempt to call a {} value", typename)));
         }
-        dbg!("hello", label >> BASIC_TYPE_BIT);
-        match label >> BASIC_TYPE_BIT {
+
+        let lrfunc;
+        let rfunc;
+        let function: &RFUNC = match label >> BASIC_TYPE_BIT {
             1 => {
-                dbg!();
-                let function = unsafe { obj.get_value().val_rfunc.into_inner().unwrap().as_ref() };
-                dbg!();
-                //let function
-                dbg!();
-                //dbg!(function as *const ());
-                //dbg!(test_01 as *const());
-                // checking stack status and resize it silently
-                state.stack_check(LUA_MIN_STACK as usize);
-                dbg!();
-                // add a new call info, the info of cci in state changes as well
-                self.cci_index = state.add_next_ci(func_index, sresults);
-                dbg!();
-                //let function=unsafe{&mut *function};
-                //dbg!("2 times",function as *const ());
-                // if !function.is_null(){
-                //     dbg!("fuck you");
-                // }
-                let rresults = function(state);
-                dbg!();
-                dbg!(rresults);
-
-                let _ = state.pop_stack();
-                if !state.cci_check(self.cci_index, rresults) {
-                    self.ci_err_index = self.cci_index;
-                    state.write_ci_status(self.cci_index, LuaCallInfoStatus::StackOverFlow);
-                    state.set_status(LuaStateStatus::LuaErrErr);
-                    return Err(ErrCode::OverFlow);
+                lrfunc = obj.as_lrfunc().unwrap();
+                &lrfunc
+            }
+            2 => match state.get_rfunc(&obj) {
+                Some(closure) => {
+                    rfunc = closure;
+                    &*rfunc
                 }
+                None => state.error(LuaError::Runtime(
+                    "internal error: the closure of a function is not in the heap".to_string(),
+                )),
+            },
+            // a lua closure, none is made without a compiler
+            _ => state.error(LuaError::Runtime(
+                "internal error: a function of an unknown variant".to_string(),
+            )),
+        };
 
-                self.post_call(func_index, rresults, sresults);
-
-                self.cci_index -= 1;
+        // checking stack status and resize it silently
+        if state.stack_check(state.get_min_stack()).is_err() {
+            Routine::raise(state, LuaCallInfoStatus::StackOverFlow);
+            return Err(ErrCode::OverFlow);
+        }
 
-                return Ok(ErrCode::Fine);
-            }
-            _ => {
-                // self.ci_err_index = self.cci_index;
-                // return Err(ErrCode::MisMatch);
-                panic!("Mismatch Type!");
+        // add a new call info, the info of cci in state changes as well
+        if !state.calls_check() {
+            Routine::raise(state, LuaCallInfoStatus::TooManyCall);
+            return Err(ErrCode::OverFlow);
+        }
+        match state.add_next_ci(func_index, sresults) {
+            Ok(index) => state.set_cci_index(index),
+            Err(ecode) => {
+                Routine::raise(state, LuaCallInfoStatus::TooManyCall);
+                return Err(ecode);
             }
+        };
+
+        // a panic of the function becomes a lua error, so that the protected
+        // boundary restores the calls and the stack. the panic is resumed
+        // once the error leaves the machine
+        let rresults = match catch_unwind(AssertUnwindSafe(|| function(&mut *state))) {
+            Ok(rresults) => rresults,
+            Err(payload) if payload.is::<LuaThrow>() => resume_unwind(payload),
+            Err(payload) => state.error(LuaError::callback_panic(payload)),
+        };
+        // the error of a function that cannot unwind into the machine, such
+        // as a native module with its own copy of std
+        if rresults == LUA_RAISE_TOP {
+            let err = state.get_value(-1);
+            state.error(LuaError::from_value(err));
         }
+
+        // the results are the top rresults values
+        if rresults + func_index + 1 > state.get_top_index()
+            || !state.cci_check(state.get_cci_index(), rresults)
+        {
+            Routine::raise(state, LuaCallInfoStatus::StackOverFlow);
+            return Err(ErrCode::OverFlow);
+        }
+
+        Routine::post_call(state, func_index, rresults, sresults);
+
+        let cci_index = state.remove_ci();
+        state.set_cci_index(cci_index);
+
+        Ok(ErrCode::Fine)
     }
 
-    fn post_call(&self, func_index: usize, rresults: usize, sresults: isize) {
-        let state = ptr_get!(self, cstate).ok().unwrap();
-        let stack = ptr_get!(state.get_stack_mut_ref()).ok().unwrap();
+    /// brief: move the results to where the function was, pad with nil
+    fn post_call(state: &mut LuaState, func_index: usize, rresults: usize, sresults: isize) {
+        let wanted = match sresults {
+            LUA_MUL_RET => rresults,
+            _ => sresults as usize,
+        }; // inside match, deal with stack
+
+        let first_result = state.get_top_index() - rresults;
+        let stack = state.get_stack_mut_ref();
+        for i in 0..wanted {
+            let mut elem = if i < rresults {
+                stack.get_elem(first_result + i).unwrap()
+            } else {
+                StkElem::default()
+            };
+            let _ = stack.swap_elem(func_index + i, &mut elem).ok().unwrap();
+        }
+        state.move_top_to(func_index + wanted);
+    }
+}
 
-        match sresults {
-            0 => {
-                state.move_top_to(func_index);
-                // not really necessary, just in case
-            }
-            1 => {
-                if rresults == 0 {
-                    let mut empty_elem = StkElem::default();
-                    let _ = stack.swap_elem(func_index, &mut empty_elem).ok().unwrap();
+#[cfg(test)]
+mod test {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::common::lua::{LuaConfig, LuaError, StdLib};
+    use crate::common::obj::objconv::{MultiValue, Variadic};
+    use crate::common::obj::objdef::TObj;
+    use crate::common::obj::objtype::GcRef;
+    use crate::common::obj::objvalue::{Function, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    fn add(state: &mut LuaState) -> usize {
+        let b = state.pop_integer();
+        let a = state.pop_integer();
+        state.push_integer(a + b);
+        1
+    }
+
+    #[test]
+    fn call_light_function() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        state.push_lrfunc(add);
+        state.push_integer(2);
+        state.push_integer(3);
+        assert_eq!(machine.call(2, 1), Ok(1));
+        assert_eq!(machine.get_state().pop_integer(), 5);
+        assert_eq!(machine.get_state().get_top_index(), 0);
+    }
+
+    #[test]
+    fn call_rust_closure() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        state.push_rfunc(&add);
+        state.push_integer(4);
+        state.push_integer(5);
+        assert_eq!(machine.call(2, 1), Ok(1));
+        assert_eq!(machine.get_state().pop_integer(), 9);
+    }
+
+    #[test]
+    fn machines_are_independent() {
+        let mut first = Machine::new();
+        let mut second = Machine::builder().stack_size(64).build().unwrap();
+        first.get_state().push_integer(1);
+        second.get_state().push_integer(2);
+        assert_eq!(first.get_state().pop_integer(), 1);
+        assert_eq!(second.get_state().pop_integer(), 2);
+    }
+
+    #[test]
+    fn too_deep_recursion_is_an_error() {
+        fn reenter(state: &mut LuaState) -> usize {
+            state.push_lrfunc(reenter);
+            let func_index = state.get_top_index() - 1;
+            let failed = crate::machine::machdef::Routine::run(state, func_index, 0).is_err();
+            state.move_top_to(func_index);
+            state.push_bool(failed);
+            1
+        }
+        let mut machine = Machine::builder().max_rust_calls(1).build().unwrap();
+        machine.get_state().push_lrfunc(reenter);
+        machine.execute_unprotected(0, 1);
+        assert_eq!(machine.get_state().pop_integer(), 0);
+        assert!(machine.get_state().pop_bool());
+    }
+
+    #[test]
+    fn function_handle_from_globals() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let add = state.create_function(|_, (a, b): (i64, i64)| Ok(a + b)).unwrap();
+        state.set_global("add", add).unwrap();
+
+        let add: Function = state.get_global("add").unwrap();
+        assert_eq!(add.call::<_, i64>(state, (2, 3)), Ok(5));
+        assert_eq!(state.get_top_index(), 0);
+    }
+
+    #[test]
+    fn function_returns_many_values() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let split = state.create_function(|_, s: String| {
+            Ok(Variadic(s.split(',').map(str::to_string).collect::<Vec<_>>()))
+        }).unwrap();
+        let (first, rest): (String, Variadic<String>) = split.call(state, "a,b,c").unwrap();
+        assert_eq!(first, "a");
+        assert_eq!(rest.0, vec!["b", "c"]);
+
+        // missing results are nil
+        let (a, b): (String, Option<String>) = split.call(state, "x").unwrap();
+        assert_eq!((a.as_str(), b), ("x", None));
+    }
+
+    #[test]
+    fn function_error_is_returned() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let fail = state.create_function(|_, ()| -> Result<(), LuaError> {
+            Err(LuaError::Runtime("boom".to_string()))
+        }).unwrap();
+        assert_eq!(fail.call::<_, ()>(state, ()), Err(LuaError::Runtime("boom".to_string())));
+        assert_eq!(state.get_top_index(), 0);
+
+        // a failed conversion of the arguments is an error as well
+        let add = state.create_function(|_, (a, b): (i64, i64)| Ok(a + b)).unwrap();
+        assert!(add.call::<_, i64>(state, ("x", 1)).is_err());
+    }
+
+    #[test]
+    fn bind_prepends_arguments() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let sub = state.create_function(|_, (a, b): (i64, i64)| Ok(a - b)).unwrap();
+        let ten_minus = sub.bind(state, 10).unwrap();
+        assert_eq!(ten_minus.call::<_, i64>(state, 3), Ok(7));
+        let seven = ten_minus.bind(state, 3).unwrap();
+        assert_eq!(seven.call::<_, i64>(state, ()), Ok(7));
+    }
+
+    #[test]
+    fn machine_call_reports_errors() {
+        fn fail(state: &mut LuaState) -> usize {
+            state.error(LuaError::Runtime("failed".to_string()))
+        }
+        let mut machine = Machine::new();
+        machine.get_state().push_integer(7);
+        machine.get_state().push_lrfunc(fail);
+        assert_eq!(machine.call(0, 0), Err(LuaError::Runtime("failed".to_string())));
+        assert_eq!(machine.get_state().get_top_index(), 1);
+        assert_eq!(machine.get_state().pop_integer(), 7);
+    }
+
+    fn mismatch(state: &mut LuaState) -> usize {
+        state.push_bool(true);
+        state.pop_integer();
+        0
+    }
+
+    #[test]
+    fn callback_panic_is_a_lua_error() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let (ncalls, nrcalls) = (state.get_ncalls(), state.get_nrcalls());
+        let cci_index = state.get_cci_index();
+        let top = state.get_top_index();
+
+        let func = Value::Function(state.create_function(|state, ()| Ok(mismatch(state))).unwrap());
+        let err = state.call_value(&func, MultiValue::new()).unwrap_err();
+        match &err {
+            LuaError::CallbackPanic { message, .. } => assert_eq!(message, "FATAL ERROR: MISMATCH TYPE"),
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(err.to_string(), "rust panic: FATAL ERROR: MISMATCH TYPE");
+        assert_eq!((state.get_ncalls(), state.get_nrcalls()), (ncalls, nrcalls));
+        assert_eq!(state.get_cci_index(), cci_index);
+        assert_eq!(state.get_top_index(), top);
+
+        // the state is still usable
+        state.push_lrfunc(add);
+        state.push_integer(1);
+        state.push_integer(2);
+        assert_eq!(machine.call(2, 1), Ok(1));
+        assert_eq!(machine.get_state().pop_integer(), 3);
+    }
+
+    #[test]
+    fn callback_panic_resumes_out_of_machine() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        // the panic passes through a lua error raised by an outer callback
+        let inner = Value::Function(state.create_function(|state, ()| Ok(mismatch(state))).unwrap());
+        let outer = state.create_function(move |state, ()| state.call_value(&inner, MultiValue::new())).unwrap();
+        state.push_value(&Value::Function(outer));
+        state.push_integer(1);
+
+        let res = catch_unwind(AssertUnwindSafe(|| machine.call(1, 0)));
+        let payload = res.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"FATAL ERROR: MISMATCH TYPE"));
+        assert_eq!(machine.get_state().get_top_index(), 0);
+
+        machine.get_state().push_lrfunc(mismatch);
+        let res = catch_unwind(AssertUnwindSafe(|| machine.call(0, 0)));
+        assert!(res.is_err());
+        assert_eq!(machine.get_state().get_top_index(), 0);
+    }
+
+    #[test]
+    fn taken_panic_is_not_resumed() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let inner = Value::Function(state.create_function(|state, ()| Ok(mismatch(state))).unwrap());
+        let outer = state.create_function(move |state, ()| {
+            match state.call_value(&inner, MultiValue::new()) {
+                Err(LuaError::CallbackPanic { payload, .. }) => {
+                    // handled here, the error goes on as a plain lua error
+                    assert!(payload.take().is_some());
+                    Err(LuaError::Runtime("handled".to_string()))
                 }
-                state.move_top_to(func_index + 1);
+                _ => Ok(()),
+            }
+        }).unwrap();
+        state.push_value(&Value::Function(outer));
+        assert_eq!(machine.call(0, 0), Err(LuaError::Runtime("handled".to_string())));
+    }
+
+    #[test]
+    fn calling_a_non_function_is_a_lua_error() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        state.push_integer(1);
+        assert_eq!(
+            machine.call(0, 0),
+            Err(LuaError::Runtime("attempt to call a number value".to_string()))
+        );
+        assert_eq!(machine.get_state().get_top_index(), 0);
+
+        // a closure missing from the heap is a bug of the machine, still an error
+        let stale = GcRef {
+            index: u32::MAX,
+            gen: 0,
+        };
+        machine.get_state().push_obj(TObj::new_rfunc(stale));
+        let err = machine.call(0, 0).unwrap_err().to_string();
+        assert_eq!(err, "internal error: the closure of a function is not in the heap");
+        assert_eq!(machine.get_state().get_ncalls(), 1);
+    }
+
+    #[test]
+    fn out_of_memory_is_a_lua_error() {
+        let limit = LuaConfig::default().initial_bytes() + (1 << 16);
+        let mut machine = Machine::builder().libs(StdLib::NONE).memory_limit(limit).build().unwrap();
+        let state = machine.get_state();
+        let grow = state.create_function(|state, ()| {
+            let table = state.create_table_value(0, 0)?;
+            for i in 1.. {
+                table.set(state, i, format!("{:064}", i))?;
+                let inner = state.create_table_value(4, 0)?;
+                table.set(state, -i, inner)?;
             }
-            LUA_MUL_RET => {
-                todo!()
-            } //
-            _ => panic!("dd"),
-        } // inside match, deal with stack
-
-        let mut callinfo = CallInfo::default();
-        let civ = ptr_get!(state.get_civ_mut_ref()).ok().unwrap();
-        let _ = civ.swap_elem(self.cci_index, &mut callinfo).ok().unwrap();
-        // deal with call info
+            Ok(())
+        }).unwrap();
+        let err = grow.call::<_, ()>(state, ()).unwrap_err();
+        assert_eq!(err, LuaError::Memory("not enough memory".to_string()));
+        assert!(state.get_total_bytes() <= limit);
+
+        // the state goes on once the garbage is collected
+        state.collect_garbage();
+        state.push_integer(7);
+        assert_eq!(state.pop_integer(), 7);
     }
 }
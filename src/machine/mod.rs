@@ -1 +1,2 @@
+pub mod machbuilder;
 pub mod machdef;
\ No newline at end of file
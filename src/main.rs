@@ -8,22 +8,16 @@
 
 
 
-pub mod machine;
-pub mod common;
+use naive_lua2::common::obj::objtype::INT;
+use naive_lua2::common::state::statedef::LuaState;
 
-
-
-
-use common::state::statedef::LuaState;
-
-use crate::machine::machdef::Machine;
+use naive_lua2::machine::machdef::Machine;
 
 
 pub fn test_01(state:&mut LuaState)->usize{
-    let k=state.pop_bool();
-    let i=state.pop_integer();
+    let (i,k):(INT,bool)=state.get_args().expect("bad arguments");
     println!("the value is {},{}",i,k);
-    return 0;
+    0
 }
 
 
@@ -31,7 +25,8 @@ fn main(){
 
     
 
-    let state=Machine::machine().get_state();
+    let mut machine=Machine::new();
+    let state=machine.get_state();
     //d(state);
     state.push_rfunc(&test_01);
     //let f=state.pop_stack();
@@ -41,5 +36,7 @@ fn main(){
     //u(state);
     state.push_integer(2);
     state.push_bool(true);
-    Machine::machine().call(2, 0);
+    if let Err(err)=machine.call(2, 0){
+        eprintln!("error: {}",err);
+    }
 }
@@ -0,0 +1,688 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+use std::process::{self, ExitStatus};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::lua::LuaError;
+use crate::common::obj::objconv::{fmt_number, str_to_number};
+use crate::common::obj::objtable::float_to_integer;
+use crate::common::obj::objtype::{FLT, INT, LRFUNC};
+use crate::common::obj::objvalue::{AnyUserData, FuncRef, Function, LuaString, Table, Value};
+use crate::common::state::statedef::LuaState;
+
+use super::libpackage::LOADED_TABLE;
+
+/// brief: raise "bad argument #arg to 'fname' (extramsg)"
+pub fn arg_error(state: &mut LuaState, arg: isize, fname: &str, extramsg: &str) -> ! {
+    state.error(LuaError::Runtime(format!(
+        "bad argument #{} to '{}' ({})",
+        arg, fname, extramsg
+    )))
+}
+
+/// brief: the type of the argument in messages, its `__name` first
+pub(crate) fn arg_type_name(state: &mut LuaState, arg: isize) -> String {
+    if state.is_none(arg) {
+        return "no value".to_string();
+    }
+    let val = state.get_value(arg);
+    match state.get_metafield(&val, "__name") {
+        Value::String(name) => name.to_string_lossy(),
+        _ => match val {
+            Value::LightUserData(_) => "light userdata".to_string(),
+            val => val.type_name().to_string(),
+        },
+    }
+}
+
+/// brief: raise "bad argument #arg to 'fname' (expected expected, got type)"
+pub fn type_error(state: &mut LuaState, arg: isize, fname: &str, expected: &str) -> ! {
+    let actual = arg_type_name(state, arg);
+    arg_error(
+        state,
+        arg,
+        fname,
+        &format!("{} expected, got {}", expected, actual),
+    )
+}
+
+/// brief: none or nil
+pub fn is_none_or_nil(state: &mut LuaState, arg: isize) -> bool {
+    state.is_none(arg) || state.get_value(arg).is_nil()
+}
+
+pub fn check_any(state: &mut LuaState, arg: isize, fname: &str) -> Value {
+    if state.is_none(arg) {
+        arg_error(state, arg, fname, "value expected");
+    }
+    state.get_value(arg)
+}
+
+pub fn check_table(state: &mut LuaState, arg: isize, fname: &str) -> Table {
+    match state.get_value(arg) {
+        Value::Table(table) => table,
+        _ => type_error(state, arg, fname, "table"),
+    }
+}
+
+pub fn check_function(state: &mut LuaState, arg: isize, fname: &str) -> Function {
+    match state.get_value(arg) {
+        Value::Function(function) => function,
+        _ => type_error(state, arg, fname, "function"),
+    }
+}
+
+/// brief: an integer argument, a float or a string of an integral value is
+/// accepted too
+pub fn check_integer(state: &mut LuaState, arg: isize, fname: &str) -> INT {
+    let val = match state.get_value(arg) {
+        Value::String(s) => str_to_number(s.as_bytes()).unwrap_or(Value::String(s)),
+        val => val,
+    };
+    match val {
+        Value::Integer(i) => i,
+        Value::Number(n) => match float_to_integer(n) {
+            Some(i) => i,
+            None => arg_error(state, arg, fname, "number has no integer representation"),
+        },
+        _ => type_error(state, arg, fname, "number"),
+    }
+}
+
+/// brief: a number argument, a string of a numeral is converted
+pub fn check_number(state: &mut LuaState, arg: isize, fname: &str) -> FLT {
+    let val = match state.get_value(arg) {
+        Value::String(s) => str_to_number(s.as_bytes()).unwrap_or(Value::String(s)),
+        val => val,
+    };
+    match val {
+        Value::Integer(i) => i as FLT,
+        Value::Number(n) => n,
+        _ => type_error(state, arg, fname, "number"),
+    }
+}
+
+pub fn opt_integer(state: &mut LuaState, arg: isize, fname: &str, default: INT) -> INT {
+    if is_none_or_nil(state, arg) {
+        default
+    } else {
+        check_integer(state, arg, fname)
+    }
+}
+
+pub fn opt_number(state: &mut LuaState, arg: isize, fname: &str, default: FLT) -> FLT {
+    if is_none_or_nil(state, arg) {
+        default
+    } else {
+        check_number(state, arg, fname)
+    }
+}
+
+/// brief: #obj with `__len` as an integer, `luaL_len`
+pub fn len(state: &mut LuaState, obj: &Value) -> INT {
+    let len = match state.len(obj) {
+        Ok(len) => len,
+        Err(err) => state.error(err),
+    };
+    let len = match len {
+        Value::String(s) => str_to_number(s.as_bytes()).unwrap_or(Value::Nil),
+        len => len,
+    };
+    match len {
+        Value::Integer(i) => i,
+        Value::Number(n) => match float_to_integer(n) {
+            Some(i) => i,
+            None => state.error(LuaError::Runtime("object length is not an integer".to_string())),
+        },
+        _ => state.error(LuaError::Runtime("object length is not an integer".to_string())),
+    }
+}
+
+/// brief: a string argument, a number is converted
+pub fn check_lstring(state: &mut LuaState, arg: isize, fname: &str) -> LuaString {
+    match state.get_value(arg) {
+        Value::String(s) => s,
+        Value::Integer(i) => LuaString::from(i.to_string().as_str()),
+        Value::Number(n) => LuaString::from(fmt_number(n).as_str()),
+        _ => type_error(state, arg, fname, "string"),
+    }
+}
+
+pub fn opt_lstring(state: &mut LuaState, arg: isize, fname: &str, default: &str) -> LuaString {
+    if is_none_or_nil(state, arg) {
+        LuaString::from(default)
+    } else {
+        check_lstring(state, arg, fname)
+    }
+}
+
+/// brief: the position of a string argument in `options`
+pub fn check_option(
+    state: &mut LuaState,
+    arg: isize,
+    fname: &str,
+    default: Option<&str>,
+    options: &[&str],
+) -> usize {
+    let name = match default {
+        Some(default) => opt_lstring(state, arg, fname, default),
+        None => check_lstring(state, arg, fname),
+    };
+    match options
+        .iter()
+        .position(|option| option.as_bytes() == name.as_bytes())
+    {
+        Some(position) => position,
+        None => arg_error(
+            state,
+            arg,
+            fname,
+            &format!("invalid option '{}'", name.to_string_lossy()),
+        ),
+    }
+}
+
+/// brief: the name of a function in the loaded modules, "name" for a
+/// global one and "module.name" for the others
+pub fn function_name(state: &mut LuaState, function: &Value) -> Option<String> {
+    if !matches!(function, Value::Function(_)) {
+        return None;
+    }
+    let registry = state.registry();
+    let Value::Table(loaded) = state.raw_get(&registry, &Value::from(LOADED_TABLE)) else {
+        return None;
+    };
+    let mut found = None;
+    for (modname, module) in state.raw_pairs(&loaded) {
+        let (Value::String(modname), Value::Table(module)) = (modname, module) else {
+            continue;
+        };
+        for (name, val) in state.raw_pairs(&module) {
+            if let (Value::String(name), true) = (&name, &val == function) {
+                let name = name.to_string_lossy();
+                if modname.as_bytes() == b"_G" {
+                    return Some(name);
+                }
+                found.get_or_insert_with(|| format!("{}.{}", modname.to_string_lossy(), name));
+            }
+        }
+    }
+    found
+}
+
+/// brief: the name of the running function, see function_name
+pub(crate) fn running_function_name(state: &mut LuaState) -> Option<String> {
+    let function = state.get_call_functions().into_iter().next()?;
+    function_name(state, &function)
+}
+
+/// brief: a number that tells objects apart in messages, 0 for the values
+/// that are no objects
+pub(crate) fn value_address(val: &Value) -> usize {
+    match val {
+        Value::Table(t) => ((t.0.gc.gen as usize) << 32) | t.0.gc.index as usize,
+        Value::UserData(u) => ((u.0.gc.gen as usize) << 32) | u.0.gc.index as usize,
+        Value::Function(f) => match &f.0 {
+            FuncRef::Light(lrfunc) => *lrfunc as usize,
+            FuncRef::Closure(closure) => ((closure.gc.gen as usize) << 32) | closure.gc.index as usize,
+        },
+        Value::Thread(t) => t.heap as usize,
+        Value::LightUserData(p) => *p as usize,
+        Value::String(s) => s.as_bytes().as_ptr() as usize,
+        _ => 0,
+    }
+}
+
+/// brief: the string of any value, `__tostring` and `__name` are honoured
+pub fn tolstring(state: &mut LuaState, val: &Value) -> LuaString {
+    let handler = state.get_metafield(val, "__tostring");
+    if !handler.is_nil() {
+        let results = match state.call_value(&handler, [val.clone()].into()) {
+            Ok(results) => results,
+            Err(err) => state.error(err),
+        };
+        return match results.into_iter().next() {
+            Some(Value::String(s)) => s,
+            _ => state.error(LuaError::Runtime("'__tostring' must return a string".to_string())),
+        };
+    }
+    match val {
+        Value::Nil => LuaString::from("nil"),
+        Value::Boolean(b) => LuaString::from(if *b { "true" } else { "false" }),
+        Value::Integer(i) => LuaString::from(i.to_string().as_str()),
+        Value::Number(n) => LuaString::from(fmt_number(*n).as_str()),
+        Value::String(s) => s.clone(),
+        val => {
+            let name = match state.get_metafield(val, "__name") {
+                Value::String(name) => name.to_string_lossy(),
+                _ => val.type_name().to_string(),
+            };
+            let text = match val {
+                Value::Function(Function(FuncRef::Light(_))) => {
+                    format!("{}: builtin: {:#x}", name, value_address(val))
+                }
+                _ => format!("{}: {:#x}", name, value_address(val)),
+            };
+            LuaString::from(text.as_str())
+        }
+    }
+}
+
+/// brief: a table of the functions of a library
+pub fn new_lib(state: &mut LuaState, funcs: &[(&str, LRFUNC)]) -> Table {
+    let lib = state.new_table_value(0, funcs.len());
+    for (name, lrfunc) in funcs {
+        let _ = state.raw_set(
+            &lib,
+            Value::from(*name),
+            Value::Function(Function::light(*lrfunc)),
+        );
+    }
+    lib
+}
+
+/// brief: the table at `table[name]`, a new one is put there when missing
+pub fn get_subtable(state: &mut LuaState, table: &Table, name: &str) -> Table {
+    let table = Value::Table(table.clone());
+    match state.index(&table, &Value::from(name)) {
+        Ok(Value::Table(sub)) => sub,
+        Ok(_) => {
+            let sub = state.new_table_value(0, 0);
+            if let Err(err) = state.set_index(&table, Value::from(name), Value::Table(sub.clone())) {
+                state.error(err);
+            }
+            sub
+        }
+        Err(err) => state.error(err),
+    }
+}
+
+/// brief: the metatable `tname` of the registry, made with its `__name`
+/// when missing. the flag tells whether it was made
+pub fn new_metatable(state: &mut LuaState, tname: &str) -> (Table, bool) {
+    let registry = state.registry();
+    if let Value::Table(metatable) = state.raw_get(&registry, &Value::from(tname)) {
+        return (metatable, false);
+    }
+    let metatable = state.new_table_value(0, 2);
+    let _ = state.raw_set(&metatable, Value::from("__name"), Value::from(tname));
+    let _ = state.raw_set(&registry, Value::from(tname), Value::Table(metatable.clone()));
+    (metatable, true)
+}
+
+/// brief: give a table or a full userdata the metatable `tname` of the
+/// registry, nil when there is none
+pub fn set_metatable(state: &mut LuaState, obj: &Value, tname: &str) {
+    let registry = state.registry();
+    let metatable = match state.raw_get(&registry, &Value::from(tname)) {
+        Value::Table(metatable) => Some(metatable),
+        _ => None,
+    };
+    match obj {
+        Value::Table(table) => state.set_metatable(table, metatable.as_ref()),
+        Value::UserData(ud) => state.set_userdata_metatable(ud, metatable.as_ref()),
+        val => state.set_type_metatable(val, metatable.as_ref()),
+    }
+}
+
+/// brief: the full userdata at `arg` when its metatable is the one `tname`
+/// of the registry
+pub fn test_udata(state: &mut LuaState, arg: isize, tname: &str) -> Option<AnyUserData> {
+    let val = state.get_value(arg);
+    let Value::UserData(ud) = &val else {
+        return None;
+    };
+    let metatable = state.get_metatable(&val)?;
+    let registry = state.registry();
+    match state.raw_get(&registry, &Value::from(tname)) {
+        Value::Table(expected) if expected == metatable => Some(ud.clone()),
+        _ => None,
+    }
+}
+
+pub fn check_udata(state: &mut LuaState, arg: isize, tname: &str, fname: &str) -> AnyUserData {
+    match test_udata(state, arg, tname) {
+        Some(ud) => ud,
+        None => type_error(state, arg, fname, tname),
+    }
+}
+
+/// brief: the module `modname`, opened by `open` with its name unless it is
+/// in `package.loaded` already, and made global when `global` is set
+pub fn requiref(state: &mut LuaState, modname: &str, open: LRFUNC, global: bool) -> Value {
+    let registry = state.registry();
+    let loaded = get_subtable(state, &registry, LOADED_TABLE);
+    let loaded = Value::Table(loaded);
+    let mut module = match state.index(&loaded, &Value::from(modname)) {
+        Ok(module) => module,
+        Err(err) => state.error(err),
+    };
+    if !module.is_truthy() {
+        let open = Value::Function(Function::light(open));
+        module = match state.call_value(&open, [Value::from(modname)].into()) {
+            Ok(results) => results.into_iter().next().unwrap_or(Value::Nil),
+            Err(err) => state.error(err),
+        };
+        if let Err(err) = state.set_index(&loaded, Value::from(modname), module.clone()) {
+            state.error(err);
+        }
+    }
+    if global {
+        let globals = Value::Table(state.globals());
+        if let Err(err) = state.set_index(&globals, Value::from(modname), module.clone()) {
+            state.error(err);
+        }
+    }
+    module
+}
+
+/// brief: the position of the code at `level` for messages, `luaL_where`.
+/// every function of this machine is native and has none, as in lua it is
+/// empty
+pub fn where_(_state: &mut LuaState, _level: usize) -> String {
+    String::new()
+}
+
+/// brief: the traceback of the calls from `level`, 0 for the running
+/// function, after `msg` when one is given. the functions found in the
+/// loaded modules are named
+pub fn traceback(state: &mut LuaState, msg: Option<&str>, level: usize) -> String {
+    const LEVELS1: usize = 10; // the levels shown at the top
+    const LEVELS2: usize = 11; // the levels shown at the bottom
+    let functions = state.get_call_functions();
+    let functions = functions.get(level..).unwrap_or_default();
+    let mut text = match msg {
+        Some(msg) => format!("{}\nstack traceback:", msg),
+        None => "stack traceback:".to_string(),
+    };
+    let skip = functions.len().saturating_sub(LEVELS1 + LEVELS2);
+    for (i, function) in functions.iter().enumerate() {
+        if skip > 0 && i == LEVELS1 {
+            text.push_str(&format!("\n\t...\t(skipping {} levels)", skip));
+        }
+        if skip > 0 && (LEVELS1..LEVELS1 + skip).contains(&i) {
+            continue;
+        }
+        match function_name(state, function) {
+            Some(name) => text.push_str(&format!("\n\t[C]: in function '{}'", name)),
+            None => text.push_str("\n\t[C]: in ?"),
+        }
+    }
+    text
+}
+
+/// brief: a string built piece by piece, `luaL_Buffer`
+#[derive(Clone, Debug, Default)]
+pub struct Buffer(Vec<u8>);
+
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer(Vec::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Buffer(Vec::with_capacity(capacity))
+    }
+
+    pub fn add_char(&mut self, c: u8) {
+        self.0.push(c);
+    }
+
+    pub fn add_bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub fn add_str(&mut self, s: &str) {
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    /// brief: add a string or a number, an error for another value
+    pub fn add_value(&mut self, state: &mut LuaState, val: &Value) {
+        match val {
+            Value::String(s) => self.add_bytes(s.as_bytes()),
+            Value::Integer(i) => self.add_str(&i.to_string()),
+            Value::Number(n) => self.add_str(&fmt_number(*n)),
+            val => state.error(LuaError::Runtime(format!(
+                "string expected, got {}",
+                val.type_name()
+            ))),
+        }
+    }
+
+    /// brief: drop the last `n` bytes, `luaL_buffsub`
+    pub fn sub(&mut self, n: usize) {
+        self.0.truncate(self.0.len().saturating_sub(n));
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_string(self) -> LuaString {
+        LuaString::new(&self.0)
+    }
+
+    /// brief: push the string built, `luaL_pushresult`
+    pub fn push_result(self, state: &mut LuaState) {
+        state.push_string(&self.0);
+    }
+}
+
+/// brief: the message of an os error without the error number rust appends
+pub(crate) fn os_error_message(err: &io::Error) -> String {
+    let message = err.to_string();
+    match message.find(" (os error ") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+/// brief: push true on success, otherwise fail, the message and the error
+/// number. the message starts with `fname` when one is given
+pub(crate) fn file_result(state: &mut LuaState, result: io::Result<()>, fname: Option<&str>) -> usize {
+    match result {
+        Ok(()) => {
+            state.push_bool(true);
+            1
+        }
+        Err(err) => {
+            let message = match fname {
+                Some(fname) => format!("{}: {}", fname, os_error_message(&err)),
+                None => os_error_message(&err),
+            };
+            state.push_nil();
+            state.push_str(&message);
+            state.push_integer(err.raw_os_error().unwrap_or(0) as INT);
+            3
+        }
+    }
+}
+
+/// brief: push the status of a finished process: true or fail, then "exit"
+/// and the exit code, or "signal" and the signal number
+pub(crate) fn exec_result(state: &mut LuaState, result: io::Result<ExitStatus>) -> usize {
+    let status = match result {
+        Ok(status) => status,
+        Err(err) => return file_result(state, Err(err), None),
+    };
+    let (what, code) = match status.code() {
+        Some(code) => ("exit", code),
+        None => ("signal", exit_signal(&status)),
+    };
+    if what == "exit" && code == 0 {
+        state.push_bool(true);
+    } else {
+        state.push_nil();
+    }
+    state.push_str(what);
+    state.push_integer(code as INT);
+    3
+}
+
+#[cfg(unix)]
+fn exit_signal(status: &ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().unwrap_or(0)
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &ExitStatus) -> i32 {
+    0
+}
+
+/// brief: a new file of a unique name "lua_XXXXXX" in the temporary directory,
+/// opened for reading and writing
+pub(crate) fn create_temp_file() -> io::Result<(PathBuf, File)> {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let dir = env::temp_dir();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    loop {
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut seed =
+            (process::id() as u64) << 32 ^ (nanos as u64) ^ (count as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        let mut name = String::from("lua_");
+        for _ in 0..6 {
+            name.push(CHARS[(seed % CHARS.len() as u64) as usize] as char);
+            seed /= CHARS.len() as u64;
+        }
+        let path = dir.join(name);
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::obj::objud::UserData;
+    use crate::common::obj::objvalue::{AnyUserData, Function, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    use super::*;
+
+    struct Point(i64);
+
+    impl UserData for Point {}
+
+    fn open_probe(state: &mut LuaState) -> usize {
+        let lib = new_lib(state, &[("trace", probe_trace), ("point", probe_point)]);
+        state.push_value(&Value::Table(lib));
+        1
+    }
+
+    fn probe_trace(state: &mut LuaState) -> usize {
+        let level = opt_integer(state, 1, "trace", 0) as usize;
+        let text = traceback(state, Some("here"), level);
+        state.push_str(&text);
+        1
+    }
+
+    fn probe_point(state: &mut LuaState) -> usize {
+        let ud = check_udata(state, 1, "probe.point", "point");
+        let x = state.borrow_userdata(&ud, |point: &Point| point.0).unwrap();
+        state.push_integer(x);
+        1
+    }
+
+    #[test]
+    fn requiref_opens_once() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let Value::Table(module) = requiref(state, "probe", open_probe, true) else {
+            panic!("the module is a table");
+        };
+        let again = requiref(state, "probe", open_probe, false);
+        assert_eq!(again, Value::Table(module.clone()));
+        let global: Table = state.get_global("probe").unwrap();
+        assert_eq!(global, module);
+
+        let trace: Function = module.get(state, "trace").unwrap();
+        assert_eq!(
+            function_name(state, &Value::Function(trace.clone())).as_deref(),
+            Some("probe.trace")
+        );
+        let text: String = trace.call(state, ()).unwrap();
+        assert_eq!(text, "here\nstack traceback:\n\t[C]: in function 'probe.trace'");
+        let text: String = trace.call(state, 1).unwrap();
+        assert_eq!(text, "here\nstack traceback:");
+        let pcall: Function = state.get_global("pcall").unwrap();
+        let (_, text): (bool, String) = pcall.call(state, trace).unwrap();
+        assert_eq!(
+            text,
+            "here\nstack traceback:\n\t[C]: in function 'probe.trace'\n\t[C]: in function 'pcall'"
+        );
+    }
+
+    #[test]
+    fn metatables_of_the_registry() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let (metatable, created) = new_metatable(state, "probe.point");
+        assert!(created);
+        let (same, created) = new_metatable(state, "probe.point");
+        assert!(!created);
+        assert_eq!(same, metatable);
+        let name: String = metatable.get(state, "__name").unwrap();
+        assert_eq!(name, "probe.point");
+
+        let Value::Table(module) = requiref(state, "probe", open_probe, false) else {
+            panic!("the module is a table");
+        };
+        let point: Function = module.get(state, "point").unwrap();
+        let ud: AnyUserData = state.create_userdata(Point(7)).unwrap();
+        let err = point.call::<_, i64>(state, ud.clone()).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("bad argument #1 to 'point' (probe.point expected, got "));
+        set_metatable(state, &Value::UserData(ud.clone()), "probe.point");
+        assert_eq!(point.call::<_, i64>(state, ud), Ok(7));
+        let err = point.call::<_, i64>(state, ()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bad argument #1 to 'point' (probe.point expected, got no value)"
+        );
+    }
+
+    #[test]
+    fn buffer_builds_strings() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let mut buffer = Buffer::new();
+        assert!(buffer.is_empty());
+        buffer.add_str("a");
+        buffer.add_char(b'=');
+        buffer.add_value(state, &Value::Integer(1));
+        buffer.add_bytes(b", ");
+        buffer.add_value(state, &Value::Number(2.0));
+        buffer.add_bytes(b", ");
+        buffer.sub(2);
+        assert_eq!(buffer.as_bytes(), b"a=1, 2.0");
+        assert_eq!(buffer.len(), 8);
+        buffer.clone().push_result(state);
+        assert_eq!(state.pop_value(), Value::from("a=1, 2.0"));
+        assert_eq!(buffer.into_string().as_bytes(), b"a=1, 2.0");
+        assert_eq!(where_(state, 1), "");
+    }
+}
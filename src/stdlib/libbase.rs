@@ -0,0 +1,1041 @@
+use std::fs;
+use std::io::{self, Read, Write};
+
+use crate::common::lua::LuaError;
+use crate::common::obj::objconv::{str_to_number, MultiValue};
+use crate::common::obj::objtype::{INT, LRFUNC};
+use crate::common::obj::objvalue::{Function, Value};
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::{arg_error, check_any, check_function, check_integer, check_lstring};
+use super::libaux::{check_option, check_table, is_none_or_nil, new_lib, opt_integer};
+use super::libaux::{opt_lstring, os_error_message, tolstring, type_error};
+
+pub const LUA_VERSION: &str = "Lua 5.4";
+
+const BASE_FUNCS: &[(&str, LRFUNC)] = &[
+    ("assert", base_assert),
+    ("collectgarbage", base_collectgarbage),
+    ("dofile", base_dofile),
+    ("error", base_error),
+    ("getmetatable", base_getmetatable),
+    ("ipairs", base_ipairs),
+    ("loadfile", base_loadfile),
+    ("load", base_load),
+    ("next", base_next),
+    ("pairs", base_pairs),
+    ("pcall", base_pcall),
+    ("print", base_print),
+    ("warn", base_warn),
+    ("rawequal", base_rawequal),
+    ("rawlen", base_rawlen),
+    ("rawget", base_rawget),
+    ("rawset", base_rawset),
+    ("select", base_select),
+    ("setmetatable", base_setmetatable),
+    ("tonumber", base_tonumber),
+    ("tostring", base_tostring),
+    ("type", base_type),
+    ("xpcall", base_xpcall),
+];
+
+/// brief: put the base functions, `_G` and `_VERSION` in the globals
+pub fn open_base(state: &mut LuaState) {
+    let lib = new_lib(state, BASE_FUNCS);
+    let globals = state.globals();
+    for (name, val) in state.raw_pairs(&lib) {
+        let _ = state.raw_set(&globals, name, val);
+    }
+    let _ = state.raw_set(&globals, Value::from("_G"), Value::Table(globals.clone()));
+    let _ = state.raw_set(&globals, Value::from("_VERSION"), Value::from(LUA_VERSION));
+}
+
+/// brief: push the results of a base function, return the number of them
+fn push_results(state: &mut LuaState, results: MultiValue) -> usize {
+    match state.push_results(results) {
+        Ok(nresults) => nresults,
+        Err(err) => state.error(err),
+    }
+}
+
+/// brief: the arguments from `first` on
+fn args_from(state: &mut LuaState, first: isize) -> MultiValue {
+    (first..=state.get_top() as isize)
+        .map(|idx| state.get_value(idx))
+        .collect()
+}
+
+fn base_print(state: &mut LuaState) -> usize {
+    for idx in 1..=state.get_top() as isize {
+        let val = state.get_value(idx);
+        let text = tolstring(state, &val);
+        let mut stdout = io::stdout().lock();
+        if idx > 1 {
+            let _ = stdout.write_all(b"\t");
+        }
+        let _ = stdout.write_all(text.as_bytes());
+    }
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(b"\n");
+    let _ = stdout.flush();
+    0
+}
+
+fn base_warn(state: &mut LuaState) -> usize {
+    let n = state.get_top() as isize;
+    check_lstring(state, 1, "warn");
+    for idx in 2..=n {
+        check_lstring(state, idx, "warn");
+    }
+    for idx in 1..=n {
+        let piece = check_lstring(state, idx, "warn");
+        state.warning(piece.as_bytes(), idx < n);
+    }
+    0
+}
+
+fn base_type(state: &mut LuaState) -> usize {
+    let val = check_any(state, 1, "type");
+    state.push_str(val.type_name());
+    1
+}
+
+fn base_tostring(state: &mut LuaState) -> usize {
+    let val = check_any(state, 1, "tostring");
+    let text = tolstring(state, &val);
+    state.push_string(text.as_bytes());
+    1
+}
+
+/// brief: C isspace, rust leaves out the vertical tab
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r')
+}
+
+/// brief: an integer numeral in `base`, the value wraps around
+fn str_to_int(bytes: &[u8], base: INT) -> Option<INT> {
+    let mut pos = bytes.iter().position(|b| !is_space(*b)).unwrap_or(bytes.len());
+    let negative = match bytes.get(pos) {
+        Some(b'-') => {
+            pos += 1;
+            true
+        }
+        Some(b'+') => {
+            pos += 1;
+            false
+        }
+        _ => false,
+    };
+    if !bytes.get(pos).is_some_and(|b| b.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let mut value: INT = 0;
+    while let Some(byte) = bytes.get(pos).filter(|b| b.is_ascii_alphanumeric()) {
+        let digit = (*byte as char).to_digit(36)? as INT;
+        if digit >= base {
+            return None;
+        }
+        value = value.wrapping_mul(base).wrapping_add(digit);
+        pos += 1;
+    }
+    if !bytes[pos..].iter().all(|b| is_space(*b)) {
+        return None;
+    }
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+fn base_tonumber(state: &mut LuaState) -> usize {
+    let result = if is_none_or_nil(state, 2) {
+        match state.get_value(1) {
+            val @ (Value::Integer(_) | Value::Number(_)) => val,
+            Value::String(s) => str_to_number(s.as_bytes()).unwrap_or(Value::Nil),
+            _ => {
+                check_any(state, 1, "tonumber");
+                Value::Nil
+            }
+        }
+    } else {
+        let base = check_integer(state, 2, "tonumber");
+        let text = match state.get_value(1) {
+            Value::String(s) => s,
+            _ => type_error(state, 1, "tonumber", "string"),
+        };
+        if !(2..=36).contains(&base) {
+            arg_error(state, 2, "tonumber", "base out of range");
+        }
+        str_to_int(text.as_bytes(), base).map_or(Value::Nil, Value::Integer)
+    };
+    state.push_value(&result);
+    1
+}
+
+fn ipairs_aux(state: &mut LuaState) -> usize {
+    let index = check_integer(state, 2, "ipairs").wrapping_add(1);
+    let obj = state.get_value(1);
+    let val = match state.index(&obj, &Value::Integer(index)) {
+        Ok(val) => val,
+        Err(err) => state.error(err),
+    };
+    if val.is_nil() {
+        state.push_nil();
+        return 1;
+    }
+    state.push_integer(index);
+    state.push_value(&val);
+    2
+}
+
+fn base_ipairs(state: &mut LuaState) -> usize {
+    check_any(state, 1, "ipairs");
+    state.push_lrfunc(ipairs_aux);
+    state.push_index(1);
+    state.push_integer(0);
+    3
+}
+
+fn base_next(state: &mut LuaState) -> usize {
+    let table = check_table(state, 1, "next");
+    let key = state.get_value(2);
+    match state.raw_next(&table, &key) {
+        Ok(Some((key, val))) => {
+            state.push_value(&key);
+            state.push_value(&val);
+            2
+        }
+        Ok(None) => {
+            state.push_nil();
+            1
+        }
+        Err(err) => state.error(err),
+    }
+}
+
+fn base_pairs(state: &mut LuaState) -> usize {
+    let obj = check_any(state, 1, "pairs");
+    let handler = state.get_metafield(&obj, "__pairs");
+    if handler.is_nil() {
+        state.push_lrfunc(base_next);
+        state.push_index(1);
+        state.push_nil();
+        return 3;
+    }
+    let mut results = match state.call_value(&handler, MultiValue::from([obj])) {
+        Ok(results) => results,
+        Err(err) => state.error(err),
+    };
+    results.resize(3, Value::Nil);
+    push_results(state, results)
+}
+
+fn base_select(state: &mut LuaState) -> usize {
+    let n = state.get_top() as INT;
+    if let Value::String(s) = state.get_value(1) {
+        if s.as_bytes().first() == Some(&b'#') {
+            state.push_integer(n - 1);
+            return 1;
+        }
+    }
+    let mut index = check_integer(state, 1, "select");
+    if index < 0 {
+        index += n;
+    } else if index > n {
+        index = n;
+    }
+    if index < 1 {
+        arg_error(state, 1, "select", "index out of range");
+    }
+    // the selected arguments are the top ones already
+    (n - index) as usize
+}
+
+fn base_rawequal(state: &mut LuaState) -> usize {
+    let a = check_any(state, 1, "rawequal");
+    let b = check_any(state, 2, "rawequal");
+    state.push_bool(a == b);
+    1
+}
+
+fn base_rawlen(state: &mut LuaState) -> usize {
+    let len = match state.get_value(1) {
+        Value::Table(table) => state.raw_len(&table),
+        Value::String(s) => s.as_bytes().len() as INT,
+        _ => arg_error(state, 1, "rawlen", "table or string expected"),
+    };
+    state.push_integer(len);
+    1
+}
+
+fn base_rawget(state: &mut LuaState) -> usize {
+    let table = check_table(state, 1, "rawget");
+    let key = check_any(state, 2, "rawget");
+    let val = state.raw_get(&table, &key);
+    state.push_value(&val);
+    1
+}
+
+fn base_rawset(state: &mut LuaState) -> usize {
+    let table = check_table(state, 1, "rawset");
+    let key = check_any(state, 2, "rawset");
+    let val = check_any(state, 3, "rawset");
+    if let Err(err) = state.raw_set(&table, key, val) {
+        state.error(err);
+    }
+    state.push_index(1);
+    1
+}
+
+fn base_getmetatable(state: &mut LuaState) -> usize {
+    let obj = check_any(state, 1, "getmetatable");
+    match state.get_metatable(&obj) {
+        Some(metatable) => {
+            let protected = state.raw_get(&metatable, &Value::from("__metatable"));
+            if protected.is_nil() {
+                state.push_value(&Value::Table(metatable));
+            } else {
+                state.push_value(&protected);
+            }
+        }
+        None => state.push_nil(),
+    }
+    1
+}
+
+fn base_setmetatable(state: &mut LuaState) -> usize {
+    let table = check_table(state, 1, "setmetatable");
+    let metatable = match state.get_value(2) {
+        Value::Table(metatable) => Some(metatable),
+        Value::Nil if !state.is_none(2) => None,
+        _ => type_error(state, 2, "setmetatable", "nil or table"),
+    };
+    if !state
+        .get_metafield(&Value::Table(table.clone()), "__metatable")
+        .is_nil()
+    {
+        state.error(LuaError::Runtime(
+            "cannot change a protected metatable".to_string(),
+        ));
+    }
+    state.set_metatable(&table, metatable.as_ref());
+    state.push_index(1);
+    1
+}
+
+fn base_assert(state: &mut LuaState) -> usize {
+    if state.get_value(1).is_truthy() {
+        return state.get_top();
+    }
+    check_any(state, 1, "assert");
+    let message = if state.is_none(2) {
+        Value::from("assertion failed!")
+    } else {
+        state.get_value(2)
+    };
+    state.error(LuaError::from_value(message))
+}
+
+fn base_error(state: &mut LuaState) -> usize {
+    // the level picks the position added to a message, a rust function
+    // has none to add
+    let _level = opt_integer(state, 2, "error", 1);
+    let val = state.get_value(1);
+    state.error(LuaError::from_value(val))
+}
+
+fn base_pcall(state: &mut LuaState) -> usize {
+    let func = check_any(state, 1, "pcall");
+    let args = args_from(state, 2);
+    match state.call_value(&func, args) {
+        Ok(mut results) => {
+            results.push_front(Value::Boolean(true));
+            push_results(state, results)
+        }
+        // a panic is no lua error, it goes on to the host
+        Err(err @ LuaError::CallbackPanic { .. }) => state.error(err),
+        Err(err) => {
+            state.push_bool(false);
+            state.push_value(&err.into_value());
+            2
+        }
+    }
+}
+
+fn base_xpcall(state: &mut LuaState) -> usize {
+    let handler = Value::Function(check_function(state, 2, "xpcall"));
+    let func = state.get_value(1);
+    let args = args_from(state, 3);
+    match state.call_value(&func, args) {
+        Ok(mut results) => {
+            results.push_front(Value::Boolean(true));
+            push_results(state, results)
+        }
+        Err(err @ LuaError::CallbackPanic { .. }) => state.error(err),
+        Err(err) => {
+            let result = match state.call_value(&handler, MultiValue::from([err.into_value()])) {
+                Ok(mut results) => results.pop_front().unwrap_or(Value::Nil),
+                Err(err @ LuaError::CallbackPanic { .. }) => state.error(err),
+                Err(_) => Value::from("error in error handling"),
+            };
+            state.push_bool(false);
+            state.push_value(&result);
+            2
+        }
+    }
+}
+
+/// brief: the pieces of a chunk returned by a reader function
+fn read_chunk(state: &mut LuaState, reader: &Value) -> Result<Vec<u8>, LuaError> {
+    let mut chunk = Vec::new();
+    loop {
+        let mut results = state.call_value(reader, MultiValue::new())?;
+        match results.pop_front() {
+            None | Some(Value::Nil) => return Ok(chunk),
+            Some(Value::String(piece)) if piece.as_bytes().is_empty() => return Ok(chunk),
+            Some(Value::String(piece)) => chunk.extend_from_slice(piece.as_bytes()),
+            Some(_) => {
+                return Err(LuaError::Runtime(
+                    "reader function must return a string".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+/// brief: push the function of a loaded chunk, or nil and the message
+fn load_aux(state: &mut LuaState, loaded: Result<Function, LuaError>) -> usize {
+    match loaded {
+        Ok(function) => {
+            state.push_value(&Value::Function(function));
+            1
+        }
+        Err(err @ LuaError::CallbackPanic { .. }) => state.error(err),
+        Err(err) => {
+            state.push_nil();
+            state.push_value(&err.into_value());
+            2
+        }
+    }
+}
+
+/// brief: load(chunk [, chunkname [, mode [, env]]]), the arguments are
+/// checked and a reader is drained, but the machine has no compiler and no
+/// undump: every chunk ends as nil and a message, see LuaState::load
+fn base_load(state: &mut LuaState) -> usize {
+    let mode = opt_lstring(state, 3, "load", "bt").to_string_lossy();
+    let loaded = match state.get_value(1) {
+        Value::String(s) => {
+            let chunkname = opt_lstring(state, 2, "load", &s.to_string_lossy());
+            state.load(s.as_bytes(), &chunkname.to_string_lossy(), &mode)
+        }
+        Value::Integer(_) | Value::Number(_) => {
+            let s = check_lstring(state, 1, "load");
+            let chunkname = opt_lstring(state, 2, "load", &s.to_string_lossy());
+            state.load(s.as_bytes(), &chunkname.to_string_lossy(), &mode)
+        }
+        _ => {
+            let chunkname = opt_lstring(state, 2, "load", "=(load)");
+            let reader = Value::Function(check_function(state, 1, "load"));
+            read_chunk(state, &reader)
+                .and_then(|chunk| state.load(&chunk, &chunkname.to_string_lossy(), &mode))
+        }
+    };
+    load_aux(state, loaded)
+}
+
+/// brief: load a file, stdin without a name. a first line starting with '#'
+/// is skipped, its newline is kept for the line numbers
+pub(crate) fn load_file(state: &mut LuaState, filename: Option<&str>, mode: &str) -> Result<Function, LuaError> {
+    let (chunkname, shown, read) = match filename {
+        Some(filename) => (format!("@{}", filename), filename, fs::read(filename)),
+        None => {
+            let mut chunk = Vec::new();
+            let read = io::stdin().lock().read_to_end(&mut chunk).map(|_| chunk);
+            ("=stdin".to_string(), "stdin", read)
+        }
+    };
+    let chunk = match read {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            let what = if filename.is_some() && err.kind() == io::ErrorKind::NotFound {
+                "open"
+            } else {
+                "read"
+            };
+            return Err(LuaError::Runtime(format!(
+                "cannot {} {}: {}",
+                what,
+                shown,
+                os_error_message(&err)
+            )));
+        }
+    };
+    let chunk = match chunk.first() {
+        Some(b'#') => &chunk[chunk.iter().position(|b| *b == b'\n').unwrap_or(chunk.len())..],
+        _ => &chunk[..],
+    };
+    state.load(chunk, &chunkname, mode)
+}
+
+/// brief: loadfile([filename [, mode]]), a missing file is reported as lua
+/// does, a file that is read is still refused as by load
+fn base_loadfile(state: &mut LuaState) -> usize {
+    let filename = (!is_none_or_nil(state, 1)).then(|| check_lstring(state, 1, "loadfile"));
+    let mode = opt_lstring(state, 2, "loadfile", "bt").to_string_lossy();
+    let filename = filename.map(|name| name.to_string_lossy());
+    let loaded = load_file(state, filename.as_deref(), &mode);
+    load_aux(state, loaded)
+}
+
+/// brief: dofile([filename]), the error of loadfile is raised, so a call
+/// never runs a chunk on this machine
+fn base_dofile(state: &mut LuaState) -> usize {
+    let filename = (!is_none_or_nil(state, 1)).then(|| check_lstring(state, 1, "dofile"));
+    let filename = filename.map(|name| name.to_string_lossy());
+    let function = match load_file(state, filename.as_deref(), "bt") {
+        Ok(function) => function,
+        Err(err) => state.error(err),
+    };
+    match state.call_value(&Value::Function(function), MultiValue::new()) {
+        Ok(results) => push_results(state, results),
+        Err(err) => state.error(err),
+    }
+}
+
+const GC_OPTIONS: &[&str] = &[
+    "stop",
+    "restart",
+    "collect",
+    "count",
+    "step",
+    "setpause",
+    "setstepmul",
+    "isrunning",
+    "generational",
+    "incremental",
+];
+
+fn base_collectgarbage(state: &mut LuaState) -> usize {
+    let fname = "collectgarbage";
+    match GC_OPTIONS[check_option(state, 1, fname, Some("collect"), GC_OPTIONS)] {
+        "stop" => {
+            state.set_gc_running(false);
+            state.push_integer(0);
+        }
+        "restart" => {
+            state.set_gc_running(true);
+            state.push_integer(0);
+        }
+        "collect" => {
+            state.collect_garbage();
+            state.push_integer(0);
+        }
+        "count" => state.push_float(state.get_total_bytes() as f64 / 1024.0),
+        "step" => {
+            // a step of n kbytes, a basic step when n is 0
+            let kbytes = opt_integer(state, 2, fname, 0).max(0) as usize;
+            let allocated = match kbytes {
+                0 => 1 << state.get_config().gc.step_size,
+                _ => kbytes.saturating_mul(1024),
+            };
+            let ended = state.step_garbage(allocated);
+            state.push_bool(ended);
+        }
+        option @ ("setpause" | "setstepmul") => {
+            let val = opt_integer(state, 2, fname, 0).clamp(0, u32::MAX as INT) as u32;
+            let params = state.get_gc_params_mut();
+            let previous = match option {
+                "setpause" => std::mem::replace(&mut params.pause, val),
+                _ => std::mem::replace(&mut params.step_mul, val),
+            };
+            state.push_integer(previous as INT);
+        }
+        "isrunning" => state.push_bool(state.is_gc_running()),
+        option => {
+            let generational = option == "generational";
+            if !generational {
+                let pause = opt_integer(state, 2, fname, 0).clamp(0, u32::MAX as INT) as u32;
+                let step_mul = opt_integer(state, 3, fname, 0).clamp(0, u32::MAX as INT) as u32;
+                let step_size = opt_integer(state, 4, fname, 0).clamp(0, u32::MAX as INT) as u32;
+                let params = state.get_gc_params_mut();
+                if pause != 0 {
+                    params.pause = pause;
+                }
+                if step_mul != 0 {
+                    params.step_mul = step_mul;
+                }
+                if step_size != 0 {
+                    params.step_size = step_size;
+                }
+            }
+            let previous = state.set_gc_generational(generational);
+            state.push_str(if previous { "generational" } else { "incremental" });
+        }
+    }
+    1
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::lua::{LuaError, LuaResult};
+    use crate::common::obj::objconv::MultiValue;
+    use crate::common::obj::objvalue::{Function, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+    use crate::stdlib::libtest::{call, call_err};
+
+    use super::str_to_int;
+
+    #[test]
+    fn globals_are_opened() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let version: String = state.get_global("_VERSION").unwrap();
+        assert_eq!(version, "Lua 5.4");
+        let g: Table = state.get_global("_G").unwrap();
+        assert_eq!(g, state.globals());
+        for name in ["print", "pcall", "xpcall", "select", "collectgarbage", "warn"] {
+            assert!(matches!(
+                state.get_global::<Value>(name).unwrap(),
+                Value::Function(_)
+            ));
+        }
+
+        let bare = Machine::builder()
+            .libs(crate::common::lua::StdLib::NONE)
+            .build()
+            .unwrap();
+        let mut bare = bare;
+        assert_eq!(bare.get_state().get_global::<Value>("print").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn type_tostring_tonumber() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        assert_eq!(
+            call(state, "type", 1.5).unwrap(),
+            MultiValue::from([Value::from("number")])
+        );
+        assert_eq!(
+            call_err(state, "type", ()),
+            "bad argument #1 to 'type' (value expected)"
+        );
+        assert_eq!(
+            call(state, "tostring", 10.0).unwrap(),
+            MultiValue::from([Value::from("10.0")])
+        );
+        assert_eq!(
+            call(state, "tostring", ()).map_err(|e| e.to_string()),
+            Err("bad argument #1 to 'tostring' (value expected)".to_string())
+        );
+
+        let t = state.create_table_value(0, 0).unwrap();
+        let text: String = state
+            .get_global::<Function>("tostring")
+            .unwrap()
+            .call(state, t.clone())
+            .unwrap();
+        assert!(text.starts_with("table: 0x"), "{}", text);
+        let mt = state.create_table_value(0, 0).unwrap();
+        mt.set(state, "__name", "Point").unwrap();
+        t.set_metatable(state, Some(&mt));
+        let text: String = state
+            .get_global::<Function>("tostring")
+            .unwrap()
+            .call(state, t.clone())
+            .unwrap();
+        assert!(text.starts_with("Point: 0x"), "{}", text);
+        let tostring = state.create_function(|_, _: Table| Ok("point")).unwrap();
+        mt.set(state, "__tostring", tostring).unwrap();
+        let text: String = state
+            .get_global::<Function>("tostring")
+            .unwrap()
+            .call(state, t.clone())
+            .unwrap();
+        assert_eq!(text, "point");
+        let tostring = state.create_function(|_, _: Table| Ok(1)).unwrap();
+        mt.set(state, "__tostring", tostring).unwrap();
+        assert_eq!(
+            call_err(state, "tostring", t),
+            "'__tostring' must return a string"
+        );
+
+        let tonumber =
+            |state: &mut LuaState, args: MultiValue| call(state, "tonumber", args).unwrap()[0].clone();
+        assert_eq!(
+            tonumber(state, MultiValue::from([Value::from(" 0x10 ")])),
+            Value::Integer(16)
+        );
+        assert_eq!(
+            tonumber(state, MultiValue::from([Value::from("1e2")])),
+            Value::Number(100.0)
+        );
+        assert_eq!(
+            tonumber(state, MultiValue::from([Value::from("0x1p4")])),
+            Value::Number(16.0)
+        );
+        assert_eq!(
+            tonumber(state, MultiValue::from([Value::from("0x1.8")])),
+            Value::Number(1.5)
+        );
+        assert_eq!(tonumber(state, MultiValue::from([Value::from("z")])), Value::Nil);
+        assert_eq!(
+            tonumber(state, MultiValue::from([Value::Boolean(true)])),
+            Value::Nil
+        );
+        assert_eq!(
+            tonumber(
+                state,
+                MultiValue::from([Value::from("7fffffffffffffff"), Value::Integer(16)])
+            ),
+            Value::Integer(i64::MAX)
+        );
+        assert_eq!(
+            tonumber(state, MultiValue::from([Value::from("zz"), Value::Integer(36)])),
+            Value::Integer(1295)
+        );
+        assert_eq!(
+            call_err(state, "tonumber", (10, 16)),
+            "bad argument #1 to 'tonumber' (string expected, got number)"
+        );
+        assert_eq!(
+            call_err(state, "tonumber", ("1", 99)),
+            "bad argument #2 to 'tonumber' (base out of range)"
+        );
+        assert_eq!(
+            call_err(state, "tonumber", ()),
+            "bad argument #1 to 'tonumber' (value expected)"
+        );
+    }
+
+    #[test]
+    fn select_and_raw_access() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        assert_eq!(
+            call(state, "select", ("#", 1, 2, 3)).unwrap(),
+            MultiValue::from([Value::Integer(3)])
+        );
+        let tail: (i64, i64) = state
+            .get_global::<Function>("select")
+            .unwrap()
+            .call(state, (2, "a", 5, 6))
+            .unwrap();
+        assert_eq!(tail, (5, 6));
+        let last: i64 = state
+            .get_global::<Function>("select")
+            .unwrap()
+            .call(state, (-1, 4, 5, 6))
+            .unwrap();
+        assert_eq!(last, 6);
+        assert_eq!(call(state, "select", (9, 1)).unwrap(), MultiValue::new());
+        assert_eq!(
+            call_err(state, "select", (-3, 1)),
+            "bad argument #1 to 'select' (index out of range)"
+        );
+        assert_eq!(
+            call_err(state, "select", 1.5),
+            "bad argument #1 to 'select' (number has no integer representation)"
+        );
+
+        let t = state.create_table_value(0, 0).unwrap();
+        call(state, "rawset", (t.clone(), "k", 1)).unwrap();
+        assert_eq!(
+            call(state, "rawget", (t.clone(), "k")).unwrap()[0],
+            Value::Integer(1)
+        );
+        assert_eq!(
+            call_err(state, "rawset", (t.clone(), Value::Nil, 1)),
+            "index is nil"
+        );
+        assert_eq!(call(state, "rawlen", "abc").unwrap()[0], Value::Integer(3));
+        assert_eq!(
+            call_err(state, "rawlen", 1),
+            "bad argument #1 to 'rawlen' (table or string expected)"
+        );
+        assert_eq!(
+            call(state, "rawequal", (1, 1.0)).unwrap()[0],
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            call(state, "rawequal", (t.clone(), t)).unwrap()[0],
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            call_err(state, "rawequal", 1),
+            "bad argument #2 to 'rawequal' (value expected)"
+        );
+    }
+
+    #[test]
+    fn metatables_can_be_protected() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let t = state.create_table_value(0, 0).unwrap();
+        let mt = state.create_table_value(0, 0).unwrap();
+        call(state, "setmetatable", (t.clone(), mt.clone())).unwrap();
+        assert_eq!(
+            call(state, "getmetatable", t.clone()).unwrap()[0],
+            Value::Table(mt.clone())
+        );
+        mt.set(state, "__metatable", "locked").unwrap();
+        assert_eq!(
+            call(state, "getmetatable", t.clone()).unwrap()[0],
+            Value::from("locked")
+        );
+        assert_eq!(
+            call_err(state, "setmetatable", (t.clone(), Value::Nil)),
+            "cannot change a protected metatable"
+        );
+        assert_eq!(
+            call_err(state, "setmetatable", t),
+            "bad argument #2 to 'setmetatable' (nil or table expected, got no value)"
+        );
+        assert_eq!(
+            call_err(state, "setmetatable", (1, Value::Nil)),
+            "bad argument #1 to 'setmetatable' (table expected, got number)"
+        );
+    }
+
+    #[test]
+    fn iteration_functions() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let t = state.create_table_value(0, 0).unwrap();
+        for i in 1..=3 {
+            t.raw_set(state, i, i * 10).unwrap();
+        }
+
+        // ipairs goes through __index and stops at the first nil
+        let fallback = state.create_table_value(0, 0).unwrap();
+        fallback.raw_set(state, 4, 40).unwrap();
+        let mt = state.create_table_value(0, 0).unwrap();
+        mt.set(state, "__index", fallback).unwrap();
+        t.set_metatable(state, Some(&mt));
+        let (iter, obj, start): (Function, Value, i64) = state
+            .get_global::<Function>("ipairs")
+            .unwrap()
+            .call(state, t.clone())
+            .unwrap();
+        assert_eq!(start, 0);
+        let mut index = 0;
+        let mut sum = 0;
+        loop {
+            let (i, v): (Option<i64>, Option<i64>) = iter.call(state, (obj.clone(), index)).unwrap();
+            match (i, v) {
+                (Some(i), Some(v)) => {
+                    index = i;
+                    sum += v;
+                }
+                _ => break,
+            }
+        }
+        assert_eq!((index, sum), (4, 100));
+
+        // pairs without __pairs is next, t, nil
+        let (next, _, key): (Function, Value, Value) = state
+            .get_global::<Function>("pairs")
+            .unwrap()
+            .call(state, t.clone())
+            .unwrap();
+        assert_eq!(key, Value::Nil);
+        let (k, v): (i64, i64) = next.call(state, (t.clone(), Value::Nil)).unwrap();
+        assert_eq!((k, v), (1, 10));
+        let end: Value = next.call(state, (t.clone(), 3)).unwrap();
+        assert_eq!(end, Value::Nil);
+        assert_eq!(
+            call_err(state, "next", (t.clone(), "missing")),
+            "invalid key to 'next'"
+        );
+
+        let custom = state.create_function(|_, _: Table| Ok(("iter", "state"))).unwrap();
+        mt.set(state, "__pairs", custom).unwrap();
+        let results = call(state, "pairs", t).unwrap();
+        assert_eq!(
+            results,
+            MultiValue::from([Value::from("iter"), Value::from("state"), Value::Nil])
+        );
+        assert_eq!(
+            call_err(state, "ipairs", ()),
+            "bad argument #1 to 'ipairs' (value expected)"
+        );
+    }
+
+    #[test]
+    fn protected_calls() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let error: Function = state.get_global("error").unwrap();
+        let assert: Function = state.get_global("assert").unwrap();
+
+        let results = call(state, "pcall", (error.clone(), "boom")).unwrap();
+        assert_eq!(
+            results,
+            MultiValue::from([Value::Boolean(false), Value::from("boom")])
+        );
+        let object = state.create_table_value(0, 0).unwrap();
+        let results = call(state, "pcall", (error.clone(), object.clone())).unwrap();
+        assert_eq!(
+            results,
+            MultiValue::from([Value::Boolean(false), Value::Table(object.clone())])
+        );
+        let results = call(state, "pcall", (assert.clone(), 1, 2)).unwrap();
+        assert_eq!(
+            results,
+            MultiValue::from([Value::Boolean(true), Value::Integer(1), Value::Integer(2)])
+        );
+        let results = call(state, "pcall", (assert.clone(), false)).unwrap();
+        assert_eq!(results[1], Value::from("assertion failed!"));
+        let results = call(state, "pcall", (assert, Value::Nil, 42)).unwrap();
+        assert_eq!(results[1], Value::Integer(42));
+        let results = call(state, "pcall", ()).map_err(|e| e.to_string());
+        assert_eq!(
+            results,
+            Err("bad argument #1 to 'pcall' (value expected)".to_string())
+        );
+        let results = call(state, "pcall", 1).unwrap();
+        assert_eq!(results[1], Value::from("attempt to call a number value"));
+
+        // an error object escaping to the host
+        let err = error.call::<_, ()>(state, object.clone()).unwrap_err();
+        assert_eq!(err, LuaError::Object(Value::Table(object)));
+        assert_eq!(err.to_string(), "(error object is a table value)");
+
+        // the handler of xpcall sees the error object
+        let handler = state.create_function(|_, msg: String| Ok(format!("handled: {}", msg))).unwrap();
+        let results = call(state, "xpcall", (error.clone(), handler, "oops")).unwrap();
+        assert_eq!(
+            results,
+            MultiValue::from([Value::Boolean(false), Value::from("handled: oops")])
+        );
+        let results = call(state, "xpcall", (error.clone(), error.clone(), "oops")).unwrap();
+        assert_eq!(results[1], Value::from("error in error handling"));
+        assert_eq!(
+            call_err(state, "xpcall", (error, 1)),
+            "bad argument #2 to 'xpcall' (function expected, got number)"
+        );
+
+        // a table with __call can be called
+        let callable = state.create_table_value(0, 0).unwrap();
+        let mt = state.create_table_value(0, 0).unwrap();
+        let handler = state.create_function(|_, (_, x): (Table, i64)| Ok(x * 2)).unwrap();
+        mt.set(state, "__call", handler).unwrap();
+        callable.set_metatable(state, Some(&mt));
+        let results = call(state, "pcall", (callable, 21)).unwrap();
+        assert_eq!(
+            results,
+            MultiValue::from([Value::Boolean(true), Value::Integer(42)])
+        );
+    }
+
+    #[test]
+    fn pcall_does_not_catch_panics() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let panics = state.create_function(|_, ()| -> LuaResult<()> { panic!("bug") }).unwrap();
+        let err = call(state, "pcall", panics).unwrap_err();
+        assert!(matches!(err, LuaError::CallbackPanic { .. }));
+    }
+
+    #[test]
+    fn loading_needs_a_compiler() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let results = call(state, "load", "return 1").unwrap();
+        assert_eq!(results[0], Value::Nil);
+        assert_eq!(
+            results[1],
+            Value::from("[string \"return 1\"]: cannot load a text chunk, this machine has no compiler")
+        );
+        let results = call(state, "load", ("return 1", "=chunk", "b")).unwrap();
+        assert_eq!(
+            results[1],
+            Value::from("attempt to load a text chunk (mode is 'b')")
+        );
+        let results = call(state, "load", ("\x1bLua", "=chunk", "t")).unwrap();
+        assert_eq!(
+            results[1],
+            Value::from("attempt to load a binary chunk (mode is 't')")
+        );
+
+        // the pieces of a reader must be strings
+        let reader = state.create_function(|_, ()| Ok(1)).unwrap();
+        let results = call(state, "load", reader).unwrap();
+        assert_eq!(results[1], Value::from("reader function must return a string"));
+        let results = call(state, "load", "a\nb").unwrap();
+        assert_eq!(
+            results[1],
+            Value::from("[string \"a...\"]: cannot load a text chunk, this machine has no compiler")
+        );
+
+        let results = call(state, "loadfile", "/nonexistent/file.lua").unwrap();
+        assert_eq!(
+            results[1],
+            Value::from("cannot open /nonexistent/file.lua: No such file or directory")
+        );
+        assert_eq!(
+            call_err(state, "dofile", "/nonexistent/file.lua"),
+            "cannot open /nonexistent/file.lua: No such file or directory"
+        );
+    }
+
+    #[test]
+    fn collectgarbage_options() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        assert_eq!(call(state, "collectgarbage", ()).unwrap()[0], Value::Integer(0));
+        assert!(matches!(call(state, "collectgarbage", "count").unwrap()[0], Value::Number(kb) if kb > 0.0));
+        assert_eq!(
+            call(state, "collectgarbage", "isrunning").unwrap()[0],
+            Value::Boolean(true)
+        );
+        call(state, "collectgarbage", "stop").unwrap();
+        assert_eq!(
+            call(state, "collectgarbage", "isrunning").unwrap()[0],
+            Value::Boolean(false)
+        );
+        call(state, "collectgarbage", "restart").unwrap();
+        // basic steps end the cycle once their work covers the live bytes
+        let ended = (0..1000).find(|_| call(state, "collectgarbage", "step").unwrap()[0] == Value::Boolean(true));
+        assert!(ended.is_some());
+        assert_eq!(
+            call(state, "collectgarbage", ("step", 1 << 20)).unwrap()[0],
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            call(state, "collectgarbage", ("setpause", 150)).unwrap()[0],
+            Value::Integer(200)
+        );
+        assert_eq!(
+            call(state, "collectgarbage", ("setpause", 200)).unwrap()[0],
+            Value::Integer(150)
+        );
+        assert_eq!(
+            call(state, "collectgarbage", "generational").unwrap()[0],
+            Value::from("incremental")
+        );
+        assert_eq!(
+            call(state, "collectgarbage", "incremental").unwrap()[0],
+            Value::from("generational")
+        );
+        assert_eq!(
+            call_err(state, "collectgarbage", "sweep"),
+            "bad argument #1 to 'collectgarbage' (invalid option 'sweep')"
+        );
+        assert_eq!(
+            call_err(state, "warn", ("a", 1, Value::Nil)),
+            "bad argument #3 to 'warn' (string expected, got nil)"
+        );
+        call(state, "warn", "@on").unwrap();
+        call(state, "warn", ("one", "two")).unwrap();
+    }
+
+    #[test]
+    fn integer_numerals_in_a_base() {
+        assert_eq!(str_to_int(b"ff", 16), Some(255));
+        assert_eq!(str_to_int(b"  -Zz\t", 36), Some(-1295));
+        assert_eq!(str_to_int(b"8", 8), None);
+        assert_eq!(str_to_int(b"1 0", 2), None);
+        assert_eq!(str_to_int(b"", 10), None);
+        assert_eq!(str_to_int(b"-", 10), None);
+    }
+}
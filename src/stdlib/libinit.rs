@@ -0,0 +1,43 @@
+use crate::common::lua::StdLib;
+use crate::common::obj::objvalue::Value;
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::get_subtable;
+use super::libbase::open_base;
+use super::libio::open_io;
+use super::libmath::open_math;
+use super::libos::open_os;
+use super::libpackage::{open_package, LOADED_TABLE};
+use super::libstring::open_string;
+use super::libtable::open_table;
+use super::libutf8::open_utf8;
+
+type OpenLib = fn(&mut LuaState);
+
+/// brief: the standard libraries in the order they are opened, with the
+/// global each one is kept in
+const LOADED_LIBS: &[(StdLib, &str, OpenLib)] = &[
+    (StdLib::BASE, "_G", open_base),
+    (StdLib::PACKAGE, "package", open_package),
+    (StdLib::STRING, "string", open_string),
+    (StdLib::TABLE, "table", open_table),
+    (StdLib::MATH, "math", open_math),
+    (StdLib::IO, "io", open_io),
+    (StdLib::OS, "os", open_os),
+    (StdLib::UTF8, "utf8", open_utf8),
+];
+
+/// brief: open the standard libraries in `libs`, each one is put in
+/// package.loaded as well
+pub fn open_libs(state: &mut LuaState, libs: StdLib) {
+    let registry = state.registry();
+    let loaded = get_subtable(state, &registry, LOADED_TABLE);
+    for (lib, name, open) in LOADED_LIBS {
+        if libs.contains(*lib) {
+            open(state);
+            let globals = state.globals();
+            let module = state.raw_get(&globals, &Value::from(*name));
+            let _ = state.raw_set(&loaded, Value::from(*name), module);
+        }
+    }
+}
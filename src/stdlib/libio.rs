@@ -0,0 +1,1213 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::rc::Rc;
+
+use crate::common::lua::LuaError;
+use crate::common::obj::objconv::{fmt_number, str_to_number};
+use crate::common::obj::objtype::{INT, LRFUNC};
+use crate::common::obj::objud::UserData;
+use crate::common::obj::objvalue::{AnyUserData, LuaString, Value};
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::create_temp_file;
+use super::libaux::{arg_error, arg_type_name, check_any, check_integer, check_lstring, check_option};
+use super::libaux::{exec_result, file_result, is_none_or_nil, new_lib, opt_integer, opt_lstring};
+use super::libaux::{os_error_message, type_error, value_address};
+
+const BUFSIZ: usize = 8192; // the buffer a file starts with
+const LUAL_BUFFERSIZE: usize = 1024; // the default size of setvbuf
+const L_MAXLENNUM: usize = 200; // the longest numeral read
+const MAXARGLINE: usize = 250; // the most formats of lines
+
+const ESPIPE: i32 = 29;
+const EBADF: i32 = 9;
+const EINVAL: i32 = 22;
+
+const IO_INPUT: &str = "_IO_input";
+const IO_OUTPUT: &str = "_IO_output";
+
+const IO_FUNCS: &[(&str, LRFUNC)] = &[
+    ("close", io_close),
+    ("flush", io_flush),
+    ("input", io_input),
+    ("lines", io_lines),
+    ("open", io_open),
+    ("output", io_output),
+    ("popen", io_popen),
+    ("read", io_read),
+    ("tmpfile", io_tmpfile),
+    ("type", io_type),
+    ("write", io_write),
+];
+
+const FILE_METHODS: &[(&str, LRFUNC)] = &[
+    ("close", f_close),
+    ("flush", f_flush),
+    ("lines", f_lines),
+    ("read", f_read),
+    ("seek", f_seek),
+    ("setvbuf", f_setvbuf),
+    ("write", f_write),
+];
+
+const FILE_META: &[(&str, LRFUNC)] = &[("__close", f_gc), ("__gc", f_gc), ("__tostring", f_tostring)];
+
+/// brief: the stream under a file handle
+enum Handle {
+    File(fs::File),
+    Stdin,
+    Stdout,
+    Stderr,
+    Pipe(Child), // the end of the pipe the handle uses is kept in the child
+}
+
+fn bad_fd() -> io::Error {
+    io::Error::from_raw_os_error(EBADF)
+}
+
+impl Handle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Handle::File(file) => file.read(buf),
+            Handle::Stdin => io::stdin().lock().read(buf),
+            Handle::Pipe(child) => child.stdout.as_mut().ok_or_else(bad_fd)?.read(buf),
+            Handle::Stdout | Handle::Stderr => Err(bad_fd()),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Handle::File(file) => file.write_all(buf),
+            Handle::Stdout => io::stdout().lock().write_all(buf),
+            Handle::Stderr => io::stderr().lock().write_all(buf),
+            Handle::Pipe(child) => child.stdin.as_mut().ok_or_else(bad_fd)?.write_all(buf),
+            Handle::Stdin => Err(bad_fd()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Handle::File(file) => file.flush(),
+            Handle::Stdout => io::stdout().flush(),
+            Handle::Stderr => io::stderr().flush(),
+            Handle::Pipe(child) => child.stdin.as_mut().map_or(Ok(()), |stdin| stdin.flush()),
+            Handle::Stdin => Ok(()),
+        }
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Handle::File(file) => file.seek(pos),
+            _ => Err(io::Error::from_raw_os_error(ESPIPE)),
+        }
+    }
+}
+
+/// brief: the buffering of the writes, as setvbuf sets it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BufMode {
+    No,
+    Full,
+    Line,
+}
+
+/// brief: a format of read and lines
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Chars(usize),
+    Number,
+    Line { chop: bool },
+    All,
+}
+
+/// brief: a file handle, the stream is closed when the handle is collected
+pub struct LuaFile {
+    handle: Option<Handle>, // none once closed
+    writable: bool,         // the stream was opened for writing
+    rbuf: Vec<u8>,
+    rpos: usize,
+    wbuf: Vec<u8>,
+    mode: BufMode,
+    size: usize,
+}
+
+impl UserData for LuaFile {}
+
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl LuaFile {
+    fn new(handle: Handle, writable: bool) -> Self {
+        // the standard streams share the buffers of print
+        let mode = match handle {
+            Handle::Stdin | Handle::Stdout | Handle::Stderr => BufMode::No,
+            _ => BufMode::Full,
+        };
+        Self {
+            handle: Some(handle),
+            writable,
+            rbuf: Vec::new(),
+            rpos: 0,
+            wbuf: Vec::new(),
+            mode,
+            size: BUFSIZ,
+        }
+    }
+
+    fn is_std(&self) -> bool {
+        matches!(self.handle, Some(Handle::Stdin | Handle::Stdout | Handle::Stderr))
+    }
+
+    fn handle(&mut self) -> io::Result<&mut Handle> {
+        self.handle.as_mut().ok_or_else(bad_fd)
+    }
+
+    fn flush_write(&mut self) -> io::Result<()> {
+        if self.wbuf.is_empty() {
+            return Ok(());
+        }
+        let wbuf = std::mem::take(&mut self.wbuf);
+        self.handle()?.write_all(&wbuf)
+    }
+
+    /// brief: drop the bytes read ahead, the stream goes back to the position
+    /// the reader is at
+    fn discard_read(&mut self) -> io::Result<()> {
+        let unread = self.rbuf.len() - self.rpos;
+        self.rbuf.clear();
+        self.rpos = 0;
+        match self.handle()? {
+            Handle::File(file) if unread > 0 => file.seek(SeekFrom::Current(-(unread as i64))).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.rpos == self.rbuf.len() {
+            self.flush_write()?;
+            self.rbuf.resize(BUFSIZ, 0);
+            self.rpos = 0;
+            let read = loop {
+                match self.handle.as_mut().ok_or_else(bad_fd)?.read(&mut self.rbuf) {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    read => break read,
+                }
+            };
+            self.rbuf.truncate(*read.as_ref().unwrap_or(&0));
+            read?;
+        }
+        Ok(&self.rbuf[self.rpos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.rpos += n;
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.fill_buf()?.first().copied())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        // a stream not opened for writing fails at once, as fwrite does,
+        // instead of when the buffer is flushed
+        if !self.writable {
+            return Err(bad_fd());
+        }
+        self.discard_read()?;
+        match self.mode {
+            BufMode::No => {
+                self.flush_write()?;
+                self.handle()?.write_all(data)
+            }
+            BufMode::Full | BufMode::Line => {
+                self.wbuf.extend_from_slice(data);
+                if self.wbuf.len() >= self.size || (self.mode == BufMode::Line && data.contains(&b'\n')) {
+                    self.flush_write()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_write()?;
+        self.handle()?.flush()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush_write()?;
+        // the stream is ahead of the reader by the unread bytes
+        let unread = (self.rbuf.len() - self.rpos) as i64;
+        let pos = match pos {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - unread),
+            pos => pos,
+        };
+        let res = self.handle()?.seek(pos);
+        if res.is_ok() {
+            self.rbuf.clear();
+            self.rpos = 0;
+        }
+        res
+    }
+
+    fn setvbuf(&mut self, mode: BufMode, size: usize) -> io::Result<()> {
+        self.flush_write()?;
+        if !self.is_std() {
+            self.mode = mode;
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    /// brief: close the stream, the status of the process for a pipe
+    fn close(&mut self) -> io::Result<Option<ExitStatus>> {
+        let flushed = self.flush_write().and_then(|_| self.handle()?.flush());
+        let closed = match self.handle.take() {
+            Some(Handle::Pipe(mut child)) => {
+                drop(child.stdin.take());
+                drop(child.stdout.take());
+                child.wait().map(Some)
+            }
+            _ => Ok(None),
+        };
+        self.rbuf.clear();
+        self.rpos = 0;
+        flushed.and(closed)
+    }
+
+    fn read_chars(&mut self, n: usize) -> io::Result<Option<Value>> {
+        let mut bytes = Vec::new();
+        while bytes.len() < n {
+            let buf = self.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let take = buf.len().min(n - bytes.len());
+            bytes.extend_from_slice(&buf[..take]);
+            self.consume(take);
+        }
+        Ok((!bytes.is_empty()).then(|| Value::String(LuaString::new(&bytes))))
+    }
+
+    fn read_line(&mut self, chop: bool) -> io::Result<Option<Value>> {
+        let mut bytes = Vec::new();
+        let mut newline = false;
+        loop {
+            let buf = self.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            match buf.iter().position(|b| *b == b'\n') {
+                Some(end) => {
+                    bytes.extend_from_slice(&buf[..end + usize::from(!chop)]);
+                    self.consume(end + 1);
+                    newline = true;
+                    break;
+                }
+                None => {
+                    let n = buf.len();
+                    bytes.extend_from_slice(buf);
+                    self.consume(n);
+                }
+            }
+        }
+        Ok((newline || !bytes.is_empty()).then(|| Value::String(LuaString::new(&bytes))))
+    }
+
+    fn read_all(&mut self) -> io::Result<Value> {
+        let mut bytes = Vec::new();
+        loop {
+            let buf = self.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let n = buf.len();
+            bytes.extend_from_slice(buf);
+            self.consume(n);
+        }
+        Ok(Value::String(LuaString::new(&bytes)))
+    }
+
+    /// brief: read the longest prefix of a numeral, as lua's l_getn does, and
+    /// convert it. fails when the prefix is no numeral
+    fn read_number(&mut self) -> io::Result<Option<Value>> {
+        let mut reader = NumReader {
+            file: self,
+            buff: Vec::new(),
+            overflow: false,
+        };
+        while reader
+            .current()?
+            .is_some_and(|c| c.is_ascii_whitespace() || c == 0x0b)
+        {
+            reader.file.consume(1);
+        }
+        reader.test2(b"-+")?;
+        let mut count = 0;
+        let mut hex = false;
+        if reader.test2(b"00")? {
+            if reader.test2(b"xX")? {
+                hex = true;
+            } else {
+                count = 1;
+            }
+        }
+        count += reader.read_digits(hex)?;
+        if reader.test2(b"..")? {
+            count += reader.read_digits(hex)?;
+        }
+        if count > 0 && reader.test2(if hex { b"pP" } else { b"eE" })? {
+            reader.test2(b"-+")?;
+            reader.read_digits(false)?;
+        }
+        if reader.overflow {
+            return Ok(None);
+        }
+        Ok(str_to_number(&reader.buff))
+    }
+
+    fn read_formats(&mut self, formats: &[Format]) -> io::Result<Vec<Value>> {
+        let mut results = Vec::with_capacity(formats.len());
+        for format in formats {
+            let val = match *format {
+                Format::Chars(0) => {
+                    // test for the end of the file
+                    let eof = self.fill_buf()?.is_empty();
+                    (!eof).then(|| Value::from(""))
+                }
+                Format::Chars(n) => self.read_chars(n)?,
+                Format::Number => self.read_number()?,
+                Format::Line { chop } => self.read_line(chop)?,
+                Format::All => Some(self.read_all()?),
+            };
+            match val {
+                Some(val) => results.push(val),
+                None => {
+                    results.push(Value::Nil);
+                    break;
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// brief: the state of read_number, `buff` holds the numeral read so far
+struct NumReader<'a> {
+    file: &'a mut LuaFile,
+    buff: Vec<u8>,
+    overflow: bool,
+}
+
+impl NumReader<'_> {
+    fn current(&mut self) -> io::Result<Option<u8>> {
+        self.file.peek()
+    }
+
+    /// brief: take the current char into the numeral, false when too long
+    fn next_char(&mut self, c: u8) -> bool {
+        if self.buff.len() >= L_MAXLENNUM {
+            self.overflow = true;
+            return false;
+        }
+        self.buff.push(c);
+        self.file.consume(1);
+        true
+    }
+
+    /// brief: take the current char if it is either char of `set`
+    fn test2(&mut self, set: &[u8; 2]) -> io::Result<bool> {
+        match self.current()? {
+            Some(c) if set.contains(&c) => Ok(self.next_char(c)),
+            _ => Ok(false),
+        }
+    }
+
+    fn read_digits(&mut self, hex: bool) -> io::Result<usize> {
+        let mut count = 0;
+        while let Some(c) = self.current()? {
+            let digit = if hex {
+                c.is_ascii_hexdigit()
+            } else {
+                c.is_ascii_digit()
+            };
+            if !digit || !self.next_char(c) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// brief: the file userdata of `val` and whether it is open
+fn file_state(state: &LuaState, val: &Value) -> Option<(AnyUserData, bool)> {
+    match val {
+        Value::UserData(ud) => {
+            let open = state
+                .borrow_userdata::<LuaFile, _>(ud, |file| file.handle.is_some())
+                .ok()?;
+            Some((ud.clone(), open))
+        }
+        _ => None,
+    }
+}
+
+/// brief: an open file argument
+fn check_file(state: &mut LuaState, arg: isize, fname: &str) -> AnyUserData {
+    let val = state.get_value(arg);
+    match file_state(state, &val) {
+        Some((ud, true)) => ud,
+        Some((_, false)) => state.error(LuaError::Runtime("attempt to use a closed file".to_string())),
+        None => type_error(state, arg, fname, "FILE*"),
+    }
+}
+
+/// brief: run `f` on the file of a userdata
+fn with_file<R>(state: &mut LuaState, ud: &AnyUserData, f: impl FnOnce(&mut LuaFile) -> R) -> R {
+    match state.borrow_userdata_mut(ud, f) {
+        Ok(res) => res,
+        Err(err) => state.error(err),
+    }
+}
+
+fn new_file(state: &mut LuaState, handle: Handle, writable: bool) -> AnyUserData {
+    match state.create_userdata(LuaFile::new(handle, writable)) {
+        Ok(ud) => ud,
+        Err(err) => state.error(err),
+    }
+}
+
+/// brief: a mode of fopen: one of "rwa", an optional '+' and any 'b'
+fn check_mode(mode: &[u8]) -> bool {
+    match mode.split_first() {
+        Some((b'r' | b'w' | b'a', rest)) => {
+            let rest = rest.strip_prefix(b"+").unwrap_or(rest);
+            rest.iter().all(|c| *c == b'b')
+        }
+        _ => false,
+    }
+}
+
+fn open_file(filename: &str, mode: &[u8]) -> io::Result<fs::File> {
+    let plus = mode.get(1) == Some(&b'+');
+    let mut options = OpenOptions::new();
+    match mode[0] {
+        b'r' => options.read(true).write(plus),
+        b'w' => options.write(true).read(plus).create(true).truncate(true),
+        _ => options.append(true).read(plus).create(true),
+    };
+    options.open(filename)
+}
+
+/// brief: the mode of fopen writes, "w", "a" or any mode with '+'
+fn is_writable(mode: &[u8]) -> bool {
+    mode[0] != b'r' || mode.get(1) == Some(&b'+')
+}
+
+/// brief: a new file removed as soon as it is made, the stream keeps it
+/// until it is closed
+fn tmp_file() -> io::Result<fs::File> {
+    let (path, file) = create_temp_file()?;
+    let _ = fs::remove_file(path);
+    Ok(file)
+}
+
+/// brief: the formats from the argument `first`, a line when there is none.
+/// the arguments are numbered from `first` in messages
+fn check_formats(state: &mut LuaState, first: isize, fname: &str) -> Vec<Format> {
+    let top = state.get_top() as isize;
+    if top < first {
+        return vec![Format::Line { chop: true }];
+    }
+    let mut formats = Vec::with_capacity((top - first + 1) as usize);
+    for arg in first..=top {
+        let format = match state.get_value(arg) {
+            Value::Integer(_) | Value::Number(_) => {
+                let n = check_integer(state, arg, fname);
+                Format::Chars(n as u64 as usize)
+            }
+            val => {
+                let format = match val {
+                    Value::String(s) => s,
+                    _ => {
+                        let actual = arg_type_name(state, arg);
+                        let message = format!("string expected, got {}", actual);
+                        arg_error(state, arg - first + 1, fname, &message)
+                    }
+                };
+                let bytes = format.as_bytes();
+                // the '*' of lua 5.3 is accepted
+                let bytes = bytes.strip_prefix(b"*").unwrap_or(bytes);
+                match bytes.first() {
+                    Some(b'n') => Format::Number,
+                    Some(b'l') => Format::Line { chop: true },
+                    Some(b'L') => Format::Line { chop: false },
+                    Some(b'a') => Format::All,
+                    _ => arg_error(state, arg - first + 1, fname, "invalid format"),
+                }
+            }
+        };
+        formats.push(format);
+    }
+    formats
+}
+
+fn g_read(state: &mut LuaState, ud: &AnyUserData, first: isize) -> usize {
+    let formats = check_formats(state, first, "read");
+    if state.stack_check(formats.len()).is_err() {
+        state.error(LuaError::Runtime("too many arguments".to_string()));
+    }
+    match with_file(state, ud, |file| file.read_formats(&formats)) {
+        Ok(results) => {
+            for val in results.iter() {
+                state.push_value(val);
+            }
+            results.len()
+        }
+        Err(err) => file_result(state, Err(err), None),
+    }
+}
+
+fn g_write(state: &mut LuaState, ud: &AnyUserData, first: isize) -> usize {
+    let mut data = Vec::new();
+    for arg in first..=state.get_top() as isize {
+        match state.get_value(arg) {
+            Value::String(s) => data.extend_from_slice(s.as_bytes()),
+            Value::Integer(i) => data.extend_from_slice(i.to_string().as_bytes()),
+            Value::Number(n) => {
+                // written as "%.14g", without the ".0" of tostring
+                let text = fmt_number(n);
+                data.extend_from_slice(text.strip_suffix(".0").unwrap_or(&text).as_bytes());
+            }
+            _ => {
+                let actual = arg_type_name(state, arg);
+                let message = format!("string expected, got {}", actual);
+                arg_error(state, arg - first + 1, "write", &message)
+            }
+        }
+    }
+    match with_file(state, ud, |file| file.write(&data)) {
+        Ok(()) => {
+            state.push_value(&Value::UserData(ud.clone()));
+            1
+        }
+        Err(err) => file_result(state, Err(err), None),
+    }
+}
+
+fn aux_close(state: &mut LuaState, ud: &AnyUserData) -> usize {
+    if with_file(state, ud, |file| file.is_std()) {
+        state.push_nil();
+        state.push_str("cannot close standard file");
+        return 2;
+    }
+    match with_file(state, ud, |file| file.close()) {
+        Ok(Some(status)) => exec_result(state, Ok(status)),
+        Ok(None) => file_result(state, Ok(()), None),
+        Err(err) => file_result(state, Err(err), None),
+    }
+}
+
+/// brief: an iterator over the lines of a file, or what `formats` read.
+/// with `toclose` the file is closed at its end
+fn aux_lines(state: &mut LuaState, ud: AnyUserData, first: isize, toclose: bool) -> Value {
+    let nformats = (state.get_top() as isize - first + 1).max(0) as usize;
+    if nformats > MAXARGLINE {
+        arg_error(state, MAXARGLINE as isize + 2, "lines", "too many arguments");
+    }
+    let formats = check_formats(state, first, "lines");
+    let iter = state.new_closure(Rc::new(move |state: &mut LuaState| {
+        io_readline(state, &ud, &formats, toclose)
+    }));
+    Value::Function(iter)
+}
+
+fn io_readline(state: &mut LuaState, ud: &AnyUserData, formats: &[Format], toclose: bool) -> usize {
+    if !with_file(state, ud, |file| file.handle.is_some()) {
+        state.error(LuaError::Runtime("file is already closed".to_string()));
+    }
+    match with_file(state, ud, |file| file.read_formats(formats)) {
+        Ok(results) if !results[0].is_nil() => {
+            for val in results.iter() {
+                state.push_value(val);
+            }
+            results.len()
+        }
+        Ok(_) => {
+            if toclose {
+                let _ = with_file(state, ud, |file| file.close());
+            }
+            0
+        }
+        Err(err) => state.error(LuaError::Runtime(os_error_message(&err))),
+    }
+}
+
+/// brief: the default file of `key`, which must be open
+fn get_io_file(state: &mut LuaState, key: &str) -> AnyUserData {
+    let registry = state.registry();
+    let val = state.raw_get(&registry, &Value::from(key));
+    match file_state(state, &val) {
+        Some((ud, true)) => ud,
+        _ => state.error(LuaError::Runtime(format!("default {} file is closed", &key[4..]))),
+    }
+}
+
+/// brief: io.input and io.output, set the default file of `key` to a file
+/// or a file opened by name, and return the default file
+fn g_iofile(state: &mut LuaState, key: &str, mode: &[u8], fname: &str) -> usize {
+    let registry = state.registry();
+    if !is_none_or_nil(state, 1) {
+        let file = match state.get_value(1) {
+            Value::String(filename) => {
+                let filename = filename.to_string_lossy();
+                match open_file(&filename, mode) {
+                    Ok(file) => Value::UserData(new_file(state, Handle::File(file), is_writable(mode))),
+                    Err(err) => state.error(LuaError::Runtime(format!(
+                        "cannot open file '{}' ({})",
+                        filename,
+                        os_error_message(&err)
+                    ))),
+                }
+            }
+            _ => Value::UserData(check_file(state, 1, fname)),
+        };
+        let _ = state.raw_set(&registry, Value::from(key), file);
+    }
+    let file = state.raw_get(&registry, &Value::from(key));
+    state.push_value(&file);
+    1
+}
+
+fn io_open(state: &mut LuaState) -> usize {
+    let filename = check_lstring(state, 1, "open").to_string_lossy();
+    let mode = opt_lstring(state, 2, "open", "r");
+    if !check_mode(mode.as_bytes()) {
+        arg_error(state, 2, "open", "invalid mode");
+    }
+    match open_file(&filename, mode.as_bytes()) {
+        Ok(file) => {
+            let ud = new_file(state, Handle::File(file), is_writable(mode.as_bytes()));
+            state.push_value(&Value::UserData(ud));
+            1
+        }
+        Err(err) => file_result(state, Err(err), Some(&filename)),
+    }
+}
+
+fn io_popen(state: &mut LuaState) -> usize {
+    let prog = check_lstring(state, 1, "popen").to_string_lossy();
+    let mode = opt_lstring(state, 2, "popen", "r");
+    let reading = match mode.as_bytes() {
+        b"r" => true,
+        b"w" => false,
+        _ => arg_error(state, 2, "popen", "invalid mode"),
+    };
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(&prog);
+    if reading {
+        command.stdout(Stdio::piped());
+    } else {
+        command.stdin(Stdio::piped());
+    }
+    match command.spawn() {
+        Ok(child) => {
+            let ud = new_file(state, Handle::Pipe(child), !reading);
+            state.push_value(&Value::UserData(ud));
+            1
+        }
+        Err(err) => file_result(state, Err(err), Some(&prog)),
+    }
+}
+
+fn io_tmpfile(state: &mut LuaState) -> usize {
+    match tmp_file() {
+        Ok(file) => {
+            let ud = new_file(state, Handle::File(file), true);
+            state.push_value(&Value::UserData(ud));
+            1
+        }
+        Err(err) => file_result(state, Err(err), None),
+    }
+}
+
+fn io_type(state: &mut LuaState) -> usize {
+    let val = check_any(state, 1, "type");
+    match file_state(state, &val) {
+        Some((_, true)) => state.push_str("file"),
+        Some((_, false)) => state.push_str("closed file"),
+        None => state.push_nil(),
+    }
+    1
+}
+
+fn io_close(state: &mut LuaState) -> usize {
+    let ud = if state.is_none(1) {
+        let registry = state.registry();
+        let output = state.raw_get(&registry, &Value::from(IO_OUTPUT));
+        state.push_value(&output);
+        check_file(state, state.get_top() as isize, "close")
+    } else {
+        check_file(state, 1, "close")
+    };
+    aux_close(state, &ud)
+}
+
+fn io_flush(state: &mut LuaState) -> usize {
+    let ud = get_io_file(state, IO_OUTPUT);
+    let res = with_file(state, &ud, |file| file.flush());
+    file_result(state, res, None)
+}
+
+fn io_input(state: &mut LuaState) -> usize {
+    g_iofile(state, IO_INPUT, b"r", "input")
+}
+
+fn io_output(state: &mut LuaState) -> usize {
+    g_iofile(state, IO_OUTPUT, b"w", "output")
+}
+
+/// brief: io.lines([filename, ...]), the file of a name is closed at the end
+/// and returned as the closing value of a generic for
+fn io_lines(state: &mut LuaState) -> usize {
+    if is_none_or_nil(state, 1) {
+        let ud = get_io_file(state, IO_INPUT);
+        let iter = aux_lines(state, ud, 2, false);
+        state.push_value(&iter);
+        return 1;
+    }
+    let filename = check_lstring(state, 1, "lines").to_string_lossy();
+    let file = match open_file(&filename, b"r") {
+        Ok(file) => file,
+        Err(err) => state.error(LuaError::Runtime(format!(
+            "{}: {}",
+            filename,
+            os_error_message(&err)
+        ))),
+    };
+    let ud = new_file(state, Handle::File(file), false);
+    let iter = aux_lines(state, ud.clone(), 2, true);
+    state.push_value(&iter);
+    state.push_nil();
+    state.push_nil();
+    state.push_value(&Value::UserData(ud));
+    4
+}
+
+fn io_read(state: &mut LuaState) -> usize {
+    let ud = get_io_file(state, IO_INPUT);
+    g_read(state, &ud, 1)
+}
+
+fn io_write(state: &mut LuaState) -> usize {
+    let ud = get_io_file(state, IO_OUTPUT);
+    g_write(state, &ud, 1)
+}
+
+fn f_close(state: &mut LuaState) -> usize {
+    let ud = check_file(state, 1, "close");
+    aux_close(state, &ud)
+}
+
+fn f_flush(state: &mut LuaState) -> usize {
+    let ud = check_file(state, 1, "flush");
+    let res = with_file(state, &ud, |file| file.flush());
+    file_result(state, res, None)
+}
+
+fn f_lines(state: &mut LuaState) -> usize {
+    let ud = check_file(state, 1, "lines");
+    let iter = aux_lines(state, ud, 2, false);
+    state.push_value(&iter);
+    1
+}
+
+fn f_read(state: &mut LuaState) -> usize {
+    let ud = check_file(state, 1, "read");
+    g_read(state, &ud, 2)
+}
+
+fn f_write(state: &mut LuaState) -> usize {
+    let ud = check_file(state, 1, "write");
+    g_write(state, &ud, 2)
+}
+
+fn f_seek(state: &mut LuaState) -> usize {
+    let ud = check_file(state, 1, "seek");
+    let whence = check_option(state, 2, "seek", Some("cur"), &["set", "cur", "end"]);
+    let offset = opt_integer(state, 3, "seek", 0);
+    let pos = match whence {
+        // lseek refuses a negative position, so does the file
+        0 if offset < 0 => Err(io::Error::from_raw_os_error(EINVAL)),
+        0 => Ok(SeekFrom::Start(offset as u64)),
+        1 => Ok(SeekFrom::Current(offset)),
+        _ => Ok(SeekFrom::End(offset)),
+    };
+    match pos.and_then(|pos| with_file(state, &ud, |file| file.seek(pos))) {
+        Ok(pos) => {
+            state.push_integer(pos as INT);
+            1
+        }
+        Err(err) => file_result(state, Err(err), None),
+    }
+}
+
+fn f_setvbuf(state: &mut LuaState) -> usize {
+    let ud = check_file(state, 1, "setvbuf");
+    let modes = [BufMode::No, BufMode::Full, BufMode::Line];
+    let mode = modes[check_option(state, 2, "setvbuf", None, &["no", "full", "line"])];
+    let size = opt_integer(state, 3, "setvbuf", LUAL_BUFFERSIZE as INT).max(1) as usize;
+    let res = with_file(state, &ud, |file| file.setvbuf(mode, size));
+    file_result(state, res, None)
+}
+
+/// brief: __gc and __close, an open file other than a standard one is closed
+fn f_gc(state: &mut LuaState) -> usize {
+    let val = state.get_value(1);
+    if let Some((ud, true)) = file_state(state, &val) {
+        if !with_file(state, &ud, |file| file.is_std()) {
+            let _ = with_file(state, &ud, |file| file.close());
+        }
+    }
+    0
+}
+
+fn f_tostring(state: &mut LuaState) -> usize {
+    let val = state.get_value(1);
+    match file_state(state, &val) {
+        Some((_, true)) => state.push_str(&format!("file ({:#x})", value_address(&val))),
+        Some((_, false)) => state.push_str("file (closed)"),
+        None => type_error(state, 1, "tostring", "FILE*"),
+    }
+    1
+}
+
+/// brief: put the io library in the global `io`, with the standard files as
+/// the default input and output
+pub fn open_io(state: &mut LuaState) {
+    let lib = new_lib(state, IO_FUNCS);
+    let stdin = new_file(state, Handle::Stdin, false);
+    let stdout = new_file(state, Handle::Stdout, true);
+    let stderr = new_file(state, Handle::Stderr, true);
+
+    // the methods are light functions, as the other libraries have
+    if let Some(metatable) = state.get_metatable(&Value::UserData(stdin.clone())) {
+        let methods = new_lib(state, FILE_METHODS);
+        let meta = new_lib(state, FILE_META);
+        for (name, _) in FILE_META {
+            let method = state.raw_get(&meta, &Value::from(*name));
+            let _ = state.raw_set(&metatable, Value::from(*name), method);
+        }
+        let _ = state.raw_set(&metatable, Value::from("__index"), Value::Table(methods));
+        let _ = state.raw_set(&metatable, Value::from("__name"), Value::from("FILE*"));
+    }
+
+    let registry = state.registry();
+    let _ = state.raw_set(&registry, Value::from(IO_INPUT), Value::UserData(stdin.clone()));
+    let _ = state.raw_set(&registry, Value::from(IO_OUTPUT), Value::UserData(stdout.clone()));
+    for (name, ud) in [("stdin", stdin), ("stdout", stdout), ("stderr", stderr)] {
+        let _ = state.raw_set(&lib, Value::from(name), Value::UserData(ud));
+    }
+    let globals = state.globals();
+    let _ = state.raw_set(&globals, Value::from("io"), Value::Table(lib));
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::common::lua::LuaResult;
+    use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti, MultiValue};
+    use crate::common::obj::objvalue::{Function, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    fn call<A: IntoLuaMulti, R: FromLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<R> {
+        let io: Table = state.get_global("io")?;
+        let function: Function = io.get(state, name)?;
+        function.call(state, args)
+    }
+
+    fn method<A: IntoLuaMulti>(
+        state: &mut LuaState,
+        file: &Value,
+        name: &str,
+        args: A,
+    ) -> LuaResult<MultiValue> {
+        let function = state.index(file, &Value::from(name))?;
+        let mut args = args.into_lua_multi(state)?;
+        args.insert(0, file.clone());
+        state.call_value(&function, args)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("naive_lua2_io_{}_{}", std::process::id(), name))
+    }
+
+    fn strings(values: MultiValue) -> Vec<Option<String>> {
+        values
+            .iter()
+            .map(|val| match val {
+                Value::String(s) => Some(s.to_string_lossy()),
+                Value::Nil => None,
+                val => Some(format!("{:?}", val)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn write_then_read_formats() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let path = temp_path("formats");
+        let name = path.to_str().unwrap();
+
+        let file: Value = call(state, "open", (name, "w")).unwrap();
+        let written = method(
+            state,
+            &file,
+            "write",
+            ("first line\n", 42, " ", 1.5, " ", 2.0, "\n"),
+        )
+        .unwrap();
+        assert_eq!(written[0], file);
+        method(state, &file, "write", "0x1F -3.5e2 rest\nlast").unwrap();
+        let closed: bool = call(state, "close", file.clone()).unwrap();
+        assert!(closed);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "first line\n42 1.5 2\n0x1F -3.5e2 rest\nlast"
+        );
+
+        let file: Value = call(state, "open", name).unwrap();
+        let line = method(state, &file, "read", ()).unwrap();
+        assert_eq!(strings(line), [Some("first line".to_string())]);
+        let numbers = method(state, &file, "read", ("n", "n", "L")).unwrap();
+        assert_eq!(numbers[0], Value::Integer(42));
+        assert_eq!(numbers[1], Value::Number(1.5));
+        assert_eq!(strings(numbers)[2], Some(" 2\n".to_string()));
+        let hex = method(state, &file, "read", ("n", "*n", 3)).unwrap();
+        assert_eq!(hex[0], Value::Integer(31));
+        assert_eq!(hex[1], Value::Number(-350.0));
+        assert_eq!(strings(hex)[2], Some(" re".to_string()));
+        // a failed number stops the reading
+        let failed = method(state, &file, "read", ("n", "l")).unwrap();
+        assert_eq!(strings(failed), [None]);
+        let rest = method(state, &file, "read", ("l", "a", "a", "l", 0)).unwrap();
+        assert_eq!(
+            strings(rest),
+            [
+                Some("st".to_string()),
+                Some("last".to_string()),
+                Some("".to_string()),
+                None
+            ]
+        );
+        assert_eq!(
+            method(state, &file, "read", "x").unwrap_err().to_string(),
+            "bad argument #1 to 'read' (invalid format)"
+        );
+        method(state, &file, "close", ()).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn seek_and_update_modes() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let path = temp_path("seek");
+        let name = path.to_str().unwrap();
+
+        let file: Value = call(state, "open", (name, "w+")).unwrap();
+        method(state, &file, "write", "hello world").unwrap();
+        let pos = method(state, &file, "seek", ("set", 6)).unwrap();
+        assert_eq!(pos[0], Value::Integer(6));
+        let word = method(state, &file, "read", 3).unwrap();
+        assert_eq!(strings(word), [Some("wor".to_string())]);
+        let pos = method(state, &file, "seek", ()).unwrap();
+        assert_eq!(pos[0], Value::Integer(9));
+        method(state, &file, "write", "LD").unwrap();
+        let size = method(state, &file, "seek", "end").unwrap();
+        assert_eq!(size[0], Value::Integer(11));
+        method(state, &file, "seek", "set").unwrap();
+        let all = method(state, &file, "read", "a").unwrap();
+        assert_eq!(strings(all), [Some("hello worLD".to_string())]);
+        let bad = method(state, &file, "seek", ("set", -5)).unwrap();
+        assert_eq!(bad[0], Value::Nil);
+        assert_eq!(strings(bad.clone())[1], Some("Invalid argument".to_string()));
+        assert_eq!(bad[2], Value::Integer(22));
+        let bad = method(state, &file, "seek", ("cur", -100)).unwrap();
+        assert_eq!(bad[2], Value::Integer(22));
+        let mode = method(state, &file, "setvbuf", "no").unwrap();
+        assert_eq!(mode[0], Value::Boolean(true));
+        method(state, &file, "close", ()).unwrap();
+
+        let file: Value = call(state, "open", (name, "a")).unwrap();
+        method(state, &file, "write", "!").unwrap();
+        method(state, &file, "close", ()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello worLD!");
+        assert_eq!(
+            call::<_, MultiValue>(state, "open", (name, "rw"))
+                .unwrap_err()
+                .to_string(),
+            "bad argument #2 to 'open' (invalid mode)"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn errors_are_results() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let path = temp_path("missing");
+        let name = path.to_str().unwrap();
+
+        let (file, message, errno): (Value, String, i64) = call(state, "open", name).unwrap();
+        assert_eq!(file, Value::Nil);
+        assert_eq!(message, format!("{}: No such file or directory", name));
+        assert_eq!(errno, 2);
+        let err = call::<_, MultiValue>(state, "lines", name)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, format!("{}: No such file or directory", name));
+
+        // a write to a file opened for reading fails at the write itself
+        fs::write(&path, "text").unwrap();
+        let file: Value = call(state, "open", name).unwrap();
+        let (ok, message, errno): (Value, String, i64) = method(state, &file, "write", "x")
+            .and_then(|results| FromLuaMulti::from_lua_multi(results, state))
+            .unwrap();
+        assert_eq!((ok, message.as_str(), errno), (Value::Nil, "Bad file descriptor", 9));
+        let flushed = method(state, &file, "flush", ()).unwrap();
+        assert_eq!(flushed[0], Value::Boolean(true));
+        let closed = method(state, &file, "close", ()).unwrap();
+        assert_eq!(closed[0], Value::Boolean(true));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "text");
+        fs::remove_file(&path).unwrap();
+
+        let stdout: Value = call(state, "output", ()).unwrap();
+        let (ok, message): (Value, String) = call(state, "close", stdout).unwrap();
+        assert_eq!((ok, message.as_str()), (Value::Nil, "cannot close standard file"));
+
+        let file: Value = call(state, "tmpfile", ()).unwrap();
+        let kind: String = call(state, "type", file.clone()).unwrap();
+        assert_eq!(kind, "file");
+        method(state, &file, "close", ()).unwrap();
+        let kind: String = call(state, "type", file.clone()).unwrap();
+        assert_eq!(kind, "closed file");
+        let kind: Value = call(state, "type", 1).unwrap();
+        assert_eq!(kind, Value::Nil);
+        assert_eq!(
+            method(state, &file, "read", ()).unwrap_err().to_string(),
+            "attempt to use a closed file"
+        );
+        let text = crate::stdlib::libaux::tolstring(state, &file);
+        assert_eq!(text.to_string_lossy(), "file (closed)");
+    }
+
+    #[test]
+    fn lines_and_default_files() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let path = temp_path("lines");
+        let name = path.to_str().unwrap();
+
+        call::<_, Value>(state, "output", name).unwrap();
+        call::<_, Value>(state, "write", ("a\n", "b\n", "c")).unwrap();
+        let closed: bool = call(state, "close", ()).unwrap();
+        assert!(closed);
+        assert_eq!(
+            call::<_, MultiValue>(state, "write", "x")
+                .unwrap_err()
+                .to_string(),
+            "default output file is closed"
+        );
+
+        let results: MultiValue = call(state, "lines", name).unwrap();
+        assert_eq!(results.len(), 4);
+        let iter = results[0].clone();
+        let mut lines = Vec::new();
+        loop {
+            let line = state.call_value(&iter, MultiValue::new()).unwrap();
+            match line.front() {
+                Some(Value::String(s)) => lines.push(s.to_string_lossy()),
+                _ => break,
+            }
+        }
+        assert_eq!(lines, ["a", "b", "c"]);
+        // the file of a name is closed at its end
+        let kind: String = call(state, "type", results[3].clone()).unwrap();
+        assert_eq!(kind, "closed file");
+        assert_eq!(
+            state
+                .call_value(&iter, MultiValue::new())
+                .unwrap_err()
+                .to_string(),
+            "file is already closed"
+        );
+
+        call::<_, Value>(state, "input", name).unwrap();
+        let chars = strings(call(state, "read", (1, "L", "n")).unwrap());
+        assert_eq!(chars, [Some("a".to_string()), Some("\n".to_string()), None]);
+        let file: Value = call(state, "input", ()).unwrap();
+        let iter = method(state, &file, "lines", 1).unwrap()[0].clone();
+        let byte = state.call_value(&iter, MultiValue::new()).unwrap();
+        assert_eq!(strings(byte), [Some("b".to_string())]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // miri cannot spawn processes
+    fn pipes_run_commands() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let pipe: Value = call(state, "popen", "echo hello; exit 3").unwrap();
+        let out = method(state, &pipe, "read", "a").unwrap();
+        assert_eq!(strings(out), [Some("hello\n".to_string())]);
+        let status = method(state, &pipe, "close", ()).unwrap();
+        assert_eq!(status[0], Value::Nil);
+        assert_eq!(status[1], Value::from("exit"));
+        assert_eq!(status[2], Value::Integer(3));
+
+        let path = temp_path("pipe");
+        let command = format!("cat > {}", path.to_str().unwrap());
+        let pipe: Value = call(state, "popen", (command.as_str(), "w")).unwrap();
+        method(state, &pipe, "write", "piped").unwrap();
+        let status = method(state, &pipe, "close", ()).unwrap();
+        assert_eq!(status[0], Value::Boolean(true));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "piped");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn files_close_when_collected() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let path = temp_path("collected");
+        let name = path.to_str().unwrap();
+
+        let file: Value = call(state, "open", (name, "w")).unwrap();
+        method(state, &file, "write", "buffered").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        drop(file);
+        state.collect_garbage();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "buffered");
+
+        let file: Value = call(state, "open", (name, "w")).unwrap();
+        method(state, &file, "write", "closed").unwrap();
+        let close = state.get_metafield(&file, "__close");
+        state
+            .call_value(&close, vec![file.clone(), Value::Nil].into())
+            .unwrap();
+        let kind: String = call(state, "type", file).unwrap();
+        assert_eq!(kind, "closed file");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "closed");
+        fs::remove_file(&path).unwrap();
+    }
+}
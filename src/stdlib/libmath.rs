@@ -0,0 +1,528 @@
+use std::cell::Cell;
+use std::rc::Rc;
+#[cfg(not(miri))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::lua::LuaError;
+use crate::common::obj::objconv::str_to_number;
+use crate::common::obj::objtable::float_to_integer;
+use crate::common::obj::objtype::{FLT, INT, LRFUNC};
+use crate::common::obj::objvalue::Value;
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::{arg_error, check_any, check_integer, check_number, new_lib, opt_integer};
+
+const PI: FLT = std::f64::consts::PI;
+
+const MATH_FUNCS: &[(&str, LRFUNC)] = &[
+    ("abs", math_abs),
+    ("acos", math_acos),
+    ("asin", math_asin),
+    ("atan", math_atan),
+    ("ceil", math_ceil),
+    ("cos", math_cos),
+    ("exp", math_exp),
+    ("floor", math_floor),
+    ("fmod", math_fmod),
+    ("log", math_log),
+    ("max", math_max),
+    ("min", math_min),
+    ("modf", math_modf),
+    ("sin", math_sin),
+    ("sqrt", math_sqrt),
+    ("tan", math_tan),
+    ("tointeger", math_toint),
+    ("type", math_type),
+    ("ult", math_ult),
+];
+
+/// brief: put the math library in the global `math`, the generator is seeded
+/// at random
+pub fn open_math(state: &mut LuaState) {
+    let lib = new_lib(state, MATH_FUNCS);
+    let constants = [
+        ("pi", Value::Number(PI)),
+        ("huge", Value::Number(FLT::INFINITY)),
+        ("maxinteger", Value::Integer(INT::MAX)),
+        ("mininteger", Value::Integer(INT::MIN)),
+    ];
+    for (name, val) in constants {
+        let _ = state.raw_set(&lib, Value::from(name), val);
+    }
+
+    // random and randomseed share the state of the generator
+    let rng = Rc::new(Xoshiro256::default());
+    let (n1, n2) = random_seed(state);
+    rng.set_seed(n1, n2);
+    let generator = rng.clone();
+    let random = state.new_closure(Rc::new(move |state: &mut LuaState| {
+        math_random(state, &generator)
+    }));
+    let randomseed = state.new_closure(Rc::new(move |state: &mut LuaState| math_randomseed(state, &rng)));
+    for (name, function) in [("random", random), ("randomseed", randomseed)] {
+        let _ = state.raw_set(&lib, Value::from(name), Value::Function(function));
+    }
+
+    let globals = state.globals();
+    let _ = state.raw_set(&globals, Value::from("math"), Value::Table(lib));
+}
+
+/// brief: push an integer for a float of an integral value that fits, the
+/// float otherwise
+fn push_numint(state: &mut LuaState, d: FLT) {
+    match float_to_integer(d) {
+        Some(n) => state.push_integer(n),
+        None => state.push_float(d),
+    }
+}
+
+fn math_abs(state: &mut LuaState) -> usize {
+    match state.get_value(1) {
+        Value::Integer(n) => state.push_integer(n.wrapping_abs()),
+        _ => {
+            let d = check_number(state, 1, "abs");
+            state.push_float(d.abs());
+        }
+    }
+    1
+}
+
+/// brief: a math function of one float
+macro_rules! float_fn {
+    ($name:ident, $fname:literal, $op:expr) => {
+        fn $name(state: &mut LuaState) -> usize {
+            let d = check_number(state, 1, $fname);
+            state.push_float($op(d));
+            1
+        }
+    };
+}
+
+float_fn!(math_sin, "sin", FLT::sin);
+float_fn!(math_cos, "cos", FLT::cos);
+float_fn!(math_tan, "tan", FLT::tan);
+float_fn!(math_asin, "asin", FLT::asin);
+float_fn!(math_acos, "acos", FLT::acos);
+float_fn!(math_sqrt, "sqrt", FLT::sqrt);
+float_fn!(math_exp, "exp", FLT::exp);
+
+fn math_atan(state: &mut LuaState) -> usize {
+    let y = check_number(state, 1, "atan");
+    let x = if state.is_none(2) {
+        1.0
+    } else {
+        check_number(state, 2, "atan")
+    };
+    state.push_float(y.atan2(x));
+    1
+}
+
+fn math_toint(state: &mut LuaState) -> usize {
+    let val = match state.get_value(1) {
+        Value::String(s) => str_to_number(s.as_bytes()).unwrap_or(Value::Nil),
+        val => val,
+    };
+    match val {
+        Value::Integer(n) => state.push_integer(n),
+        Value::Number(d) if float_to_integer(d).is_some() => state.push_integer(float_to_integer(d).unwrap()),
+        _ => {
+            check_any(state, 1, "tointeger");
+            state.push_nil();
+        }
+    }
+    1
+}
+
+fn math_floor(state: &mut LuaState) -> usize {
+    match state.get_value(1) {
+        // an integer is its own floor
+        Value::Integer(n) => state.push_integer(n),
+        _ => {
+            let d = check_number(state, 1, "floor");
+            push_numint(state, d.floor());
+        }
+    }
+    1
+}
+
+fn math_ceil(state: &mut LuaState) -> usize {
+    match state.get_value(1) {
+        Value::Integer(n) => state.push_integer(n),
+        _ => {
+            let d = check_number(state, 1, "ceil");
+            push_numint(state, d.ceil());
+        }
+    }
+    1
+}
+
+fn math_fmod(state: &mut LuaState) -> usize {
+    match (state.get_value(1), state.get_value(2)) {
+        (Value::Integer(m), Value::Integer(d)) => {
+            // d is 0 or -1, -1 would overflow the minimum integer
+            if (d as u64).wrapping_add(1) <= 1 {
+                if d == 0 {
+                    arg_error(state, 2, "fmod", "zero");
+                }
+                state.push_integer(0);
+            } else {
+                // truncated, as C does
+                state.push_integer(m % d);
+            }
+        }
+        _ => {
+            let a = check_number(state, 1, "fmod");
+            let b = check_number(state, 2, "fmod");
+            state.push_float(a % b);
+        }
+    }
+    1
+}
+
+/// brief: the integral part, rounded toward zero, and the fractional part
+fn math_modf(state: &mut LuaState) -> usize {
+    if let Value::Integer(n) = state.get_value(1) {
+        // an integer is its own integral part
+        state.push_integer(n);
+        state.push_float(0.0);
+        return 2;
+    }
+    let n = check_number(state, 1, "modf");
+    let ip = if n < 0.0 { n.ceil() } else { n.floor() };
+    state.push_float(ip);
+    // the test is needed for inf and -inf
+    state.push_float(if n == ip { 0.0 } else { n - ip });
+    2
+}
+
+fn math_log(state: &mut LuaState) -> usize {
+    let x = check_number(state, 1, "log");
+    let res = if state.is_none(2) {
+        x.ln()
+    } else {
+        match check_number(state, 2, "log") {
+            2.0 => x.log2(),
+            10.0 => x.log10(),
+            base => x.ln() / base.ln(),
+        }
+    };
+    state.push_float(res);
+    1
+}
+
+fn math_ult(state: &mut LuaState) -> usize {
+    let a = check_integer(state, 1, "ult");
+    let b = check_integer(state, 2, "ult");
+    state.push_bool((a as u64) < (b as u64));
+    1
+}
+
+fn math_type(state: &mut LuaState) -> usize {
+    match check_any(state, 1, "type") {
+        Value::Integer(_) => state.push_str("integer"),
+        Value::Number(_) => state.push_str("float"),
+        _ => state.push_nil(),
+    }
+    1
+}
+
+/// brief: the largest argument for `max`, the smallest otherwise
+fn min_max(state: &mut LuaState, fname: &str, max: bool) -> usize {
+    let n = state.get_top() as isize;
+    if n < 1 {
+        arg_error(state, 1, fname, "value expected");
+    }
+    check_number(state, 1, fname);
+    let mut best = state.get_value(1);
+    for i in 2..=n {
+        check_number(state, i, fname);
+        let val = state.get_value(i);
+        let better = if max {
+            state.less_than(&best, &val)
+        } else {
+            state.less_than(&val, &best)
+        };
+        match better {
+            Ok(true) => best = val,
+            Ok(false) => {}
+            Err(err) => state.error(err),
+        }
+    }
+    state.push_value(&best);
+    1
+}
+
+fn math_max(state: &mut LuaState) -> usize {
+    min_max(state, "max", true)
+}
+
+fn math_min(state: &mut LuaState) -> usize {
+    min_max(state, "min", false)
+}
+
+/// brief: the xoshiro256** generator of lua 5.4
+#[derive(Default)]
+struct Xoshiro256 {
+    s: Cell<[u64; 4]>,
+}
+
+impl Xoshiro256 {
+    fn next(&self) -> u64 {
+        let mut s = self.s.get();
+        let res = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        self.s.set(s);
+        res
+    }
+
+    fn set_seed(&self, n1: u64, n2: u64) {
+        // 0xff avoids a zero state
+        self.s.set([n1, 0xff, n2, 0]);
+        // discard the initial values to spread the seed
+        for _ in 0..16 {
+            self.next();
+        }
+    }
+}
+
+/// brief: a float in [0, 1) of the 53 higher bits
+fn i2d(x: u64) -> FLT {
+    (x >> 11) as FLT * (0.5 / (1u64 << 52) as FLT)
+}
+
+/// brief: project a random integer into [0, n]
+fn project(mut ran: u64, n: u64, rng: &Xoshiro256) -> u64 {
+    // n + 1 is a power of 2, no bias
+    if n & n.wrapping_add(1) == 0 {
+        return ran & n;
+    }
+    // the smallest 2^b - 1 not smaller than n
+    let lim = u64::MAX >> n.leading_zeros();
+    loop {
+        ran &= lim;
+        if ran <= n {
+            return ran;
+        }
+        ran = rng.next();
+    }
+}
+
+/// brief: a seed of the time and an address, the state when no seed is given
+/// miri isolates the clock, there the time part is a constant
+fn random_seed(state: &LuaState) -> (u64, u64) {
+    #[cfg(not(miri))]
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    #[cfg(miri)]
+    let time = 0;
+    (time, state as *const LuaState as u64)
+}
+
+fn math_random(state: &mut LuaState, rng: &Xoshiro256) -> usize {
+    let rv = rng.next();
+    let (low, up) = match state.get_top() {
+        0 => {
+            state.push_float(i2d(rv));
+            return 1;
+        }
+        1 => {
+            let up = check_integer(state, 1, "random");
+            if up == 0 {
+                // a full random integer for a single 0
+                state.push_integer(rv as INT);
+                return 1;
+            }
+            (1, up)
+        }
+        2 => (
+            check_integer(state, 1, "random"),
+            check_integer(state, 2, "random"),
+        ),
+        _ => state.error(LuaError::Runtime("wrong number of arguments".to_string())),
+    };
+    if low > up {
+        arg_error(state, 1, "random", "interval is empty");
+    }
+    let p = project(rv, (up as u64).wrapping_sub(low as u64), rng);
+    state.push_integer(p.wrapping_add(low as u64) as INT);
+    1
+}
+
+fn math_randomseed(state: &mut LuaState, rng: &Xoshiro256) -> usize {
+    let (n1, n2) = if state.is_none(1) {
+        random_seed(state)
+    } else {
+        let n1 = check_integer(state, 1, "randomseed");
+        let n2 = opt_integer(state, 2, "randomseed", 0);
+        (n1 as u64, n2 as u64)
+    };
+    rng.set_seed(n1, n2);
+    state.push_integer(n1 as INT);
+    state.push_integer(n2 as INT);
+    2
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::lua::LuaResult;
+    use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti, MultiValue};
+    use crate::common::obj::objvalue::{Function, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    use super::Xoshiro256;
+
+    fn call<A: IntoLuaMulti, R: FromLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<R> {
+        let math: Table = state.get_global("math")?;
+        let function: Function = math.get(state, name)?;
+        function.call(state, args)
+    }
+
+    fn call1<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> Value {
+        call(state, name, args).unwrap()
+    }
+
+    fn call_err<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> String {
+        call::<_, MultiValue>(state, name, args).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn integers_and_floats() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        assert_eq!(call1(state, "abs", -3), Value::Integer(3));
+        assert_eq!(call1(state, "abs", i64::MIN), Value::Integer(i64::MIN));
+        assert_eq!(call1(state, "abs", -2.5), Value::Number(2.5));
+        assert_eq!(call1(state, "floor", -3.5), Value::Integer(-4));
+        assert_eq!(call1(state, "ceil", 3.2), Value::Integer(4));
+        assert_eq!(call1(state, "floor", 7), Value::Integer(7));
+        assert_eq!(call1(state, "ceil", 1e300), Value::Number(1e300));
+        assert_eq!(call1(state, "floor", "2.5"), Value::Integer(2));
+        assert_eq!(call1(state, "fmod", (7, -3)), Value::Integer(1));
+        assert_eq!(call1(state, "fmod", (-7, 3)), Value::Integer(-1));
+        assert_eq!(call1(state, "fmod", (i64::MIN, -1)), Value::Integer(0));
+        assert_eq!(call1(state, "fmod", (7.5, 2)), Value::Number(1.5));
+        assert_eq!(
+            call_err(state, "fmod", (1, 0)),
+            "bad argument #2 to 'fmod' (zero)"
+        );
+        let parts: (Value, Value) = call(state, "modf", 3.75).unwrap();
+        assert_eq!(parts, (Value::Number(3.0), Value::Number(0.75)));
+        let parts: (Value, Value) = call(state, "modf", -3.75).unwrap();
+        assert_eq!(parts, (Value::Number(-3.0), Value::Number(-0.75)));
+        let parts: (Value, Value) = call(state, "modf", 5).unwrap();
+        assert_eq!(parts, (Value::Integer(5), Value::Number(0.0)));
+        let parts: (Value, Value) = call(state, "modf", f64::INFINITY).unwrap();
+        assert_eq!(parts, (Value::Number(f64::INFINITY), Value::Number(0.0)));
+        assert_eq!(call1(state, "tointeger", 3.0), Value::Integer(3));
+        assert_eq!(call1(state, "tointeger", "8"), Value::Integer(8));
+        assert_eq!(call1(state, "tointeger", 3.5), Value::Nil);
+        assert_eq!(call1(state, "tointeger", "x"), Value::Nil);
+        assert_eq!(call1(state, "type", 1), Value::from("integer"));
+        assert_eq!(call1(state, "type", 1.0), Value::from("float"));
+        assert_eq!(call1(state, "type", "1"), Value::Nil);
+        assert_eq!(
+            call_err(state, "type", ()),
+            "bad argument #1 to 'type' (value expected)"
+        );
+        assert_eq!(call1(state, "ult", (1, -1)), Value::Boolean(true));
+        assert_eq!(call1(state, "ult", (-1, 1)), Value::Boolean(false));
+    }
+
+    /// brief: the float result of a libm function, miri perturbs those by a few ulps
+    fn assert_number(value: Value, expected: f64) {
+        match value {
+            Value::Number(n) if cfg!(miri) => assert!((n - expected).abs() < 1e-12, "{}", n),
+            other => assert_eq!(other, Value::Number(expected)),
+        }
+    }
+
+    #[test]
+    fn functions_and_constants() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        assert_number(call1(state, "log", (8, 2)), 3.0);
+        assert_number(call1(state, "log", (100, 10)), 2.0);
+        assert_number(call1(state, "log", 1), 0.0);
+        assert_number(call1(state, "sqrt", 16), 4.0);
+        assert_number(call1(state, "exp", 0), 1.0);
+        assert_number(call1(state, "atan", (1, 1)), std::f64::consts::FRAC_PI_4);
+        assert_number(call1(state, "sin", 0), 0.0);
+        assert_eq!(call1(state, "max", (1, 2.5, -1)), Value::Number(2.5));
+        assert_eq!(call1(state, "min", (3, 1.0, 2)), Value::Number(1.0));
+        assert_eq!(call1(state, "max", 4), Value::Integer(4));
+        assert_eq!(
+            call_err(state, "max", ()),
+            "bad argument #1 to 'max' (value expected)"
+        );
+        assert_eq!(
+            call_err(state, "min", (1, "x")),
+            "bad argument #2 to 'min' (number expected, got string)"
+        );
+        let math: Table = state.get_global("math").unwrap();
+        let pi: f64 = math.get(state, "pi").unwrap();
+        assert_eq!(pi, std::f64::consts::PI);
+        let huge: f64 = math.get(state, "huge").unwrap();
+        assert_eq!(huge, f64::INFINITY);
+        let max: i64 = math.get(state, "maxinteger").unwrap();
+        let min: i64 = math.get(state, "mininteger").unwrap();
+        assert_eq!((max, min), (i64::MAX, i64::MIN));
+    }
+
+    #[test]
+    fn xoshiro_matches_the_reference_step() {
+        // the step of the reference implementation of xoshiro256**
+        let rng = Xoshiro256::default();
+        rng.s.set([1, 2, 3, 4]);
+        assert_eq!(rng.next(), 11520);
+        assert_eq!(rng.next(), 0);
+        assert_eq!(rng.next(), 1509978240);
+        assert_eq!(rng.next(), 1215971899390074240);
+    }
+
+    #[test]
+    fn seeded_sequences_repeat() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let seeds: (i64, i64) = call(state, "randomseed", 42).unwrap();
+        assert_eq!(seeds, (42, 0));
+        let first: Vec<Value> = (0..4).map(|_| call1(state, "random", (1, 100))).collect();
+        let float: f64 = call(state, "random", ()).unwrap();
+        assert!((0.0..1.0).contains(&float));
+        call::<_, MultiValue>(state, "randomseed", 42).unwrap();
+        let again: Vec<Value> = (0..4).map(|_| call1(state, "random", (1, 100))).collect();
+        assert_eq!(first, again);
+
+        let mut other = Machine::new();
+        let other = other.get_state();
+        call::<_, MultiValue>(other, "randomseed", 42).unwrap();
+        let elsewhere: Vec<Value> = (0..4).map(|_| call1(other, "random", (1, 100))).collect();
+        assert_eq!(first, elsewhere);
+
+        for _ in 0..100 {
+            let Value::Integer(n) = call1(state, "random", (-3, 3)) else {
+                panic!("random returned no integer")
+            };
+            assert!((-3..=3).contains(&n));
+            let Value::Integer(n) = call1(state, "random", 6) else {
+                panic!("random returned no integer")
+            };
+            assert!((1..=6).contains(&n));
+        }
+        assert!(matches!(call1(state, "random", 0), Value::Integer(_)));
+        let full = call1(state, "random", (i64::MIN, i64::MAX));
+        assert!(matches!(full, Value::Integer(_)));
+        assert_eq!(
+            call_err(state, "random", (2, 1)),
+            "bad argument #1 to 'random' (interval is empty)"
+        );
+        assert_eq!(call_err(state, "random", (1, 2, 3)), "wrong number of arguments");
+    }
+}
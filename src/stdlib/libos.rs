@@ -0,0 +1,812 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::lua::LuaError;
+use crate::common::obj::objconv::FromLua;
+use crate::common::obj::objtype::{FLT, INT, LRFUNC};
+use crate::common::obj::objvalue::Value;
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::{arg_error, check_integer, check_lstring, check_option, check_table, create_temp_file};
+use super::libaux::{exec_result, file_result, is_none_or_nil, new_lib, opt_integer, opt_lstring};
+
+/// brief: the conversions of os.date, as strftime of C99 knows them. the
+/// options of one char come first, the blocks are split by '|'
+const LUA_STRFTIMEOPTIONS: &[u8] =
+    b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%||EcECExEXEyEYOdOeOHOIOmOMOSOuOUOVOwOWOy";
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const OS_FUNCS: &[(&str, LRFUNC)] = &[
+    ("clock", os_clock),
+    ("date", os_date),
+    ("difftime", os_difftime),
+    ("execute", os_execute),
+    ("exit", os_exit),
+    ("getenv", os_getenv),
+    ("remove", os_remove),
+    ("rename", os_rename),
+    ("setlocale", os_setlocale),
+    ("time", os_time),
+    ("tmpname", os_tmpname),
+];
+
+/// brief: put the os library in the global `os`
+pub fn open_os(state: &mut LuaState) {
+    let lib = new_lib(state, OS_FUNCS);
+    let globals = state.globals();
+    let _ = state.raw_set(&globals, Value::from("os"), Value::Table(lib));
+}
+
+/// brief: a broken down time, the fields are those of the C struct tm
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Tm {
+    sec: i32,
+    min: i32,
+    hour: i32,
+    mday: i32,
+    mon: i32,  // months since january
+    year: i32, // years since 1900
+    wday: i32, // days since sunday
+    yday: i32, // days since january 1
+    isdst: i32,
+    gmtoff: i64,
+    zone: String,
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// brief: the year, month (1-12) and day of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// brief: the count of days since 1970-01-01 of a date, month in 1-12
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// brief: the universal time of `t`, none when the year does not fit
+fn gm_time(t: i64) -> Option<Tm> {
+    let days = t.div_euclid(86400);
+    let secs = t.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    Some(Tm {
+        sec: (secs % 60) as i32,
+        min: (secs / 60 % 60) as i32,
+        hour: (secs / 3600) as i32,
+        mday: day as i32,
+        mon: (month - 1) as i32,
+        year: i32::try_from(year - 1900).ok()?,
+        wday: (days + 4).rem_euclid(7) as i32,
+        yday: (days - days_from_civil(year, 1, 1)) as i32,
+        isdst: 0,
+        gmtoff: 0,
+        zone: "GMT".to_string(),
+    })
+}
+
+#[cfg(unix)]
+mod ctime {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int, c_long};
+
+    use super::Tm;
+
+    #[repr(C)]
+    struct CTm {
+        tm_sec: c_int,
+        tm_min: c_int,
+        tm_hour: c_int,
+        tm_mday: c_int,
+        tm_mon: c_int,
+        tm_year: c_int,
+        tm_wday: c_int,
+        tm_yday: c_int,
+        tm_isdst: c_int,
+        tm_gmtoff: c_long,
+        tm_zone: *const c_char,
+    }
+
+    extern "C" {
+        fn tzset();
+        fn localtime_r(t: *const c_long, tm: *mut CTm) -> *mut CTm;
+        fn mktime(tm: *mut CTm) -> c_long;
+        fn clock() -> c_long;
+    }
+
+    const CLOCKS_PER_SEC: f64 = 1_000_000.0;
+
+    /// brief: a c long as an i64, it is one on 64 bit targets
+    #[cfg(target_pointer_width = "64")]
+    fn widen(n: c_long) -> i64 {
+        n
+    }
+
+    /// brief: a c long as an i64, it is narrower on 32 bit targets
+    #[cfg(not(target_pointer_width = "64"))]
+    fn widen(n: c_long) -> i64 {
+        i64::from(n)
+    }
+
+    impl CTm {
+        fn new(tm: &Tm) -> Self {
+            CTm {
+                tm_sec: tm.sec,
+                tm_min: tm.min,
+                tm_hour: tm.hour,
+                tm_mday: tm.mday,
+                tm_mon: tm.mon,
+                tm_year: tm.year,
+                tm_wday: tm.wday,
+                tm_yday: tm.yday,
+                tm_isdst: tm.isdst,
+                tm_gmtoff: 0,
+                tm_zone: std::ptr::null(),
+            }
+        }
+
+        fn to_tm(&self) -> Tm {
+            let zone = if self.tm_zone.is_null() {
+                String::new()
+            } else {
+                // SAFETY: the zone of localtime_r and mktime is a static string
+                unsafe { CStr::from_ptr(self.tm_zone) }
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            Tm {
+                sec: self.tm_sec,
+                min: self.tm_min,
+                hour: self.tm_hour,
+                mday: self.tm_mday,
+                mon: self.tm_mon,
+                year: self.tm_year,
+                wday: self.tm_wday,
+                yday: self.tm_yday,
+                isdst: self.tm_isdst,
+                gmtoff: widen(self.tm_gmtoff),
+                zone,
+            }
+        }
+    }
+
+    /// brief: the local time of `t`, as the time zone of the process has it
+    pub(super) fn local_time(t: i64) -> Option<Tm> {
+        let t = c_long::try_from(t).ok()?;
+        let mut ctm = CTm::new(&Tm::default());
+        // SAFETY: both pointers are valid for the call, tzset has no arguments
+        let res = unsafe {
+            tzset();
+            localtime_r(&t, &mut ctm)
+        };
+        (!res.is_null()).then(|| ctm.to_tm())
+    }
+
+    /// brief: the time of a local broken down time, and the time normalized
+    pub(super) fn make_time(tm: &Tm) -> (i64, Tm) {
+        let mut ctm = CTm::new(tm);
+        // SAFETY: the pointer is valid for the call
+        let t = unsafe {
+            tzset();
+            mktime(&mut ctm)
+        };
+        (widen(t), ctm.to_tm())
+    }
+
+    /// brief: the processor time of the process in seconds
+    pub(super) fn cpu_time() -> f64 {
+        // SAFETY: clock has no arguments
+        unsafe { clock() as f64 / CLOCKS_PER_SEC }
+    }
+}
+
+#[cfg(not(unix))]
+mod ctime {
+    use std::time::Instant;
+
+    use super::{days_from_civil, gm_time, Tm};
+
+    /// brief: without a time zone database the local time is universal
+    pub(super) fn local_time(t: i64) -> Option<Tm> {
+        gm_time(t)
+    }
+
+    pub(super) fn make_time(tm: &Tm) -> (i64, Tm) {
+        let year = tm.year as i64 + 1900 + (tm.mon as i64).div_euclid(12);
+        let days = days_from_civil(year, (tm.mon as i64).rem_euclid(12) + 1, 1) + tm.mday as i64 - 1;
+        let t = days * 86400 + tm.hour as i64 * 3600 + tm.min as i64 * 60 + tm.sec as i64;
+        (t, gm_time(t).unwrap_or_default())
+    }
+
+    pub(super) fn cpu_time() -> f64 {
+        thread_local!(static START: Instant = Instant::now());
+        START.with(|start| start.elapsed().as_secs_f64())
+    }
+}
+
+fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs_f64().ceil() as i64),
+    }
+}
+
+/// brief: the days since the monday of the first iso week of the year, a
+/// negative count for the days of the last week of the year before
+fn iso_week_days(yday: i64, wday: i64) -> i64 {
+    const BIG_ENOUGH_MULTIPLE_OF_7: i64 = (366 / 7 + 2) * 7;
+    yday - (yday - wday + 4 + BIG_ENOUGH_MULTIPLE_OF_7) % 7 + 3
+}
+
+/// brief: the iso 8601 year and week of a time
+fn iso_week(tm: &Tm) -> (i64, i64) {
+    let mut year = tm.year as i64 + 1900;
+    let yday = tm.yday as i64;
+    let wday = tm.wday as i64;
+    let mut days = iso_week_days(yday, wday);
+    if days < 0 {
+        year -= 1;
+        days = iso_week_days(yday + 365 + i64::from(is_leap(year)), wday);
+    } else {
+        let next = iso_week_days(yday - 365 - i64::from(is_leap(year)), wday);
+        if next >= 0 {
+            year += 1;
+            days = next;
+        }
+    }
+    (year, days / 7 + 1)
+}
+
+/// brief: append the conversion `conv` of strftime in the C locale, the
+/// modifiers E and O change nothing there
+fn strftime(out: &mut Vec<u8>, conv: &[u8], tm: &Tm) {
+    let spec = *conv.last().unwrap();
+    let year = tm.year as i64 + 1900;
+    let hour12 = if tm.hour % 12 == 0 { 12 } else { tm.hour % 12 };
+    let text = match spec {
+        b'a' => WEEKDAYS[tm.wday.rem_euclid(7) as usize][..3].to_string(),
+        b'A' => WEEKDAYS[tm.wday.rem_euclid(7) as usize].to_string(),
+        b'b' | b'h' => MONTHS[tm.mon.rem_euclid(12) as usize][..3].to_string(),
+        b'B' => MONTHS[tm.mon.rem_euclid(12) as usize].to_string(),
+        b'c' => return strftime_all(out, b"%a %b %e %H:%M:%S %Y", tm),
+        b'C' => format!("{:02}", year.div_euclid(100)),
+        b'd' => format!("{:02}", tm.mday),
+        b'D' | b'x' => return strftime_all(out, b"%m/%d/%y", tm),
+        b'e' => format!("{:2}", tm.mday),
+        b'F' => return strftime_all(out, b"%Y-%m-%d", tm),
+        b'g' => format!("{:02}", iso_week(tm).0.rem_euclid(100)),
+        b'G' => iso_week(tm).0.to_string(),
+        b'H' => format!("{:02}", tm.hour),
+        b'I' => format!("{:02}", hour12),
+        b'j' => format!("{:03}", tm.yday + 1),
+        b'm' => format!("{:02}", tm.mon + 1),
+        b'M' => format!("{:02}", tm.min),
+        b'n' => "\n".to_string(),
+        b'p' => if tm.hour < 12 { "AM" } else { "PM" }.to_string(),
+        b'r' => return strftime_all(out, b"%I:%M:%S %p", tm),
+        b'R' => return strftime_all(out, b"%H:%M", tm),
+        b'S' => format!("{:02}", tm.sec),
+        b't' => "\t".to_string(),
+        b'T' | b'X' => return strftime_all(out, b"%H:%M:%S", tm),
+        b'u' => (if tm.wday == 0 { 7 } else { tm.wday }).to_string(),
+        b'U' => format!("{:02}", (tm.yday + 7 - tm.wday) / 7),
+        b'V' => format!("{:02}", iso_week(tm).1),
+        b'w' => tm.wday.to_string(),
+        b'W' => format!("{:02}", (tm.yday + 7 - (tm.wday + 6) % 7) / 7),
+        b'y' => format!("{:02}", year.rem_euclid(100)),
+        b'Y' => year.to_string(),
+        b'z' => {
+            let sign = if tm.gmtoff < 0 { '-' } else { '+' };
+            let minutes = tm.gmtoff.abs() / 60;
+            format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+        }
+        b'Z' => tm.zone.clone(),
+        _ => "%".to_string(),
+    };
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// brief: append a format of valid conversions
+fn strftime_all(out: &mut Vec<u8>, format: &[u8], tm: &Tm) {
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] == b'%' {
+            strftime(out, &format[i + 1..i + 2], tm);
+            i += 2;
+        } else {
+            out.push(format[i]);
+            i += 1;
+        }
+    }
+}
+
+/// brief: the length of the valid conversion at the start of `conv`
+fn check_conversion(conv: &[u8]) -> Option<usize> {
+    let mut oplen = 1;
+    let mut option = LUA_STRFTIMEOPTIONS;
+    while !option.is_empty() && oplen <= conv.len() {
+        if option[0] == b'|' {
+            // the next block has longer options
+            oplen += 1;
+        } else if option[..oplen] == conv[..oplen] {
+            return Some(oplen);
+        }
+        option = &option[oplen.min(option.len())..];
+    }
+    None
+}
+
+/// brief: a time argument
+fn check_time(state: &mut LuaState, arg: isize, fname: &str) -> i64 {
+    check_integer(state, arg, fname)
+}
+
+/// brief: set the fields of a date table, its metamethods are honoured
+fn set_all_fields(state: &mut LuaState, table: &Value, tm: &Tm) {
+    let fields = [
+        ("year", tm.year as INT + 1900),
+        ("month", tm.mon as INT + 1),
+        ("day", tm.mday as INT),
+        ("hour", tm.hour as INT),
+        ("min", tm.min as INT),
+        ("sec", tm.sec as INT),
+        ("yday", tm.yday as INT + 1),
+        ("wday", tm.wday as INT + 1),
+    ];
+    for (key, val) in fields {
+        if let Err(err) = state.set_index(table, Value::from(key), Value::Integer(val)) {
+            state.error(err);
+        }
+    }
+    // an undefined isdst is left out
+    if tm.isdst >= 0 {
+        if let Err(err) = state.set_index(table, Value::from("isdst"), Value::Boolean(tm.isdst != 0)) {
+            state.error(err);
+        }
+    }
+}
+
+/// brief: a field of a date table less `delta`, `default` when absent; a
+/// negative default makes the field required
+fn get_field(state: &mut LuaState, table: &Value, key: &str, default: i32, delta: i32) -> i32 {
+    let val = match state.index(table, &Value::from(key)) {
+        Ok(val) => val,
+        Err(err) => state.error(err),
+    };
+    match INT::from_lua(val.clone(), state) {
+        Ok(res) => {
+            let fits = if res >= 0 {
+                res - delta as INT <= i32::MAX as INT
+            } else {
+                i32::MIN as INT + delta as INT <= res
+            };
+            if !fits {
+                state.error(LuaError::Runtime(format!("field '{}' is out-of-bound", key)));
+            }
+            (res - delta as INT) as i32
+        }
+        Err(_) if !val.is_nil() => {
+            state.error(LuaError::Runtime(format!("field '{}' is not an integer", key)))
+        }
+        Err(_) if default < 0 => state.error(LuaError::Runtime(format!(
+            "field '{}' missing in date table",
+            key
+        ))),
+        Err(_) => default,
+    }
+}
+
+/// brief: a boolean field of a date table, -1 when absent
+fn get_bool_field(state: &mut LuaState, table: &Value, key: &str) -> i32 {
+    match state.index(table, &Value::from(key)) {
+        Ok(Value::Nil) => -1,
+        Ok(val) => i32::from(val.is_truthy()),
+        Err(err) => state.error(err),
+    }
+}
+
+/// brief: os.date([format [, time]]), a date table for "*t" and a string
+/// otherwise. a format starting with '!' is in universal time
+fn os_date(state: &mut LuaState) -> usize {
+    let format = opt_lstring(state, 1, "date", "%c");
+    let t = if is_none_or_nil(state, 2) {
+        now()
+    } else {
+        check_time(state, 2, "date")
+    };
+    let bytes = format.as_bytes();
+    let (bytes, tm) = match bytes.strip_prefix(b"!") {
+        Some(rest) => (rest, gm_time(t)),
+        None => (bytes, ctime::local_time(t)),
+    };
+    let tm = match tm {
+        Some(tm) => tm,
+        None => state.error(LuaError::Runtime(
+            "date result cannot be represented in this installation".to_string(),
+        )),
+    };
+    if bytes == b"*t" {
+        let table = Value::Table(state.new_table_value(0, 9));
+        set_all_fields(state, &table, &tm);
+        state.push_value(&table);
+        return 1;
+    }
+
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let conv = &bytes[i + 1..];
+        match check_conversion(conv) {
+            Some(len) => {
+                strftime(&mut out, &conv[..len], &tm);
+                i += 1 + len;
+            }
+            None => {
+                let message = format!(
+                    "invalid conversion specifier '%{}'",
+                    String::from_utf8_lossy(conv)
+                );
+                arg_error(state, 1, "date", &message)
+            }
+        }
+    }
+    state.push_string(&out);
+    1
+}
+
+/// brief: os.time([table]), the current time, or the time of a date table
+/// whose fields are normalized in place
+fn os_time(state: &mut LuaState) -> usize {
+    let t = if is_none_or_nil(state, 1) {
+        now()
+    } else {
+        let table = Value::Table(check_table(state, 1, "time"));
+        let mut tm = Tm {
+            year: get_field(state, &table, "year", -1, 1900),
+            mon: get_field(state, &table, "month", -1, 1),
+            mday: get_field(state, &table, "day", -1, 0),
+            hour: get_field(state, &table, "hour", 12, 0),
+            min: get_field(state, &table, "min", 0, 0),
+            sec: get_field(state, &table, "sec", 0, 0),
+            ..Tm::default()
+        };
+        tm.isdst = get_bool_field(state, &table, "isdst");
+        let (t, normalized) = ctime::make_time(&tm);
+        if t == -1 {
+            state.error(LuaError::Runtime(
+                "time result cannot be represented in this installation".to_string(),
+            ));
+        }
+        set_all_fields(state, &table, &normalized);
+        t
+    };
+    state.push_integer(t);
+    1
+}
+
+fn os_clock(state: &mut LuaState) -> usize {
+    state.push_float(ctime::cpu_time());
+    1
+}
+
+fn os_difftime(state: &mut LuaState) -> usize {
+    let t1 = check_time(state, 1, "difftime");
+    let t2 = check_time(state, 2, "difftime");
+    state.push_float(t1 as FLT - t2 as FLT);
+    1
+}
+
+fn os_getenv(state: &mut LuaState) -> usize {
+    let name = check_lstring(state, 1, "getenv").to_string_lossy();
+    match env::var_os(name) {
+        Some(val) => state.push_string(val.to_string_lossy().as_bytes()),
+        None => state.push_nil(),
+    }
+    1
+}
+
+/// brief: os.remove(filename), an empty directory is removed as well
+fn os_remove(state: &mut LuaState) -> usize {
+    let filename = check_lstring(state, 1, "remove").to_string_lossy();
+    let res = match fs::symlink_metadata(&filename) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir(&filename),
+        _ => fs::remove_file(&filename),
+    };
+    file_result(state, res, Some(&filename))
+}
+
+fn os_rename(state: &mut LuaState) -> usize {
+    let from = check_lstring(state, 1, "rename").to_string_lossy();
+    let to = check_lstring(state, 2, "rename").to_string_lossy();
+    let res = fs::rename(&from, to);
+    file_result(state, res, Some(&from))
+}
+
+/// brief: the name of a new empty file, which the caller removes
+fn os_tmpname(state: &mut LuaState) -> usize {
+    match create_temp_file() {
+        Ok((path, _)) => state.push_str(&path.to_string_lossy()),
+        Err(_) => state.error(LuaError::Runtime(
+            "unable to generate a unique filename".to_string(),
+        )),
+    }
+    1
+}
+
+/// brief: os.execute([command]), whether a shell exists without a command,
+/// the status of the command otherwise
+fn os_execute(state: &mut LuaState) -> usize {
+    if is_none_or_nil(state, 1) {
+        state.push_bool(Path::new("/bin/sh").exists());
+        return 1;
+    }
+    let command = check_lstring(state, 1, "execute").to_string_lossy();
+    let status = Command::new("/bin/sh").arg("-c").arg(command).status();
+    exec_result(state, status)
+}
+
+/// brief: os.exit([code [, close]]), the state is closed first with a true
+/// `close`, which drops its objects
+fn os_exit(state: &mut LuaState) -> usize {
+    let code = match state.get_value(1) {
+        Value::Boolean(success) => i32::from(!success),
+        _ => opt_integer(state, 1, "exit", 0) as i32,
+    };
+    if state.get_value(2).is_truthy() {
+        state.close();
+    }
+    process::exit(code)
+}
+
+/// brief: os.setlocale([locale [, category]]), only the C locale exists;
+/// "" and "POSIX" name it as well
+fn os_setlocale(state: &mut LuaState) -> usize {
+    const CATEGORIES: [&str; 6] = ["all", "collate", "ctype", "monetary", "numeric", "time"];
+    check_option(state, 2, "setlocale", Some("all"), &CATEGORIES);
+    let known = match state.get_value(1) {
+        Value::Nil => true,
+        _ => matches!(
+            check_lstring(state, 1, "setlocale").as_bytes(),
+            b"" | b"C" | b"POSIX"
+        ),
+    };
+    if known {
+        state.push_str("C");
+    } else {
+        state.push_nil();
+    }
+    1
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::common::lua::LuaResult;
+    use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti, MultiValue};
+    use crate::common::obj::objvalue::{Function, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    use super::{gm_time, iso_week, set_all_fields};
+
+    fn call<A: IntoLuaMulti, R: FromLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<R> {
+        let os: Table = state.get_global("os")?;
+        let function: Function = os.get(state, name)?;
+        function.call(state, args)
+    }
+
+    /// brief: the date table of `t` in universal time
+    fn utc_table(state: &mut LuaState, t: i64) -> Table {
+        let table = state.create_table_value(0, 9).unwrap();
+        set_all_fields(state, &Value::Table(table.clone()), &gm_time(t).unwrap());
+        table
+    }
+
+    fn date(state: &mut LuaState, format: &str, t: i64) -> String {
+        call(state, "date", (format, t)).unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // miri cannot call into the C time functions
+    fn dates_in_universal_time() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let t = 1_700_000_000;
+        assert_eq!(date(state, "!%Y-%m-%d %H:%M:%S", t), "2023-11-14 22:13:20");
+        assert_eq!(date(state, "!%c", t), "Tue Nov 14 22:13:20 2023");
+        assert_eq!(date(state, "!%a %A %b %B %h", t), "Tue Tuesday Nov November Nov");
+        assert_eq!(
+            date(state, "!%C %y %G %g %V %U %W %j", t),
+            "20 23 2023 23 46 46 46 318"
+        );
+        assert_eq!(
+            date(state, "!%D|%F|%T|%R|%r|%x|%X", t),
+            "11/14/23|2023-11-14|22:13:20|22:13|10:13:20 PM|11/14/23|22:13:20"
+        );
+        assert_eq!(
+            date(state, "!%e|%I|%p|%u|%w|%z|%Z|%%|%n|%t", 0),
+            " 1|12|AM|4|4|+0000|GMT|%|\n|\t"
+        );
+        assert_eq!(date(state, "!%Ey %EY %Od %OH", t), "23 2023 14 22");
+        assert_eq!(date(state, "!ä %d ü", t), "ä 14 ü");
+        assert_eq!(date(state, "!%Y", -1), "1969");
+        assert_eq!(
+            call::<_, MultiValue>(state, "date", ("%Ex and %Q", t))
+                .unwrap_err()
+                .to_string(),
+            "bad argument #1 to 'date' (invalid conversion specifier '%Q')"
+        );
+        assert_eq!(
+            call::<_, MultiValue>(state, "date", ("%Ez", t))
+                .unwrap_err()
+                .to_string(),
+            "bad argument #1 to 'date' (invalid conversion specifier '%Ez')"
+        );
+        assert_eq!(
+            call::<_, MultiValue>(state, "date", ("x%", t))
+                .unwrap_err()
+                .to_string(),
+            "bad argument #1 to 'date' (invalid conversion specifier '%')"
+        );
+
+        let table: Table = call(state, "date", ("!*t", t)).unwrap();
+        let fields: Vec<i64> = ["year", "month", "day", "hour", "min", "sec", "yday", "wday"]
+            .iter()
+            .map(|key| table.get(state, *key).unwrap())
+            .collect();
+        assert_eq!(fields, [2023, 11, 14, 22, 13, 20, 318, 3]);
+        let isdst: bool = table.get(state, "isdst").unwrap();
+        assert!(!isdst);
+    }
+
+    #[test]
+    fn iso_weeks_cross_years() {
+        // 2021-01-03 is in week 53 of 2020, 2024-12-30 in week 1 of 2025
+        let cases = [
+            (1_609_632_000, (2020, 53)),
+            (1_735_516_800, (2025, 1)),
+            (1_704_067_200, (2024, 1)),
+            (1_230_768_000, (2009, 1)),
+        ];
+        for (t, week) in cases {
+            assert_eq!(iso_week(&gm_time(t).unwrap()), week);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // miri cannot call into the C time functions
+    fn times_normalize_tables() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let t = 1_700_000_000;
+        let table: Table = call(state, "date", ("*t", t)).unwrap();
+        let back: i64 = call(state, "time", table).unwrap();
+        assert_eq!(back, t);
+
+        let table = state.create_table_value(0, 4).unwrap();
+        table.set(state, "year", 2024).unwrap();
+        table.set(state, "month", 1).unwrap();
+        table.set(state, "day", 32).unwrap();
+        table.set(state, "isdst", false).unwrap();
+        let _: i64 = call(state, "time", table.clone()).unwrap();
+        let fields: Vec<i64> = ["month", "day", "hour", "yday"]
+            .iter()
+            .map(|key| table.get(state, *key).unwrap())
+            .collect();
+        assert_eq!(fields, [2, 1, 12, 32]);
+
+        let utc = utc_table(state, 0);
+        utc.set(state, "month", "x").unwrap();
+        assert_eq!(
+            call::<_, MultiValue>(state, "time", utc.clone())
+                .unwrap_err()
+                .to_string(),
+            "field 'month' is not an integer"
+        );
+        utc.set(state, "month", Value::Nil).unwrap();
+        assert_eq!(
+            call::<_, MultiValue>(state, "time", utc.clone())
+                .unwrap_err()
+                .to_string(),
+            "field 'month' missing in date table"
+        );
+        utc.set(state, "month", 1).unwrap();
+        utc.set(state, "day", 1i64 << 40).unwrap();
+        assert_eq!(
+            call::<_, MultiValue>(state, "time", utc).unwrap_err().to_string(),
+            "field 'day' is out-of-bound"
+        );
+
+        let now: i64 = call(state, "time", ()).unwrap();
+        assert!(now > t);
+        let diff: f64 = call(state, "difftime", (t, 1_600_000_000)).unwrap();
+        assert_eq!(diff, 100_000_000.0);
+        let clock: f64 = call(state, "clock", ()).unwrap();
+        assert!(clock >= 0.0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // miri cannot spawn processes
+    fn files_and_processes() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let name: String = call(state, "tmpname", ()).unwrap();
+        assert!(fs::metadata(&name).unwrap().is_file());
+        let renamed = format!("{}_renamed", name);
+        let ok: bool = call(state, "rename", (name.as_str(), renamed.as_str())).unwrap();
+        assert!(ok);
+        let ok: bool = call(state, "remove", renamed.as_str()).unwrap();
+        assert!(ok);
+        let (ok, message, errno): (Value, String, i64) = call(state, "remove", renamed.as_str()).unwrap();
+        assert_eq!(ok, Value::Nil);
+        assert_eq!(message, format!("{}: No such file or directory", renamed));
+        assert_eq!(errno, 2);
+
+        let shell: bool = call(state, "execute", ()).unwrap();
+        assert!(shell);
+        let status: (Value, String, i64) = call(state, "execute", "exit 3").unwrap();
+        assert_eq!(status, (Value::Nil, "exit".to_string(), 3));
+        let status: (bool, String, i64) = call(state, "execute", "true").unwrap();
+        assert_eq!(status, (true, "exit".to_string(), 0));
+        let status: (Value, String, i64) = call(state, "execute", "kill -9 $$").unwrap();
+        assert_eq!(status, (Value::Nil, "signal".to_string(), 9));
+
+        let path: Option<String> = call(state, "getenv", "PATH").unwrap();
+        assert!(path.is_some());
+        let missing: Option<String> = call(state, "getenv", "NAIVE_LUA2_NO_SUCH_VARIABLE").unwrap();
+        assert_eq!(missing, None);
+        let locale: String = call(state, "setlocale", ()).unwrap();
+        assert_eq!(locale, "C");
+        let locale: Value = call(state, "setlocale", ("fr_FR", "time")).unwrap();
+        assert_eq!(locale, Value::Nil);
+    }
+}
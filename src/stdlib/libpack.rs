@@ -0,0 +1,544 @@
+use crate::common::lua::LuaError;
+use crate::common::obj::objconv::MultiValue;
+use crate::common::obj::objtype::{FLT, INT};
+use crate::common::obj::objvalue::{LuaString, Value};
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::{arg_error, check_integer, check_lstring, check_number, opt_integer};
+use super::libstring::{posrelat_i, MAX_SIZE};
+
+const MAXINTSIZE: usize = 16; // the widest integer of a format
+const SZINT: usize = std::mem::size_of::<INT>();
+const MAXALIGN: usize = 8; // the alignment of the widest native type
+const PACKPADBYTE: u8 = 0x00;
+
+/// brief: the kind of an option of a pack format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KOption {
+    Int,       // signed integers
+    Uint,      // unsigned integers
+    Float,     // single floats
+    Number,    // lua floats
+    Double,    // double floats
+    Char,      // fixed-length strings
+    String,    // strings with a length before
+    Zstr,      // zero-terminated strings
+    Padding,   // a padding byte
+    PaddAlign, // padding to an alignment
+    Nop,       // no data
+}
+
+/// brief: the state of reading a pack format
+struct Header<'a> {
+    fmt: &'a [u8],
+    pos: usize,
+    fname: &'static str,
+    islittle: bool,
+    maxalign: usize,
+}
+
+impl<'a> Header<'a> {
+    fn new(fmt: &'a [u8], fname: &'static str) -> Self {
+        Self {
+            fmt,
+            pos: 0,
+            fname,
+            islittle: cfg!(target_endian = "little"),
+            maxalign: 1,
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.fmt.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.fmt.get(self.pos).copied()
+    }
+
+    /// brief: the number at the format, `df` for none
+    fn get_num(&mut self, df: usize) -> usize {
+        if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            return df;
+        }
+        let mut a = 0;
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_digit()) {
+            a = a * 10 + (c - b'0') as usize;
+            self.pos += 1;
+            if a > (MAX_SIZE - 9) / 10 {
+                break;
+            }
+        }
+        a
+    }
+
+    /// brief: the size of an integer option, 1 to MAXINTSIZE
+    fn get_num_limit(&mut self, state: &mut LuaState, df: usize) -> usize {
+        let size = self.get_num(df);
+        if size > MAXINTSIZE || size == 0 {
+            state.error(LuaError::Runtime(format!(
+                "integral size ({}) out of limits [1,{}]",
+                size, MAXINTSIZE
+            )));
+        }
+        size
+    }
+
+    /// brief: read an option, its kind and size
+    fn get_option(&mut self, state: &mut LuaState) -> (KOption, usize) {
+        let opt = self.fmt[self.pos];
+        self.pos += 1;
+        match opt {
+            b'b' => (KOption::Int, 1),
+            b'B' => (KOption::Uint, 1),
+            b'h' => (KOption::Int, 2),
+            b'H' => (KOption::Uint, 2),
+            b'l' | b'j' => (KOption::Int, SZINT),
+            b'L' | b'J' => (KOption::Uint, SZINT),
+            b'T' => (KOption::Uint, std::mem::size_of::<usize>()),
+            b'f' => (KOption::Float, 4),
+            b'n' => (KOption::Number, std::mem::size_of::<FLT>()),
+            b'd' => (KOption::Double, 8),
+            b'i' => (KOption::Int, self.get_num_limit(state, 4)),
+            b'I' => (KOption::Uint, self.get_num_limit(state, 4)),
+            b's' => (
+                KOption::String,
+                self.get_num_limit(state, std::mem::size_of::<usize>()),
+            ),
+            b'c' => match self.get_num(usize::MAX) {
+                usize::MAX => state.error(LuaError::Runtime(
+                    "missing size for format option 'c'".to_string(),
+                )),
+                size => (KOption::Char, size),
+            },
+            b'z' => (KOption::Zstr, 0),
+            b'x' => (KOption::Padding, 1),
+            b'X' => (KOption::PaddAlign, 0),
+            b' ' => (KOption::Nop, 0),
+            b'<' => {
+                self.islittle = true;
+                (KOption::Nop, 0)
+            }
+            b'>' => {
+                self.islittle = false;
+                (KOption::Nop, 0)
+            }
+            b'=' => {
+                self.islittle = cfg!(target_endian = "little");
+                (KOption::Nop, 0)
+            }
+            b'!' => {
+                self.maxalign = self.get_num_limit(state, MAXALIGN);
+                (KOption::Nop, 0)
+            }
+            opt => state.error(LuaError::Runtime(format!(
+                "invalid format option '{}'",
+                opt as char
+            ))),
+        }
+    }
+
+    /// brief: read an option, its kind, size and the padding to align it at
+    /// `totalsize`
+    fn get_details(&mut self, state: &mut LuaState, totalsize: usize) -> (KOption, usize, usize) {
+        let (opt, size) = self.get_option(state);
+        // usually, alignment follows size
+        let mut align = size;
+        if opt == KOption::PaddAlign {
+            // 'X' gets alignment from following option
+            let next = if self.at_end() {
+                None
+            } else {
+                Some(self.get_option(state))
+            };
+            match next {
+                Some((next, next_align)) if next != KOption::Char && next_align != 0 => align = next_align,
+                _ => arg_error(state, 1, self.fname, "invalid next option for option 'X'"),
+            }
+        }
+        if align <= 1 || opt == KOption::Char {
+            return (opt, size, 0);
+        }
+        align = align.min(self.maxalign);
+        if !align.is_power_of_two() {
+            arg_error(state, 1, self.fname, "format asks for alignment not power of 2");
+        }
+        let ntoalign = (align - (totalsize & (align - 1))) & (align - 1);
+        (opt, size, ntoalign)
+    }
+}
+
+/// brief: append the `size` bytes of `n`, sign extended past the lua integer
+fn pack_int(buf: &mut Vec<u8>, n: u64, islittle: bool, size: usize, neg: bool) {
+    let mut bytes = [0u8; MAXINTSIZE];
+    for (i, byte) in bytes.iter_mut().enumerate().take(size) {
+        *byte = if i < SZINT {
+            (n >> (i * 8)) as u8
+        } else if neg {
+            0xff
+        } else {
+            0
+        };
+    }
+    let bytes = &mut bytes[..size];
+    if !islittle {
+        bytes.reverse();
+    }
+    buf.extend_from_slice(bytes);
+}
+
+/// brief: append native bytes in the asked endianness
+fn copy_with_endian(buf: &mut Vec<u8>, native: &[u8], islittle: bool) {
+    if islittle == cfg!(target_endian = "little") {
+        buf.extend_from_slice(native);
+    } else {
+        buf.extend(native.iter().rev());
+    }
+}
+
+pub(crate) fn str_pack(state: &mut LuaState) -> usize {
+    let fmt = check_lstring(state, 1, "pack");
+    let mut h = Header::new(fmt.as_bytes(), "pack");
+    let mut buf = Vec::new();
+    let mut arg = 1;
+    let mut totalsize = 0;
+    while !h.at_end() {
+        let (opt, size, ntoalign) = h.get_details(state, totalsize);
+        totalsize += ntoalign + size;
+        buf.resize(buf.len() + ntoalign, PACKPADBYTE);
+        arg += 1;
+        match opt {
+            KOption::Int => {
+                let n = check_integer(state, arg, "pack");
+                if size < SZINT {
+                    let lim: INT = 1 << (size * 8 - 1);
+                    if !(-lim <= n && n < lim) {
+                        arg_error(state, arg, "pack", "integer overflow");
+                    }
+                }
+                pack_int(&mut buf, n as u64, h.islittle, size, n < 0);
+            }
+            KOption::Uint => {
+                let n = check_integer(state, arg, "pack");
+                if size < SZINT && (n as u64) >= 1 << (size * 8) {
+                    arg_error(state, arg, "pack", "unsigned overflow");
+                }
+                pack_int(&mut buf, n as u64, h.islittle, size, false);
+            }
+            KOption::Float => {
+                let f = check_number(state, arg, "pack") as f32;
+                copy_with_endian(&mut buf, &f.to_ne_bytes(), h.islittle);
+            }
+            KOption::Number | KOption::Double => {
+                let f = check_number(state, arg, "pack");
+                copy_with_endian(&mut buf, &f.to_ne_bytes(), h.islittle);
+            }
+            KOption::Char => {
+                let s = check_lstring(state, arg, "pack");
+                let s = s.as_bytes();
+                if s.len() > size {
+                    arg_error(state, arg, "pack", "string longer than given size");
+                }
+                buf.extend_from_slice(s);
+                buf.resize(buf.len() + size - s.len(), PACKPADBYTE);
+            }
+            KOption::String => {
+                let s = check_lstring(state, arg, "pack");
+                let s = s.as_bytes();
+                if size < std::mem::size_of::<usize>() && s.len() as u64 >= 1 << (size * 8) {
+                    arg_error(state, arg, "pack", "string length does not fit in given size");
+                }
+                pack_int(&mut buf, s.len() as u64, h.islittle, size, false);
+                buf.extend_from_slice(s);
+                totalsize += s.len();
+            }
+            KOption::Zstr => {
+                let s = check_lstring(state, arg, "pack");
+                let s = s.as_bytes();
+                if s.contains(&0) {
+                    arg_error(state, arg, "pack", "string contains zeros");
+                }
+                buf.extend_from_slice(s);
+                buf.push(0);
+                totalsize += s.len() + 1;
+            }
+            KOption::Padding | KOption::PaddAlign | KOption::Nop => {
+                if opt == KOption::Padding {
+                    buf.push(PACKPADBYTE);
+                }
+                // no argument taken
+                arg -= 1;
+            }
+        }
+    }
+    state.push_string(&buf);
+    1
+}
+
+pub(crate) fn str_packsize(state: &mut LuaState) -> usize {
+    let fmt = check_lstring(state, 1, "packsize");
+    let mut h = Header::new(fmt.as_bytes(), "packsize");
+    let mut totalsize = 0;
+    while !h.at_end() {
+        let (opt, size, ntoalign) = h.get_details(state, totalsize);
+        if opt == KOption::String || opt == KOption::Zstr {
+            arg_error(state, 1, "packsize", "variable-size format in packsize");
+        }
+        let size = size + ntoalign;
+        if size > MAX_SIZE || totalsize > MAX_SIZE - size {
+            arg_error(state, 1, "packsize", "format result too large");
+        }
+        totalsize += size;
+    }
+    state.push_integer(totalsize as INT);
+    1
+}
+
+/// brief: read an integer of `size` bytes, the bytes past the lua integer
+/// must be its sign extension
+fn unpack_int(state: &mut LuaState, bytes: &[u8], islittle: bool, size: usize, issigned: bool) -> INT {
+    let byte_at = |i: usize| if islittle { bytes[i] } else { bytes[size - 1 - i] };
+    let limit = size.min(SZINT);
+    let mut res: u64 = 0;
+    for i in (0..limit).rev() {
+        res = (res << 8) | byte_at(i) as u64;
+    }
+    if size < SZINT {
+        if issigned {
+            let mask: u64 = 1 << (size * 8 - 1);
+            res = (res ^ mask).wrapping_sub(mask);
+        }
+    } else if size > SZINT {
+        let mask = if !issigned || (res as INT) >= 0 { 0 } else { 0xff };
+        if (limit..size).any(|i| byte_at(i) != mask) {
+            state.error(LuaError::Runtime(format!(
+                "{}-byte integer does not fit into Lua Integer",
+                size
+            )));
+        }
+    }
+    res as INT
+}
+
+/// brief: native bytes of `data` in the asked endianness
+fn read_with_endian<const N: usize>(data: &[u8], islittle: bool) -> [u8; N] {
+    let mut bytes: [u8; N] = data[..N].try_into().unwrap();
+    if islittle != cfg!(target_endian = "little") {
+        bytes.reverse();
+    }
+    bytes
+}
+
+pub(crate) fn str_unpack(state: &mut LuaState) -> usize {
+    let fmt = check_lstring(state, 1, "unpack");
+    let data = check_lstring(state, 2, "unpack");
+    let data = data.as_bytes();
+    let ld = data.len();
+    let mut pos = posrelat_i(opt_integer(state, 3, "unpack", 1), ld) - 1;
+    if pos > ld {
+        arg_error(state, 3, "unpack", "initial position out of string");
+    }
+    let mut h = Header::new(fmt.as_bytes(), "unpack");
+    let mut results = MultiValue::new();
+    while !h.at_end() {
+        let (opt, size, ntoalign) = h.get_details(state, pos);
+        if ntoalign.saturating_add(size) > ld - pos {
+            arg_error(state, 2, "unpack", "data string too short");
+        }
+        // skip alignment
+        pos += ntoalign;
+        let item = &data[pos..];
+        match opt {
+            KOption::Int | KOption::Uint => {
+                let n = unpack_int(state, item, h.islittle, size, opt == KOption::Int);
+                results.push_back(Value::Integer(n));
+            }
+            KOption::Float => {
+                let f = f32::from_ne_bytes(read_with_endian(item, h.islittle));
+                results.push_back(Value::Number(f as FLT));
+            }
+            KOption::Number | KOption::Double => {
+                let f = FLT::from_ne_bytes(read_with_endian(item, h.islittle));
+                results.push_back(Value::Number(f));
+            }
+            KOption::Char => results.push_back(Value::String(LuaString::new(&item[..size]))),
+            KOption::String => {
+                let len = unpack_int(state, item, h.islittle, size, false) as u64;
+                if len > (ld - pos - size) as u64 {
+                    arg_error(state, 2, "unpack", "data string too short");
+                }
+                let len = len as usize;
+                results.push_back(Value::String(LuaString::new(&item[size..size + len])));
+                // skip the string
+                pos += len;
+            }
+            KOption::Zstr => {
+                let len = match item.iter().position(|c| *c == 0) {
+                    Some(len) => len,
+                    None => arg_error(state, 2, "unpack", "unfinished string for format 'z'"),
+                };
+                results.push_back(Value::String(LuaString::new(&item[..len])));
+                // skip the string and the zero
+                pos += len + 1;
+            }
+            KOption::PaddAlign | KOption::Padding | KOption::Nop => {}
+        }
+        pos += size;
+    }
+    // the next position
+    results.push_back(Value::Integer(pos as INT + 1));
+    match state.push_results(results) {
+        Ok(nresults) => nresults,
+        Err(err) => state.error(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::lua::LuaResult;
+    use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti, MultiValue};
+    use crate::common::obj::objvalue::{Function, LuaString, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    fn call<A: IntoLuaMulti, R: FromLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<R> {
+        let string: Table = state.get_global("string")?;
+        let function: Function = string.get(state, name)?;
+        function.call(state, args)
+    }
+
+    fn call_err<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> String {
+        call::<_, MultiValue>(state, name, args).unwrap_err().to_string()
+    }
+
+    fn pack<A: IntoLuaMulti>(state: &mut LuaState, args: A) -> Vec<u8> {
+        let packed: LuaString = call(state, "pack", args).unwrap();
+        packed.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn integers_and_endianness() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        assert_eq!(pack(state, ("<i4", 1)), [1, 0, 0, 0]);
+        assert_eq!(pack(state, (">i4", 1)), [0, 0, 0, 1]);
+        assert_eq!(pack(state, ("<h", -2)), [0xfe, 0xff]);
+        assert_eq!(pack(state, ("bB", -1, 255)), [0xff, 0xff]);
+        assert_eq!(pack(state, ("<i16", -1)), [0xff; 16]);
+        assert_eq!(pack(state, (">I3", 0x010203)), [1, 2, 3]);
+        let packed = LuaString::new(&pack(state, ("<i16", -5)));
+        let n: i64 = call(state, "unpack", ("<i16", packed)).unwrap();
+        assert_eq!(n, -5);
+        let (n, next): (i64, i64) = call(state, "unpack", ("<h", LuaString::new(&[0xfe, 0xff]))).unwrap();
+        assert_eq!((n, next), (-2, 3));
+        let n: i64 = call(state, "unpack", ("<H", LuaString::new(&[0xfe, 0xff]))).unwrap();
+        assert_eq!(n, 0xfffe);
+        let packed = LuaString::new(&pack(state, (">j", i64::MIN)));
+        let n: i64 = call(state, "unpack", (">j", packed)).unwrap();
+        assert_eq!(n, i64::MIN);
+
+        assert_eq!(
+            call_err(state, "pack", ("i1", 128)),
+            "bad argument #2 to 'pack' (integer overflow)"
+        );
+        assert_eq!(
+            call_err(state, "pack", ("I1", -1)),
+            "bad argument #2 to 'pack' (unsigned overflow)"
+        );
+        assert_eq!(
+            call_err(state, "pack", ("i17", 1)),
+            "integral size (17) out of limits [1,16]"
+        );
+        let mut wide = vec![0u8; 16];
+        wide[8] = 1;
+        assert_eq!(
+            call_err(state, "unpack", ("<i16", LuaString::new(&wide))),
+            "16-byte integer does not fit into Lua Integer"
+        );
+    }
+
+    #[test]
+    fn floats_and_strings() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        assert_eq!(pack(state, ("<f", 1.5)), 1.5f32.to_le_bytes());
+        assert_eq!(pack(state, (">d", 1.5)), 1.5f64.to_be_bytes());
+        let packed = LuaString::new(&pack(state, ("<n", 0.1)));
+        let f: f64 = call(state, "unpack", ("<n", packed)).unwrap();
+        assert_eq!(f, 0.1);
+
+        assert_eq!(pack(state, ("c5", "ab")), b"ab\0\0\0");
+        assert_eq!(pack(state, ("<s2", "hi")), b"\x02\0hi");
+        assert_eq!(pack(state, ("zB", "hi", 7)), b"hi\0\x07");
+        let (a, b, c, next): (String, String, String, i64) =
+            call(state, "unpack", ("c2 s1 z", LuaString::new(b"ab\x03xyzend\0"))).unwrap();
+        assert_eq!(
+            (a.as_str(), b.as_str(), c.as_str(), next),
+            ("ab", "xyz", "end", 11)
+        );
+
+        assert_eq!(
+            call_err(state, "pack", ("c1", "ab")),
+            "bad argument #2 to 'pack' (string longer than given size)"
+        );
+        assert_eq!(
+            call_err(state, "pack", ("s1", "x".repeat(256))),
+            "bad argument #2 to 'pack' (string length does not fit in given size)"
+        );
+        assert_eq!(
+            call_err(state, "pack", ("z", "a\0b")),
+            "bad argument #2 to 'pack' (string contains zeros)"
+        );
+        assert_eq!(
+            call_err(state, "unpack", ("z", "abc")),
+            "bad argument #2 to 'unpack' (unfinished string for format 'z')"
+        );
+        assert_eq!(
+            call_err(state, "unpack", ("s1", LuaString::new(b"\x05ab"))),
+            "bad argument #2 to 'unpack' (data string too short)"
+        );
+        assert_eq!(call_err(state, "pack", "c"), "missing size for format option 'c'");
+        assert_eq!(call_err(state, "pack", "y"), "invalid format option 'y'");
+    }
+
+    #[test]
+    fn alignment_and_sizes() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        assert_eq!(pack(state, ("<!4 b i4", 1, 2)), [1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(pack(state, ("<!2 b x Xh b", 1, 2)), [1, 0, 2]);
+        assert_eq!(pack(state, ("<!8 b Xi8", 1)), [1, 0, 0, 0, 0, 0, 0, 0]);
+        let size: i64 = call(state, "packsize", "!8 b d").unwrap();
+        assert_eq!(size, 16);
+        let size: i64 = call(state, "packsize", "i4 i8 c3").unwrap();
+        assert_eq!(size, 15);
+        let data = LuaString::new(&[9, 0, 0, 0, 1, 0, 0, 0, 7]);
+        let (b, n, next): (i64, i64, i64) = call(state, "unpack", ("<!4 b i4", data.clone())).unwrap();
+        assert_eq!((b, n, next), (9, 1, 9));
+        let (b, next): (i64, i64) = call(state, "unpack", ("b", data, -1)).unwrap();
+        assert_eq!((b, next), (7, 10));
+
+        assert_eq!(
+            call_err(state, "packsize", "s"),
+            "bad argument #1 to 'packsize' (variable-size format in packsize)"
+        );
+        assert_eq!(
+            call_err(state, "packsize", "c1000000000 c1000000000 c1000000000"),
+            "bad argument #1 to 'packsize' (format result too large)"
+        );
+        assert_eq!(
+            call_err(state, "pack", ("X", 1)),
+            "bad argument #1 to 'pack' (invalid next option for option 'X')"
+        );
+        assert_eq!(
+            call_err(state, "pack", ("!4 i3", 1)),
+            "bad argument #1 to 'pack' (format asks for alignment not power of 2)"
+        );
+        assert_eq!(
+            call_err(state, "unpack", ("b", "", 3)),
+            "bad argument #3 to 'unpack' (initial position out of string)"
+        );
+        let empty: MultiValue = call(state, "unpack", ("", "")).unwrap();
+        assert_eq!(empty, MultiValue::from([Value::Integer(1)]));
+    }
+}
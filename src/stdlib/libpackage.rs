@@ -0,0 +1,1004 @@
+use std::env;
+use std::ffi::{c_char, CStr};
+use std::fmt::Display;
+use std::fs::File;
+use std::mem::size_of;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
+
+use crate::capi::capidef::{c_closure, CFunction};
+use crate::capi::capiheader::CAPI_MARK;
+use crate::common::lua::{LuaError, LuaResult, LUA_RAISE_TOP};
+use crate::common::obj::objconv::MultiValue;
+use crate::common::obj::objtype::LRFUNC;
+use crate::common::obj::objvalue::{Function, LuaString, Table, Value};
+use crate::common::state::statedef::{LuaState, LuaThrow};
+
+use super::libaux::{check_lstring, get_subtable, new_lib, opt_lstring, tolstring};
+use super::libbase::load_file;
+
+pub(crate) const LOADED_TABLE: &str = "_LOADED";
+pub(crate) const PRELOAD_TABLE: &str = "_PRELOAD";
+const RUST_MODULES_TABLE: &str = "_RUST_MODULES"; // the modules a host registered
+const CLIBS_TABLE: &str = "_CLIBS"; // the native libraries loaded, by path
+const NOENV: &str = "LUA_NOENV"; // a true registry field ignores the environment
+
+const PATH_SEP: &str = ";";
+const PATH_MARK: &str = "?";
+const EXEC_DIR: &str = "!";
+const IGMARK: &str = "-";
+const DIRSEP: &str = "/";
+const OFSEP: &str = "_"; // the separator of the submodules in an entry point
+const POF: &str = "luaopen_"; // the prefix of the entry points
+
+#[cfg(target_os = "linux")]
+const LIB_FAIL: &str = "open";
+#[cfg(not(target_os = "linux"))]
+const LIB_FAIL: &str = "absent";
+
+const LUA_LDIR: &str = "/usr/local/share/lua/5.4/";
+const LUA_CDIR: &str = "/usr/local/lib/lua/5.4/";
+const VERSUFFIX: &str = "_5_4";
+
+const PACKAGE_FUNCS: &[(&str, LRFUNC)] = &[("loadlib", ll_loadlib), ("searchpath", ll_searchpath)];
+
+/// brief: the entry point `luaopen_<name>` of a native module. it is called
+/// as a function of the machine and returns the number of its results.
+/// the library links its own copy of this crate and of std, so a panic or a
+/// lua error cannot unwind from it into the machine: the entry point and
+/// every function it makes run their body in `module_call`, which gives the
+/// error back as data
+///
+/// ```ignore
+/// #[no_mangle]
+/// pub extern "C" fn luaopen_sample(state: &mut LuaState) -> usize {
+///     module_call(state, |state| {
+///         let module = state.create_table_value(0, 1)?;
+///         ...
+///         state.push_value(&Value::Table(module));
+///         Ok(1)
+///     })
+/// }
+/// ```
+///
+/// the loader only opens a library exporting the `ModuleAbi` of the machine.
+/// a library exporting `CAPI_MARK`, which the headers of
+/// `capi::capiheader` define, is a module of C instead: its entry points
+/// are called as `lua_CFunction`, and errors may escape them when it is
+/// built with unwind tables (`-fexceptions`)
+pub type LuaOpen = extern "C" fn(&mut LuaState) -> usize;
+
+/// brief: run the body of a function of a native module. the results are
+/// counted as usual, and an error, raised or returned, or a panic is pushed
+/// and reported with `LUA_RAISE_TOP`, the machine raises it on its side
+pub fn module_call(state: &mut LuaState, body: impl FnOnce(&mut LuaState) -> LuaResult<usize>) -> usize {
+    let err = match catch_unwind(AssertUnwindSafe(|| body(&mut *state))) {
+        Ok(Ok(nresults)) => return nresults,
+        Ok(Err(err)) => err,
+        Err(payload) => match payload.downcast::<LuaThrow>() {
+            Ok(throw) => throw.0,
+            Err(payload) => LuaError::callback_panic(payload),
+        },
+    };
+    // the message may not fit in the memory left, the stack has room for nil
+    let err = err.into_value();
+    if catch_unwind(AssertUnwindSafe(|| state.push_value(&err))).is_err() {
+        state.push_nil();
+    }
+    LUA_RAISE_TOP
+}
+
+/// brief: what a native module of rust is built against. every library
+/// linking this crate exports it as `MODULE_ABI_SYMBOL`, and a module is
+/// opened only when it agrees with the machine, since both sides share the
+/// layout of `LuaState`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleAbi {
+    version: *const c_char, // the crate and the compiler, nul terminated
+    state_size: usize,
+    value_size: usize,
+}
+
+// SAFETY: the version points to a static string
+unsafe impl Sync for ModuleAbi {}
+
+impl ModuleAbi {
+    pub const CURRENT: ModuleAbi = ModuleAbi {
+        version: concat!(env!("CARGO_PKG_VERSION"), " ", env!("NAIVE_LUA2_RUSTC"), "\0").as_ptr() as *const c_char,
+        state_size: size_of::<LuaState>(),
+        value_size: size_of::<Value>(),
+    };
+
+    fn version(&self) -> String {
+        // SAFETY: every ModuleAbi is built from a static nul terminated string
+        unsafe { CStr::from_ptr(self.version) }.to_string_lossy().into_owned()
+    }
+
+    /// brief: an error when a module built against `self` cannot run in
+    /// this machine
+    fn check(&self) -> Result<(), String> {
+        let ours = ModuleAbi::CURRENT;
+        if self.version() != ours.version()
+            || self.state_size != ours.state_size
+            || self.value_size != ours.value_size
+        {
+            return Err(format!(
+                "module built for naive_lua2 {}, the machine is {}",
+                self.version(),
+                ours.version()
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub const MODULE_ABI_SYMBOL: &str = "naive_lua2_module_abi";
+
+#[export_name = "naive_lua2_module_abi"]
+pub static MODULE_ABI: ModuleAbi = ModuleAbi::CURRENT;
+
+/// brief: the `path` of lua files when the environment sets none
+fn path_default() -> String {
+    format!(
+        "{ldir}?.lua;{ldir}?/init.lua;{cdir}?.lua;{cdir}?/init.lua;./?.lua;./?/init.lua",
+        ldir = LUA_LDIR,
+        cdir = LUA_CDIR
+    )
+}
+
+/// brief: the `cpath` of native libraries when the environment sets none
+fn cpath_default() -> String {
+    format!("{cdir}?.so;{cdir}loadall.so;./?.so", cdir = LUA_CDIR)
+}
+
+/// brief: put the package library in the global `package` and `require`
+/// in the globals, both sharing `package` for the paths and the searchers
+pub fn open_package(state: &mut LuaState) {
+    let package = new_lib(state, PACKAGE_FUNCS);
+    let searchers = create_searchers(state, &package);
+    let _ = state.raw_set(&package, Value::from("searchers"), Value::Table(searchers));
+
+    let path = env_path(state, "LUA_PATH", &path_default());
+    let _ = state.raw_set(&package, Value::from("path"), Value::from(path.as_str()));
+    let cpath = env_path(state, "LUA_CPATH", &cpath_default());
+    let _ = state.raw_set(&package, Value::from("cpath"), Value::from(cpath.as_str()));
+    let config = [DIRSEP, PATH_SEP, PATH_MARK, EXEC_DIR, IGMARK].join("\n") + "\n";
+    let _ = state.raw_set(&package, Value::from("config"), Value::from(config.as_str()));
+
+    let registry = state.registry();
+    let loaded = get_subtable(state, &registry, LOADED_TABLE);
+    let _ = state.raw_set(&package, Value::from("loaded"), Value::Table(loaded));
+    let preload = get_subtable(state, &registry, PRELOAD_TABLE);
+    let _ = state.raw_set(&package, Value::from("preload"), Value::Table(preload));
+
+    let lib = package.clone();
+    let require = state.new_closure(Rc::new(move |state: &mut LuaState| ll_require(state, &lib)));
+    let globals = state.globals();
+    let _ = state.raw_set(&globals, Value::from("require"), Value::Function(require));
+    let _ = state.raw_set(&globals, Value::from("package"), Value::Table(package));
+}
+
+/// brief: make `open` the loader of the module `name`, found by `require`
+/// before any file is searched. `open` is called with the name and ":rust:"
+/// and returns the value of the module
+///
+/// ```ignore
+/// register_module(state, "json", open_json);
+/// ```
+pub fn register_module(state: &mut LuaState, name: &str, open: LRFUNC) {
+    let registry = state.registry();
+    let modules = get_subtable(state, &registry, RUST_MODULES_TABLE);
+    let _ = state.raw_set(
+        &modules,
+        Value::from(name),
+        Value::Function(Function::light(open)),
+    );
+}
+
+/// brief: the searchers `require` tries in order: the preload table, the
+/// modules of the host, the lua files of `package.path`, then the native
+/// libraries of `package.cpath`, by the name and by its root
+fn create_searchers(state: &mut LuaState, package: &Table) -> Table {
+    let searchers = state.new_table_value(5, 0);
+    let with_package = |state: &mut LuaState, searcher: fn(&mut LuaState, &Table) -> usize| {
+        let lib = package.clone();
+        state.new_closure(Rc::new(move |state: &mut LuaState| searcher(state, &lib)))
+    };
+    let functions = [
+        Function::light(searcher_preload),
+        Function::light(searcher_rust),
+        with_package(state, searcher_lua),
+        with_package(state, searcher_c),
+        with_package(state, searcher_croot),
+    ];
+    for (i, function) in functions.into_iter().enumerate() {
+        let _ = state.raw_set(
+            &searchers,
+            Value::Integer(i as i64 + 1),
+            Value::Function(function),
+        );
+    }
+    searchers
+}
+
+/// brief: the path in the environment variable `envname`, the versioned name
+/// first. a ";;" in it stands for the default path
+fn env_path(state: &mut LuaState, envname: &str, default: &str) -> String {
+    let registry = state.registry();
+    if state.raw_get(&registry, &Value::from(NOENV)).is_truthy() {
+        return default.to_string();
+    }
+    let path = env::var(format!("{}{}", envname, VERSUFFIX)).or_else(|_| env::var(envname));
+    match path {
+        Ok(path) => insert_default(&path, default),
+        Err(_) => default.to_string(),
+    }
+}
+
+/// brief: replace the first ";;" of `path` by the default path
+fn insert_default(path: &str, default: &str) -> String {
+    let mark = [PATH_SEP, PATH_SEP].concat();
+    let Some(at) = path.find(&mark) else {
+        return path.to_string();
+    };
+    let (prefix, suffix) = (&path[..at], &path[at + mark.len()..]);
+    let mut result = String::new();
+    if !prefix.is_empty() {
+        result.push_str(prefix);
+        result.push_str(PATH_SEP);
+    }
+    result.push_str(default);
+    if !suffix.is_empty() {
+        result.push_str(PATH_SEP);
+        result.push_str(suffix);
+    }
+    result
+}
+
+fn replace(s: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.windows(from.len()).position(|window| window == from) {
+        result.extend_from_slice(&rest[..at]);
+        result.extend_from_slice(to);
+        rest = &rest[at + from.len()..];
+    }
+    result.extend_from_slice(rest);
+    result
+}
+
+/// brief: the first readable file of the templates in `path` with `name` in
+/// place of the marks, or the list of the files tried
+pub(crate) fn search_path(name: &[u8], path: &[u8], sep: &[u8], dirsep: &[u8]) -> Result<String, String> {
+    // the separators of the name are directory separators
+    let name = match sep.first() {
+        Some(first) if name.contains(first) => replace(name, sep, dirsep),
+        _ => name.to_vec(),
+    };
+    let pathname = replace(path, PATH_MARK.as_bytes(), &name);
+    let pathname = String::from_utf8_lossy(&pathname).into_owned();
+    for filename in pathname.split(PATH_SEP).filter(|filename| !filename.is_empty()) {
+        if File::open(filename).is_ok() {
+            return Ok(filename.to_string());
+        }
+    }
+    Err(format!(
+        "no file '{}'",
+        pathname.replace(PATH_SEP, "'\n\tno file '")
+    ))
+}
+
+/// brief: package.searchpath(name, path [, sep [, rep]]), the file found or
+/// fail and the files tried
+fn ll_searchpath(state: &mut LuaState) -> usize {
+    let name = check_lstring(state, 1, "searchpath");
+    let path = check_lstring(state, 2, "searchpath");
+    let sep = opt_lstring(state, 3, "searchpath", ".");
+    let dirsep = opt_lstring(state, 4, "searchpath", DIRSEP);
+    match search_path(
+        name.as_bytes(),
+        path.as_bytes(),
+        sep.as_bytes(),
+        dirsep.as_bytes(),
+    ) {
+        Ok(filename) => {
+            state.push_str(&filename);
+            1
+        }
+        Err(tried) => {
+            state.push_nil();
+            state.push_str(&tried);
+            2
+        }
+    }
+}
+
+/// brief: the file of `name` in the path `package[pname]`
+fn find_file(state: &mut LuaState, package: &Table, name: &[u8], pname: &str) -> Result<String, String> {
+    let path = match state.index(&Value::Table(package.clone()), &Value::from(pname)) {
+        Ok(path @ (Value::String(_) | Value::Integer(_) | Value::Number(_))) => tolstring(state, &path),
+        Ok(_) => state.error(LuaError::Runtime(format!("'package.{}' must be a string", pname))),
+        Err(err) => state.error(err),
+    };
+    search_path(name, path.as_bytes(), b".", DIRSEP.as_bytes())
+}
+
+fn searcher_preload(state: &mut LuaState) -> usize {
+    let name = check_lstring(state, 1, "?");
+    let registry = state.registry();
+    let preload = get_subtable(state, &registry, PRELOAD_TABLE);
+    let loader = match state.index(&Value::Table(preload), &Value::String(name.clone())) {
+        Ok(loader) => loader,
+        Err(err) => state.error(err),
+    };
+    if loader.is_nil() {
+        state.push_str(&format!("no field package.preload['{}']", name.to_string_lossy()));
+        return 1;
+    }
+    state.push_value(&loader);
+    state.push_str(":preload:");
+    2
+}
+
+fn searcher_rust(state: &mut LuaState) -> usize {
+    let name = check_lstring(state, 1, "?");
+    let registry = state.registry();
+    let modules = get_subtable(state, &registry, RUST_MODULES_TABLE);
+    let loader = state.raw_get(&modules, &Value::String(name.clone()));
+    if loader.is_nil() {
+        state.push_str(&format!(
+            "no module '{}' registered by the host",
+            name.to_string_lossy()
+        ));
+        return 1;
+    }
+    state.push_value(&loader);
+    state.push_str(":rust:");
+    2
+}
+
+fn searcher_lua(state: &mut LuaState, package: &Table) -> usize {
+    let name = check_lstring(state, 1, "?");
+    let filename = match find_file(state, package, name.as_bytes(), "path") {
+        Ok(filename) => filename,
+        Err(tried) => {
+            state.push_str(&tried);
+            return 1;
+        }
+    };
+    match load_file(state, Some(&filename), "bt") {
+        Ok(loader) => {
+            state.push_value(&Value::Function(loader));
+            state.push_str(&filename);
+            2
+        }
+        Err(err) => load_error(state, &name, &filename, err),
+    }
+}
+
+fn load_error(state: &mut LuaState, name: &LuaString, filename: &str, msg: impl Display) -> ! {
+    state.error(LuaError::Runtime(format!(
+        "error loading module '{}' from file '{}':\n\t{}",
+        name.to_string_lossy(),
+        filename,
+        msg
+    )))
+}
+
+/// brief: why a native function could not be found, with the message of
+/// the system
+enum LoadFail {
+    Lib(String),
+    Func(String),
+}
+
+/// brief: the function `sym` of the library at `path`, the library is loaded
+/// once and kept for the life of the process. "*" only loads the library,
+/// its symbols made global, and gives true
+fn look_for_func(state: &mut LuaState, path: &str, sym: &str) -> Result<Value, LoadFail> {
+    let registry = state.registry();
+    let clibs = get_subtable(state, &registry, CLIBS_TABLE);
+    let handle = match state.raw_get(&clibs, &Value::from(path)) {
+        Value::LightUserData(handle) => handle,
+        _ => {
+            let handle = dl::load(path, sym.starts_with('*')).map_err(LoadFail::Lib)?;
+            let _ = state.raw_set(&clibs, Value::from(path), Value::LightUserData(handle));
+            handle
+        }
+    };
+    if sym.starts_with('*') {
+        return Ok(Value::Boolean(true));
+    }
+    let entry = dl::sym(handle, sym).map_err(LoadFail::Func)?;
+    let function = if dl::sym(handle, CAPI_MARK).is_ok() {
+        // SAFETY: the entry points of a module of the C headers are lua_CFunction
+        let open = unsafe { std::mem::transmute::<*mut (), CFunction>(entry) };
+        c_closure(state, open, None)
+    } else {
+        check_module_abi(handle).map_err(LoadFail::Lib)?;
+        // SAFETY: the entry points of a module of rust have the shape of LuaOpen
+        let open = unsafe { std::mem::transmute::<*mut (), LuaOpen>(entry) };
+        state.new_closure(Rc::new(move |state: &mut LuaState| open(state)))
+    };
+    Ok(Value::Function(function))
+}
+
+/// brief: the ABI a library of rust was built against, an error when it
+/// differs from the machine
+fn check_module_abi(handle: *mut ()) -> Result<(), String> {
+    let abi = dl::sym(handle, MODULE_ABI_SYMBOL)
+        .map_err(|_| format!("no {}, not a module of naive_lua2", MODULE_ABI_SYMBOL))?;
+    // SAFETY: the symbol of a module of naive_lua2 is a ModuleAbi of repr(C)
+    unsafe { *(abi as *const ModuleAbi) }.check()
+}
+
+/// brief: the entry points of a module: "a.b" opens with luaopen_a_b, and a
+/// name "v2-a" with luaopen_v2, then luaopen_a
+fn open_func_names(modname: &str) -> Vec<String> {
+    let modname = modname.replace('.', OFSEP);
+    match modname.split_once(IGMARK) {
+        Some((version, name)) => vec![format!("{}{}", POF, version), format!("{}{}", POF, name)],
+        None => vec![format!("{}{}", POF, modname)],
+    }
+}
+
+/// brief: the entry point of the module `modname` in the library `filename`
+fn load_func(state: &mut LuaState, filename: &str, modname: &str) -> Result<Value, LoadFail> {
+    let mut fail = None;
+    for sym in open_func_names(modname) {
+        match look_for_func(state, filename, &sym) {
+            Err(LoadFail::Func(msg)) => fail = Some(LoadFail::Func(msg)),
+            other => return other,
+        }
+    }
+    Err(fail.expect("a module has an entry point"))
+}
+
+/// brief: package.loadlib(path, funcname), the function of the library, or
+/// fail, the message and where it failed: "open" or "init"
+fn ll_loadlib(state: &mut LuaState) -> usize {
+    let path = check_lstring(state, 1, "loadlib").to_string_lossy();
+    let init = check_lstring(state, 2, "loadlib").to_string_lossy();
+    let (msg, what) = match look_for_func(state, &path, &init) {
+        Ok(function) => {
+            state.push_value(&function);
+            return 1;
+        }
+        Err(LoadFail::Lib(msg)) => (msg, LIB_FAIL),
+        Err(LoadFail::Func(msg)) => (msg, "init"),
+    };
+    state.push_nil();
+    state.push_str(&msg);
+    state.push_str(what);
+    3
+}
+
+fn searcher_c(state: &mut LuaState, package: &Table) -> usize {
+    let name = check_lstring(state, 1, "?");
+    let filename = match find_file(state, package, name.as_bytes(), "cpath") {
+        Ok(filename) => filename,
+        Err(tried) => {
+            state.push_str(&tried);
+            return 1;
+        }
+    };
+    match load_func(state, &filename, &name.to_string_lossy()) {
+        Ok(loader) => {
+            state.push_value(&loader);
+            state.push_str(&filename);
+            2
+        }
+        Err(LoadFail::Lib(msg) | LoadFail::Func(msg)) => load_error(state, &name, &filename, msg),
+    }
+}
+
+/// brief: the searcher of a submodule "a.b" in the library of its root "a"
+fn searcher_croot(state: &mut LuaState, package: &Table) -> usize {
+    let name = check_lstring(state, 1, "?");
+    let modname = name.to_string_lossy();
+    let Some((root, _)) = modname.split_once('.') else {
+        return 0;
+    };
+    let filename = match find_file(state, package, root.as_bytes(), "cpath") {
+        Ok(filename) => filename,
+        Err(tried) => {
+            state.push_str(&tried);
+            return 1;
+        }
+    };
+    match load_func(state, &filename, &modname) {
+        Ok(loader) => {
+            state.push_value(&loader);
+            state.push_str(&filename);
+            2
+        }
+        Err(LoadFail::Lib(msg)) => load_error(state, &name, &filename, msg),
+        Err(LoadFail::Func(_)) => {
+            state.push_str(&format!("no module '{}' in file '{}'", modname, filename));
+            1
+        }
+    }
+}
+
+/// brief: the loader of `name` and its data from the first searcher that
+/// finds one, otherwise an error with the messages of every searcher
+fn find_loader(state: &mut LuaState, package: &Table, name: &LuaString) -> (Value, Value) {
+    let searchers = match state.index(&Value::Table(package.clone()), &Value::from("searchers")) {
+        Ok(Value::Table(searchers)) => searchers,
+        Ok(_) => state.error(LuaError::Runtime(
+            "'package.searchers' must be a table".to_string(),
+        )),
+        Err(err) => state.error(err),
+    };
+    let mut msg = Vec::new();
+    for i in 1.. {
+        let searcher = state.raw_get(&searchers, &Value::Integer(i));
+        if searcher.is_nil() {
+            break;
+        }
+        let args = MultiValue::from(vec![Value::String(name.clone())]);
+        let mut results = match state.call_value(&searcher, args) {
+            Ok(results) => results,
+            Err(err) => state.error(err),
+        };
+        let loader = results.pop_front().unwrap_or(Value::Nil);
+        let data = results.pop_front().unwrap_or(Value::Nil);
+        match loader {
+            Value::Function(_) => return (loader, data),
+            Value::String(_) | Value::Integer(_) | Value::Number(_) => {
+                msg.extend_from_slice(b"\n\t");
+                msg.extend_from_slice(tolstring(state, &loader).as_bytes());
+            }
+            _ => {}
+        }
+    }
+    state.error(LuaError::Runtime(format!(
+        "module '{}' not found:{}",
+        name.to_string_lossy(),
+        String::from_utf8_lossy(&msg)
+    )))
+}
+
+/// brief: require(name), the value of the module and the data of its loader.
+/// the value is kept in package.loaded, a module is loaded once
+fn ll_require(state: &mut LuaState, package: &Table) -> usize {
+    let name = check_lstring(state, 1, "require");
+    let registry = state.registry();
+    let loaded = Value::Table(get_subtable(state, &registry, LOADED_TABLE));
+    let key = Value::String(name.clone());
+    let module = match state.index(&loaded, &key) {
+        Ok(module) => module,
+        Err(err) => state.error(err),
+    };
+    if module.is_truthy() {
+        state.push_value(&module);
+        return 1;
+    }
+    let (loader, data) = find_loader(state, package, &name);
+    let args = MultiValue::from(vec![key.clone(), data.clone()]);
+    let result = match state.call_value(&loader, args) {
+        Ok(mut results) => results.pop_front().unwrap_or(Value::Nil),
+        Err(err) => state.error(err),
+    };
+    if !result.is_nil() {
+        if let Err(err) = state.set_index(&loaded, key.clone(), result) {
+            state.error(err);
+        }
+    }
+    let module = match state.index(&loaded, &key) {
+        Ok(Value::Nil) => {
+            // a module that sets no value is loaded as true
+            if let Err(err) = state.set_index(&loaded, key, Value::Boolean(true)) {
+                state.error(err);
+            }
+            Value::Boolean(true)
+        }
+        Ok(module) => module,
+        Err(err) => state.error(err),
+    };
+    state.push_value(&module);
+    state.push_value(&data);
+    2
+}
+
+/// brief: the dynamic loader of the system
+#[cfg(target_os = "linux")]
+mod dl {
+    use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+    const RTLD_LOCAL: c_int = 0;
+    const RTLD_NOW: c_int = 2;
+    const RTLD_GLOBAL: c_int = 0x100;
+
+    extern "C" {
+        fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+        fn dlerror() -> *mut c_char;
+    }
+
+    /// brief: a string of C, cut at the first zero as C would read it
+    fn c_string(s: &str) -> CString {
+        let bytes = s.as_bytes();
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        CString::new(&bytes[..end]).expect("the zeros are cut")
+    }
+
+    fn last_error() -> String {
+        // SAFETY: dlerror gives null or a string valid until the next call
+        unsafe {
+            let err = dlerror();
+            if err.is_null() {
+                "unknown error".to_string()
+            } else {
+                CStr::from_ptr(err).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    pub(super) fn load(path: &str, global: bool) -> Result<*mut (), String> {
+        let path = c_string(path);
+        let flag = RTLD_NOW | if global { RTLD_GLOBAL } else { RTLD_LOCAL };
+        // SAFETY: the path is a valid string, the initializers of the library run
+        let handle = unsafe { dlopen(path.as_ptr(), flag) };
+        if handle.is_null() {
+            Err(last_error())
+        } else {
+            Ok(handle as *mut ())
+        }
+    }
+
+    /// brief: the address of the symbol `name` in the library, the caller
+    /// knows its type
+    pub(super) fn sym(handle: *mut (), name: &str) -> Result<*mut (), String> {
+        let name = c_string(name);
+        // SAFETY: the handle comes from dlopen and is never closed
+        let sym = unsafe { dlsym(handle as *mut c_void, name.as_ptr()) };
+        if sym.is_null() {
+            Err(last_error())
+        } else {
+            Ok(sym as *mut ())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod dl {
+    const DLMSG: &str = "dynamic libraries not enabled; check your Lua installation";
+
+    pub(super) fn load(_path: &str, _global: bool) -> Result<*mut (), String> {
+        Err(DLMSG.to_string())
+    }
+
+    pub(super) fn sym(_handle: *mut (), _name: &str) -> Result<*mut (), String> {
+        Err(DLMSG.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::sync::OnceLock;
+
+    use crate::common::lua::{LuaError, LuaResult};
+    use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti, MultiValue};
+    use crate::common::obj::objvalue::{Function, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+    use crate::stdlib::libaux::{check_integer, create_temp_file};
+
+    use super::{insert_default, module_call, open_func_names, register_module, search_path, ModuleAbi};
+
+    fn require<R: FromLuaMulti>(state: &mut LuaState, name: &str) -> LuaResult<R> {
+        let require: Function = state.get_global("require")?;
+        require.call(state, name)
+    }
+
+    fn call<A: IntoLuaMulti, R: FromLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<R> {
+        let package: Table = state.get_global("package")?;
+        let function: Function = package.get(state, name)?;
+        function.call(state, args)
+    }
+
+    fn open_counter(state: &mut LuaState) -> usize {
+        let module = state.create_table_value(0, 1).unwrap();
+        let (name, data): (Value, Value) = state.get_args().unwrap();
+        let _ = state.raw_set(&module, Value::from("name"), name);
+        let _ = state.raw_set(&module, Value::from("data"), data);
+        state.push_value(&Value::Table(module));
+        1
+    }
+
+    #[test]
+    fn package_fields() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let package: Table = state.get_global("package").unwrap();
+        let config: String = package.get(state, "config").unwrap();
+        assert_eq!(config, "/\n;\n?\n!\n-\n");
+        let searchers: Table = package.get(state, "searchers").unwrap();
+        assert_eq!(searchers.raw_len(state), 5);
+        let path: String = package.get(state, "path").unwrap();
+        assert!(path.contains("?.lua"));
+
+        // package.loaded is the table of the libraries opened
+        let loaded: Table = package.get(state, "loaded").unwrap();
+        let string: Table = loaded.get(state, "string").unwrap();
+        assert_eq!(Value::Table(string), state.get_global::<Value>("string").unwrap());
+        let g: Value = loaded.get(state, "_G").unwrap();
+        assert_eq!(g, Value::Table(state.globals()));
+        let same: Table = require(state, "package").unwrap();
+        assert_eq!(same, package);
+
+        let default = "/lib/?.lua";
+        assert_eq!(insert_default("a;;b", default), "a;/lib/?.lua;b");
+        assert_eq!(insert_default(";;", default), "/lib/?.lua");
+        assert_eq!(insert_default("a/?.lua", default), "a/?.lua");
+        assert_eq!(insert_default(";;b;;", default), "/lib/?.lua;b;;");
+    }
+
+    #[test]
+    fn searchpath_lists_the_files_tried() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let (path, _) = create_temp_file().unwrap();
+        let dir = path.parent().unwrap().to_str().unwrap();
+        let stem = path.file_name().unwrap().to_str().unwrap();
+        let template = format!("/nonexistent/?.x;;{}/?", dir);
+        assert_eq!(
+            search_path(stem.as_bytes(), template.as_bytes(), b".", b"/"),
+            Ok(path.to_str().unwrap().to_string())
+        );
+        let found: String = call(state, "searchpath", (stem, template.as_str())).unwrap();
+        assert_eq!(found, path.to_str().unwrap());
+
+        let (fail, tried): (Value, String) =
+            call(state, "searchpath", ("a.b", "/x/?.lua;/y/?/init.lua")).unwrap();
+        assert_eq!(fail, Value::Nil);
+        assert_eq!(tried, "no file '/x/a/b.lua'\n\tno file '/y/a/b/init.lua'");
+        let (_, tried): (Value, String) = call(state, "searchpath", ("a.b", "/x/?", "", "")).unwrap();
+        assert_eq!(tried, "no file '/x/a.b'");
+        let (_, tried): (Value, String) = call(state, "searchpath", ("a::b", "/x/?", "::", "_")).unwrap();
+        assert_eq!(tried, "no file '/x/a_b'");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn require_finds_preload_and_host_modules() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        register_module(state, "counter", open_counter);
+        let (module, data): (Table, String) = require(state, "counter").unwrap();
+        assert_eq!(data, ":rust:");
+        let name: String = module.get(state, "name").unwrap();
+        assert_eq!(name, "counter");
+        // a second require gives the same module, without the data
+        let (again, data): (Table, Value) = require(state, "counter").unwrap();
+        assert_eq!(again, module);
+        assert_eq!(data, Value::Nil);
+
+        // the preload table comes first
+        let package: Table = state.get_global("package").unwrap();
+        let preload: Table = package.get(state, "preload").unwrap();
+        let loader = state.create_function(|_, (name, _): (String, Value)| Ok(format!("preloaded {}", name))).unwrap();
+        preload.set(state, "counter2", loader).unwrap();
+        register_module(state, "counter2", open_counter);
+        let (module, data): (String, String) = require(state, "counter2").unwrap();
+        assert_eq!(
+            (module.as_str(), data.as_str()),
+            ("preloaded counter2", ":preload:")
+        );
+
+        // a loader that returns nothing loads the module as true
+        let nothing = state.create_function(|_, ()| Ok(())).unwrap();
+        preload.set(state, "empty", nothing).unwrap();
+        let module: bool = require(state, "empty").unwrap();
+        assert!(module);
+        let loaded: Table = package.get(state, "loaded").unwrap();
+        assert!(loaded.get::<_, bool>(state, "empty").unwrap());
+    }
+
+    #[test]
+    fn require_reports_every_searcher() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let package: Table = state.get_global("package").unwrap();
+        package
+            .set(state, "path", "/nonexistent/?.lua;/nonexistent/?/init.lua")
+            .unwrap();
+        package.set(state, "cpath", "/nonexistent/?.so").unwrap();
+        let err = require::<MultiValue>(state, "no.such").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "module 'no.such' not found:\n\tno field package.preload['no.such']\n\t\
+             no module 'no.such' registered by the host\n\t\
+             no file '/nonexistent/no/such.lua'\n\tno file '/nonexistent/no/such/init.lua'\n\t\
+             no file '/nonexistent/no/such.so'\n\tno file '/nonexistent/no.so'"
+        );
+
+        // a lua file found is loaded with loadfile
+        let (path, _) = create_temp_file().unwrap();
+        let dir = path.parent().unwrap().to_str().unwrap();
+        let stem = path.file_name().unwrap().to_str().unwrap();
+        package.set(state, "path", format!("{}/?", dir)).unwrap();
+        let err = require::<MultiValue>(state, stem).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "error loading module '{stem}' from file '{file}':\n\t{file}: \
+                 cannot load a text chunk, this machine has no compiler",
+                stem = stem,
+                file = path.to_str().unwrap()
+            )
+        );
+        fs::remove_file(&path).unwrap();
+
+        package.set(state, "path", true).unwrap();
+        let err = require::<MultiValue>(state, "x").unwrap_err().to_string();
+        assert_eq!(err, "'package.path' must be a string");
+        package.set(state, "searchers", Value::Nil).unwrap();
+        let err = require::<MultiValue>(state, "x").unwrap_err().to_string();
+        assert_eq!(err, "'package.searchers' must be a table");
+    }
+
+    /// brief: the directory of the sample module, built once with cargo in a
+    /// target directory of its own
+    fn sample_dir() -> &'static PathBuf {
+        static DIR: OnceLock<PathBuf> = OnceLock::new();
+        DIR.get_or_init(|| {
+            let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+            let target = root.join("target").join("sample");
+            let status = Command::new(env!("CARGO"))
+                .arg("build")
+                .arg("--quiet")
+                .arg("--manifest-path")
+                .arg(root.join("naive_lua2_sample").join("Cargo.toml"))
+                .arg("--target-dir")
+                .arg(&target)
+                .status()
+                .expect("cargo runs");
+            assert!(status.success(), "the sample module builds");
+            target.join("debug")
+        })
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // miri cannot spawn the build nor dlopen its output
+    fn native_modules_are_loaded() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let dir = sample_dir().to_str().unwrap().to_string();
+        let package: Table = state.get_global("package").unwrap();
+        package.set(state, "cpath", format!("{}/lib?.so", dir)).unwrap();
+
+        let (module, filename): (Table, String) = require(state, "sample").unwrap();
+        assert_eq!(filename, format!("{}/libsample.so", dir));
+        let version: String = module.get(state, "version").unwrap();
+        assert_eq!(version, "sample 1.0");
+        let add: Function = module.get(state, "add").unwrap();
+        let sum: i64 = add.call(state, (40, 2)).unwrap();
+        assert_eq!(sum, 42);
+        let greet: Function = module.get(state, "greet").unwrap();
+        let greeting: String = greet.call(state, "lua").unwrap();
+        assert_eq!(greeting, "hello, lua");
+
+        // a submodule is found in the library of its root
+        let sub: String = require(state, "sample.sub").unwrap();
+        assert_eq!(sub, format!("sample.sub from {}/libsample.so", dir));
+        let err = require::<MultiValue>(state, "sample.none")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&format!(
+            "\n\tno module 'sample.none' in file '{}/libsample.so'",
+            dir
+        )));
+
+        // loadlib gives the entry point itself
+        let library = format!("{}/libsample.so", dir);
+        let open: Function = call(state, "loadlib", (library.as_str(), "luaopen_sample")).unwrap();
+        let module: Table = open.call(state, ()).unwrap();
+        let version: String = module.get(state, "version").unwrap();
+        assert_eq!(version, "sample 1.0");
+        let loaded: bool = call(state, "loadlib", (library.as_str(), "*")).unwrap();
+        assert!(loaded);
+        let (fail, msg, what): (Value, String, String) =
+            call(state, "loadlib", (library.as_str(), "luaopen_none")).unwrap();
+        assert_eq!(fail, Value::Nil);
+        assert!(msg.contains("luaopen_none"), "{}", msg);
+        assert_eq!(what, "init");
+        let (_, msg, what): (Value, String, String) =
+            call(state, "loadlib", ("/nonexistent/lib.so", "luaopen_x")).unwrap();
+        assert!(msg.contains("/nonexistent/lib.so"), "{}", msg);
+        assert_eq!(what, "open");
+
+        // a library of rust must be a module of this machine
+        let (_, msg, what): (Value, String, String) =
+            call(state, "loadlib", ("libm.so.6", "cos")).unwrap();
+        assert!(msg.contains("not a module of naive_lua2"), "{}", msg);
+        assert_eq!(what, "open");
+
+        assert_eq!(open_func_names("a.b"), ["luaopen_a_b"]);
+        assert_eq!(open_func_names("v2-a.b"), ["luaopen_v2", "luaopen_a_b"]);
+    }
+    #[test]
+    #[cfg_attr(miri, ignore)] // miri cannot spawn the build nor dlopen its output
+    fn native_module_errors_are_raised() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let dir = sample_dir().to_str().unwrap().to_string();
+        let package: Table = state.get_global("package").unwrap();
+        package.set(state, "cpath", format!("{}/lib?.so", dir)).unwrap();
+
+        // the errors of the functions of a module reach the caller
+        let module: Table = require(state, "sample").unwrap();
+        let add: Function = module.get(state, "add").unwrap();
+        let err = add.call::<_, i64>(state, ("x", 2)).unwrap_err().to_string();
+        assert_eq!(err, "bad argument #1 to 'add' (number expected, got string)");
+        let err = add.call::<_, i64>(state, 1).unwrap_err().to_string();
+        assert_eq!(err, "bad argument #2 to 'add' (number expected, got no value)");
+        let greet: Function = module.get(state, "greet").unwrap();
+        let err = greet.call::<_, String>(state, ()).unwrap_err().to_string();
+        assert_eq!(err, "bad argument #1 to 'greet' (string expected, got no value)");
+        let pcall: Function = state.get_global("pcall").unwrap();
+        let (ok, msg): (bool, String) = pcall.call(state, (add, 1.5, 2)).unwrap();
+        assert!(!ok);
+        assert_eq!(msg, "bad argument #1 to 'add' (number has no integer representation)");
+
+        // and so do the errors of an entry point, the machine goes on
+        let err = require::<MultiValue>(state, "sample.broken")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("sample.broken cannot be opened"), "{}", err);
+        let module: Table = require(state, "sample").unwrap();
+        let add: Function = module.get(state, "add").unwrap();
+        let sum: i64 = add.call(state, (1, 2)).unwrap();
+        assert_eq!(sum, 3);
+    }
+
+    #[test]
+    fn module_call_returns_errors() {
+        fn raise(state: &mut LuaState) -> usize {
+            module_call(state, |state| {
+                let n = check_integer(state, 1, "raise");
+                state.push_integer(n);
+                Ok(1)
+            })
+        }
+        fn fail(state: &mut LuaState) -> usize {
+            module_call(state, |_| Err(LuaError::Object(Value::Boolean(false))))
+        }
+        fn panic(state: &mut LuaState) -> usize {
+            module_call(state, |_| panic!("out of order"))
+        }
+
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let n: i64 = Function::light(raise).call(state, 7).unwrap();
+        assert_eq!(n, 7);
+        let err = Function::light(raise).call::<_, i64>(state, "x").unwrap_err();
+        assert_eq!(err.to_string(), "bad argument #1 to 'raise' (number expected, got string)");
+        let err = Function::light(fail).call::<_, ()>(state, ()).unwrap_err();
+        assert_eq!(err.into_value(), Value::Boolean(false));
+        // the panic was caught on the side of the module, only its message is left
+        let err = Function::light(panic).call::<_, ()>(state, ()).unwrap_err();
+        assert_eq!(err.to_string(), "rust panic: out of order");
+    }
+
+    #[test]
+    fn module_abi_must_agree() {
+        assert_eq!(ModuleAbi::CURRENT.check(), Ok(()));
+        let other = ModuleAbi {
+            state_size: 0,
+            ..ModuleAbi::CURRENT
+        };
+        assert!(other.check().unwrap_err().contains("module built for naive_lua2"));
+        let other = ModuleAbi {
+            version: c"0.0.0 rustc 1.0.0".as_ptr(),
+            ..ModuleAbi::CURRENT
+        };
+        let err = other.check().unwrap_err();
+        assert!(err.starts_with("module built for naive_lua2 0.0.0 rustc 1.0.0, the machine is"), "{}", err);
+    }
+}
@@ -0,0 +1,510 @@
+/// brief: the most captures of a pattern
+pub const LUA_MAXCAPTURES: usize = 32;
+const MAXCCALLS: usize = 200; // the depth of the recursive matcher
+const L_ESC: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+/// brief: a capture of a successful match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture<'a> {
+    Bytes(&'a [u8]),
+    Position(usize), // from 1, `()` in the pattern
+}
+
+/// brief: a malformed pattern is an error message, a failing match `Ok(None)`
+pub type PatternResult<T> = Result<T, String>;
+
+/// brief: the pattern has no magic characters, a plain search will do
+pub fn no_specials(pat: &[u8]) -> bool {
+    !pat.iter().any(|b| SPECIALS.contains(b))
+}
+
+#[derive(Clone, Copy)]
+struct CaptureSlot {
+    init: usize,
+    len: isize,
+}
+
+/// brief: the state of matching a lua pattern against a subject
+pub struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    matchdepth: usize,
+    capture: [CaptureSlot; LUA_MAXCAPTURES],
+}
+
+fn match_class(c: u8, cl: u8) -> bool {
+    let res = match cl.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => matches!(c, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r'),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return cl == c,
+    };
+    if cl.is_ascii_uppercase() {
+        !res
+    } else {
+        res
+    }
+}
+
+impl<'a> MatchState<'a> {
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+        Self {
+            src,
+            pat,
+            level: 0,
+            matchdepth: MAXCCALLS,
+            capture: [CaptureSlot { init: 0, len: 0 }; LUA_MAXCAPTURES],
+        }
+    }
+
+    /// brief: forget the captures of the last attempt
+    pub fn reprep(&mut self) {
+        self.level = 0;
+        self.matchdepth = MAXCCALLS;
+    }
+
+    /// brief: the subject of the match
+    pub fn source(&self) -> &'a [u8] {
+        self.src
+    }
+
+    /// brief: the byte of the pattern at `p`, 0 past its end as in C
+    #[inline(always)]
+    fn pat_at(&self, p: usize) -> u8 {
+        self.pat.get(p).copied().unwrap_or(0)
+    }
+
+    #[inline(always)]
+    fn src_at(&self, s: usize) -> u8 {
+        self.src.get(s).copied().unwrap_or(0)
+    }
+
+    fn check_capture(&self, l: u8) -> PatternResult<usize> {
+        let l = l as isize - b'1' as isize;
+        if l < 0 || l as usize >= self.level || self.capture[l as usize].len == CAP_UNFINISHED {
+            return Err(format!("invalid capture index %{}", l + 1));
+        }
+        Ok(l as usize)
+    }
+
+    fn capture_to_close(&self) -> PatternResult<usize> {
+        (0..self.level)
+            .rev()
+            .find(|level| self.capture[*level].len == CAP_UNFINISHED)
+            .ok_or_else(|| "invalid pattern capture".to_string())
+    }
+
+    /// brief: the end of the single character class at `p`
+    fn class_end(&self, mut p: usize) -> PatternResult<usize> {
+        let c = self.pat_at(p);
+        p += 1;
+        match c {
+            L_ESC => {
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (ends with '%')".to_string());
+                }
+                Ok(p + 1)
+            }
+            b'[' => {
+                if self.pat_at(p) == b'^' {
+                    p += 1;
+                }
+                // look for a ']', the first one closes nothing
+                loop {
+                    if p >= self.pat.len() {
+                        return Err("malformed pattern (missing ']')".to_string());
+                    }
+                    let c = self.pat[p];
+                    p += 1;
+                    if c == L_ESC && p < self.pat.len() {
+                        p += 1; // skip escapes, '%]'
+                    }
+                    if self.pat_at(p) == b']' {
+                        break;
+                    }
+                }
+                Ok(p + 1)
+            }
+            _ => Ok(p),
+        }
+    }
+
+    /// brief: `c` is in the set from `p`, the '[', to `ec`, the ']'
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut sig = true;
+        if self.pat_at(p + 1) == b'^' {
+            sig = false;
+            p += 1;
+        }
+        p += 1;
+        while p < ec {
+            if self.pat[p] == L_ESC {
+                p += 1;
+                if match_class(c, self.pat_at(p)) {
+                    return sig;
+                }
+            } else if self.pat_at(p + 1) == b'-' && p + 2 < ec {
+                p += 2;
+                if self.pat[p - 2] <= c && c <= self.pat[p] {
+                    return sig;
+                }
+            } else if self.pat[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        if s >= self.src.len() {
+            return false;
+        }
+        let c = self.src[s];
+        match self.pat[p] {
+            b'.' => true,
+            L_ESC => match_class(c, self.pat_at(p + 1)),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> PatternResult<Option<usize>> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        if s >= self.src.len() || self.src[s] != self.pat[p] {
+            return Ok(None);
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        let mut depth = 1;
+        for (i, c) in self.src.iter().enumerate().skip(s + 1) {
+            if *c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if *c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> PatternResult<Option<usize>> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        // try with the most repetitions first
+        loop {
+            if let Some(res) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> PatternResult<Option<usize>> {
+        loop {
+            if let Some(res) = self.do_match(s, ep + 1)? {
+                return Ok(Some(res));
+            } else if self.single_match(s, p, ep) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> PatternResult<Option<usize>> {
+        let level = self.level;
+        if level >= LUA_MAXCAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.capture[level] = CaptureSlot { init: s, len: what };
+        self.level = level + 1;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.level -= 1;
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> PatternResult<Option<usize>> {
+        let l = self.capture_to_close()?;
+        self.capture[l].len = (s - self.capture[l].init) as isize;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.capture[l].len = CAP_UNFINISHED;
+        }
+        Ok(res)
+    }
+
+    fn match_capture(&self, s: usize, l: u8) -> PatternResult<Option<usize>> {
+        let l = self.check_capture(l)?;
+        let CaptureSlot { init, len } = self.capture[l];
+        let len = len as usize;
+        if self.src.len() - s >= len && self.src[init..init + len] == self.src[s..s + len] {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// brief: match the pattern from `p` at the subject from `s`, return the
+    /// end of the match
+    pub fn do_match(&mut self, s: usize, p: usize) -> PatternResult<Option<usize>> {
+        if self.matchdepth == 0 {
+            return Err("pattern too complex".to_string());
+        }
+        self.matchdepth -= 1;
+        let res = self.match_loop(s, p);
+        self.matchdepth += 1;
+        res
+    }
+
+    /// brief: the body of do_match, the tail calls loop
+    fn match_loop(&mut self, mut s: usize, mut p: usize) -> PatternResult<Option<usize>> {
+        loop {
+            if p == self.pat.len() {
+                return Ok(Some(s));
+            }
+            match self.pat[p] {
+                b'(' => {
+                    return if self.pat_at(p + 1) == b')' {
+                        self.start_capture(s, p + 2, CAP_POSITION)
+                    } else {
+                        self.start_capture(s, p + 1, CAP_UNFINISHED)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pat.len() => {
+                    return Ok((s == self.src.len()).then_some(s));
+                }
+                L_ESC if self.pat_at(p + 1) == b'b' => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+                L_ESC if self.pat_at(p + 1) == b'f' => {
+                    p += 2;
+                    if self.pat_at(p) != b'[' {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    if !self.match_bracket_class(previous, p, ep - 1)
+                        && self.match_bracket_class(self.src_at(s), p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+                L_ESC if self.pat_at(p + 1).is_ascii_digit() => {
+                    match self.match_capture(s, self.pat_at(p + 1))? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {
+                    let ep = self.class_end(p)?;
+                    let epc = self.pat_at(ep);
+                    if !self.single_match(s, p, ep) {
+                        // accept empty
+                        if matches!(epc, b'*' | b'?' | b'-') {
+                            p = ep + 1;
+                            continue;
+                        }
+                        return Ok(None);
+                    }
+                    match epc {
+                        b'?' => match self.do_match(s + 1, ep + 1)? {
+                            Some(res) => return Ok(Some(res)),
+                            None => {
+                                p = ep + 1;
+                                continue;
+                            }
+                        },
+                        b'+' => return self.max_expand(s + 1, p, ep),
+                        b'*' => return self.max_expand(s, p, ep),
+                        b'-' => return self.min_expand(s, p, ep),
+                        _ => {
+                            s += 1;
+                            p = ep;
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// brief: the capture `i` of the match from `s` to `e`, the whole match
+    /// when the pattern has no captures
+    pub fn get_capture(&self, i: usize, s: usize, e: usize) -> PatternResult<Capture<'a>> {
+        if i >= self.level {
+            if i != 0 {
+                return Err(format!("invalid capture index %{}", i + 1));
+            }
+            return Ok(Capture::Bytes(&self.src[s..e]));
+        }
+        let CaptureSlot { init, len } = self.capture[i];
+        match len {
+            CAP_UNFINISHED => Err("unfinished capture".to_string()),
+            CAP_POSITION => Ok(Capture::Position(init + 1)),
+            len => Ok(Capture::Bytes(&self.src[init..init + len as usize])),
+        }
+    }
+
+    /// brief: all the captures of the match from `s` to `e`, the whole match
+    /// for a pattern without captures when `whole` is set
+    pub fn get_captures(&self, s: usize, e: usize, whole: bool) -> PatternResult<Vec<Capture<'a>>> {
+        let nlevels = if self.level == 0 && whole { 1 } else { self.level };
+        (0..nlevels).map(|i| self.get_capture(i, s, e)).collect()
+    }
+}
+
+/// brief: the first match of `pat` in `src` from `init`, its start, end and
+/// state. a leading '^' anchors the match
+pub fn find<'a>(
+    src: &'a [u8],
+    pat: &'a [u8],
+    init: usize,
+) -> PatternResult<Option<(usize, usize, MatchState<'a>)>> {
+    let (anchor, pat) = match pat.first() {
+        Some(b'^') => (true, &pat[1..]),
+        _ => (false, pat),
+    };
+    let mut ms = MatchState::new(src, pat);
+    let mut s = init;
+    loop {
+        ms.reprep();
+        if let Some(e) = ms.do_match(s, 0)? {
+            return Ok(Some((s, e, ms)));
+        }
+        s += 1;
+        if anchor || s > src.len() {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find, no_specials, Capture, MatchState};
+
+    /// brief: the captures of the first match, the whole match without any
+    fn captures<'a>(src: &'a [u8], pat: &'a [u8]) -> Result<Option<Vec<Capture<'a>>>, String> {
+        match find(src, pat, 0)? {
+            Some((s, e, ms)) => Ok(Some(ms.get_captures(s, e, true)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn matched(src: &str, pat: &str) -> Option<String> {
+        let caps = captures(src.as_bytes(), pat.as_bytes()).unwrap()?;
+        Some(
+            caps.iter()
+                .map(|cap| match cap {
+                    Capture::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                    Capture::Position(pos) => pos.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("|"),
+        )
+    }
+
+    fn error(src: &str, pat: &str) -> String {
+        captures(src.as_bytes(), pat.as_bytes()).unwrap_err()
+    }
+
+    #[test]
+    fn classes_and_sets() {
+        assert_eq!(matched("hello world", "%a+"), Some("hello".into()));
+        assert_eq!(matched("x = 42;", "%d+"), Some("42".into()));
+        assert_eq!(matched("  \t x", "%S"), Some("x".into()));
+        assert_eq!(matched("a_b1", "[%w_]+"), Some("a_b1".into()));
+        assert_eq!(matched("abc-DEF", "[^%l]+"), Some("-DEF".into()));
+        assert_eq!(matched("0x1F", "%x+$"), Some("1F".into()));
+        assert_eq!(matched("key=val", "[a-f]+"), Some("e".into()));
+        assert_eq!(matched("a]b", "[]]"), Some("]".into()));
+        assert_eq!(matched("a-b", "[a%-]+"), Some("a-".into()));
+        assert_eq!(matched("!?", "%p%p"), Some("!?".into()));
+        assert_eq!(matched("ABc", "%u*"), Some("AB".into()));
+        assert_eq!(matched("\x01a", "%c"), Some("\x01".into()));
+        assert_eq!(matched("x.y", "%."), Some(".".into()));
+    }
+
+    #[test]
+    fn quantifiers_and_anchors() {
+        assert_eq!(matched("<a><b>", "<.*>"), Some("<a><b>".into()));
+        assert_eq!(matched("<a><b>", "<.->"), Some("<a>".into()));
+        assert_eq!(matched("color colour", "colou?r"), Some("color".into()));
+        assert_eq!(matched("aaa", "^a-$"), Some("aaa".into()));
+        assert_eq!(matched("ba", "^a"), None);
+        assert_eq!(matched("ab", "b$"), Some("b".into()));
+        assert_eq!(matched("a$b", "a$b"), Some("a$b".into()));
+        assert_eq!(matched("", "x*"), Some("".into()));
+    }
+
+    #[test]
+    fn captures_and_references() {
+        assert_eq!(
+            matched("key = value", "(%w+)%s*=%s*(%w+)"),
+            Some("key|value".into())
+        );
+        assert_eq!(matched("hello", "()ll()"), Some("3|5".into()));
+        assert_eq!(matched("say \"hi\" now", "([\"'])(.-)%1"), Some("\"|hi".into()));
+        assert_eq!(matched("f(a(b)c) d", "%b()"), Some("(a(b)c)".into()));
+        assert_eq!(matched("THE (quick) fox", "%f[%a]%a+%f[%A]"), Some("THE".into()));
+        assert_eq!(matched("the quick", "%f[%l]%a+$"), Some("quick".into()));
+        assert_eq!(matched("((a))", "((%(a%)))"), Some("(a)|(a)".into()));
+    }
+
+    #[test]
+    fn malformed_patterns_are_errors() {
+        assert_eq!(error("a", "%"), "malformed pattern (ends with '%')");
+        assert_eq!(error("a", "[a"), "malformed pattern (missing ']')");
+        assert_eq!(error("a", "%b"), "malformed pattern (missing arguments to '%b')");
+        assert_eq!(error("a", "%fa"), "missing '[' after '%f' in pattern");
+        assert_eq!(error("a", "(a%2)"), "invalid capture index %2");
+        assert_eq!(error("a", "a)"), "invalid pattern capture");
+        assert_eq!(error("a", "(a"), "unfinished capture");
+        assert_eq!(error("a", &"(".repeat(33)), "too many captures");
+        assert_eq!(error(&"a".repeat(300), &"a?".repeat(300)), "pattern too complex");
+    }
+
+    #[test]
+    fn specials() {
+        assert!(no_specials(b"plain text"));
+        assert!(!no_specials(b"a.b"));
+        let mut ms = MatchState::new(b"abc", b"b");
+        assert_eq!(ms.do_match(1, 0), Ok(Some(2)));
+        ms.reprep();
+        assert_eq!(ms.do_match(0, 0), Ok(None));
+    }
+}
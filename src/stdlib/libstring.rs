@@ -0,0 +1,1137 @@
+use std::cell::Cell;
+
+use crate::common::lua::LuaError;
+use crate::common::obj::objconv::{fmt_number, MultiValue};
+use crate::common::obj::objtype::{FLT, INT, LRFUNC};
+use crate::common::obj::objvalue::{LuaString, Value};
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::{arg_error, check_integer, check_lstring, check_number, new_lib, opt_integer};
+use super::libaux::{opt_lstring, tolstring, type_error, value_address};
+use super::libpack::{str_pack, str_packsize, str_unpack};
+use super::libpattern::{self, no_specials, Capture, MatchState, PatternResult};
+
+// the flags string.format allows for the conversions
+const L_FMTFLAGSF: &[u8] = b"-+#0 ";
+const L_FMTFLAGSX: &[u8] = b"-#0";
+const L_FMTFLAGSI: &[u8] = b"-+0 ";
+const L_FMTFLAGSU: &[u8] = b"-0";
+const L_FMTFLAGSC: &[u8] = b"-";
+const MAX_FORMAT: usize = 32; // the longest conversion specification
+pub(crate) const MAX_SIZE: usize = i32::MAX as usize; // the longest string the library builds
+
+const STRING_FUNCS: &[(&str, LRFUNC)] = &[
+    ("byte", str_byte),
+    ("char", str_char),
+    ("find", str_find),
+    ("format", str_format),
+    ("gmatch", str_gmatch),
+    ("gsub", str_gsub),
+    ("len", str_len),
+    ("lower", str_lower),
+    ("match", str_match),
+    ("pack", str_pack),
+    ("packsize", str_packsize),
+    ("rep", str_rep),
+    ("reverse", str_reverse),
+    ("sub", str_sub),
+    ("unpack", str_unpack),
+    ("upper", str_upper),
+];
+
+/// brief: put the string library in the global `string`, the strings index
+/// it through their metatable
+pub fn open_string(state: &mut LuaState) {
+    let lib = new_lib(state, STRING_FUNCS);
+    let metatable = state.new_table_value(0, 1);
+    let _ = state.raw_set(&metatable, Value::from("__index"), Value::Table(lib.clone()));
+    state.set_type_metatable(&Value::from(""), Some(&metatable));
+    let globals = state.globals();
+    let _ = state.raw_set(&globals, Value::from("string"), Value::Table(lib));
+}
+
+/// brief: a relative initial position to an absolute one, from 1
+pub(crate) fn posrelat_i(pos: INT, len: usize) -> usize {
+    if pos > 0 {
+        pos as usize
+    } else if pos == 0 || pos < -(len as INT) {
+        1
+    } else {
+        (len as INT + pos + 1) as usize
+    }
+}
+
+/// brief: a relative end position to an absolute one, from 1, 0 for none
+fn get_end_pos(pos: INT, len: usize) -> usize {
+    if pos > len as INT {
+        len
+    } else if pos >= 0 {
+        pos as usize
+    } else if pos < -(len as INT) {
+        0
+    } else {
+        (len as INT + pos + 1) as usize
+    }
+}
+
+fn str_len(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "len");
+    state.push_integer(s.as_bytes().len() as INT);
+    1
+}
+
+fn str_sub(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "sub");
+    let bytes = s.as_bytes();
+    let start = posrelat_i(check_integer(state, 2, "sub"), bytes.len());
+    let end = get_end_pos(opt_integer(state, 3, "sub", -1), bytes.len());
+    if start > end {
+        state.push_str("");
+    } else {
+        state.push_string(&bytes[start - 1..end]);
+    }
+    1
+}
+
+fn str_reverse(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "reverse");
+    let reversed: Vec<u8> = s.as_bytes().iter().rev().copied().collect();
+    state.push_string(&reversed);
+    1
+}
+
+fn str_lower(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "lower");
+    state.push_string(&s.as_bytes().to_ascii_lowercase());
+    1
+}
+
+fn str_upper(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "upper");
+    state.push_string(&s.as_bytes().to_ascii_uppercase());
+    1
+}
+
+fn str_rep(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "rep");
+    let n = check_integer(state, 2, "rep");
+    let sep = opt_lstring(state, 3, "rep", "");
+    let (s, sep) = (s.as_bytes(), sep.as_bytes());
+    if n <= 0 || s.len() + sep.len() == 0 {
+        state.push_str("");
+        return 1;
+    }
+    let n = n as usize;
+    match s.len().checked_add(sep.len()) {
+        Some(l) if l <= MAX_SIZE / n => {}
+        _ => state.error(LuaError::Runtime("resulting string too large".to_string())),
+    }
+    let total = s.len() * n + sep.len() * (n - 1);
+    let mut buf = Vec::new();
+    if buf.try_reserve_exact(total).is_err() {
+        state.error(LuaError::Memory("not enough memory".to_string()));
+    }
+    for i in 0..n {
+        if i > 0 {
+            buf.extend_from_slice(sep);
+        }
+        buf.extend_from_slice(s);
+    }
+    state.push_string(&buf);
+    1
+}
+
+fn str_byte(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "byte");
+    let bytes = s.as_bytes();
+    let pi = opt_integer(state, 2, "byte", 1);
+    let start = posrelat_i(pi, bytes.len());
+    let end = get_end_pos(opt_integer(state, 3, "byte", pi), bytes.len());
+    if start > end {
+        return 0;
+    }
+    if end - start >= i32::MAX as usize {
+        state.error(LuaError::Runtime("string slice too long".to_string()));
+    }
+    for byte in &bytes[start - 1..end] {
+        state.push_integer(*byte as INT);
+    }
+    end - start + 1
+}
+
+fn str_char(state: &mut LuaState) -> usize {
+    let n = state.get_top() as isize;
+    let mut buf = Vec::with_capacity(n as usize);
+    for arg in 1..=n {
+        let c = check_integer(state, arg, "char");
+        if !(0..=u8::MAX as INT).contains(&c) {
+            arg_error(state, arg, "char", "value out of range");
+        }
+        buf.push(c as u8);
+    }
+    state.push_string(&buf);
+    1
+}
+
+/// brief: the flags, width and precision of a conversion of string.format
+#[derive(Default)]
+struct FormatSpec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// brief: the conversion specification at the start of `fmt`, up to and with
+/// the conversion character
+fn get_format<'a>(state: &mut LuaState, fmt: &'a [u8]) -> &'a [u8] {
+    let len = fmt.iter().take_while(|c| b"-+#0 123456789.".contains(c)).count() + 1;
+    if len >= MAX_FORMAT - 10 {
+        state.error(LuaError::Runtime("invalid format string to 'format'".to_string()));
+    }
+    &fmt[..len.min(fmt.len())]
+}
+
+/// brief: check the specification `form`, without the '%', allows only
+/// `flags` and a precision if `precision`
+fn check_format(state: &mut LuaState, form: &[u8], flags: &[u8], precision: bool) -> FormatSpec {
+    let at = |i: usize| form.get(i).copied().unwrap_or(0);
+    let mut spec = FormatSpec::default();
+    let mut i = 0;
+    while flags.contains(&at(i)) && at(i) != 0 {
+        match at(i) {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alt = true,
+            _ => spec.zero = true,
+        }
+        i += 1;
+    }
+    // a width cannot start with '0'
+    if at(i) != b'0' {
+        let digits = |i: &mut usize| {
+            let mut n = 0;
+            for _ in 0..2 {
+                if !at(*i).is_ascii_digit() {
+                    break;
+                }
+                n = n * 10 + (at(*i) - b'0') as usize;
+                *i += 1;
+            }
+            n
+        };
+        spec.width = digits(&mut i);
+        if at(i) == b'.' && precision {
+            i += 1;
+            spec.precision = Some(digits(&mut i));
+        }
+    }
+    if !at(i).is_ascii_alphabetic() {
+        state.error(LuaError::Runtime(format!(
+            "invalid conversion specification: '%{}'",
+            String::from_utf8_lossy(form)
+        )));
+    }
+    spec
+}
+
+/// brief: append `prefix` and `body` padded to the width, zeros go between
+/// them when `zero_ok`
+fn add_padded(buf: &mut Vec<u8>, spec: &FormatSpec, prefix: &[u8], body: &[u8], zero_ok: bool) {
+    let fill = spec.width.saturating_sub(prefix.len() + body.len());
+    if spec.left {
+        buf.extend_from_slice(prefix);
+        buf.extend_from_slice(body);
+        buf.resize(buf.len() + fill, b' ');
+    } else if spec.zero && zero_ok {
+        buf.extend_from_slice(prefix);
+        buf.resize(buf.len() + fill, b'0');
+        buf.extend_from_slice(body);
+    } else {
+        buf.resize(buf.len() + fill, b' ');
+        buf.extend_from_slice(prefix);
+        buf.extend_from_slice(body);
+    }
+}
+
+/// brief: the sign of a signed conversion
+fn sign_prefix(spec: &FormatSpec, negative: bool) -> &'static str {
+    if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    }
+}
+
+fn add_integer(buf: &mut Vec<u8>, spec: &FormatSpec, conv: u8, n: INT) {
+    let (prefix, mut digits) = match conv {
+        b'd' | b'i' => (sign_prefix(spec, n < 0), n.unsigned_abs().to_string()),
+        b'u' => ("", (n as u64).to_string()),
+        b'o' => ("", format!("{:o}", n as u64)),
+        b'x' => (
+            if spec.alt && n != 0 { "0x" } else { "" },
+            format!("{:x}", n as u64),
+        ),
+        _ => (
+            if spec.alt && n != 0 { "0X" } else { "" },
+            format!("{:X}", n as u64),
+        ),
+    };
+    if let Some(precision) = spec.precision {
+        if precision == 0 && n == 0 {
+            digits.clear();
+        }
+        if digits.len() < precision {
+            digits = format!("{}{}", "0".repeat(precision - digits.len()), digits);
+        }
+    }
+    if conv == b'o' && spec.alt && !digits.starts_with('0') {
+        digits.insert(0, '0');
+    }
+    add_padded(
+        buf,
+        spec,
+        prefix.as_bytes(),
+        digits.as_bytes(),
+        spec.precision.is_none(),
+    );
+}
+
+/// brief: `a` in "%.*e", the exponent with a sign and two digits at least
+fn fmt_exp(a: FLT, precision: usize, alt: bool) -> String {
+    let sci = format!("{:.*e}", precision, a);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let dot = if alt && precision == 0 { "." } else { "" };
+    format!(
+        "{}{}e{}{:02}",
+        mantissa,
+        dot,
+        if exp < 0 { '-' } else { '+' },
+        exp.abs()
+    )
+}
+
+/// brief: `a` in "%.*g", the shorter of "%e" and "%f"
+fn fmt_general(a: FLT, precision: usize, alt: bool) -> String {
+    let p = precision.max(1);
+    let exp = if a == 0.0 {
+        0
+    } else {
+        let sci = format!("{:.*e}", p - 1, a);
+        sci.split_once('e').unwrap().1.parse::<i32>().unwrap()
+    };
+    let (mut mantissa, exp_part) = if (p as i32) > exp && exp >= -4 {
+        (format!("{:.*}", (p as i32 - 1 - exp) as usize, a), String::new())
+    } else {
+        let sci = fmt_exp(a, p - 1, false);
+        let (mantissa, exp) = sci.split_once('e').unwrap();
+        (mantissa.to_string(), format!("e{}", exp))
+    };
+    if alt {
+        if !mantissa.contains('.') {
+            mantissa.push('.');
+        }
+    } else if mantissa.contains('.') {
+        mantissa = mantissa.trim_end_matches('0').trim_end_matches('.').to_string();
+    }
+    mantissa + &exp_part
+}
+
+/// brief: `a` in "%a" without the "0x", hexadecimal digits and a binary
+/// exponent, rounded to `precision` digits
+fn fmt_hex(a: FLT, precision: Option<usize>, alt: bool) -> String {
+    let bits = a.to_bits();
+    let biased = ((bits >> 52) & 0x7ff) as i64;
+    let mut mantissa = bits & ((1 << 52) - 1);
+    let (mut lead, exp) = if a == 0.0 {
+        (0, 0)
+    } else if biased == 0 {
+        (0, -1022) // subnormal
+    } else {
+        (1, biased - 1023)
+    };
+    let digits = match precision {
+        Some(p) if p < 13 => {
+            let shift = (13 - p) * 4;
+            let rest = mantissa & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            mantissa >>= shift;
+            // round half to even, the leading digit is the last one kept for no digits
+            let odd = if p == 0 { lead & 1 == 1 } else { mantissa & 1 == 1 };
+            if rest > half || (rest == half && odd) {
+                mantissa += 1;
+                if mantissa >> (p * 4) != 0 {
+                    mantissa &= (1 << (p * 4)) - 1;
+                    lead += 1;
+                }
+            }
+            if p == 0 {
+                String::new()
+            } else {
+                format!("{:0w$x}", mantissa, w = p)
+            }
+        }
+        Some(p) => format!("{:013x}{}", mantissa, "0".repeat(p - 13)),
+        None => format!("{:013x}", mantissa).trim_end_matches('0').to_string(),
+    };
+    let dot = if !digits.is_empty() || alt { "." } else { "" };
+    format!("{}{}{}p{:+}", lead, dot, digits, exp)
+}
+
+fn add_float(buf: &mut Vec<u8>, spec: &FormatSpec, conv: u8, n: FLT) {
+    let sign = sign_prefix(spec, n.is_sign_negative());
+    let a = n.abs();
+    let upper = conv.is_ascii_uppercase();
+    if !a.is_finite() {
+        let body = if a.is_nan() { "nan" } else { "inf" };
+        let body = if upper {
+            body.to_uppercase()
+        } else {
+            body.to_string()
+        };
+        return add_padded(buf, spec, sign.as_bytes(), body.as_bytes(), false);
+    }
+    let (prefix, body) = match conv.to_ascii_lowercase() {
+        b'a' => (format!("{}0x", sign), fmt_hex(a, spec.precision, spec.alt)),
+        b'e' => (
+            sign.to_string(),
+            fmt_exp(a, spec.precision.unwrap_or(6), spec.alt),
+        ),
+        b'g' => (
+            sign.to_string(),
+            fmt_general(a, spec.precision.unwrap_or(6), spec.alt),
+        ),
+        _ => {
+            let precision = spec.precision.unwrap_or(6);
+            let dot = if spec.alt && precision == 0 { "." } else { "" };
+            (sign.to_string(), format!("{:.*}{}", precision, a, dot))
+        }
+    };
+    let (prefix, body) = if upper {
+        (prefix.to_uppercase(), body.to_uppercase())
+    } else {
+        (prefix, body)
+    };
+    add_padded(buf, spec, prefix.as_bytes(), body.as_bytes(), true);
+}
+
+/// brief: a float as a lua literal that reads back the same, "%a"
+fn quote_float(n: FLT) -> String {
+    if n == FLT::INFINITY {
+        "1e9999".to_string()
+    } else if n == FLT::NEG_INFINITY {
+        "-1e9999".to_string()
+    } else if n.is_nan() {
+        "(0/0)".to_string()
+    } else {
+        let sign = if n.is_sign_negative() { "-" } else { "" };
+        format!("{}0x{}", sign, fmt_hex(n.abs(), None, false))
+    }
+}
+
+/// brief: append a string in double quotes with the escapes that read back
+fn add_quoted(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.push(b'"');
+    for (i, c) in s.iter().enumerate() {
+        match *c {
+            b'"' | b'\\' | b'\n' => {
+                buf.push(b'\\');
+                buf.push(*c);
+            }
+            c if c.is_ascii_control() => {
+                let next_digit = s.get(i + 1).is_some_and(|next| next.is_ascii_digit());
+                let escape = if next_digit {
+                    format!("\\{:03}", c)
+                } else {
+                    format!("\\{}", c)
+                };
+                buf.extend_from_slice(escape.as_bytes());
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push(b'"');
+}
+
+/// brief: append the value of `arg` as a lua literal, "%q"
+fn add_literal(state: &mut LuaState, buf: &mut Vec<u8>, arg: isize) {
+    match state.get_value(arg) {
+        Value::String(s) => add_quoted(buf, s.as_bytes()),
+        Value::Number(n) => buf.extend_from_slice(quote_float(n).as_bytes()),
+        // the minimum integer has no decimal literal
+        Value::Integer(INT::MIN) => buf.extend_from_slice(format!("{:#x}", INT::MIN).as_bytes()),
+        Value::Integer(i) => buf.extend_from_slice(i.to_string().as_bytes()),
+        val @ (Value::Nil | Value::Boolean(_)) => buf.extend_from_slice(tolstring(state, &val).as_bytes()),
+        _ => arg_error(state, arg, "format", "value has no literal form"),
+    }
+}
+
+fn str_format(state: &mut LuaState) -> usize {
+    let top = state.get_top() as isize;
+    let fmt = check_lstring(state, 1, "format");
+    let fmt = fmt.as_bytes();
+    let mut buf = Vec::with_capacity(fmt.len());
+    let mut arg = 1;
+    let mut i = 0;
+    while i < fmt.len() {
+        if fmt[i] != b'%' {
+            buf.push(fmt[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        if fmt.get(i) == Some(&b'%') {
+            buf.push(b'%');
+            i += 1;
+            continue;
+        }
+        arg += 1;
+        if arg > top {
+            arg_error(state, arg, "format", "no value");
+        }
+        let form = get_format(state, &fmt[i..]);
+        i += form.len();
+        let conv = form.last().copied().unwrap_or(0);
+        match conv {
+            b'c' => {
+                let c = check_integer(state, arg, "format");
+                let spec = check_format(state, form, L_FMTFLAGSC, false);
+                add_padded(&mut buf, &spec, b"", &[c as u8], false);
+            }
+            b'd' | b'i' | b'u' | b'o' | b'x' | b'X' => {
+                let n = check_integer(state, arg, "format");
+                let flags = match conv {
+                    b'd' | b'i' => L_FMTFLAGSI,
+                    b'u' => L_FMTFLAGSU,
+                    _ => L_FMTFLAGSX,
+                };
+                let spec = check_format(state, form, flags, true);
+                add_integer(&mut buf, &spec, conv, n);
+            }
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let n = check_number(state, arg, "format");
+                let spec = check_format(state, form, L_FMTFLAGSF, true);
+                add_float(&mut buf, &spec, conv, n);
+            }
+            b'p' => {
+                let address = value_address(&state.get_value(arg));
+                let spec = check_format(state, form, L_FMTFLAGSC, false);
+                let body = match address {
+                    0 => "(null)".to_string(),
+                    address => format!("{:#x}", address),
+                };
+                add_padded(&mut buf, &spec, b"", body.as_bytes(), false);
+            }
+            b'q' => {
+                if form.len() != 1 {
+                    state.error(LuaError::Runtime(
+                        "specifier '%q' cannot have modifiers".to_string(),
+                    ));
+                }
+                add_literal(state, &mut buf, arg);
+            }
+            b's' => {
+                let val = state.get_value(arg);
+                let s = tolstring(state, &val);
+                let s = s.as_bytes();
+                if form.len() == 1 {
+                    buf.extend_from_slice(s);
+                    continue;
+                }
+                if s.contains(&0) {
+                    arg_error(state, arg, "format", "string contains zeros");
+                }
+                let spec = check_format(state, form, L_FMTFLAGSC, true);
+                match spec.precision {
+                    // no precision and a long string, keep it whole
+                    None if s.len() >= 100 => buf.extend_from_slice(s),
+                    precision => {
+                        let s = &s[..precision.unwrap_or(s.len()).min(s.len())];
+                        add_padded(&mut buf, &spec, b"", s, false);
+                    }
+                }
+            }
+            _ => state.error(LuaError::Runtime(format!(
+                "invalid conversion '%{}' to 'format'",
+                String::from_utf8_lossy(form)
+            ))),
+        }
+    }
+    state.push_string(&buf);
+    1
+}
+
+/// brief: the value or the error of a pattern operation
+fn pattern_check<T>(state: &mut LuaState, result: PatternResult<T>) -> T {
+    match result {
+        Ok(val) => val,
+        Err(msg) => state.error(LuaError::Runtime(msg)),
+    }
+}
+
+fn capture_value(capture: Capture) -> Value {
+    match capture {
+        Capture::Bytes(bytes) => Value::String(LuaString::new(bytes)),
+        Capture::Position(pos) => Value::Integer(pos as INT),
+    }
+}
+
+/// brief: the captures of the match from `s` to `e` as values
+fn capture_values(ms: &MatchState, s: usize, e: usize, whole: bool) -> PatternResult<MultiValue> {
+    Ok(ms
+        .get_captures(s, e, whole)?
+        .into_iter()
+        .map(capture_value)
+        .collect())
+}
+
+fn push_results(state: &mut LuaState, results: MultiValue) -> usize {
+    match state.push_results(results) {
+        Ok(nresults) => nresults,
+        Err(err) => state.error(err),
+    }
+}
+
+/// brief: string.find when `find`, string.match otherwise
+fn str_find_aux(state: &mut LuaState, find: bool) -> usize {
+    let fname = if find { "find" } else { "match" };
+    let s = check_lstring(state, 1, fname);
+    let p = check_lstring(state, 2, fname);
+    let (src, pat) = (s.as_bytes(), p.as_bytes());
+    let init = posrelat_i(opt_integer(state, 3, fname, 1), src.len()) - 1;
+    if init > src.len() {
+        state.push_nil();
+        return 1;
+    }
+    if find && (state.get_value(4).is_truthy() || no_specials(pat)) {
+        // a plain search
+        let found = if pat.is_empty() {
+            Some(0)
+        } else {
+            src[init..].windows(pat.len()).position(|window| window == pat)
+        };
+        if let Some(offset) = found {
+            state.push_integer((init + offset + 1) as INT);
+            state.push_integer((init + offset + pat.len()) as INT);
+            return 2;
+        }
+    } else {
+        let found = libpattern::find(src, pat, init);
+        let results = match pattern_check(state, found) {
+            Some((start, end, ms)) if find => capture_values(&ms, start, end, false).map(|mut caps| {
+                caps.push_front(Value::Integer(end as INT));
+                caps.push_front(Value::Integer(start as INT + 1));
+                caps
+            }),
+            Some((start, end, ms)) => capture_values(&ms, start, end, true),
+            None => Ok(MultiValue::from([Value::Nil])),
+        };
+        let results = pattern_check(state, results);
+        return push_results(state, results);
+    }
+    state.push_nil();
+    1
+}
+
+fn str_find(state: &mut LuaState) -> usize {
+    str_find_aux(state, true)
+}
+
+fn str_match(state: &mut LuaState) -> usize {
+    str_find_aux(state, false)
+}
+
+fn str_gmatch(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "gmatch");
+    let p = check_lstring(state, 2, "gmatch");
+    let len = s.as_bytes().len();
+    let init = (posrelat_i(opt_integer(state, 3, "gmatch", 1), len) - 1).min(len + 1);
+    let next = Cell::new(init);
+    let lastmatch = Cell::new(None);
+    let iter = state.create_function(move |_, ()| {
+        let (src, pat) = (s.as_bytes(), p.as_bytes());
+        let mut ms = MatchState::new(src, pat);
+        for start in next.get()..=src.len() {
+            ms.reprep();
+            match ms.do_match(start, 0).map_err(LuaError::Runtime)? {
+                Some(end) if Some(end) != lastmatch.get() => {
+                    next.set(end);
+                    lastmatch.set(Some(end));
+                    return capture_values(&ms, start, end, true).map_err(LuaError::Runtime);
+                }
+                _ => {}
+            }
+        }
+        next.set(src.len() + 1);
+        Ok(MultiValue::new())
+    })
+    .unwrap_or_else(|err| state.error(err));
+    state.push_value(&Value::Function(iter));
+    1
+}
+
+/// brief: append the replacement string `repl` for the match from `s` to `e`
+fn add_s(ms: &MatchState, buf: &mut Vec<u8>, s: usize, e: usize, repl: &[u8]) -> PatternResult<()> {
+    let mut i = 0;
+    while i < repl.len() {
+        let c = repl[i];
+        i += 1;
+        if c != b'%' {
+            buf.push(c);
+            continue;
+        }
+        let d = repl.get(i).copied().unwrap_or(0);
+        i += 1;
+        match d {
+            b'%' => buf.push(b'%'),
+            b'0' => buf.extend_from_slice(&ms.source()[s..e]),
+            b'1'..=b'9' => match ms.get_capture((d - b'1') as usize, s, e)? {
+                Capture::Bytes(bytes) => buf.extend_from_slice(bytes),
+                Capture::Position(pos) => buf.extend_from_slice(pos.to_string().as_bytes()),
+            },
+            _ => return Err("invalid use of '%' in replacement string".to_string()),
+        }
+    }
+    Ok(())
+}
+
+/// brief: append the replacement for the match from `s` to `e`, return
+/// whether the original text was replaced
+fn add_value(
+    state: &mut LuaState,
+    ms: &MatchState,
+    buf: &mut Vec<u8>,
+    s: usize,
+    e: usize,
+    repl: &Value,
+) -> bool {
+    let src = ms.source();
+    let result = match repl {
+        Value::Function(_) => {
+            let args = pattern_check(state, capture_values(ms, s, e, true));
+            match state.call_value(repl, args) {
+                Ok(results) => results.into_iter().next().unwrap_or(Value::Nil),
+                Err(err) => state.error(err),
+            }
+        }
+        Value::Table(_) => {
+            let key = capture_value(pattern_check(state, ms.get_capture(0, s, e)));
+            match state.index(repl, &key) {
+                Ok(val) => val,
+                Err(err) => state.error(err),
+            }
+        }
+        Value::String(text) => {
+            let added = add_s(ms, buf, s, e, text.as_bytes());
+            pattern_check(state, added);
+            return true;
+        }
+        _ => unreachable!("gsub checks the replacement type"),
+    };
+    match result {
+        // keep the original text
+        Value::Nil | Value::Boolean(false) => {
+            buf.extend_from_slice(&src[s..e]);
+            false
+        }
+        Value::String(text) => {
+            buf.extend_from_slice(text.as_bytes());
+            true
+        }
+        Value::Integer(i) => {
+            buf.extend_from_slice(i.to_string().as_bytes());
+            true
+        }
+        Value::Number(n) => {
+            buf.extend_from_slice(fmt_number(n).as_bytes());
+            true
+        }
+        val => state.error(LuaError::Runtime(format!(
+            "invalid replacement value (a {})",
+            val.type_name()
+        ))),
+    }
+}
+
+fn str_gsub(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "gsub");
+    let p = check_lstring(state, 2, "gsub");
+    let repl = match state.get_value(3) {
+        val @ (Value::String(_) | Value::Function(_) | Value::Table(_)) => val,
+        Value::Integer(_) | Value::Number(_) => Value::String(check_lstring(state, 3, "gsub")),
+        _ => type_error(state, 3, "gsub", "string/function/table"),
+    };
+    let (src, pat) = (s.as_bytes(), p.as_bytes());
+    let max_s = opt_integer(state, 4, "gsub", src.len() as INT + 1);
+    let (anchor, pat) = match pat.first() {
+        Some(b'^') => (true, &pat[1..]),
+        _ => (false, pat),
+    };
+    let mut ms = MatchState::new(src, pat);
+    let mut buf = Vec::with_capacity(src.len());
+    let mut lastmatch = None;
+    let mut changed = false;
+    let mut pos = 0;
+    let mut n: INT = 0;
+    while n < max_s {
+        ms.reprep();
+        let matched = ms.do_match(pos, 0);
+        match pattern_check(state, matched) {
+            Some(end) if Some(end) != lastmatch => {
+                n += 1;
+                changed |= add_value(state, &ms, &mut buf, pos, end, &repl);
+                pos = end;
+                lastmatch = Some(end);
+            }
+            _ if pos < src.len() => {
+                buf.push(src[pos]);
+                pos += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    if changed {
+        buf.extend_from_slice(&src[pos..]);
+        state.push_string(&buf);
+    } else {
+        state.push_value(&Value::String(s.clone()));
+    }
+    state.push_integer(n);
+    2
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::lua::LuaResult;
+    use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti, MultiValue};
+    use crate::common::obj::objvalue::{Function, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    fn call<A: IntoLuaMulti, R: FromLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<R> {
+        let string: Table = state.get_global("string")?;
+        let function: Function = string.get(state, name)?;
+        function.call(state, args)
+    }
+
+    fn call_err<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> String {
+        call::<_, MultiValue>(state, name, args).unwrap_err().to_string()
+    }
+
+    fn call1<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> String {
+        call(state, name, args).unwrap()
+    }
+
+    #[test]
+    fn basic_functions() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let bytes: (i64, i64, i64) = call(state, "byte", ("ABC", 1, -1)).unwrap();
+        assert_eq!(bytes, (65, 66, 67));
+        let byte: i64 = call(state, "byte", "ABC").unwrap();
+        assert_eq!(byte, 65);
+        assert!(call::<_, MultiValue>(state, "byte", ("", 1)).unwrap().is_empty());
+        assert_eq!(call1(state, "char", (72, 105)), "Hi");
+        assert_eq!(
+            call_err(state, "char", 256),
+            "bad argument #1 to 'char' (value out of range)"
+        );
+        assert_eq!(call1(state, "sub", ("hello", 2, -2)), "ell");
+        assert_eq!(call1(state, "sub", ("hello", -3)), "llo");
+        assert_eq!(call1(state, "sub", ("hello", 0)), "hello");
+        assert_eq!(call1(state, "sub", ("hello", 4, 2)), "");
+        assert_eq!(call1(state, "sub", ("hello", -100, 100)), "hello");
+        assert_eq!(call1(state, "rep", ("ab", 3, ",")), "ab,ab,ab");
+        assert_eq!(call1(state, "rep", ("x", 0)), "");
+        assert_eq!(call1(state, "rep", ("", 1i64 << 40)), "");
+        assert_eq!(
+            call_err(state, "rep", ("x", i64::MAX, "y")),
+            "resulting string too large"
+        );
+        assert_eq!(call1(state, "reverse", "abc"), "cba");
+        assert_eq!(call1(state, "lower", "MiXeD 1"), "mixed 1");
+        assert_eq!(call1(state, "upper", "MiXeD 1"), "MIXED 1");
+        let len: i64 = call(state, "len", "a\0b").unwrap();
+        assert_eq!(len, 3);
+        let len: i64 = call(state, "len", 123).unwrap();
+        assert_eq!(len, 3);
+    }
+
+    #[test]
+    fn strings_index_the_library() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let upper = state.index(&Value::from("x"), &Value::from("upper")).unwrap();
+        let results = state
+            .call_value(&upper, MultiValue::from([Value::from("x")]))
+            .unwrap();
+        assert_eq!(results, MultiValue::from([Value::from("X")]));
+        let getmetatable: Function = state.get_global("getmetatable").unwrap();
+        let metatable: Table = getmetatable.call(state, "").unwrap();
+        let string: Table = state.get_global("string").unwrap();
+        let index: Table = metatable.raw_get(state, "__index").unwrap();
+        assert_eq!(index, string);
+    }
+
+    #[test]
+    fn format_integers_and_strings() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let format =
+            |state: &mut LuaState, args: MultiValue| -> String { call(state, "format", args).unwrap() };
+        let args = |vals: &[Value]| vals.iter().cloned().collect::<MultiValue>();
+        let i = Value::Integer;
+
+        assert_eq!(
+            format(
+                state,
+                args(&[
+                    Value::from("%5d|%-5d|%05d|%+d|% d"),
+                    i(42),
+                    i(42),
+                    i(42),
+                    i(42),
+                    i(42)
+                ])
+            ),
+            "   42|42   |00042|+42| 42"
+        );
+        assert_eq!(
+            format(state, args(&[Value::from("%.3d|%5.3d|%.0d"), i(7), i(-7), i(0)])),
+            "007| -007|"
+        );
+        assert_eq!(
+            format(
+                state,
+                args(&[
+                    Value::from("%x %X %#x %o %#o"),
+                    i(255),
+                    i(255),
+                    i(255),
+                    i(8),
+                    i(8)
+                ])
+            ),
+            "ff FF 0xff 10 010"
+        );
+        assert_eq!(
+            format(state, args(&[Value::from("%u"), i(-1)])),
+            "18446744073709551615"
+        );
+        assert_eq!(format(state, args(&[Value::from("%i"), Value::Number(3.0)])), "3");
+        assert_eq!(format(state, args(&[Value::from("%c%c"), i(76), i(117)])), "Lu");
+        assert_eq!(
+            format(
+                state,
+                args(&[
+                    Value::from("%-5s|%5.2s|%s"),
+                    Value::from("ab"),
+                    Value::from("xyz"),
+                    i(1)
+                ])
+            ),
+            "ab   |   xy|1"
+        );
+        assert_eq!(format(state, args(&[Value::from("100%%")])), "100%");
+        assert_eq!(format(state, args(&[Value::from("%s"), Value::Nil])), "nil");
+
+        assert_eq!(
+            call_err(state, "format", "%d"),
+            "bad argument #2 to 'format' (no value)"
+        );
+        assert_eq!(
+            call_err(state, "format", ("%d", 1.5)),
+            "bad argument #2 to 'format' (number has no integer representation)"
+        );
+        assert_eq!(
+            call_err(state, "format", ("%y", 1)),
+            "invalid conversion '%y' to 'format'"
+        );
+        assert_eq!(
+            call_err(state, "format", ("%123d", 1)),
+            "invalid conversion specification: '%123d'"
+        );
+        assert_eq!(
+            call_err(state, "format", ("%#d", 1)),
+            "invalid conversion specification: '%#d'"
+        );
+        assert_eq!(
+            call_err(state, "format", ("%10q", 1)),
+            "specifier '%q' cannot have modifiers"
+        );
+        assert_eq!(
+            call_err(state, "format", ("%5s", "a\0b")),
+            "bad argument #2 to 'format' (string contains zeros)"
+        );
+    }
+
+    #[test]
+    fn format_floats() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let cases: &[(&str, f64, &str)] = &[
+            ("%5.2f", 1.23456, " 1.23"),
+            ("%f", 1.0 / 3.0, "0.333333"),
+            ("%08.3f", -1.23456, "-001.235"),
+            ("%#.0f", 2.0, "2."),
+            ("%-12.3e|", 1234.56, "1.235e+03   |"),
+            ("%E", 0.000123, "1.230000E-04"),
+            ("%g", 0.0001, "0.0001"),
+            ("%g", 1e20, "1e+20"),
+            ("%g", 100.0, "100"),
+            ("%g", 123456789.0, "1.23457e+08"),
+            ("%#g", 1.0, "1.00000"),
+            ("%.3g", 0.0001234, "0.000123"),
+            ("%G", 1e-10, "1E-10"),
+            ("%a", 1.0, "0x1p+0"),
+            ("%a", 0.5, "0x1p-1"),
+            ("%a", -0.0, "-0x0p+0"),
+            ("%.1a", 1.0, "0x1.0p+0"),
+            ("%A", 255.5, "0X1.FFP+7"),
+            ("%.0a", 1.5, "0x2p+0"),
+            ("%.0a", 2.5, "0x1p+1"),
+            ("%a", 5e-324, "0x0.0000000000001p-1022"),
+            ("%f", f64::INFINITY, "inf"),
+            ("%5.1f", f64::NEG_INFINITY, " -inf"),
+            ("%F", f64::NAN, "NAN"),
+        ];
+        for (fmt, n, expected) in cases {
+            let text: String = call(state, "format", (*fmt, *n)).unwrap();
+            assert_eq!(&text, expected, "format {}", fmt);
+        }
+    }
+
+    #[test]
+    fn format_literals() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let quoted: String = call(state, "format", ("%q", "a\"b\n\0c\x001\r")).unwrap();
+        assert_eq!(quoted, "\"a\\\"b\\\n\\0c\\0001\\13\"");
+        let quoted: String = call(state, "format", ("%q %q %q", 10, 0.5, i64::MIN)).unwrap();
+        assert_eq!(quoted, "10 0x1p-1 0x8000000000000000");
+        let quoted: String = call(state, "format", ("%q %q %q", f64::INFINITY, f64::NAN, true)).unwrap();
+        assert_eq!(quoted, "1e9999 (0/0) true");
+        let table = state.create_table_value(0, 0).unwrap();
+        assert_eq!(
+            call_err(state, "format", ("%q", table)),
+            "bad argument #2 to 'format' (value has no literal form)"
+        );
+    }
+
+    #[test]
+    fn find_and_match() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let found: (i64, i64) = call(state, "find", ("hello world", "o w")).unwrap();
+        assert_eq!(found, (5, 7));
+        let found: (i64, i64, String) = call(state, "find", ("key = val", "(%w+)$")).unwrap();
+        assert_eq!(found, (7, 9, "val".to_string()));
+        let found: (i64, i64) = call(state, "find", ("a.b", ".", 1, true)).unwrap();
+        assert_eq!(found, (2, 2));
+        assert_eq!(
+            call::<_, MultiValue>(state, "find", ("abc", "b", 10)).unwrap(),
+            MultiValue::from([Value::Nil])
+        );
+        assert_eq!(
+            call::<_, MultiValue>(state, "find", ("abc", "^b")).unwrap(),
+            MultiValue::from([Value::Nil])
+        );
+        let found: (i64, i64) = call(state, "find", ("abcb", "b", -1)).unwrap();
+        assert_eq!(found, (4, 4));
+
+        let matched: (String, String) = call(state, "match", ("x = 10", "(%a+)%s*=%s*(%d+)")).unwrap();
+        assert_eq!(matched, ("x".to_string(), "10".to_string()));
+        let matched: (i64, String) = call(state, "match", ("hello", "()(ll)")).unwrap();
+        assert_eq!(matched, (3, "ll".to_string()));
+        let matched: String = call(state, "match", ("  trim  ", "^%s*(.-)%s*$")).unwrap();
+        assert_eq!(matched, "trim");
+        assert_eq!(
+            call_err(state, "match", ("a", "[a")),
+            "malformed pattern (missing ']')"
+        );
+    }
+
+    #[test]
+    fn gmatch_iterates() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let iter: Function = call(state, "gmatch", ("one two  three", "%a+")).unwrap();
+        let mut words = Vec::new();
+        loop {
+            let word: Option<String> = iter.call(state, ()).unwrap();
+            match word {
+                Some(word) => words.push(word),
+                None => break,
+            }
+        }
+        assert_eq!(words, ["one", "two", "three"]);
+
+        let iter: Function = call(state, "gmatch", ("k1=v1, k2=v2", "(%w+)=(%w+)")).unwrap();
+        let pair: (String, String) = iter.call(state, ()).unwrap();
+        assert_eq!(pair, ("k1".to_string(), "v1".to_string()));
+        let pair: (String, String) = iter.call(state, ()).unwrap();
+        assert_eq!(pair, ("k2".to_string(), "v2".to_string()));
+
+        // an empty match does not repeat at the end of the last one
+        let iter: Function = call(state, "gmatch", ("abc", "%a*")).unwrap();
+        let first: String = iter.call(state, ()).unwrap();
+        assert_eq!(first, "abc");
+        let rest: MultiValue = iter.call(state, ()).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn gsub_replacements() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let replaced: (String, i64) = call(state, "gsub", ("hello world", "o", "0")).unwrap();
+        assert_eq!(replaced, ("hell0 w0rld".to_string(), 2));
+        let replaced: (String, i64) = call(state, "gsub", ("hello world", "(%w+)", "<%1>")).unwrap();
+        assert_eq!(replaced, ("<hello> <world>".to_string(), 2));
+        let replaced: (String, i64) = call(state, "gsub", ("abc", "%w", "%0%0", 2)).unwrap();
+        assert_eq!(replaced, ("aabbc".to_string(), 2));
+        let replaced: (String, i64) = call(state, "gsub", ("abc", "", "-")).unwrap();
+        assert_eq!(replaced, ("-a-b-c-".to_string(), 4));
+        let replaced: (String, i64) = call(state, "gsub", ("hello", "^h", "j")).unwrap();
+        assert_eq!(replaced, ("jello".to_string(), 1));
+
+        let vars = state.create_table_value(0, 1).unwrap();
+        vars.set(state, "name", "lua").unwrap();
+        let replaced: (String, i64) = call(state, "gsub", ("$name is $unknown", "%$(%w+)", vars)).unwrap();
+        assert_eq!(replaced, ("lua is $unknown".to_string(), 2));
+
+        let upper = state.create_function(|_, s: String| Ok(s.to_uppercase())).unwrap();
+        let replaced: (String, i64) = call(state, "gsub", ("a b", "%a", upper)).unwrap();
+        assert_eq!(replaced, ("A B".to_string(), 2));
+
+        assert_eq!(
+            call_err(state, "gsub", ("abc", "b", "%2")),
+            "invalid capture index %2"
+        );
+        assert_eq!(
+            call_err(state, "gsub", ("abc", "b", "%x")),
+            "invalid use of '%' in replacement string"
+        );
+        let bad = state.create_function(|state, ()| state.create_table_value(0, 0)).unwrap();
+        assert_eq!(
+            call_err(state, "gsub", ("abc", "b", bad)),
+            "invalid replacement value (a table)"
+        );
+        assert_eq!(
+            call_err(state, "gsub", ("abc", "b", true)),
+            "bad argument #3 to 'gsub' (string/function/table expected, got boolean)"
+        );
+    }
+}
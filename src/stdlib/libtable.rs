@@ -0,0 +1,624 @@
+use crate::common::lua::LuaError;
+use crate::common::obj::objtype::{INT, LRFUNC};
+use crate::common::obj::objvalue::Value;
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::{arg_error, check_integer, is_none_or_nil, len, new_lib, opt_integer};
+use super::libaux::{opt_lstring, tolstring, type_error};
+
+// the operations a table-like argument must allow through its metatable
+const TAB_R: u8 = 1; // read, `__index`
+const TAB_W: u8 = 2; // write, `__newindex`
+const TAB_L: u8 = 4; // length, `__len`
+const TAB_RW: u8 = TAB_R | TAB_W;
+
+const TABLE_FUNCS: &[(&str, LRFUNC)] = &[
+    ("concat", tconcat),
+    ("insert", tinsert),
+    ("move", tmove),
+    ("pack", tpack),
+    ("remove", tremove),
+    ("sort", tsort),
+    ("unpack", tunpack),
+];
+
+/// brief: put the table library in the global `table`
+pub fn open_table(state: &mut LuaState) {
+    let lib = new_lib(state, TABLE_FUNCS);
+    let globals = state.globals();
+    let _ = state.raw_set(&globals, Value::from("table"), Value::Table(lib));
+}
+
+/// brief: a table argument, or a value whose metatable has the fields of `what`
+fn check_tab(state: &mut LuaState, arg: isize, fname: &str, what: u8) -> Value {
+    let val = state.get_value(arg);
+    if let Value::Table(_) = val {
+        return val;
+    }
+    let allowed = match state.get_metatable(&val) {
+        Some(metatable) => [(TAB_R, "__index"), (TAB_W, "__newindex"), (TAB_L, "__len")]
+            .iter()
+            .all(|(bit, event)| what & bit == 0 || !state.raw_get(&metatable, &Value::from(*event)).is_nil()),
+        None => false,
+    };
+    if !allowed {
+        type_error(state, arg, fname, "table");
+    }
+    val
+}
+
+/// brief: the length of a table argument
+fn aux_getn(state: &mut LuaState, arg: isize, fname: &str, what: u8) -> (Value, INT) {
+    let table = check_tab(state, arg, fname, what | TAB_L);
+    let n = len(state, &table);
+    (table, n)
+}
+
+/// brief: t[i] with `__index`
+fn geti(state: &mut LuaState, table: &Value, i: INT) -> Value {
+    match state.index(table, &Value::Integer(i)) {
+        Ok(val) => val,
+        Err(err) => state.error(err),
+    }
+}
+
+/// brief: t[i] = val with `__newindex`
+fn seti(state: &mut LuaState, table: &Value, i: INT, val: Value) {
+    if let Err(err) = state.set_index(table, Value::Integer(i), val) {
+        state.error(err);
+    }
+}
+
+fn tinsert(state: &mut LuaState) -> usize {
+    let (table, n) = aux_getn(state, 1, "insert", TAB_RW);
+    // the first empty element
+    let e = n.wrapping_add(1);
+    let pos = match state.get_top() {
+        2 => e,
+        3 => {
+            let pos = check_integer(state, 2, "insert");
+            // pos in [1, e]
+            if (pos as u64).wrapping_sub(1) >= e as u64 {
+                arg_error(state, 2, "insert", "position out of bounds");
+            }
+            // move up the elements
+            for i in (pos + 1..=e).rev() {
+                let val = geti(state, &table, i - 1);
+                seti(state, &table, i, val);
+            }
+            pos
+        }
+        _ => state.error(LuaError::Runtime(
+            "wrong number of arguments to 'insert'".to_string(),
+        )),
+    };
+    let val = state.get_value(state.get_top() as isize);
+    seti(state, &table, pos, val);
+    0
+}
+
+fn tremove(state: &mut LuaState) -> usize {
+    let (table, size) = aux_getn(state, 1, "remove", TAB_RW);
+    let mut pos = opt_integer(state, 2, "remove", size);
+    // pos in [1, size + 1] when given
+    if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+        arg_error(state, 2, "remove", "position out of bounds");
+    }
+    let result = geti(state, &table, pos);
+    while pos < size {
+        let val = geti(state, &table, pos + 1);
+        seti(state, &table, pos, val);
+        pos += 1;
+    }
+    seti(state, &table, pos, Value::Nil);
+    state.push_value(&result);
+    1
+}
+
+fn tmove(state: &mut LuaState) -> usize {
+    let f = check_integer(state, 2, "move");
+    let e = check_integer(state, 3, "move");
+    let t = check_integer(state, 4, "move");
+    // the destination table
+    let tt = if is_none_or_nil(state, 5) { 1 } else { 5 };
+    let src = check_tab(state, 1, "move", TAB_R);
+    let dst = check_tab(state, tt, "move", TAB_W);
+    if e >= f {
+        if !(f > 0 || e < INT::MAX + f) {
+            arg_error(state, 3, "move", "too many elements to move");
+        }
+        // the number of elements to move
+        let n = e - f + 1;
+        if t > INT::MAX - n + 1 {
+            arg_error(state, 4, "move", "destination wrap around");
+        }
+        let same = tt == 1
+            || match state.equals(&src, &dst) {
+                Ok(same) => same,
+                Err(err) => state.error(err),
+            };
+        if t > e || t <= f || !same {
+            for i in 0..n {
+                let val = geti(state, &src, f + i);
+                seti(state, &dst, t + i, val);
+            }
+        } else {
+            // overlapping, move from the end
+            for i in (0..n).rev() {
+                let val = geti(state, &src, f + i);
+                seti(state, &dst, t + i, val);
+            }
+        }
+    }
+    state.push_value(&dst);
+    1
+}
+
+fn tconcat(state: &mut LuaState) -> usize {
+    let (table, n) = aux_getn(state, 1, "concat", TAB_R);
+    let sep = opt_lstring(state, 2, "concat", "");
+    let mut i = opt_integer(state, 3, "concat", 1);
+    let last = opt_integer(state, 4, "concat", n);
+    let mut buf = Vec::new();
+    let add_field = |state: &mut LuaState, buf: &mut Vec<u8>, i: INT| match geti(state, &table, i) {
+        Value::String(s) => buf.extend_from_slice(s.as_bytes()),
+        val @ (Value::Integer(_) | Value::Number(_)) => {
+            buf.extend_from_slice(tolstring(state, &val).as_bytes())
+        }
+        _ => state.error(LuaError::Runtime(format!(
+            "invalid value (at index {}) in table for 'concat'",
+            i
+        ))),
+    };
+    while i < last {
+        add_field(state, &mut buf, i);
+        buf.extend_from_slice(sep.as_bytes());
+        i += 1;
+    }
+    if i == last {
+        add_field(state, &mut buf, i);
+    }
+    state.push_string(&buf);
+    1
+}
+
+fn tpack(state: &mut LuaState) -> usize {
+    let n = state.get_top();
+    let table = state.new_table_value(n, 1);
+    for i in 1..=n {
+        let val = state.get_value(i as isize);
+        let _ = state.raw_set(&table, Value::Integer(i as INT), val);
+    }
+    let _ = state.raw_set(&table, Value::from("n"), Value::Integer(n as INT));
+    state.push_value(&Value::Table(table));
+    1
+}
+
+fn tunpack(state: &mut LuaState) -> usize {
+    let table = state.get_value(1);
+    let mut i = opt_integer(state, 2, "unpack", 1);
+    let e = if is_none_or_nil(state, 3) {
+        len(state, &table)
+    } else {
+        check_integer(state, 3, "unpack")
+    };
+    if i > e {
+        return 0;
+    }
+    // the number of elements minus 1, no overflow
+    let n = (e as u64).wrapping_sub(i as u64);
+    if n >= i32::MAX as u64 || state.stack_check(n as usize + 1).is_err() {
+        state.error(LuaError::Runtime("too many results to unpack".to_string()));
+    }
+    while i < e {
+        let val = geti(state, &table, i);
+        state.push_value(&val);
+        i += 1;
+    }
+    let val = geti(state, &table, e);
+    state.push_value(&val);
+    n as usize + 1
+}
+
+/// brief: the state of table.sort, an introsort: quicksort with the median
+/// of three, heapsort when the partitions keep being unbalanced
+struct Sorter<'a> {
+    state: &'a mut LuaState,
+    table: Value,
+    comp: Value, // nil for `<`
+}
+
+impl Sorter<'_> {
+    fn get(&mut self, i: usize) -> Value {
+        geti(self.state, &self.table, i as INT)
+    }
+
+    fn set(&mut self, i: usize, val: Value) {
+        seti(self.state, &self.table, i as INT, val)
+    }
+
+    fn less(&mut self, a: &Value, b: &Value) -> bool {
+        let result = if self.comp.is_nil() {
+            self.state.less_than(a, b)
+        } else {
+            let args = [a.clone(), b.clone()].into();
+            self.state
+                .call_value(&self.comp, args)
+                .map(|results| results.front().is_some_and(Value::is_truthy))
+        };
+        match result {
+            Ok(less) => less,
+            Err(err) => self.state.error(err),
+        }
+    }
+
+    fn invalid_order(&mut self) -> ! {
+        self.state.error(LuaError::Runtime(
+            "invalid order function for sorting".to_string(),
+        ))
+    }
+
+    /// brief: partition a[lo..=up] around the pivot at a[up - 1], return its
+    /// final position
+    fn partition(&mut self, lo: usize, up: usize, pivot: &Value) -> usize {
+        let (mut i, mut j) = (lo, up - 1);
+        loop {
+            // repeat ++i while a[i] < P
+            let ai = loop {
+                i += 1;
+                let ai = self.get(i);
+                if !self.less(&ai, pivot) {
+                    break ai;
+                }
+                // a[i] < P but a[up - 1] == P
+                if i == up - 1 {
+                    self.invalid_order();
+                }
+            };
+            // repeat --j while P < a[j]
+            let aj = loop {
+                j -= 1;
+                let aj = self.get(j);
+                if !self.less(pivot, &aj) {
+                    break aj;
+                }
+                // j < i but a[j] > P
+                if j < i {
+                    self.invalid_order();
+                }
+            };
+            if j < i {
+                // swap the pivot with a[i]
+                self.set(up - 1, ai);
+                self.set(i, pivot.clone());
+                return i;
+            }
+            self.set(i, aj);
+            self.set(j, ai);
+        }
+    }
+
+    fn sift_down(&mut self, lo: usize, mut root: usize, end: usize) {
+        loop {
+            let mut child = 2 * root + 1;
+            if child > end {
+                return;
+            }
+            let mut child_val = self.get(lo + child);
+            if child < end {
+                let right = self.get(lo + child + 1);
+                if self.less(&child_val, &right) {
+                    child += 1;
+                    child_val = right;
+                }
+            }
+            let root_val = self.get(lo + root);
+            if !self.less(&root_val, &child_val) {
+                return;
+            }
+            self.set(lo + root, child_val);
+            self.set(lo + child, root_val);
+            root = child;
+        }
+    }
+
+    fn heap_sort(&mut self, lo: usize, up: usize) {
+        let end = up - lo;
+        for root in (0..=end / 2).rev() {
+            self.sift_down(lo, root, end);
+        }
+        for last in (1..=end).rev() {
+            let (first, other) = (self.get(lo), self.get(lo + last));
+            self.set(lo, other);
+            self.set(lo + last, first);
+            self.sift_down(lo, 0, last - 1);
+        }
+    }
+
+    fn sort(&mut self, mut lo: usize, mut up: usize, mut depth: u32) {
+        while lo < up {
+            // sort a[lo] and a[up]
+            let (alo, aup) = (self.get(lo), self.get(up));
+            if self.less(&aup, &alo) {
+                self.set(lo, aup);
+                self.set(up, alo);
+            }
+            if up - lo == 1 {
+                break;
+            }
+            if depth == 0 {
+                self.heap_sort(lo, up);
+                break;
+            }
+            depth -= 1;
+            // sort a[lo], a[p] and a[up]
+            let p = lo + (up - lo) / 2;
+            let (ap, alo) = (self.get(p), self.get(lo));
+            if self.less(&ap, &alo) {
+                self.set(p, alo);
+                self.set(lo, ap);
+            } else {
+                let aup = self.get(up);
+                if self.less(&aup, &ap) {
+                    self.set(p, aup);
+                    self.set(up, ap);
+                }
+            }
+            if up - lo == 2 {
+                break;
+            }
+            // the median is the pivot, kept at a[up - 1]
+            let pivot = self.get(p);
+            let aup1 = self.get(up - 1);
+            self.set(p, aup1);
+            self.set(up - 1, pivot.clone());
+            let p = self.partition(lo, up, &pivot);
+            // recurse into the smaller interval, loop for the larger
+            if p - lo < up - p {
+                self.sort(lo, p - 1, depth);
+                lo = p + 1;
+            } else {
+                self.sort(p + 1, up, depth);
+                up = p - 1;
+            }
+        }
+    }
+}
+
+fn tsort(state: &mut LuaState) -> usize {
+    let (table, n) = aux_getn(state, 1, "sort", TAB_RW);
+    if n > 1 {
+        if n >= i32::MAX as INT {
+            arg_error(state, 1, "sort", "array too big");
+        }
+        let comp = state.get_value(2);
+        if !matches!(comp, Value::Nil | Value::Function(_)) {
+            type_error(state, 2, "sort", "function");
+        }
+        let depth = 2 * (INT::BITS - n.leading_zeros());
+        Sorter { state, table, comp }.sort(1, n as usize, depth);
+    }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::lua::LuaResult;
+    use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti, MultiValue};
+    use crate::common::obj::objvalue::{Function, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    use super::Sorter;
+
+    fn call<A: IntoLuaMulti, R: FromLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<R> {
+        let table: Table = state.get_global("table")?;
+        let function: Function = table.get(state, name)?;
+        function.call(state, args)
+    }
+
+    fn call_err<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> String {
+        call::<_, MultiValue>(state, name, args).unwrap_err().to_string()
+    }
+
+    fn sequence(state: &mut LuaState, vals: &[i64]) -> Table {
+        let table = state.create_table_value(vals.len(), 0).unwrap();
+        for (i, val) in vals.iter().enumerate() {
+            table.raw_set(state, i as i64 + 1, *val).unwrap();
+        }
+        table
+    }
+
+    fn values(state: &mut LuaState, table: &Table) -> Vec<i64> {
+        table.sequence_values(state).collect::<LuaResult<_>>().unwrap()
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let t = sequence(state, &[1, 2, 3]);
+        call::<_, ()>(state, "insert", (t.clone(), 4)).unwrap();
+        call::<_, ()>(state, "insert", (t.clone(), 1, 0)).unwrap();
+        assert_eq!(values(state, &t), [0, 1, 2, 3, 4]);
+        let removed: i64 = call(state, "remove", t.clone()).unwrap();
+        assert_eq!(removed, 4);
+        let removed: i64 = call(state, "remove", (t.clone(), 1)).unwrap();
+        assert_eq!(removed, 0);
+        assert_eq!(values(state, &t), [1, 2, 3]);
+        let removed: Value = call(state, "remove", (t.clone(), 4)).unwrap();
+        assert_eq!(removed, Value::Nil);
+        let empty = state.create_table_value(0, 0).unwrap();
+        let removed: Value = call(state, "remove", empty).unwrap();
+        assert_eq!(removed, Value::Nil);
+
+        assert_eq!(
+            call_err(state, "insert", (t.clone(), 5, 0)),
+            "bad argument #2 to 'insert' (position out of bounds)"
+        );
+        assert_eq!(
+            call_err(state, "insert", (t.clone(), 1, 2, 3)),
+            "wrong number of arguments to 'insert'"
+        );
+        assert_eq!(
+            call_err(state, "remove", (t.clone(), 7)),
+            "bad argument #2 to 'remove' (position out of bounds)"
+        );
+        assert_eq!(
+            call_err(state, "insert", (1, 2)),
+            "bad argument #1 to 'insert' (table expected, got number)"
+        );
+    }
+
+    #[test]
+    fn concat_pack_unpack() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let t = state.create_table_value(3, 0).unwrap();
+        t.raw_set(state, 1, 1).unwrap();
+        t.raw_set(state, 2, 2.5).unwrap();
+        t.raw_set(state, 3, "a").unwrap();
+        let text: String = call(state, "concat", (t.clone(), ", ")).unwrap();
+        assert_eq!(text, "1, 2.5, a");
+        let text: String = call(state, "concat", (t.clone(), "-", 2, 3)).unwrap();
+        assert_eq!(text, "2.5-a");
+        let text: String = call(state, "concat", (t.clone(), "-", 3, 2)).unwrap();
+        assert_eq!(text, "");
+        let inner = state.create_table_value(0, 0).unwrap();
+        t.raw_set(state, 2, inner).unwrap();
+        assert_eq!(
+            call_err(state, "concat", t.clone()),
+            "invalid value (at index 2) in table for 'concat'"
+        );
+
+        let packed: Table = call(state, "pack", (1, Value::Nil, 3)).unwrap();
+        let n: i64 = packed.raw_get(state, "n").unwrap();
+        assert_eq!(n, 3);
+        let third: i64 = packed.raw_get(state, 3).unwrap();
+        assert_eq!(third, 3);
+
+        let t = sequence(state, &[1, 2, 3]);
+        let rest: (i64, i64) = call(state, "unpack", (t.clone(), 2)).unwrap();
+        assert_eq!(rest, (2, 3));
+        let padded: MultiValue = call(state, "unpack", (t.clone(), 3, 4)).unwrap();
+        assert_eq!(padded, MultiValue::from([Value::Integer(3), Value::Nil]));
+        let none: MultiValue = call(state, "unpack", (t.clone(), 1, 0)).unwrap();
+        assert!(none.is_empty());
+        assert_eq!(
+            call_err(state, "unpack", (t, 1, i64::MAX)),
+            "too many results to unpack"
+        );
+    }
+
+    #[test]
+    fn move_elements() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let t = sequence(state, &[1, 2, 3]);
+        call::<_, Table>(state, "move", (t.clone(), 1, 3, 2)).unwrap();
+        assert_eq!(values(state, &t), [1, 1, 2, 3]);
+        call::<_, Table>(state, "move", (t.clone(), 2, 4, 1)).unwrap();
+        assert_eq!(values(state, &t), [1, 2, 3, 3]);
+        let dst = state.create_table_value(0, 0).unwrap();
+        let moved: Table = call(state, "move", (t.clone(), 1, 2, 1, dst.clone())).unwrap();
+        assert_eq!(moved, dst);
+        assert_eq!(values(state, &dst), [1, 2]);
+        assert_eq!(
+            call_err(state, "move", (t.clone(), -1, i64::MAX, 1)),
+            "bad argument #3 to 'move' (too many elements to move)"
+        );
+        assert_eq!(
+            call_err(state, "move", (t, 1, 2, i64::MAX)),
+            "bad argument #4 to 'move' (destination wrap around)"
+        );
+    }
+
+    #[test]
+    fn meta_methods_are_honoured() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let backing = sequence(state, &[3, 1, 2]);
+        let proxy = state.create_table_value(0, 0).unwrap();
+        let metatable = state.create_table_value(0, 3).unwrap();
+        metatable.raw_set(state, "__index", backing.clone()).unwrap();
+        metatable.raw_set(state, "__newindex", backing.clone()).unwrap();
+        let target = backing.clone();
+        let len = state.create_function(move |state, _: Value| Ok(target.raw_len(state))).unwrap();
+        metatable.raw_set(state, "__len", len).unwrap();
+        proxy.set_metatable(state, Some(&metatable));
+
+        call::<_, ()>(state, "sort", proxy.clone()).unwrap();
+        assert_eq!(values(state, &backing), [1, 2, 3]);
+        call::<_, ()>(state, "insert", (proxy.clone(), 4)).unwrap();
+        assert_eq!(values(state, &backing), [1, 2, 3, 4]);
+        assert_eq!(proxy.raw_len(state), 0);
+        let text: String = call(state, "concat", (proxy.clone(), ",")).unwrap();
+        assert_eq!(text, "1,2,3,4");
+        let removed: i64 = call(state, "remove", proxy).unwrap();
+        assert_eq!(removed, 4);
+    }
+
+    #[test]
+    fn sort_orders() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let mut seed: i64 = 7;
+        let mut random: Vec<i64> = (0..1000)
+            .map(|_| {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (seed >> 33) % 500
+            })
+            .collect();
+        let t = sequence(state, &random);
+        call::<_, ()>(state, "sort", t.clone()).unwrap();
+        random.sort();
+        assert_eq!(values(state, &t), random);
+
+        let greater = state.create_function(|_, (a, b): (i64, i64)| Ok(a > b)).unwrap();
+        call::<_, ()>(state, "sort", (t.clone(), greater)).unwrap();
+        random.reverse();
+        assert_eq!(values(state, &t), random);
+
+        let words = state.create_table_value(3, 0).unwrap();
+        for (i, word) in ["pear", "apple", "fig"].iter().enumerate() {
+            words.raw_set(state, i as i64 + 1, *word).unwrap();
+        }
+        call::<_, ()>(state, "sort", words.clone()).unwrap();
+        let sorted: Vec<String> = words.sequence_values(state).collect::<LuaResult<_>>().unwrap();
+        assert_eq!(sorted, ["apple", "fig", "pear"]);
+
+        // the heapsort of an exhausted depth
+        let shuffled: Vec<i64> = (0..200).map(|i| (i * 37) % 200).collect();
+        let t = sequence(state, &shuffled);
+        Sorter {
+            state,
+            table: Value::Table(t.clone()),
+            comp: Value::Nil,
+        }
+        .sort(1, 200, 0);
+        assert_eq!(values(state, &t), (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sort_errors() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let t = sequence(state, &(0..100).collect::<Vec<_>>());
+        let always = state.create_function(|_, (_a, _b): (i64, i64)| Ok(true)).unwrap();
+        assert_eq!(
+            call_err(state, "sort", (t.clone(), always)),
+            "invalid order function for sorting"
+        );
+        let mixed = state.create_table_value(3, 0).unwrap();
+        mixed.raw_set(state, 1, 1).unwrap();
+        mixed.raw_set(state, 2, "x").unwrap();
+        mixed.raw_set(state, 3, 2).unwrap();
+        assert!(call_err(state, "sort", mixed).starts_with("attempt to compare"));
+        assert_eq!(
+            call_err(state, "sort", (t, 1)),
+            "bad argument #2 to 'sort' (function expected, got number)"
+        );
+    }
+}
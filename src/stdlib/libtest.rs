@@ -0,0 +1,22 @@
+use crate::common::lua::LuaResult;
+use crate::common::obj::objconv::{IntoLuaMulti, MultiValue};
+use crate::common::obj::objvalue::{Function, Table};
+use crate::common::state::statedef::LuaState;
+
+/// brief: call a function of the libraries by name, "print" for a global
+/// and "string.format" for a field of a library table
+pub(crate) fn call<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<MultiValue> {
+    let function: Function = match name.split_once('.') {
+        Some((lib, name)) => {
+            let lib: Table = state.get_global(lib)?;
+            lib.get(state, name)?
+        }
+        None => state.get_global(name)?,
+    };
+    function.call(state, args)
+}
+
+/// brief: the message of the error a call raises
+pub(crate) fn call_err<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> String {
+    call(state, name, args).unwrap_err().to_string()
+}
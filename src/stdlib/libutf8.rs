@@ -0,0 +1,471 @@
+use crate::common::lua::LuaError;
+use crate::common::obj::objtype::{INT, LRFUNC};
+use crate::common::obj::objvalue::{Function, Value};
+use crate::common::state::statedef::LuaState;
+
+use super::libaux::{arg_error, check_integer, check_lstring, new_lib, opt_integer};
+
+const MAXUNICODE: u32 = 0x10FFFF;
+const MAXUTF: u32 = 0x7FFFFFFF;
+
+const MSG_INVALID: &str = "invalid UTF-8 code";
+
+/// brief: the pattern of exactly one utf-8 byte sequence
+const UTF8PATT: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
+
+const UTF8_FUNCS: &[(&str, LRFUNC)] = &[
+    ("char", utf_char),
+    ("codepoint", codepoint),
+    ("codes", iter_codes),
+    ("len", utf_len),
+    ("offset", byte_offset),
+];
+
+/// brief: put the utf8 library in the global `utf8`
+pub fn open_utf8(state: &mut LuaState) {
+    let lib = new_lib(state, UTF8_FUNCS);
+    let _ = state.raw_set(&lib, Value::from("charpattern"), Value::String(UTF8PATT.into()));
+    let globals = state.globals();
+    let _ = state.raw_set(&globals, Value::from("utf8"), Value::Table(lib));
+}
+
+fn is_cont(c: u8) -> bool {
+    c & 0xC0 == 0x80
+}
+
+/// brief: the byte at `i`, the terminating 0 of C past the end
+fn byte_at(s: &[u8], i: usize) -> u8 {
+    s.get(i).copied().unwrap_or(0)
+}
+
+/// brief: a relative position, negative ones count from the end
+fn u_posrelat(pos: INT, len: usize) -> INT {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as INT + pos + 1
+    }
+}
+
+/// brief: decode the sequence at `i`, the code and the position after it.
+/// `strict` refuses the surrogates and the codes beyond MAXUNICODE
+fn utf8_decode(s: &[u8], i: usize, strict: bool) -> Option<(u32, usize)> {
+    const LIMITS: [u32; 6] = [!0, 0x80, 0x800, 0x10000, 0x200000, 0x4000000];
+    let mut c = byte_at(s, i) as u32;
+    let mut res: u32 = 0;
+    let mut count = 0;
+    if c < 0x80 {
+        res = c;
+    } else {
+        // the bits of the first byte tell the continuation bytes
+        while c & 0x40 != 0 {
+            count += 1;
+            let cc = byte_at(s, i + count);
+            if !is_cont(cc) {
+                return None;
+            }
+            res = (res << 6) | (cc & 0x3F) as u32;
+            c <<= 1;
+        }
+        res |= (c & 0x7F) << (count * 5);
+        // an overlong sequence is invalid
+        if count > 5 || res > MAXUTF || res < LIMITS[count] {
+            return None;
+        }
+    }
+    if strict && (res > MAXUNICODE || (0xD800..=0xDFFF).contains(&res)) {
+        return None;
+    }
+    Some((res, i + count + 1))
+}
+
+/// brief: the utf-8 sequence of a code up to MAXUTF, in up to 6 bytes
+pub(crate) fn utf8_esc(mut x: u32) -> Vec<u8> {
+    if x < 0x80 {
+        return vec![x as u8];
+    }
+    let mut bytes = Vec::with_capacity(6);
+    let mut mfb: u32 = 0x3F; // the most the first byte holds
+    loop {
+        bytes.push(0x80 | (x & 0x3F) as u8);
+        x >>= 6;
+        mfb >>= 1;
+        if x <= mfb {
+            break;
+        }
+    }
+    bytes.push(((!mfb << 1) | x) as u8);
+    bytes.reverse();
+    bytes
+}
+
+/// brief: utf8.len(s [, i [, j [, lax]]]), the count of characters between
+/// i and j, or fail and the position of the first invalid byte
+fn utf_len(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "len");
+    let s = s.as_bytes();
+    let len = s.len() as INT;
+    let posi = u_posrelat(opt_integer(state, 2, "len", 1), s.len());
+    let posj = u_posrelat(opt_integer(state, 3, "len", -1), s.len());
+    let lax = state.get_value(4).is_truthy();
+    if !(1 <= posi && posi - 1 <= len) {
+        arg_error(state, 2, "len", "initial position out of bounds");
+    }
+    if posj > len {
+        arg_error(state, 3, "len", "final position out of bounds");
+    }
+    let (mut posi, posj) = (posi - 1, posj - 1);
+    let mut n = 0;
+    while posi <= posj {
+        match utf8_decode(s, posi as usize, !lax) {
+            Some((_, next)) => posi = next as INT,
+            None => {
+                state.push_nil();
+                state.push_integer(posi + 1);
+                return 2;
+            }
+        }
+        n += 1;
+    }
+    state.push_integer(n);
+    1
+}
+
+/// brief: utf8.codepoint(s [, i [, j [, lax]]]), the codes of the
+/// characters that start between i and j
+fn codepoint(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "codepoint");
+    let s = s.as_bytes();
+    let posi = u_posrelat(opt_integer(state, 2, "codepoint", 1), s.len());
+    let pose = u_posrelat(opt_integer(state, 3, "codepoint", posi), s.len());
+    let lax = state.get_value(4).is_truthy();
+    if posi < 1 {
+        arg_error(state, 2, "codepoint", "out of bounds");
+    }
+    if pose > s.len() as INT {
+        arg_error(state, 3, "codepoint", "out of bounds");
+    }
+    if posi > pose {
+        return 0;
+    }
+    if pose - posi >= i32::MAX as INT || state.stack_check((pose - posi + 1) as usize).is_err() {
+        state.error(LuaError::Runtime("string slice too long".to_string()));
+    }
+    let mut n = 0;
+    let mut i = (posi - 1) as usize;
+    while i < pose as usize {
+        match utf8_decode(s, i, !lax) {
+            Some((code, next)) => {
+                state.push_integer(code as INT);
+                i = next;
+            }
+            None => state.error(LuaError::Runtime(MSG_INVALID.to_string())),
+        }
+        n += 1;
+    }
+    n
+}
+
+fn check_utf_char(state: &mut LuaState, arg: isize) -> Vec<u8> {
+    let code = check_integer(state, arg, "char") as u64;
+    if code > MAXUTF as u64 {
+        arg_error(state, arg, "char", "value out of range");
+    }
+    utf8_esc(code as u32)
+}
+
+/// brief: utf8.char(...), the string of the codes
+fn utf_char(state: &mut LuaState) -> usize {
+    let n = state.get_top() as isize;
+    let mut bytes = Vec::with_capacity(n as usize);
+    for arg in 1..=n {
+        bytes.extend(check_utf_char(state, arg));
+    }
+    state.push_string(&bytes);
+    1
+}
+
+/// brief: utf8.offset(s, n [, i]), the position of the n-th character from
+/// i, the start of the character at i for a 0 n
+fn byte_offset(state: &mut LuaState) -> usize {
+    let s = check_lstring(state, 1, "offset");
+    let s = s.as_bytes();
+    let len = s.len() as INT;
+    let mut n = check_integer(state, 2, "offset");
+    let default = if n >= 0 { 1 } else { len + 1 };
+    let posi = u_posrelat(opt_integer(state, 3, "offset", default), s.len());
+    if !(1 <= posi && posi - 1 <= len) {
+        arg_error(state, 3, "offset", "position out of bounds");
+    }
+    let mut posi = posi - 1;
+    let cont = |i: INT| is_cont(byte_at(s, i as usize));
+    if n == 0 {
+        // the beginning of the current sequence
+        while posi > 0 && cont(posi) {
+            posi -= 1;
+        }
+    } else {
+        if cont(posi) {
+            state.error(LuaError::Runtime(
+                "initial position is a continuation byte".to_string(),
+            ));
+        }
+        if n < 0 {
+            while n < 0 && posi > 0 {
+                // the beginning of the previous character
+                posi -= 1;
+                while posi > 0 && cont(posi) {
+                    posi -= 1;
+                }
+                n += 1;
+            }
+        } else {
+            // the first character is where we are
+            n -= 1;
+            while n > 0 && posi < len {
+                posi += 1;
+                while cont(posi) {
+                    posi += 1;
+                }
+                n -= 1;
+            }
+        }
+    }
+    if n == 0 {
+        state.push_integer(posi + 1);
+    } else {
+        state.push_nil();
+    }
+    1
+}
+
+/// brief: the step of utf8.codes, the position and the code of the
+/// character after the one at the control position
+fn iter_aux(state: &mut LuaState, strict: bool) -> usize {
+    let s = check_lstring(state, 1, "for iterator");
+    let s = s.as_bytes();
+    let mut n = match state.get_value(2) {
+        Value::Integer(n) => n as u64,
+        _ => 0,
+    };
+    if n < s.len() as u64 {
+        // skip the continuation bytes of the current character
+        while is_cont(byte_at(s, n as usize)) {
+            n += 1;
+        }
+    }
+    // a negative control is past the end as well
+    if n >= s.len() as u64 {
+        return 0;
+    }
+    match utf8_decode(s, n as usize, strict) {
+        Some((code, next)) if !is_cont(byte_at(s, next)) => {
+            state.push_integer(n as INT + 1);
+            state.push_integer(code as INT);
+            2
+        }
+        _ => state.error(LuaError::Runtime(MSG_INVALID.to_string())),
+    }
+}
+
+fn iter_aux_strict(state: &mut LuaState) -> usize {
+    iter_aux(state, true)
+}
+
+fn iter_aux_lax(state: &mut LuaState) -> usize {
+    iter_aux(state, false)
+}
+
+/// brief: utf8.codes(s [, lax]), the iterator, s and 0 of a generic for
+fn iter_codes(state: &mut LuaState) -> usize {
+    let lax = state.get_value(2).is_truthy();
+    let s = check_lstring(state, 1, "codes");
+    if is_cont(byte_at(s.as_bytes(), 0)) {
+        arg_error(state, 1, "codes", MSG_INVALID);
+    }
+    let iter: LRFUNC = if lax { iter_aux_lax } else { iter_aux_strict };
+    state.push_value(&Value::Function(Function::light(iter)));
+    state.push_value(&Value::String(s));
+    state.push_integer(0);
+    3
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::lua::LuaResult;
+    use crate::common::obj::objconv::{FromLuaMulti, IntoLuaMulti, MultiValue};
+    use crate::common::obj::objvalue::{Function, LuaString, Table, Value};
+    use crate::common::state::statedef::LuaState;
+    use crate::machine::machdef::Machine;
+
+    use super::utf8_esc;
+
+    fn call<A: IntoLuaMulti, R: FromLuaMulti>(state: &mut LuaState, name: &str, args: A) -> LuaResult<R> {
+        let utf8: Table = state.get_global("utf8")?;
+        let function: Function = utf8.get(state, name)?;
+        function.call(state, args)
+    }
+
+    fn call_err<A: IntoLuaMulti>(state: &mut LuaState, name: &str, args: A) -> String {
+        call::<_, MultiValue>(state, name, args).unwrap_err().to_string()
+    }
+
+    fn bytes(s: &[u8]) -> Value {
+        Value::String(LuaString::new(s))
+    }
+
+    /// brief: the positions and codes utf8.codes gives for `s`
+    fn codes(state: &mut LuaState, s: &[u8], lax: bool) -> LuaResult<Vec<(i64, i64)>> {
+        let (iter, s, mut control): (Function, Value, Value) = call(state, "codes", (bytes(s), lax))?;
+        let mut found = Vec::new();
+        loop {
+            let (pos, code): (Option<i64>, Option<i64>) = iter.call(state, (s.clone(), control))?;
+            match pos.zip(code) {
+                Some((pos, code)) => {
+                    found.push((pos, code));
+                    control = Value::Integer(pos);
+                }
+                None => return Ok(found),
+            }
+        }
+    }
+
+    #[test]
+    fn char_encodes_codes() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let s: Value = call(state, "char", (72, 0xE9, 0x20AC, 0x1F600)).unwrap();
+        assert_eq!(s, bytes("Hé€😀".as_bytes()));
+        let s: Value = call(state, "char", 0x7FFFFFFF).unwrap();
+        assert_eq!(s, bytes(b"\xFD\xBF\xBF\xBF\xBF\xBF"));
+        let s: Value = call(state, "char", ()).unwrap();
+        assert_eq!(s, bytes(b""));
+        assert_eq!(
+            call_err(state, "char", (65, 0x80000000i64)),
+            "bad argument #2 to 'char' (value out of range)"
+        );
+        assert_eq!(utf8_esc(0x7FF), [0xDF, 0xBF]);
+        assert_eq!(utf8_esc(0x800), [0xE0, 0xA0, 0x80]);
+        let utf8: Table = state.get_global("utf8").unwrap();
+        let pattern: Value = utf8.get(state, "charpattern").unwrap();
+        assert_eq!(pattern, bytes(b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*"));
+    }
+
+    #[test]
+    fn len_and_codepoint_validate() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let s = bytes("aé€😀".as_bytes());
+        let n: i64 = call(state, "len", s.clone()).unwrap();
+        assert_eq!(n, 4);
+        let n: i64 = call(state, "len", (s.clone(), 2)).unwrap();
+        assert_eq!(n, 3);
+        let n: i64 = call(state, "len", (s.clone(), -4)).unwrap();
+        assert_eq!(n, 1);
+        // a start in the middle of a character is invalid
+        let res: (Value, i64) = call(state, "len", (s.clone(), 3)).unwrap();
+        assert_eq!(res, (Value::Nil, 3));
+        let codes: (i64, i64, i64, i64) = call(state, "codepoint", (s.clone(), 1, -1)).unwrap();
+        assert_eq!(codes, (97, 0xE9, 0x20AC, 0x1F600));
+        let none: MultiValue = call(state, "codepoint", (s.clone(), 3, 2)).unwrap();
+        assert!(none.is_empty());
+        assert_eq!(
+            call_err(state, "codepoint", (s.clone(), 1, 20)),
+            "bad argument #3 to 'codepoint' (out of bounds)"
+        );
+        assert_eq!(
+            call_err(state, "len", (s.clone(), 20)),
+            "bad argument #2 to 'len' (initial position out of bounds)"
+        );
+
+        // overlong encodings are invalid, lax or not
+        for overlong in [&b"\xC0\x80"[..], b"\xE0\x80\xAF", b"\xF0\x82\x82\xAC"] {
+            let res: (Value, i64) = call(state, "len", (bytes(overlong), 1, -1, true)).unwrap();
+            assert_eq!(res, (Value::Nil, 1));
+        }
+        // the surrogates and the codes past 0x10FFFF only pass with lax
+        for lax_only in [
+            &b"\xED\xA0\x80"[..],
+            b"\xF4\x90\x80\x80",
+            b"\xFD\xBF\xBF\xBF\xBF\xBF",
+        ] {
+            let res: (Value, i64) = call(state, "len", bytes(lax_only)).unwrap();
+            assert_eq!(res, (Value::Nil, 1));
+            let n: i64 = call(state, "len", (bytes(lax_only), 1, -1, true)).unwrap();
+            assert_eq!(n, 1);
+        }
+        let code: i64 = call(state, "codepoint", (bytes(b"\xED\xA0\x80"), 1, 1, true)).unwrap();
+        assert_eq!(code, 0xD800);
+        assert_eq!(
+            call_err(state, "codepoint", bytes(b"\xED\xA0\x80")),
+            "invalid UTF-8 code"
+        );
+        let res: (Value, i64) = call(state, "len", bytes(b"ab\xFF")).unwrap();
+        assert_eq!(res, (Value::Nil, 3));
+        let res: (Value, i64) = call(state, "len", bytes(b"a\xE2\x82")).unwrap();
+        assert_eq!(res, (Value::Nil, 2));
+    }
+
+    #[test]
+    fn codes_iterate() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let found = codes(state, "aé€😀".as_bytes(), false).unwrap();
+        assert_eq!(found, [(1, 97), (2, 0xE9), (4, 0x20AC), (7, 0x1F600)]);
+        assert_eq!(codes(state, b"", false).unwrap(), []);
+        assert_eq!(
+            codes(state, b"a\xED\xA0\x80", false).unwrap_err().to_string(),
+            "invalid UTF-8 code"
+        );
+        assert_eq!(
+            codes(state, b"a\xED\xA0\x80", true).unwrap(),
+            [(1, 97), (2, 0xD800)]
+        );
+        // a stray continuation byte is invalid
+        assert_eq!(
+            codes(state, b"\xC3\xA9\xA9", true).unwrap_err().to_string(),
+            "invalid UTF-8 code"
+        );
+        assert_eq!(
+            codes(state, b"\x80", false).unwrap_err().to_string(),
+            "bad argument #1 to 'codes' (invalid UTF-8 code)"
+        );
+    }
+
+    #[test]
+    fn offsets_move_by_characters() {
+        let mut machine = Machine::new();
+        let state = machine.get_state();
+        let s = bytes("aé€😀".as_bytes());
+        let cases: [(i64, Option<i64>, Option<i64>); 9] = [
+            (1, None, Some(1)),
+            (3, None, Some(4)),
+            (4, None, Some(7)),
+            (5, None, Some(11)),
+            (6, None, None),
+            (-1, None, Some(7)),
+            (-4, None, Some(1)),
+            (-5, None, None),
+            (0, Some(5), Some(4)),
+        ];
+        for (n, i, expected) in cases {
+            let pos: Option<i64> = match i {
+                Some(i) => call(state, "offset", (s.clone(), n, i)).unwrap(),
+                None => call(state, "offset", (s.clone(), n)).unwrap(),
+            };
+            assert_eq!(pos, expected, "offset {} from {:?}", n, i);
+        }
+        let pos: i64 = call(state, "offset", (s.clone(), 2, 4)).unwrap();
+        assert_eq!(pos, 7);
+        assert_eq!(
+            call_err(state, "offset", (s.clone(), 1, 3)),
+            "initial position is a continuation byte"
+        );
+        assert_eq!(
+            call_err(state, "offset", (s, 1, 12)),
+            "bad argument #3 to 'offset' (position out of bounds)"
+        );
+    }
+}
@@ -0,0 +1,14 @@
+pub mod libaux;
+pub mod libbase;
+pub mod libinit;
+pub mod libio;
+pub mod libmath;
+pub mod libos;
+pub mod libpackage;
+pub mod libpack;
+pub mod libpattern;
+pub mod libstring;
+pub mod libtable;
+#[cfg(test)]
+pub(crate) mod libtest;
+pub mod libutf8;